@@ -0,0 +1,623 @@
+// An assembler for the mnemonics the disassembler writes, so a listing can
+// be edited and turned back into a ROM. A line holds an optional `label:`
+// and then an instruction or directive, with `;` starting a comment:
+//
+//     SPEED = 3                ; a constant
+//     loop:
+//         LD V0, SPEED * 2
+//         JP loop
+//     sprite:
+//         db 0b00111100, 0x42
+//
+// `db` and `dw` emit bytes and big endian words, and `db` also takes
+// strings. `include "file"` reads another file, relative to the one
+// including it. Programs are assembled for 0x200, where every platform
+// loads them, and labels and constants can be used before they're defined.
+pub mod octo;
+mod parser;
+
+use crate::debugger::SourceMap;
+use crate::instruction::Instruction;
+use parser::{parse_expr, tokenize, Expr, Location, Token};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::{error, fmt, fs};
+
+/// Where programs are assembled to run.
+pub const ORIGIN: usize = 0x200;
+
+/// An error in the source, with the file, line and column it's at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembleError {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.file, self.line, self.column, self.message
+        )
+    }
+}
+
+impl error::Error for AssembleError {}
+
+/// An assembled program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assembly {
+    pub rom: Vec<u8>,
+    /// The line each instruction came from.
+    pub source_map: SourceMap,
+    /// Where the source asked to stop, and what it called each place.
+    pub breakpoints: Vec<(usize, String)>,
+    /// Memory the source asked to see while stopped: a name for each
+    /// region, its address and its length.
+    pub monitors: Vec<(String, usize, usize)>,
+}
+
+/// Assemble `source`, which was read from `path`. The path is used in
+/// errors and to find included files.
+pub fn assemble(source: &str, path: &Path) -> Result<Assembly, AssembleError> {
+    let mut assembler = Assembler {
+        addr: ORIGIN,
+        statements: Vec::new(),
+        symbols: HashMap::new(),
+        including: Vec::new(),
+    };
+    assembler.read(source, path)?;
+
+    let mut rom = Vec::new();
+    let mut source_map = SourceMap::new();
+    for statement in &assembler.statements {
+        let bytes = assembler.encode(statement)?;
+        if !matches!(statement.mnemonic.as_str(), "DB" | "DW") {
+            let location = &statement.location;
+            source_map.insert(statement.addr, &location.file, location.line);
+        }
+        rom.extend(bytes);
+    }
+    Ok(Assembly {
+        rom,
+        source_map,
+        breakpoints: Vec::new(),
+        monitors: Vec::new(),
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Keyword {
+    I,
+    Dt,
+    St,
+    K,
+    F,
+    Hf,
+    B,
+    R,
+    Audio,
+    Pitch,
+}
+
+impl Keyword {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name.to_ascii_uppercase().as_str() {
+            "I" => Keyword::I,
+            "DT" => Keyword::Dt,
+            "ST" => Keyword::St,
+            "K" => Keyword::K,
+            "F" => Keyword::F,
+            "HF" => Keyword::Hf,
+            "B" => Keyword::B,
+            "R" => Keyword::R,
+            "AUDIO" => Keyword::Audio,
+            "PITCH" => Keyword::Pitch,
+            _ => return None,
+        })
+    }
+}
+
+fn register(name: &str) -> Option<u8> {
+    let digit = name.strip_prefix(['v', 'V'])?;
+    if digit.len() != 1 {
+        return None;
+    }
+    u8::from_str_radix(digit, 16).ok()
+}
+
+/// Names that can't be labels or constants.
+fn is_reserved(name: &str) -> bool {
+    register(name).is_some() || Keyword::parse(name).is_some() || name.eq_ignore_ascii_case("long")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Operand {
+    Register(u8),
+    /// `VX-VY`
+    Range(u8, u8),
+    /// `[I]`
+    Indirect,
+    Keyword(Keyword),
+    /// `LONG NNNN`
+    Long(Expr),
+    Value(Expr),
+    Str(Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+struct Statement {
+    /// Upper case.
+    mnemonic: String,
+    location: Location,
+    operands: Vec<(Operand, Location)>,
+    addr: usize,
+}
+
+impl Statement {
+    fn size(&self) -> usize {
+        match self.mnemonic.as_str() {
+            "DB" => self
+                .operands
+                .iter()
+                .map(|(operand, _)| match operand {
+                    Operand::Str(bytes) => bytes.len(),
+                    _ => 1,
+                })
+                .sum(),
+            "DW" => 2 * self.operands.len(),
+            _ if matches!(self.operands.get(1), Some((Operand::Long(_), _))) => 4,
+            _ => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Symbol {
+    Label(usize),
+    Constant(Expr),
+}
+
+struct Assembler {
+    addr: usize,
+    statements: Vec<Statement>,
+    symbols: HashMap<String, (Symbol, Location)>,
+    // The files being read, to catch files that include themselves
+    including: Vec<PathBuf>,
+}
+
+impl Assembler {
+    /// Read the labels, constants and statements from a file and the ones
+    /// it includes.
+    fn read(&mut self, source: &str, path: &Path) -> Result<(), AssembleError> {
+        let file: Rc<str> = path.display().to_string().into();
+        self.including
+            .push(fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()));
+        for (n, text) in source.lines().enumerate() {
+            let mut tokens = &tokenize(text, &file, n + 1)?[..];
+            let end = Location {
+                file: file.clone(),
+                line: n + 1,
+                column: text.chars().count() + 1,
+            };
+
+            if let [(Token::Name(name), location), (Token::Symbol(":"), _), rest @ ..] = tokens {
+                self.define(name, Symbol::Label(self.addr), location)?;
+                tokens = rest;
+            }
+            if let [(Token::Name(name), location), (Token::Symbol("="), equals), rest @ ..] = tokens
+            {
+                let expr = parse_expr(rest, &end).map_err(|e| match rest.is_empty() {
+                    true => equals.error("expected a value after ="),
+                    false => e,
+                })?;
+                self.define(name, Symbol::Constant(expr), location)?;
+                continue;
+            }
+            let Some(((first, location), rest)) = tokens.split_first() else {
+                continue;
+            };
+            let Token::Name(mnemonic) = first else {
+                return Err(location.error(format!("expected an instruction, found {}", first)));
+            };
+
+            let operands = operands(rest, &end)?;
+            let mnemonic = mnemonic.to_ascii_uppercase();
+            if mnemonic == "INCLUDE" {
+                let [(Operand::Str(name), location)] = &operands[..] else {
+                    return Err(location.error("include expects a file name in quotes"));
+                };
+                let name = String::from_utf8_lossy(name).into_owned();
+                let included = path.parent().unwrap_or(Path::new("")).join(&name);
+                if fs::canonicalize(&included).is_ok_and(|p| self.including.contains(&p)) {
+                    return Err(location.error(format!("{} includes itself", name)));
+                }
+                let text = fs::read_to_string(&included)
+                    .map_err(|e| location.error(format!("can't read {}: {}", name, e)))?;
+                self.read(&text, &included)?;
+                continue;
+            }
+
+            let statement = Statement {
+                mnemonic,
+                location: location.clone(),
+                operands,
+                addr: self.addr,
+            };
+            self.addr += statement.size();
+            self.statements.push(statement);
+        }
+        self.including.pop();
+        Ok(())
+    }
+
+    fn define(
+        &mut self,
+        name: &str,
+        symbol: Symbol,
+        location: &Location,
+    ) -> Result<(), AssembleError> {
+        if is_reserved(name) {
+            return Err(location.error(format!("{} is a reserved word", name)));
+        }
+        if let Some((_, previous)) = self.symbols.get(name) {
+            return Err(location.error(format!(
+                "{} is already defined at {}:{}",
+                name, previous.file, previous.line
+            )));
+        }
+        self.symbols
+            .insert(name.to_string(), (symbol, location.clone()));
+        Ok(())
+    }
+
+    fn eval(&self, expr: &Expr, depth: usize) -> Result<i64, AssembleError> {
+        expr.eval(&mut |name, location| match self.symbols.get(name) {
+            Some((Symbol::Label(addr), _)) => Ok(*addr as i64),
+            // Deep enough that only a cycle gets here
+            Some((Symbol::Constant(_), _)) if depth > 64 => {
+                Err(location.error(format!("{} is defined in terms of itself", name)))
+            }
+            Some((Symbol::Constant(expr), _)) => self.eval(expr, depth + 1),
+            None => Err(location.error(format!("{} isn't defined", name))),
+        })
+    }
+
+    /// Evaluate `expr` and check that it fits in `bits`. Negative numbers
+    /// are allowed down to the signed minimum and stored as two's complement.
+    fn value(&self, expr: &Expr, bits: u32) -> Result<u16, AssembleError> {
+        let value = self.eval(expr, 0)?;
+        if value >= 1 << bits || value < -(1 << (bits - 1)) {
+            return Err(expr
+                .location
+                .error(format!("{} doesn't fit in {} bits", value, bits)));
+        }
+        Ok((value & ((1 << bits) - 1)) as u16)
+    }
+
+    fn encode(&self, statement: &Statement) -> Result<Vec<u8>, AssembleError> {
+        use Instruction::*;
+        use Operand::{Indirect, Keyword as Kw, Long, Range, Register as V, Value};
+
+        let operands: Vec<&Operand> = statement.operands.iter().map(|(o, _)| o).collect();
+        let mnemonic = statement.mnemonic.as_str();
+        let address = |expr| self.value(expr, 12);
+        let byte = |expr| self.value(expr, 8).map(|kk| kk as u8);
+        let nibble = |expr| self.value(expr, 4).map(|n| n as u8);
+
+        match mnemonic {
+            "DB" => {
+                let mut bytes = Vec::new();
+                for (operand, location) in &statement.operands {
+                    match operand {
+                        Operand::Str(s) => bytes.extend(s),
+                        Value(expr) => bytes.push(byte(expr)?),
+                        _ => return Err(location.error("db expects numbers or strings")),
+                    }
+                }
+                return Ok(bytes);
+            }
+            "DW" => {
+                let mut bytes = Vec::new();
+                for (operand, location) in &statement.operands {
+                    match operand {
+                        Value(expr) => bytes.extend(self.value(expr, 16)?.to_be_bytes()),
+                        _ => return Err(location.error("dw expects numbers")),
+                    }
+                }
+                return Ok(bytes);
+            }
+            _ => {}
+        }
+
+        let instruction = match (mnemonic, &operands[..]) {
+            ("SYS", [Value(e)]) => Sys { nnn: address(e)? },
+            ("CLS", []) => Cls,
+            ("RET", []) => Ret,
+            ("SCD", [Value(e)]) => ScrollDown { n: nibble(e)? },
+            ("SCU", [Value(e)]) => ScrollUp { n: nibble(e)? },
+            ("SCR", []) => ScrollRight,
+            ("SCL", []) => ScrollLeft,
+            ("EXIT", []) => Exit,
+            ("LOW", []) => LowRes,
+            ("HIGH", []) => HighRes,
+            ("JP", [Value(e)]) => Jump { nnn: address(e)? },
+            ("JP", [V(0), Value(e)]) => JumpOffset { nnn: address(e)? },
+            ("CALL", [Value(e)]) => Call { nnn: address(e)? },
+            ("SE", [V(x), Value(e)]) => SkipEqImm {
+                x: *x,
+                kk: byte(e)?,
+            },
+            ("SE", [V(x), V(y)]) => SkipEqReg { x: *x, y: *y },
+            ("SNE", [V(x), Value(e)]) => SkipNeImm {
+                x: *x,
+                kk: byte(e)?,
+            },
+            ("SNE", [V(x), V(y)]) => SkipNeReg { x: *x, y: *y },
+            ("LD", [V(x), Value(e)]) => LoadImm {
+                x: *x,
+                kk: byte(e)?,
+            },
+            ("LD", [V(x), V(y)]) => Move { x: *x, y: *y },
+            ("LD", [Kw(Keyword::I), Value(e)]) => LoadI { nnn: address(e)? },
+            ("LD", [Kw(Keyword::I), Long(e)]) => {
+                let nnnn = self.value(e, 16)?;
+                let mut bytes = LoadILong.encode().to_be_bytes().to_vec();
+                bytes.extend(nnnn.to_be_bytes());
+                return Ok(bytes);
+            }
+            ("LD", [V(x), Kw(Keyword::Dt)]) => LoadDelay { x: *x },
+            ("LD", [V(x), Kw(Keyword::K)]) => WaitKey { x: *x },
+            ("LD", [Kw(Keyword::Dt), V(x)]) => SetDelay { x: *x },
+            ("LD", [Kw(Keyword::St), V(x)]) => SetSound { x: *x },
+            ("LD", [Kw(Keyword::F), V(x)]) => LoadFont { x: *x },
+            ("LD", [Kw(Keyword::Hf), V(x)]) => LoadBigFont { x: *x },
+            ("LD", [Kw(Keyword::B), V(x)]) => StoreBcd { x: *x },
+            ("LD", [Indirect, V(x)]) => StoreRegs { x: *x },
+            ("LD", [V(x), Indirect]) => LoadRegs { x: *x },
+            ("LD", [Indirect, Range(x, y)]) => StoreRange { x: *x, y: *y },
+            ("LD", [Range(x, y), Indirect]) => LoadRange { x: *x, y: *y },
+            ("LD", [Kw(Keyword::R), V(x)]) => StoreFlags { x: *x },
+            ("LD", [V(x), Kw(Keyword::R)]) => LoadFlags { x: *x },
+            ("LD", [Kw(Keyword::Audio), Indirect]) => LoadAudio,
+            ("LD", [Kw(Keyword::Pitch), V(x)]) => SetPitch { x: *x },
+            ("ADD", [V(x), Value(e)]) => AddImm {
+                x: *x,
+                kk: byte(e)?,
+            },
+            ("ADD", [V(x), V(y)]) => Add { x: *x, y: *y },
+            ("ADD", [Kw(Keyword::I), V(x)]) => AddI { x: *x },
+            ("OR", [V(x), V(y)]) => Or { x: *x, y: *y },
+            ("AND", [V(x), V(y)]) => And { x: *x, y: *y },
+            ("XOR", [V(x), V(y)]) => Xor { x: *x, y: *y },
+            ("SUB", [V(x), V(y)]) => Sub { x: *x, y: *y },
+            ("SUBN", [V(x), V(y)]) => SubN { x: *x, y: *y },
+            ("SHR", [V(x)]) => ShiftRight { x: *x, y: 0 },
+            ("SHR", [V(x), V(y)]) => ShiftRight { x: *x, y: *y },
+            ("SHL", [V(x)]) => ShiftLeft { x: *x, y: 0 },
+            ("SHL", [V(x), V(y)]) => ShiftLeft { x: *x, y: *y },
+            ("RND", [V(x), Value(e)]) => Random {
+                x: *x,
+                kk: byte(e)?,
+            },
+            ("DRW", [V(x), V(y), Value(e)]) => Draw {
+                x: *x,
+                y: *y,
+                n: nibble(e)?,
+            },
+            ("SKP", [V(x)]) => SkipKey { x: *x },
+            ("SKNP", [V(x)]) => SkipNotKey { x: *x },
+            ("PLANE", [Value(e)]) => Plane { n: nibble(e)? },
+            _ if MNEMONICS.contains(&mnemonic) => {
+                return Err(statement
+                    .location
+                    .error(format!("invalid operands for {}", mnemonic)))
+            }
+            _ => {
+                return Err(statement
+                    .location
+                    .error(format!("unknown instruction {}", mnemonic)))
+            }
+        };
+        Ok(instruction.encode().to_be_bytes().to_vec())
+    }
+}
+
+const MNEMONICS: [&str; 28] = [
+    "SYS", "CLS", "RET", "SCD", "SCU", "SCR", "SCL", "EXIT", "LOW", "HIGH", "JP", "CALL", "SE",
+    "SNE", "LD", "ADD", "OR", "AND", "XOR", "SUB", "SUBN", "SHR", "SHL", "RND", "DRW", "SKP",
+    "SKNP", "PLANE",
+];
+
+/// Split the tokens after a mnemonic into operands at the commas.
+fn operands(
+    tokens: &[(Token, Location)],
+    end: &Location,
+) -> Result<Vec<(Operand, Location)>, AssembleError> {
+    if tokens.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut operands = Vec::new();
+    let mut parts = tokens.split(|(token, _)| *token == Token::Symbol(","));
+    let commas: Vec<&Location> = tokens
+        .iter()
+        .filter(|(token, _)| *token == Token::Symbol(","))
+        .map(|(_, location)| location)
+        .chain([end])
+        .collect();
+    for (n, part) in parts.by_ref().enumerate() {
+        let Some((_, location)) = part.first() else {
+            return Err(commas[n].error("expected an operand"));
+        };
+        let operand = match part {
+            [(Token::Name(name), _)] if register(name).is_some() => {
+                Operand::Register(register(name).unwrap_or(0))
+            }
+            [(Token::Name(x), _), (Token::Symbol("-"), _), (Token::Name(y), _)]
+                if register(x).is_some() && register(y).is_some() =>
+            {
+                Operand::Range(register(x).unwrap_or(0), register(y).unwrap_or(0))
+            }
+            [(Token::Symbol("["), _), (Token::Name(i), _), (Token::Symbol("]"), _)]
+                if i.eq_ignore_ascii_case("i") =>
+            {
+                Operand::Indirect
+            }
+            [(Token::Name(name), _)] if Keyword::parse(name).is_some() => {
+                Operand::Keyword(Keyword::parse(name).unwrap_or(Keyword::I))
+            }
+            [(Token::Name(long), _), rest @ ..] if long.eq_ignore_ascii_case("long") => {
+                Operand::Long(parse_expr(rest, commas[n])?)
+            }
+            [(Token::Str(bytes), _)] => Operand::Str(bytes.clone()),
+            _ => Operand::Value(parse_expr(part, commas[n])?),
+        };
+        operands.push((operand, location.clone()));
+    }
+    Ok(operands)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{assemble, AssembleError};
+    use crate::disassembler::{Disassembly, Syntax};
+    use crate::instruction::Instruction;
+    use std::fs;
+    use std::mem;
+    use std::path::{Path, PathBuf};
+
+    fn error(source: &str) -> String {
+        assemble(source, Path::new("test.asm"))
+            .map(|_| String::new())
+            .unwrap_or_else(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_assemble() {
+        let dir = std::env::temp_dir().join("virtual_machine_test_assemble");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("sprites.asm"), "ball: db 0b11000000, \"A\"\n").unwrap();
+        let source = "\
+SPEED = TOP + 1        ; used before TOP is defined
+TOP = 2 * (3 + 4) - 1
+main:
+    LD V0, SPEED
+    ld va, -1
+    LD I, ball
+    LD [I], V0-V3
+    LD I, LONG table
+    DRW V0, VA, 1 << 1
+    JP V0, main + 2
+table:
+    dw 0x1234, table
+include \"sprites.asm\"
+";
+        let assembly = assemble(source, &dir.join("main.asm")).unwrap();
+        assert_eq!(
+            assembly.rom,
+            [
+                0x60, 0x0e, 0x6a, 0xff, 0xa2, 0x14, 0x50, 0x32, 0xf0, 0x00, 0x02, 0x10, 0xd0, 0xa2,
+                0xb2, 0x02, 0x12, 0x34, 0x02, 0x10, 0xc0, 0x41
+            ]
+        );
+        let main = dir.join("main.asm").display().to_string();
+        assert_eq!(assembly.source_map.location(0x208), Some((&main[..], 8)));
+        assert_eq!(assembly.source_map.location(0x210), None);
+
+        assert_eq!(
+            error("  LD V0, 0x100"),
+            "test.asm:1:10: 256 doesn't fit in 8 bits"
+        );
+        assert_eq!(
+            error("\n  JP nowhere"),
+            "test.asm:2:6: nowhere isn't defined"
+        );
+        assert_eq!(
+            error("  MOV V0, V1"),
+            "test.asm:1:3: unknown instruction MOV"
+        );
+        assert_eq!(error("  SKP 3"), "test.asm:1:3: invalid operands for SKP");
+        assert_eq!(error("  LD V0, (1 + 2"), "test.asm:1:16: expected )");
+        assert_eq!(error("  db 1,, 2"), "test.asm:1:8: expected an operand");
+        assert_eq!(
+            error("a = c\nc = a\n  JP a"),
+            "test.asm:1:5: c is defined in terms of itself"
+        );
+        assert_eq!(
+            error("x:\nx: CLS"),
+            "test.asm:2:1: x is already defined at test.asm:1"
+        );
+        assert_eq!(error("dt = 3"), "test.asm:1:1: dt is a reserved word");
+        assert_eq!(
+            assemble("include \"missing.asm\"", Path::new("test.asm")).map(|a| a.rom),
+            Err(AssembleError {
+                file: "test.asm".to_string(),
+                line: 1,
+                column: 9,
+                message: "can't read missing.asm: No such file or directory (os error 2)"
+                    .to_string(),
+            })
+        );
+    }
+
+    /// One of each instruction and a spread of operands, then code that
+    /// ends the flow and data that is never run.
+    pub(crate) fn every_instruction() -> Vec<u8> {
+        let mut rom = Vec::new();
+        let mut seen = Vec::new();
+        for opcode in 0..=u16::MAX {
+            let Ok(instruction) = Instruction::decode(opcode) else {
+                continue;
+            };
+            let variant = mem::discriminant(&instruction);
+            let diverts = matches!(opcode >> 12, 0x1 | 0x2 | 0xb);
+            if !diverts
+                && !matches!(opcode, 0x00ee | 0x00fd | 0xf000)
+                && (!seen.contains(&variant) || opcode % 97 == 0)
+            {
+                seen.push(variant);
+                rom.extend(opcode.to_be_bytes());
+            }
+        }
+        rom.extend([
+            0xf0, 0x00, 0x12, 0x34, 0x22, 0x00, 0x1f, 0xff, 0x00, 0xee, 0xff, 0xff, 0x01,
+        ]);
+        rom
+    }
+
+    /// The ROMs in `test-roms/` with their paths, as a corpus of real
+    /// programs for round trips.
+    pub(crate) fn test_roms() -> Vec<(PathBuf, Vec<u8>)> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test-roms");
+        let mut roms: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|e| e == "ch8"))
+            .map(|path| {
+                let rom = fs::read(&path).unwrap();
+                (path, rom)
+            })
+            .collect();
+        roms.sort();
+        assert!(!roms.is_empty());
+        roms
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let rom = every_instruction();
+        let listing = Disassembly::new(&rom, 0x200).listing(Syntax::Plain);
+        let assembly = assemble(&listing, Path::new("listing.asm")).unwrap();
+        assert_eq!(assembly.rom, rom);
+
+        for (path, rom) in test_roms() {
+            let listing = Disassembly::new(&rom, 0x200).listing(Syntax::Plain);
+            let assembly = assemble(&listing, Path::new("listing.asm")).unwrap();
+            assert_eq!(assembly.rom, rom, "{}", path.display());
+        }
+    }
+}
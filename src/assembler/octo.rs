@@ -0,0 +1,995 @@
+// A compiler for Octo, the assembly language most CHIP-8 homebrew is
+// written in, so `.8o` files run without an Octo install. Source is a
+// stream of whitespace separated tokens, with `#` starting a comment:
+//
+//     :const SPEED 2
+//     : main
+//         v0 := 0
+//         loop
+//             v0 += SPEED
+//             if v0 == 0x40 then v0 := 0
+//         again
+//
+// It covers labels, `:alias`, `:const`, `:calc` (evaluated right to left
+// with no precedence, like Octo), `:macro`, `:unpack`, `:next`, `:org`,
+// `:byte` and `:call`, the structured `loop`/`while`/`again` and
+// `if ... then` or `if ... begin ... else ... end`, the comparisons that go
+// through vf, and every SUPER-CHIP and XO-CHIP instruction. `:breakpoint`
+// and `:monitor` are kept for a debugger to pick up.
+//
+// As in Octo, 0x200 holds a jump to `main` unless `main` comes first.
+use super::parser::Location;
+use super::{AssembleError, Assembly, ORIGIN};
+use crate::debugger::SourceMap;
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+
+/// Compile Octo `source`, which was read from `path`.
+pub fn compile(source: &str, path: &Path) -> Result<Assembly, AssembleError> {
+    let file: Rc<str> = path.display().to_string().into();
+    let mut tokens = tokenize(source, &file)?;
+    let start = Location {
+        file: file.clone(),
+        line: 1,
+        column: 1,
+    };
+    let end = Location {
+        file,
+        line: source.lines().count().max(1),
+        column: source.lines().last().map_or(0, |line| line.chars().count()) + 1,
+    };
+    tokens.reverse();
+
+    let mut compiler = Compiler {
+        tokens,
+        end,
+        rom: Vec::new(),
+        here: ORIGIN,
+        jump_to_main: true,
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::new(),
+        macros: HashMap::new(),
+        fixups: Vec::new(),
+        loops: Vec::new(),
+        branches: Vec::new(),
+        expansions: 0,
+        statement: start.clone(),
+        source_map: SourceMap::new(),
+        breakpoints: Vec::new(),
+        monitors: Vec::new(),
+    };
+    // Filled in with a jump to main at the end, or dropped if main is first
+    compiler.write(ORIGIN, &[0x10, 0x00]);
+    compiler.here += 2;
+    while let Some(token) = compiler.tokens.pop() {
+        compiler.statement = token.1.clone();
+        compiler.statement(token)?;
+    }
+    compiler.finish(&start)
+}
+
+type Token = (String, Location);
+
+/// Split the source into tokens, keeping quoted strings whole.
+fn tokenize(source: &str, file: &Rc<str>) -> Result<Vec<Token>, AssembleError> {
+    let mut tokens = Vec::new();
+    for (n, line) in source.lines().enumerate() {
+        let chars: Vec<char> = line.chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            let location = Location {
+                file: file.clone(),
+                line: n + 1,
+                column: i + 1,
+            };
+            if chars[i].is_whitespace() {
+                i += 1;
+                continue;
+            }
+            if chars[i] == '#' {
+                break;
+            }
+            let start = i;
+            if chars[i] == '"' {
+                i += 1;
+                while i < chars.len() && chars[i] != '"' {
+                    i += 1;
+                }
+                if i == chars.len() {
+                    return Err(location.error("unterminated string"));
+                }
+                i += 1;
+            } else {
+                while i < chars.len() && !chars[i].is_whitespace() {
+                    i += 1;
+                }
+            }
+            tokens.push((chars[start..i].iter().collect(), location));
+        }
+    }
+    Ok(tokens)
+}
+
+fn number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let n = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()?
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -n } else { n })
+}
+
+fn register(text: &str) -> Option<u8> {
+    let digit = text.strip_prefix(['v', 'V'])?;
+    if digit.len() != 1 {
+        return None;
+    }
+    u8::from_str_radix(digit, 16).ok()
+}
+
+/// Words that mean something on their own, so can't be names.
+const KEYWORDS: [&str; 42] = [
+    "i", ":=", "+=", "-=", "=-", "|=", "&=", "^=", ">>=", "<<=", "==", "!=", "<", ">", "<=", ">=",
+    "return", ";", "clear", "bcd", "save", "load", "sprite", "jump", "jump0", "native", "exit",
+    "lores", "hires", "loop", "again", "while", "if", "then", "begin", "else", "end", "key",
+    "-key", "random", "delay", "buzzer",
+];
+
+const CALC_OPERATORS: [&str; 16] = [
+    "+", "-", "*", "/", "%", "&", "|", "^", "<<", ">>", "<", ">", "<=", ">=", "==", "!=",
+];
+
+#[derive(Debug, Clone)]
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+}
+
+/// How to patch a reference to a label that wasn't defined yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Fixup {
+    /// The low 12 bits of the instruction.
+    Address,
+    /// The 16 bit word after `F000`.
+    Long,
+    /// The two loads `:unpack` makes, with the nibble it was given.
+    Unpack(u8),
+}
+
+/// A number, or a label that isn't defined yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    Known(i64),
+    Label(String),
+}
+
+/// The right hand side of a comparison.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operand {
+    Register(u8),
+    Byte(u8),
+}
+
+#[derive(Debug, Clone)]
+struct Condition {
+    x: u8,
+    op: String,
+    operand: Option<Operand>,
+}
+
+struct Compiler {
+    // Tokens still to read, the next one last
+    tokens: Vec<Token>,
+    end: Location,
+    rom: Vec<u8>,
+    here: usize,
+    jump_to_main: bool,
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, i64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<(usize, Fixup, String, Location)>,
+    // Open `loop`s: where each starts and the jumps its `while`s made
+    loops: Vec<(usize, Vec<usize>, Location)>,
+    // Open `begin`s and `else`s, by the jump the next `else` or `end` patches
+    branches: Vec<(usize, Location)>,
+    expansions: usize,
+    // Where the statement being compiled starts, for the source map
+    statement: Location,
+    source_map: SourceMap,
+    breakpoints: Vec<(usize, String)>,
+    monitors: Vec<(String, Value, usize, Location)>,
+}
+
+impl Compiler {
+    fn next(&mut self) -> Result<Token, AssembleError> {
+        self.tokens
+            .pop()
+            .ok_or_else(|| self.end.error("unexpected end of file"))
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.last().map(|(text, _)| text.as_str())
+    }
+
+    fn expect(&mut self, word: &str) -> Result<(), AssembleError> {
+        let (text, location) = self.next()?;
+        if text != word {
+            return Err(location.error(format!("expected {}, found {}", word, text)));
+        }
+        Ok(())
+    }
+
+    /// A new name for a label, constant, alias or macro.
+    fn name(&mut self) -> Result<Token, AssembleError> {
+        let (text, location) = self.next()?;
+        if number(&text).is_some()
+            || register(&text).is_some()
+            || KEYWORDS.contains(&text.as_str())
+            || text.starts_with([':', '"', '{', '}'])
+        {
+            return Err(location.error(format!("{} can't be used as a name", text)));
+        }
+        if self.labels.contains_key(&text)
+            || self.constants.contains_key(&text)
+            || self.aliases.contains_key(&text)
+            || self.macros.contains_key(&text)
+        {
+            return Err(location.error(format!("{} is already defined", text)));
+        }
+        Ok((text, location))
+    }
+
+    fn register(&mut self) -> Result<u8, AssembleError> {
+        let (text, location) = self.next()?;
+        self.as_register(&text)
+            .ok_or_else(|| location.error(format!("expected a register, found {}", text)))
+    }
+
+    fn as_register(&self, text: &str) -> Option<u8> {
+        register(text).or_else(|| self.aliases.get(text).copied())
+    }
+
+    fn value(&mut self) -> Result<(Value, Location), AssembleError> {
+        let (text, location) = self.next()?;
+        if text == "{" {
+            return Ok((Value::Known(self.calc()?), location));
+        }
+        if let Some(n) = number(&text).or_else(|| self.constants.get(&text).copied()) {
+            return Ok((Value::Known(n), location));
+        }
+        if let Some(&addr) = self.labels.get(&text) {
+            return Ok((Value::Known(addr as i64), location));
+        }
+        if self.as_register(&text).is_some()
+            || KEYWORDS.contains(&text.as_str())
+            || text.starts_with([':', '"', '}'])
+        {
+            return Err(location.error(format!("expected a value, found {}", text)));
+        }
+        Ok((Value::Label(text), location))
+    }
+
+    /// A value that has to be known now, in the range `min..=max`.
+    fn known(&mut self, min: i64, max: i64) -> Result<i64, AssembleError> {
+        match self.value()? {
+            (Value::Known(n), location) => check(n, min, max, &location),
+            (Value::Label(name), location) => {
+                Err(location.error(format!("{} isn't defined", name)))
+            }
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, AssembleError> {
+        Ok(self.known(-128, 255)? as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u8, AssembleError> {
+        Ok(self.known(0, 15)? as u8)
+    }
+
+    fn write(&mut self, addr: usize, bytes: &[u8]) {
+        let offset = addr - ORIGIN;
+        if self.rom.len() < offset + bytes.len() {
+            self.rom.resize(offset + bytes.len(), 0);
+        }
+        self.rom[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn emit(&mut self, opcode: u16) {
+        let location = &self.statement;
+        self.source_map
+            .insert(self.here, &location.file, location.line);
+        self.write(self.here, &opcode.to_be_bytes());
+        self.here += 2;
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        self.write(self.here, &[byte]);
+        self.here += 1;
+    }
+
+    /// Emit `base` with a 12 bit address, patched later for a label
+    /// that isn't defined yet.
+    fn emit_address(&mut self, base: u16) -> Result<(), AssembleError> {
+        let addr = match self.value()? {
+            (Value::Known(n), location) => check(n, 0, 0xfff, &location)? as u16,
+            (Value::Label(name), location) => {
+                self.fixups
+                    .push((self.here, Fixup::Address, name, location));
+                0
+            }
+        };
+        self.emit(base | addr);
+        Ok(())
+    }
+
+    fn emit_x(&mut self, base: u16) -> Result<(), AssembleError> {
+        let x = self.register()?;
+        self.emit(base | (x as u16) << 8);
+        Ok(())
+    }
+
+    fn emit_xy(&mut self, base: u16, x: u8, y: u8) {
+        self.emit(base | (x as u16) << 8 | (y as u16) << 4);
+    }
+
+    /// Emit a jump to be patched with `patch` once its target is known.
+    fn emit_jump(&mut self) -> usize {
+        let at = self.here;
+        self.emit(0x1000);
+        at
+    }
+
+    fn patch(&mut self, at: usize, target: usize) {
+        self.write(at, &(0x1000 | target as u16 & 0xfff).to_be_bytes());
+    }
+
+    fn define(&mut self, name: String, location: &Location) -> Result<(), AssembleError> {
+        if self.here > 0xfff {
+            return Err(location.error(format!("{} is past 0xFFF", name)));
+        }
+        self.labels.insert(name, self.here);
+        Ok(())
+    }
+
+    fn statement(&mut self, (text, location): Token) -> Result<(), AssembleError> {
+        match text.as_str() {
+            ":" => {
+                let (name, location) = self.name()?;
+                if name == "main" && self.jump_to_main && self.here == ORIGIN + 2 {
+                    self.rom.clear();
+                    self.here = ORIGIN;
+                    self.jump_to_main = false;
+                }
+                self.define(name, &location)?;
+            }
+            ":next" => {
+                // The byte after, which is the operand of the next instruction
+                let (name, location) = self.name()?;
+                self.here += 1;
+                let result = self.define(name, &location);
+                self.here -= 1;
+                result?;
+            }
+            ":alias" => {
+                let (name, _) = self.name()?;
+                let x = self.register()?;
+                self.aliases.insert(name, x);
+            }
+            ":const" => {
+                let (name, _) = self.name()?;
+                let value = self.known(i64::MIN, i64::MAX)?;
+                self.constants.insert(name, value);
+            }
+            ":calc" => {
+                let (name, _) = self.name()?;
+                self.expect("{")?;
+                let value = self.calc()?;
+                self.constants.insert(name, value);
+            }
+            ":macro" => {
+                let (name, _) = self.name()?;
+                let mut args = Vec::new();
+                loop {
+                    let (arg, location) = self.next()?;
+                    if arg == "{" {
+                        break;
+                    }
+                    if arg.starts_with(':') {
+                        return Err(location.error(format!("expected {{, found {}", arg)));
+                    }
+                    args.push(arg);
+                }
+                let mut body = Vec::new();
+                let mut depth = 0;
+                loop {
+                    let token = self.next()?;
+                    match token.0.as_str() {
+                        "{" => depth += 1,
+                        "}" if depth == 0 => break,
+                        "}" => depth -= 1,
+                        _ => {}
+                    }
+                    body.push(token);
+                }
+                self.macros.insert(name, Macro { args, body });
+            }
+            ":unpack" => {
+                let n = self.nibble()?;
+                let addr = match self.value()? {
+                    (Value::Known(addr), location) => check(addr, 0, 0xfff, &location)? as u16,
+                    (Value::Label(name), location) => {
+                        self.fixups
+                            .push((self.here, Fixup::Unpack(n), name, location));
+                        0
+                    }
+                };
+                self.emit(0x6000 | (n as u16) << 4 | addr >> 8);
+                self.emit(0x6100 | addr & 0xff);
+            }
+            ":breakpoint" => {
+                let (name, _) = self.next()?;
+                self.breakpoints.push((self.here, name));
+            }
+            ":monitor" => {
+                // Named after how the address was written
+                let name = self.peek().unwrap_or_default().to_string();
+                let (addr, location) = self.value()?;
+                // A format string shows one byte per % field
+                let len = match self.peek() {
+                    Some(format) if format.starts_with('"') => self.next()?.0.matches('%').count(),
+                    _ => self.known(0, 0xffff)? as usize,
+                };
+                self.monitors.push((name, addr, len, location));
+            }
+            ":org" => {
+                self.here = self.known(ORIGIN as i64, 0xffff)? as usize;
+            }
+            ":byte" => {
+                let byte = self.byte()?;
+                self.emit_byte(byte);
+            }
+            ":call" => self.emit_address(0x2000)?,
+            "return" | ";" => self.emit(0x00ee),
+            "clear" => self.emit(0x00e0),
+            "exit" => self.emit(0x00fd),
+            "lores" => self.emit(0x00fe),
+            "hires" => self.emit(0x00ff),
+            "scroll-right" => self.emit(0x00fb),
+            "scroll-left" => self.emit(0x00fc),
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.emit(0x00c0 | n as u16);
+            }
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.emit(0x00d0 | n as u16);
+            }
+            "audio" => self.emit(0xf002),
+            "plane" => {
+                let n = self.nibble()?;
+                self.emit(0xf001 | (n as u16) << 8);
+            }
+            "bcd" => self.emit_x(0xf033)?,
+            "saveflags" => self.emit_x(0xf075)?,
+            "loadflags" => self.emit_x(0xf085)?,
+            "save" | "load" => {
+                let x = self.register()?;
+                if self.peek() == Some("-") {
+                    self.next()?;
+                    let y = self.register()?;
+                    let base = if text == "save" { 0x5002 } else { 0x5003 };
+                    self.emit_xy(base, x, y);
+                } else {
+                    let base = if text == "save" { 0xf055 } else { 0xf065 };
+                    self.emit_xy(base, x, 0);
+                }
+            }
+            "sprite" => {
+                let (x, y) = (self.register()?, self.register()?);
+                let n = self.nibble()?;
+                self.emit_xy(0xd000 | n as u16, x, y);
+            }
+            "jump" => self.emit_address(0x1000)?,
+            "jump0" => self.emit_address(0xb000)?,
+            "native" => self.emit_address(0x0000)?,
+            "loop" => self.loops.push((self.here, Vec::new(), location)),
+            "while" => {
+                let condition = self.condition()?;
+                self.emit_condition(&condition, true);
+                let jump = self.emit_jump();
+                match self.loops.last_mut() {
+                    Some((_, whiles, _)) => whiles.push(jump),
+                    None => return Err(location.error("while outside a loop")),
+                }
+            }
+            "again" => {
+                let (start, whiles, _) = self
+                    .loops
+                    .pop()
+                    .ok_or_else(|| location.error("again without a loop"))?;
+                self.emit(0x1000 | start as u16);
+                for jump in whiles {
+                    self.patch(jump, self.here);
+                }
+            }
+            "if" => {
+                let condition = self.condition()?;
+                let (word, location) = self.next()?;
+                match word.as_str() {
+                    "then" => self.emit_condition(&condition, false),
+                    "begin" => {
+                        self.emit_condition(&condition, true);
+                        let jump = self.emit_jump();
+                        self.branches.push((jump, location));
+                    }
+                    _ => {
+                        return Err(
+                            location.error(format!("expected then or begin, found {}", word))
+                        )
+                    }
+                }
+            }
+            "else" => {
+                let (jump, _) = self
+                    .branches
+                    .pop()
+                    .ok_or_else(|| location.error("else without begin"))?;
+                let over = self.emit_jump();
+                self.patch(jump, self.here);
+                self.branches.push((over, location));
+            }
+            "end" => {
+                let (jump, _) = self
+                    .branches
+                    .pop()
+                    .ok_or_else(|| location.error("end without begin"))?;
+                self.patch(jump, self.here);
+            }
+            "i" => {
+                let (op, location) = self.next()?;
+                match op.as_str() {
+                    ":=" => match self.peek() {
+                        Some("hex") => {
+                            self.next()?;
+                            self.emit_x(0xf029)?;
+                        }
+                        Some("bighex") => {
+                            self.next()?;
+                            self.emit_x(0xf030)?;
+                        }
+                        Some("long") => {
+                            self.next()?;
+                            let nnnn = match self.value()? {
+                                (Value::Known(n), location) => {
+                                    check(n, 0, 0xffff, &location)? as u16
+                                }
+                                (Value::Label(name), location) => {
+                                    self.fixups.push((self.here, Fixup::Long, name, location));
+                                    0
+                                }
+                            };
+                            self.emit(0xf000);
+                            self.write(self.here, &nnnn.to_be_bytes());
+                            self.here += 2;
+                        }
+                        _ => self.emit_address(0xa000)?,
+                    },
+                    "+=" => self.emit_x(0xf01e)?,
+                    _ => {
+                        return Err(
+                            location.error(format!("expected := or += after i, found {}", op))
+                        )
+                    }
+                }
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let base = match text.as_str() {
+                    "delay" => 0xf015,
+                    "buzzer" => 0xf018,
+                    _ => 0xf03a,
+                };
+                self.emit_x(base)?;
+            }
+            _ => {
+                if let Some(x) = self.as_register(&text) {
+                    return self.assignment(x);
+                }
+                if let Some(n) = number(&text) {
+                    let byte = check(n, -128, 255, &location)?;
+                    self.emit_byte(byte as u8);
+                    return Ok(());
+                }
+                if let Some(m) = self.macros.get(&text).cloned() {
+                    return self.expand(m, &location);
+                }
+                if let Some(&n) = self.constants.get(&text) {
+                    let byte = check(n, -128, 255, &location)?;
+                    self.emit_byte(byte as u8);
+                    return Ok(());
+                }
+                // Anything else names a subroutine to call
+                self.tokens.push((text, location));
+                self.emit_address(0x2000)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// `vx := ...` and the other operators on a register.
+    fn assignment(&mut self, x: u8) -> Result<(), AssembleError> {
+        let (op, location) = self.next()?;
+        let y = self.peek().and_then(|text| self.as_register(text));
+        if let Some(y) = y {
+            let base = match op.as_str() {
+                ":=" => 0x8000,
+                "|=" => 0x8001,
+                "&=" => 0x8002,
+                "^=" => 0x8003,
+                "+=" => 0x8004,
+                "-=" => 0x8005,
+                ">>=" => 0x8006,
+                "=-" => 0x8007,
+                "<<=" => 0x800e,
+                _ => return Err(location.error(format!("{} can't take a register", op))),
+            };
+            self.next()?;
+            self.emit_xy(base, x, y);
+            return Ok(());
+        }
+        let x = (x as u16) << 8;
+        match (op.as_str(), self.peek()) {
+            (":=", Some("random")) => {
+                self.next()?;
+                let kk = self.byte()?;
+                self.emit(0xc000 | x | kk as u16);
+            }
+            (":=", Some("delay")) => {
+                self.next()?;
+                self.emit(0xf007 | x);
+            }
+            (":=", Some("key")) => {
+                self.next()?;
+                self.emit(0xf00a | x);
+            }
+            (":=", _) => {
+                let kk = self.byte()?;
+                self.emit(0x6000 | x | kk as u16);
+            }
+            ("+=", _) => {
+                let kk = self.byte()?;
+                self.emit(0x7000 | x | kk as u16);
+            }
+            ("-=", _) => {
+                // Adding the negation, since there's no subtract immediate
+                let kk = self.byte()?.wrapping_neg();
+                self.emit(0x7000 | x | kk as u16);
+            }
+            _ => return Err(location.error(format!("{} needs a register", op))),
+        }
+        Ok(())
+    }
+
+    fn condition(&mut self) -> Result<Condition, AssembleError> {
+        let x = self.register()?;
+        let (op, location) = self.next()?;
+        let operand = match op.as_str() {
+            "key" | "-key" => None,
+            "==" | "!=" | "<" | ">" | "<=" | ">=" => {
+                match self.peek().and_then(|text| self.as_register(text)) {
+                    Some(y) => {
+                        self.next()?;
+                        Some(Operand::Register(y))
+                    }
+                    None => Some(Operand::Byte(self.byte()?)),
+                }
+            }
+            _ => return Err(location.error(format!("expected a comparison, found {}", op))),
+        };
+        Ok(Condition { x, op, operand })
+    }
+
+    /// Emit code that skips the next instruction unless `condition` holds,
+    /// or unless it doesn't when `negate` is set.
+    fn emit_condition(&mut self, condition: &Condition, negate: bool) {
+        let op = match (condition.op.as_str(), negate) {
+            (op, false) => op,
+            ("==", true) => "!=",
+            ("!=", true) => "==",
+            ("<", true) => ">=",
+            (">=", true) => "<",
+            (">", true) => "<=",
+            ("<=", true) => ">",
+            ("key", true) => "-key",
+            (_, true) => "key",
+        };
+        let x = condition.x;
+        let xkk = |base: u16, kk: u8| base | (x as u16) << 8 | kk as u16;
+        match (op, condition.operand) {
+            ("key", _) => self.emit(xkk(0xe0a1, 0)),
+            ("-key", _) => self.emit(xkk(0xe09e, 0)),
+            ("==", Some(Operand::Byte(kk))) => self.emit(xkk(0x4000, kk)),
+            ("!=", Some(Operand::Byte(kk))) => self.emit(xkk(0x3000, kk)),
+            ("==", Some(Operand::Register(y))) => self.emit_xy(0x9000, x, y),
+            ("!=", Some(Operand::Register(y))) => self.emit_xy(0x5000, x, y),
+            (op, Some(operand)) => {
+                // vf := the operand, then a subtraction leaves vf = 1 when
+                // x >= operand (=-) or operand >= x (-=)
+                match operand {
+                    Operand::Register(y) => self.emit_xy(0x8000, 0xf, y),
+                    Operand::Byte(kk) => self.emit(0x6f00 | kk as u16),
+                }
+                match op {
+                    "<" | ">=" => self.emit_xy(0x8007, 0xf, x),
+                    _ => self.emit_xy(0x8005, 0xf, x),
+                }
+                match op {
+                    "<" | ">" => self.emit(0x3f01),
+                    _ => self.emit(0x3f00),
+                }
+            }
+            (_, None) => unreachable!("comparisons always have an operand"),
+        }
+    }
+
+    fn expand(&mut self, m: Macro, location: &Location) -> Result<(), AssembleError> {
+        self.expansions += 1;
+        if self.expansions > 100_000 {
+            return Err(location.error("too many macro expansions, is a macro calling itself?"));
+        }
+        let mut values = HashMap::new();
+        for arg in &m.args {
+            let (value, _) = self.next()?;
+            values.insert(arg.as_str(), value);
+        }
+        for (text, location) in m.body.into_iter().rev() {
+            let text = values.get(text.as_str()).cloned().unwrap_or(text);
+            self.tokens.push((text, location));
+        }
+        Ok(())
+    }
+
+    /// Evaluate a `:calc` expression after its `{`, through the `}`.
+    fn calc(&mut self) -> Result<i64, AssembleError> {
+        let value = self.calc_expr()?;
+        self.expect("}")?;
+        Ok(value)
+    }
+
+    // Right to left with no precedence, so `2 * 3 + 1` is 8
+    fn calc_expr(&mut self) -> Result<i64, AssembleError> {
+        let left = self.calc_term()?;
+        let Some(op) = self.peek().filter(|op| CALC_OPERATORS.contains(op)) else {
+            return Ok(left);
+        };
+        let op = op.to_string();
+        let (_, location) = self.next()?;
+        let right = self.calc_expr()?;
+        Ok(match op.as_str() {
+            "+" => left.wrapping_add(right),
+            "-" => left.wrapping_sub(right),
+            "*" => left.wrapping_mul(right),
+            "/" | "%" if right == 0 => return Err(location.error("division by zero")),
+            "/" => left.wrapping_div(right),
+            "%" => left.wrapping_rem(right),
+            "&" => left & right,
+            "|" => left | right,
+            "^" => left ^ right,
+            "<<" => left.checked_shl(right as u32).unwrap_or(0),
+            ">>" => left.checked_shr(right as u32).unwrap_or(0),
+            "<" => (left < right) as i64,
+            ">" => (left > right) as i64,
+            "<=" => (left <= right) as i64,
+            ">=" => (left >= right) as i64,
+            "==" => (left == right) as i64,
+            _ => (left != right) as i64,
+        })
+    }
+
+    fn calc_term(&mut self) -> Result<i64, AssembleError> {
+        match self.peek() {
+            Some("(") => {
+                self.next()?;
+                let value = self.calc_expr()?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            Some("-") => {
+                self.next()?;
+                Ok(self.calc_term()?.wrapping_neg())
+            }
+            Some("~") => {
+                self.next()?;
+                Ok(!self.calc_term()?)
+            }
+            Some("!") => {
+                self.next()?;
+                Ok((self.calc_term()? == 0) as i64)
+            }
+            Some("HERE") => {
+                self.next()?;
+                Ok(self.here as i64)
+            }
+            _ => self.known(i64::MIN, i64::MAX),
+        }
+    }
+
+    fn finish(mut self, start: &Location) -> Result<Assembly, AssembleError> {
+        if let Some((_, _, location)) = self.loops.first() {
+            return Err(location.error("loop without again"));
+        }
+        if let Some((_, location)) = self.branches.first() {
+            return Err(location.error("begin without end"));
+        }
+        let main = *self
+            .labels
+            .get("main")
+            .ok_or_else(|| start.error("no main label"))?;
+        if self.jump_to_main {
+            self.patch(ORIGIN, main);
+        }
+        for (at, fixup, name, location) in std::mem::take(&mut self.fixups) {
+            let addr = *self
+                .labels
+                .get(&name)
+                .ok_or_else(|| location.error(format!("{} isn't defined", name)))?;
+            let offset = at - ORIGIN;
+            match fixup {
+                Fixup::Address => {
+                    let addr = check(addr as i64, 0, 0xfff, &location)? as u16;
+                    let opcode = u16::from_be_bytes([self.rom[offset], self.rom[offset + 1]]);
+                    self.write(at, &(opcode & 0xf000 | addr).to_be_bytes());
+                }
+                Fixup::Long => self.write(at + 2, &(addr as u16).to_be_bytes()),
+                Fixup::Unpack(n) => {
+                    let addr = check(addr as i64, 0, 0xfff, &location)? as u16;
+                    self.write(at, &(0x6000 | (n as u16) << 4 | addr >> 8).to_be_bytes());
+                    self.write(at + 2, &(0x6100 | addr & 0xff).to_be_bytes());
+                }
+            }
+        }
+        let mut monitors = Vec::new();
+        for (name, addr, len, location) in std::mem::take(&mut self.monitors) {
+            let addr = match addr {
+                Value::Known(addr) => check(addr, 0, 0xffff, &location)? as usize,
+                Value::Label(label) => *self
+                    .labels
+                    .get(&label)
+                    .ok_or_else(|| location.error(format!("{} isn't defined", label)))?,
+            };
+            monitors.push((name, addr, len));
+        }
+        Ok(Assembly {
+            rom: self.rom,
+            source_map: self.source_map,
+            breakpoints: self.breakpoints,
+            monitors,
+        })
+    }
+}
+
+/// `n` if it's in `min..=max`, otherwise an error at `location`.
+fn check(n: i64, min: i64, max: i64, location: &Location) -> Result<i64, AssembleError> {
+    if n < min || n > max {
+        return Err(location.error(format!("{} is out of range", n)));
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::compile;
+    use crate::assembler::tests::{every_instruction, test_roms};
+    use crate::disassembler::{Disassembly, Syntax};
+    use std::path::Path;
+
+    fn rom(source: &str) -> Vec<u8> {
+        compile(source, Path::new("test.8o")).unwrap().rom
+    }
+
+    fn error(source: &str) -> String {
+        compile(source, Path::new("test.8o"))
+            .map(|_| String::new())
+            .unwrap_or_else(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_compile() {
+        let source = "\
+:alias x v3
+:const SPEED 2
+:calc DOUBLE { SPEED * 2 + 1 }   # right to left, so 6
+:macro bump reg { reg += SPEED }
+: main
+  x := DOUBLE
+  loop
+    bump x
+    while x != 0x10
+    if x > 5 begin
+      x -= 1
+    else
+      i := long shape
+    end
+  again
+  draw
+  :breakpoint done
+  :monitor shape 2
+: draw
+  i := shape sprite v0 x 1 ;
+: shape
+  0b11000000 0x40
+";
+        let assembly = compile(source, Path::new("test.8o")).unwrap();
+        assert_eq!(
+            assembly.rom,
+            [
+                0x63, 0x06, // x := DOUBLE
+                0x73, 0x02, // bump x
+                0x43, 0x10, 0x12, 0x1a, // while x != 0x10
+                0x6f, 0x05, 0x8f, 0x35, 0x3f, 0x00, 0x12, 0x14, // if x > 5 begin
+                0x73, 0xff, 0x12, 0x18, // x -= 1, else
+                0xf0, 0x00, 0x02, 0x22, // i := long shape
+                0x12, 0x02, // again
+                0x22, 0x1c, // draw
+                0xa2, 0x22, 0xd0, 0x31, 0x00, 0xee, // : draw
+                0xc0, 0x40, // : shape
+            ]
+        );
+        assert_eq!(assembly.breakpoints, [(0x21c, "done".to_string())]);
+        assert_eq!(assembly.monitors, [("shape".to_string(), 0x222, 2)]);
+        assert_eq!(assembly.source_map.location(0x210), Some(("test.8o", 11)));
+
+        // Without main first, 0x200 jumps to it
+        assert_eq!(rom(": f ; : main f"), [0x12, 0x04, 0x00, 0xee, 0x22, 0x02]);
+        assert_eq!(
+            rom(": main :unpack 0xA data : data :next op v0 := 0 if v1 key then vf := key"),
+            [0x60, 0xa2, 0x61, 0x04, 0x60, 0x00, 0xe1, 0xa1, 0xff, 0x0a]
+        );
+
+        assert_eq!(
+            error(": main jump nowhere"),
+            "test.8o:1:13: nowhere isn't defined"
+        );
+        assert_eq!(
+            error(": main\n  v0 := 256"),
+            "test.8o:2:9: 256 is out of range"
+        );
+        assert_eq!(error(": main\nloop"), "test.8o:2:1: loop without again");
+        assert_eq!(
+            error(": main if v0 != 1 jump main"),
+            "test.8o:1:19: expected then or begin, found jump"
+        );
+        assert_eq!(error("v0 := 1"), "test.8o:1:1: no main label");
+        assert_eq!(
+            error(": main :monitor -1 4"),
+            "test.8o:1:17: -1 is out of range"
+        );
+    }
+
+    #[test]
+    fn test_disassembly() {
+        // The disassembler's Octo listings compile back to the same ROM
+        let rom = every_instruction();
+        let listing = Disassembly::new(&rom, 0x200).listing(Syntax::Octo);
+        let assembly = compile(&listing, Path::new("listing.8o")).unwrap();
+        assert_eq!(assembly.rom, rom);
+
+        for (path, rom) in test_roms() {
+            let listing = Disassembly::new(&rom, 0x200).listing(Syntax::Octo);
+            let assembly = compile(&listing, Path::new("listing.8o")).unwrap();
+            assert_eq!(assembly.rom, rom, "{}", path.display());
+        }
+    }
+}
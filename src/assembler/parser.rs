@@ -0,0 +1,308 @@
+// Tokens and expressions for the assembler. Every token remembers where it
+// came from so errors can point at it. Expressions have the usual C
+// precedence: `|`, then `^`, `&`, shifts, `+ -` and `* / %`, with unary
+// `-` and `~` binding tightest.
+use super::AssembleError;
+use std::fmt;
+use std::rc::Rc;
+
+/// A place in a source file. Lines and columns count from 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub file: Rc<str>,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Location {
+    pub fn error(&self, message: impl Into<String>) -> AssembleError {
+        AssembleError {
+            file: self.file.to_string(),
+            line: self.line,
+            column: self.column,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    Number(i64),
+    Name(String),
+    Str(Vec<u8>),
+    Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Name(name) => write!(f, "{}", name),
+            Token::Str(_) => write!(f, "string"),
+            Token::Symbol(symbol) => write!(f, "{}", symbol),
+        }
+    }
+}
+
+// Longest first, so `<<` isn't read as two `<`
+const SYMBOLS: [&str; 18] = [
+    "<<", ">>", ",", ":", "=", "(", ")", "[", "]", "+", "-", "*", "/", "%", "&", "|", "^", "~",
+];
+
+/// Split a line into tokens, stopping at a `;` comment.
+pub fn tokenize(
+    text: &str,
+    file: &Rc<str>,
+    line: usize,
+) -> Result<Vec<(Token, Location)>, AssembleError> {
+    let location = |offset: usize| Location {
+        file: file.clone(),
+        line,
+        column: text[..offset].chars().count() + 1,
+    };
+    let mut tokens = Vec::new();
+    let mut offset = 0;
+    while let Some(c) = text[offset..].chars().next() {
+        let rest = &text[offset..];
+        let start = location(offset);
+        if c == ';' {
+            break;
+        } else if c.is_whitespace() {
+            offset += c.len_utf8();
+            continue;
+        } else if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(rest.len());
+            let word = &rest[..end];
+            let token = if c.is_ascii_digit() {
+                let lower = word.to_ascii_lowercase();
+                let n = if let Some(hex) = lower.strip_prefix("0x") {
+                    i64::from_str_radix(hex, 16)
+                } else if let Some(binary) = lower.strip_prefix("0b") {
+                    i64::from_str_radix(binary, 2)
+                } else {
+                    lower.parse()
+                };
+                Token::Number(n.map_err(|_| start.error(format!("invalid number {}", word)))?)
+            } else {
+                Token::Name(word.to_string())
+            };
+            tokens.push((token, start));
+            offset += end;
+        } else if c == '"' {
+            let mut bytes = Vec::new();
+            let mut chars = rest.char_indices().skip(1);
+            let end = loop {
+                let Some((i, c)) = chars.next() else {
+                    return Err(start.error("unterminated string"));
+                };
+                match c {
+                    '"' => break i + 1,
+                    '\\' => {
+                        let escaped = match chars.next().map(|(_, c)| c) {
+                            Some('n') => '\n',
+                            Some('0') => '\0',
+                            Some(c @ ('"' | '\\')) => c,
+                            _ => return Err(location(offset + i).error("invalid escape in string")),
+                        };
+                        bytes.push(escaped as u8);
+                    }
+                    c if c.is_ascii() => bytes.push(c as u8),
+                    _ => return Err(location(offset + i).error("strings must be ASCII")),
+                }
+            };
+            tokens.push((Token::Str(bytes), start));
+            offset += end;
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|symbol| rest.starts_with(*symbol))
+                .ok_or_else(|| start.error(format!("unexpected character {}", c)))?;
+            tokens.push((Token::Symbol(symbol), start));
+            offset += symbol.len();
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Kind {
+    Number(i64),
+    Name(String),
+    Negate(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+/// An expression, kept unevaluated until every label is known.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expr {
+    kind: Kind,
+    pub location: Location,
+}
+
+impl Expr {
+    /// Evaluate with `lookup` giving the values of names.
+    pub fn eval(
+        &self,
+        lookup: &mut dyn FnMut(&str, &Location) -> Result<i64, AssembleError>,
+    ) -> Result<i64, AssembleError> {
+        Ok(match &self.kind {
+            Kind::Number(n) => *n,
+            Kind::Name(name) => lookup(name, &self.location)?,
+            Kind::Negate(expr) => expr.eval(lookup)?.wrapping_neg(),
+            Kind::Not(expr) => !expr.eval(lookup)?,
+            Kind::Binary(op, a, b) => {
+                let (a, b) = (a.eval(lookup)?, b.eval(lookup)?);
+                match op {
+                    Op::Add => a.wrapping_add(b),
+                    Op::Sub => a.wrapping_sub(b),
+                    Op::Mul => a.wrapping_mul(b),
+                    Op::Div | Op::Rem if b == 0 => {
+                        return Err(self.location.error("division by zero"))
+                    }
+                    Op::Div => a.wrapping_div(b),
+                    Op::Rem => a.wrapping_rem(b),
+                    Op::And => a & b,
+                    Op::Or => a | b,
+                    Op::Xor => a ^ b,
+                    Op::Shl => a.checked_shl(b as u32).unwrap_or(0),
+                    Op::Shr => a.checked_shr(b as u32).unwrap_or(0),
+                }
+            }
+        })
+    }
+}
+
+/// Parse all of `tokens` as one expression. `end` is where to point if
+/// there are none.
+pub fn parse_expr(tokens: &[(Token, Location)], end: &Location) -> Result<Expr, AssembleError> {
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        end,
+    };
+    let expr = parser.or()?;
+    match tokens.get(parser.pos) {
+        Some((token, location)) => Err(location.error(format!("unexpected {}", token))),
+        None => Ok(expr),
+    }
+}
+
+struct Parser<'a> {
+    tokens: &'a [(Token, Location)],
+    pos: usize,
+    end: &'a Location,
+}
+
+impl Parser<'_> {
+    fn eat(&mut self, symbol: &str) -> Option<Location> {
+        match self.tokens.get(self.pos) {
+            Some((Token::Symbol(s), location)) if *s == symbol => {
+                self.pos += 1;
+                Some(location.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Parse a left associative chain of `next` joined by any of `ops`.
+    fn chain(
+        &mut self,
+        ops: &[(&str, Op)],
+        next: fn(&mut Self) -> Result<Expr, AssembleError>,
+    ) -> Result<Expr, AssembleError> {
+        let mut expr = next(self)?;
+        'outer: loop {
+            for &(symbol, op) in ops {
+                if let Some(location) = self.eat(symbol) {
+                    let kind = Kind::Binary(op, Box::new(expr), Box::new(next(self)?));
+                    expr = Expr { kind, location };
+                    continue 'outer;
+                }
+            }
+            return Ok(expr);
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, AssembleError> {
+        self.chain(&[("|", Op::Or)], Self::xor)
+    }
+
+    fn xor(&mut self) -> Result<Expr, AssembleError> {
+        self.chain(&[("^", Op::Xor)], Self::and)
+    }
+
+    fn and(&mut self) -> Result<Expr, AssembleError> {
+        self.chain(&[("&", Op::And)], Self::shift)
+    }
+
+    fn shift(&mut self) -> Result<Expr, AssembleError> {
+        self.chain(&[("<<", Op::Shl), (">>", Op::Shr)], Self::sum)
+    }
+
+    fn sum(&mut self) -> Result<Expr, AssembleError> {
+        self.chain(&[("+", Op::Add), ("-", Op::Sub)], Self::product)
+    }
+
+    fn product(&mut self) -> Result<Expr, AssembleError> {
+        let ops = [("*", Op::Mul), ("/", Op::Div), ("%", Op::Rem)];
+        self.chain(&ops, Self::unary)
+    }
+
+    fn unary(&mut self) -> Result<Expr, AssembleError> {
+        if let Some(location) = self.eat("-") {
+            let kind = Kind::Negate(Box::new(self.unary()?));
+            return Ok(Expr { kind, location });
+        }
+        if let Some(location) = self.eat("~") {
+            let kind = Kind::Not(Box::new(self.unary()?));
+            return Ok(Expr { kind, location });
+        }
+        if self.eat("+").is_some() {
+            return self.unary();
+        }
+        if self.eat("(").is_some() {
+            let expr = self.or()?;
+            return match self.eat(")") {
+                Some(_) => Ok(expr),
+                None => Err(self.here().error("expected )")),
+            };
+        }
+        let Some((token, location)) = self.tokens.get(self.pos) else {
+            return Err(self.end.error("expected a value"));
+        };
+        let kind = match token {
+            Token::Number(n) => Kind::Number(*n),
+            Token::Name(name) => Kind::Name(name.clone()),
+            token => return Err(location.error(format!("expected a value, found {}", token))),
+        };
+        self.pos += 1;
+        Ok(Expr {
+            kind,
+            location: location.clone(),
+        })
+    }
+
+    // Where the next token is, or the end
+    fn here(&self) -> &Location {
+        self.tokens
+            .get(self.pos)
+            .map_or(self.end, |(_, location)| location)
+    }
+}
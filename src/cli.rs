@@ -0,0 +1,406 @@
+// Command line parsing for the emulator binary
+use std::time::Duration;
+use virtual_machine::disassembler::Syntax;
+use virtual_machine::headless::{KeyPress, Limits};
+use virtual_machine::rewind::DEFAULT_MEMORY;
+use virtual_machine::{ErrorPolicies, ImageFormat, Platform, Rewind, Rng, RngMode, Scheduler};
+
+pub const USAGE: &str = "\
+Usage: virtual_machine [OPTIONS] <ROM>
+       virtual_machine headless [OPTIONS] [HEADLESS OPTIONS] <ROM>
+       virtual_machine test-suite [--dir <DIR>] [--update]
+       virtual_machine disasm [--syntax <plain|octo>] <ROM>
+       virtual_machine asm [--output <FILE>] [--source-map <FILE>] <SOURCE>
+       virtual_machine decompile <ROM>
+
+Commands:
+  headless                  Run without a window or audio until the program halts,
+                            jumps to itself or a limit is reached
+  test-suite                Run the test ROMs in DIR (default: test-roms) on every
+                            platform and compare the results with the expected
+                            images, or rewrite them with --update
+  disasm                    Print a listing of ROM, separating code from data by
+                            following jumps and calls, as mnemonics or as Octo
+                            source that assembles back to the ROM (default: plain)
+  asm                       Assemble SOURCE, written in the disassembler's plain
+                            syntax or in Octo if it ends in .8o, to a ROM
+                            (default: SOURCE with a .ch8 extension) and optionally
+                            a source map for --dap
+  decompile                 Print ROM as structured Octo source, with loops and
+                            conditionals recovered from jumps and skips and
+                            registers named by how they're used
+
+A ROM ending in .8o is Octo source, compiled before it runs.
+
+Options:
+  --platform <NAME>         Machine to emulate: vip, chip48, schip10, schip11,
+                            xochip or modern (default: modern)
+  --cpu-hz <HZ>             Instructions executed per second (default: per platform)
+  --ipf <N>                 Instructions executed per 60 Hz frame
+  --quirks <LIST>           Comma separated quirks to turn on, or off with a
+                            `no-` prefix: vf-reset, memory[=x+1|x|none],
+                            display-wait, clipping, shifting, jumping
+  --stack-errors <POLICY>   On stack overflow/underflow: halt, log or wrap (default: halt)
+  --memory-errors <POLICY>  On out of bounds memory access: halt, log or wrap (default: wrap)
+  --opcode-errors <POLICY>  On invalid opcodes: halt or log (default: halt)
+  --seed <N>                Seed for the random number generator (default: random)
+  --rng <MODE>              Random number generator for CXKK: xorshift, or vip for
+                            the VIP interpreter's counter method over emulated
+                            memory; not its exact numbers (default: xorshift)
+  --state <FILE>            Start from a save state
+  --record <FILE>           Record the keypad to a movie, from the start state if given
+  --play <FILE>             Play back a movie, which also sets the platform, quirks,
+                            seed and speed
+  --monitor                 Start paused in the machine language monitor, which F12
+                            also opens. Type `help` at its prompt for the commands
+  --gdb <PORT>              Start paused, waiting for GDB to attach to PORT on
+                            localhost with `target remote :PORT`
+  --dap <stdio|PORT>        Serve the Debug Adapter Protocol to an editor on stdin
+                            and stdout, or on PORT on localhost
+  --source-map <FILE>       Addresses and source lines for --dap, one `0x200 FILE:LINE`
+                            per line
+  --debug-panels            Show the registers, stack, code around pc and memory
+                            around I next to the display as the program runs
+  --rewind-memory <MIB>     Memory kept for rewinding, 0 to turn it off (default: 16)
+  --rewind-interval <N>     Frames between rewind snapshots (default: 1)
+  -h, --help                Print this message
+
+Headless options:
+  --frames <N>              Stop after N frames (default: the movie's length with --play)
+  --timeout <SECONDS>       Give up after this much wall clock time (default: 10,
+                            or none with --gdb or --dap)
+  --no-loop-stop            Keep running when the program jumps to itself
+  --key <FRAME:KEY[:N]>     Hold hex KEY for N frames (default: 1) after FRAME
+  --output <FILE>           Write the final display to FILE, or stdout for `-`
+  --format <FORMAT>         png, pbm or ascii (default: from the extension, or ascii)
+
+Headless exit status: 0 when stopped by a halt, a jump to self or --frames, 1 on
+an emulator error, 2 on bad arguments and 3 on timeout.";
+
+pub enum Command {
+    Run(Box<Options>),
+    TestSuite {
+        dir: String,
+        update: bool,
+    },
+    Disasm {
+        rom: String,
+        syntax: Syntax,
+    },
+    Asm {
+        source: String,
+        output: Option<String>,
+        source_map: Option<String>,
+    },
+    Decompile {
+        rom: String,
+    },
+}
+
+impl Command {
+    /// Parse the arguments following the program name. `Ok(None)` means help was requested.
+    pub fn parse(args: &[String]) -> Result<Option<Self>, String> {
+        if args.first().is_some_and(|arg| arg == "test-suite") {
+            return Self::parse_test_suite(&args[1..]);
+        }
+        if args.first().is_some_and(|arg| arg == "disasm") {
+            return Self::parse_disasm(&args[1..]);
+        }
+        if args.first().is_some_and(|arg| arg == "asm") {
+            return Self::parse_asm(&args[1..]);
+        }
+        if args.first().is_some_and(|arg| arg == "decompile") {
+            return Self::parse_decompile(&args[1..]);
+        }
+        Ok(Options::parse(args)?.map(|options| Command::Run(Box::new(options))))
+    }
+
+    fn parse_test_suite(args: &[String]) -> Result<Option<Self>, String> {
+        let mut dir = "test-roms".to_string();
+        let mut update = false;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "--dir" => dir = value(arg, &mut args)?.to_string(),
+                "--update" => update = true,
+                other => return Err(format!("unexpected argument {}", other)),
+            }
+        }
+        Ok(Some(Command::TestSuite { dir, update }))
+    }
+
+    fn parse_disasm(args: &[String]) -> Result<Option<Self>, String> {
+        let mut rom = None;
+        let mut syntax = Syntax::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "--syntax" => syntax = value(arg, &mut args)?.parse()?,
+                flag if flag.starts_with('-') => return Err(format!("unknown option {}", flag)),
+                path if rom.is_none() => rom = Some(path.to_string()),
+                extra => return Err(format!("unexpected argument {}", extra)),
+            }
+        }
+        let rom = rom.ok_or("no ROM given")?;
+        Ok(Some(Command::Disasm { rom, syntax }))
+    }
+
+    fn parse_asm(args: &[String]) -> Result<Option<Self>, String> {
+        let mut source = None;
+        let (mut output, mut source_map) = (None, None);
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "--output" => output = Some(value(arg, &mut args)?.to_string()),
+                "--source-map" => source_map = Some(value(arg, &mut args)?.to_string()),
+                flag if flag.starts_with('-') => return Err(format!("unknown option {}", flag)),
+                path if source.is_none() => source = Some(path.to_string()),
+                extra => return Err(format!("unexpected argument {}", extra)),
+            }
+        }
+        let source = source.ok_or("no source file given")?;
+        Ok(Some(Command::Asm {
+            source,
+            output,
+            source_map,
+        }))
+    }
+
+    fn parse_decompile(args: &[String]) -> Result<Option<Self>, String> {
+        let mut rom = None;
+        for arg in args {
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                flag if flag.starts_with('-') => return Err(format!("unknown option {}", flag)),
+                path if rom.is_none() => rom = Some(path.to_string()),
+                extra => return Err(format!("unexpected argument {}", extra)),
+            }
+        }
+        let rom = rom.ok_or("no ROM given")?;
+        Ok(Some(Command::Decompile { rom }))
+    }
+}
+
+pub struct Headless {
+    pub limits: Limits,
+    pub keys: Vec<KeyPress>,
+    pub output: Option<String>,
+    pub format: Option<ImageFormat>,
+}
+
+/// Where a Debug Adapter Protocol client talks to us.
+pub enum Dap {
+    Stdio,
+    Port(u16),
+}
+
+pub struct Options {
+    pub rom: String,
+    pub scheduler: Scheduler,
+    pub platform: Platform,
+    pub policies: ErrorPolicies,
+    pub rewind: Option<Rewind>,
+    pub rng: Rng,
+    pub state: Option<String>,
+    pub record: Option<String>,
+    pub play: Option<String>,
+    pub monitor: bool,
+    pub gdb: Option<u16>,
+    pub dap: Option<Dap>,
+    pub source_map: Option<String>,
+    pub debug_panels: bool,
+    pub headless: Option<Headless>,
+}
+
+fn value<'a>(flag: &str, args: &mut impl Iterator<Item = &'a String>) -> Result<&'a str, String> {
+    args.next()
+        .map(|s| s.as_str())
+        .ok_or_else(|| format!("{} expects a value", flag))
+}
+
+/// The fastest `--cpu-hz` accepted. Far beyond what any machine can keep up
+/// with, but small enough that the scheduler's arithmetic stays meaningful.
+const MAX_CPU_HZ: f64 = 1e9;
+
+fn number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {}: {}", flag, value))
+}
+
+impl Options {
+    /// Parse the arguments following the program name. `Ok(None)` means help was requested.
+    pub fn parse(args: &[String]) -> Result<Option<Self>, String> {
+        let mut rom = None;
+        let mut platform = Platform::default();
+        let mut scheduler = None;
+        let mut quirks = Vec::new();
+        let mut policies = ErrorPolicies::default();
+        let mut rewind_memory = DEFAULT_MEMORY;
+        let mut rewind_interval = 1;
+        let mut seed = None;
+        let mut rng_mode = RngMode::Xorshift;
+        let (mut state, mut record, mut play) = (None, None, None);
+        let mut monitor = false;
+        let mut gdb = None;
+        let (mut dap, mut source_map) = (None, None);
+        let mut debug_panels = false;
+        let mut timeout = None;
+        let mut limits = Limits::default();
+        let mut keys = Vec::new();
+        let (mut output, mut format) = (None, None);
+
+        let mut args = args.iter().peekable();
+        let headless = args.next_if(|arg| *arg == "headless").is_some();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "--platform" => platform = value(arg, &mut args)?.parse()?,
+                "--cpu-hz" => {
+                    let hz: f64 = number(arg, value(arg, &mut args)?)?;
+                    if !hz.is_finite() || hz <= 0. || hz > MAX_CPU_HZ {
+                        return Err(format!(
+                            "{} must be above 0 and no larger than {}",
+                            arg, MAX_CPU_HZ
+                        ));
+                    }
+                    scheduler = Some(Scheduler::with_cpu_hz(hz));
+                }
+                "--ipf" => {
+                    let n = number(arg, value(arg, &mut args)?)?;
+                    scheduler = Some(Scheduler::with_instructions_per_frame(n));
+                }
+                "--quirks" => quirks.push(value(arg, &mut args)?),
+                "--stack-errors" => policies.stack = value(arg, &mut args)?.parse()?,
+                "--memory-errors" => policies.memory = value(arg, &mut args)?.parse()?,
+                "--opcode-errors" => policies.invalid_opcode = value(arg, &mut args)?.parse()?,
+                "--seed" => seed = Some(number(arg, value(arg, &mut args)?)?),
+                "--rng" => rng_mode = value(arg, &mut args)?.parse()?,
+                "--state" => state = Some(value(arg, &mut args)?.to_string()),
+                "--record" => record = Some(value(arg, &mut args)?.to_string()),
+                "--play" => play = Some(value(arg, &mut args)?.to_string()),
+                "--monitor" => monitor = true,
+                "--gdb" => gdb = Some(number(arg, value(arg, &mut args)?)?),
+                "--dap" => {
+                    dap = Some(match value(arg, &mut args)? {
+                        "stdio" => Dap::Stdio,
+                        port => Dap::Port(number(arg, port)?),
+                    })
+                }
+                "--source-map" => source_map = Some(value(arg, &mut args)?.to_string()),
+                "--debug-panels" if headless => {
+                    return Err("--debug-panels needs a window".to_string())
+                }
+                "--debug-panels" => debug_panels = true,
+                "--rewind-memory" => {
+                    let mib: usize = number(arg, value(arg, &mut args)?)?;
+                    rewind_memory = mib
+                        .checked_mul(1 << 20)
+                        .ok_or_else(|| format!("{} is too large: {}", arg, mib))?;
+                }
+                "--rewind-interval" => rewind_interval = number(arg, value(arg, &mut args)?)?,
+                "--frames" | "--timeout" | "--no-loop-stop" | "--key" | "--output" | "--format"
+                    if !headless =>
+                {
+                    return Err(format!("{} only applies to the headless command", arg))
+                }
+                "--frames" => limits.frames = Some(number(arg, value(arg, &mut args)?)?),
+                "--timeout" => {
+                    let seconds: f64 = number(arg, value(arg, &mut args)?)?;
+                    let duration = Duration::try_from_secs_f64(seconds)
+                        .map_err(|_| format!("invalid value for {}: {}", arg, seconds))?;
+                    timeout = Some(duration);
+                }
+                "--no-loop-stop" => limits.stop_on_loop = false,
+                "--key" => keys.push(value(arg, &mut args)?.parse()?),
+                "--output" => output = Some(value(arg, &mut args)?.to_string()),
+                "--format" => format = Some(value(arg, &mut args)?.parse()?),
+                flag if flag.starts_with('-') => return Err(format!("unknown option {}", flag)),
+                path if rom.is_none() => rom = Some(path.to_string()),
+                extra => return Err(format!("unexpected argument {}", extra)),
+            }
+        }
+
+        let rom = rom.ok_or("no ROM given")?;
+        if play.is_some() && (record.is_some() || state.is_some()) {
+            return Err("--play can't be combined with --record or --state".to_string());
+        }
+        if [monitor, gdb.is_some(), dap.is_some()]
+            .iter()
+            .filter(|&&on| on)
+            .count()
+            > 1
+        {
+            return Err("only one of --monitor, --gdb and --dap can be used".to_string());
+        }
+        if source_map.is_some() && dap.is_none() {
+            return Err("--source-map only applies with --dap".to_string());
+        }
+        // Time spent stopped in a debugger shouldn't count against a headless run
+        if gdb.is_some() || dap.is_some() || timeout.is_some() {
+            limits.timeout = timeout;
+        }
+        // Explicit settings win over the platform's, whatever the argument order
+        for spec in quirks {
+            platform.quirks.apply(spec)?;
+        }
+        let scheduler = scheduler.unwrap_or_else(|| Scheduler::with_cpu_hz(platform.cpu_hz));
+        let rng = match seed {
+            Some(seed) => Rng::new(rng_mode, seed),
+            None => Rng::from_entropy(rng_mode),
+        };
+        let rewind = (rewind_memory > 0).then(|| Rewind::new(rewind_interval, rewind_memory));
+        let headless = headless.then_some(Headless {
+            limits,
+            keys,
+            output,
+            format,
+        });
+        Ok(Some(Self {
+            rom,
+            scheduler,
+            platform,
+            policies,
+            rewind,
+            rng,
+            state,
+            record,
+            play,
+            monitor,
+            gdb,
+            dap,
+            source_map,
+            debug_panels,
+            headless,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Options;
+
+    fn parse(args: &[&str]) -> Result<Option<Options>, String> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        Options::parse(&args)
+    }
+
+    #[test]
+    fn test_rewind_memory() {
+        assert!(parse(&["game.ch8", "--rewind-memory", "64"]).is_ok());
+        assert!(parse(&["game.ch8", "--rewind-memory", "18446744073709551615"]).is_err());
+        assert!(parse(&["game.ch8", "--rewind-memory", "17592186044416"]).is_err());
+    }
+
+    #[test]
+    fn test_cpu_hz() {
+        assert!(parse(&["game.ch8", "--cpu-hz", "1000"]).unwrap().is_some());
+        assert!(parse(&["game.ch8", "--cpu-hz", "0.5"]).unwrap().is_some());
+        for hz in ["inf", "NaN", "-inf", "1e308", "fast", "-5", "0"] {
+            assert!(parse(&["game.ch8", "--cpu-hz", hz]).is_err(), "{}", hz);
+        }
+    }
+}
@@ -0,0 +1,1065 @@
+// A Debug Adapter Protocol server, so VS Code and other editors can debug
+// programs through their own UI: stepping, breakpoints, registers, timers,
+// the call stack, memory and disassembly. With a `SourceMap`, breakpoints
+// and stepping work on source lines. Requests are read on a thread of their
+// own, so a pause can reach the processor while it runs.
+mod json;
+
+use crate::debugger::{
+    self, Condition, DebugFrontend, Debugger, Event, Point, Register, SourceMap, Trigger,
+};
+use crate::processor::Processor;
+use json::Json;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, TcpListener};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+// Variable references for the scopes
+const REGISTERS: i64 = 1;
+const TIMERS: i64 = 2;
+const STACK: i64 = 3;
+
+/// The longest message read from a client. Writing all of XO-CHIP's memory
+/// in base64 takes under 100 KiB.
+const MAX_MESSAGE: usize = 1 << 20;
+/// The most instructions one disassemble request can ask for.
+const MAX_INSTRUCTIONS: i64 = 0x10000;
+
+/// When the server stops the processor again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Mode {
+    /// Before `configurationDone`, while the editor sets breakpoints.
+    Configuring,
+    /// Only at breakpoints.
+    Run,
+    /// Before the next instruction.
+    Step,
+    /// Once `pc` comes back with no more than `depth` subroutines running.
+    Over { pc: usize, depth: usize },
+    /// Once fewer than `depth` subroutines are running.
+    Out { depth: usize },
+    /// Once an instruction from another source line is reached, with no
+    /// more than `depth` subroutines running if given.
+    Line {
+        file: String,
+        line: usize,
+        depth: Option<usize>,
+    },
+}
+
+/// What the processor does after a request.
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    Nothing,
+    Resume(Mode),
+    /// Stop, telling the editor why.
+    Stop(&'static str),
+    Quit,
+}
+
+pub struct DapServer {
+    requests: Receiver<Result<Json, String>>,
+    output: Box<dyn Write>,
+    seq: i64,
+    debugger: Debugger,
+    source_map: SourceMap,
+    mode: Mode,
+    stop_on_entry: bool,
+    // A stop asked for while running
+    pending: Option<&'static str>,
+    // The debugger's ids for the breakpoints of each kind the editor set
+    source_breakpoints: HashMap<String, Vec<usize>>,
+    instruction_breakpoints: Vec<usize>,
+    data_breakpoints: Vec<usize>,
+}
+
+impl DapServer {
+    /// A server reading requests from `input` and writing to `output`. The
+    /// program stays stopped until the editor has finished configuring.
+    pub fn new(input: impl Read + Send + 'static, output: Box<dyn Write>) -> Self {
+        let (sender, requests) = mpsc::channel();
+        thread::spawn(move || {
+            let mut input = BufReader::new(input);
+            while let Ok(Some(message)) = read_message(&mut input) {
+                if sender.send(message.parse()).is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            requests,
+            output,
+            seq: 0,
+            debugger: Debugger::new(),
+            source_map: SourceMap::new(),
+            mode: Mode::Configuring,
+            stop_on_entry: false,
+            pending: None,
+            source_breakpoints: HashMap::new(),
+            instruction_breakpoints: Vec::new(),
+            data_breakpoints: Vec::new(),
+        }
+    }
+
+    /// A server on stdin and stdout, the way editors usually launch one.
+    pub fn stdio() -> Self {
+        Self::new(io::stdin(), Box::new(io::stdout()))
+    }
+
+    /// Wait for an editor to connect on `port` of the loopback interface.
+    pub fn listen(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        eprintln!(
+            "waiting for a debug adapter client on {}",
+            listener.local_addr()?
+        );
+        let (stream, addr) = listener.accept()?;
+        eprintln!("debug adapter client connected from {}", addr);
+        stream.set_nodelay(true)?;
+        Ok(Self::new(stream.try_clone()?, Box::new(stream)))
+    }
+
+    /// Map addresses to source lines, for source breakpoints and stepping.
+    pub fn with_source_map(mut self, source_map: SourceMap) -> Self {
+        self.source_map = source_map;
+        self
+    }
+
+    /// Send a message, numbering it.
+    fn send(&mut self, mut message: Json) {
+        self.seq += 1;
+        if let Json::Object(fields) = &mut message {
+            fields.insert(0, ("seq".to_string(), Json::from(self.seq as u64)));
+        }
+        let body = message.to_string();
+        let message = format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+        // A client that has gone will be noticed when reading
+        let _ = self
+            .output
+            .write_all(message.as_bytes())
+            .and_then(|()| self.output.flush());
+    }
+
+    fn event(&mut self, event: &str, body: Json) {
+        self.send(Json::object([
+            ("type", "event".into()),
+            ("event", event.into()),
+            ("body", body),
+        ]));
+    }
+
+    fn stopped(&mut self, reason: &str, hit_ids: Vec<usize>) {
+        let ids = hit_ids.into_iter().map(Json::from).collect::<Vec<_>>();
+        self.event(
+            "stopped",
+            Json::object([
+                ("reason", reason.into()),
+                ("threadId", 1u8.into()),
+                ("allThreadsStopped", true.into()),
+                ("hitBreakpointIds", ids.into()),
+            ]),
+        );
+    }
+
+    /// Handle a message, replying to it.
+    fn dispatch(&mut self, processor: &mut Processor, message: Result<Json, String>) -> Action {
+        let request = match message {
+            Ok(request) => request,
+            Err(e) => {
+                eprintln!("warning: ignoring a debug adapter message: {}", e);
+                return Action::Nothing;
+            }
+        };
+        let command = request
+            .get("command")
+            .and_then(Json::as_str)
+            .unwrap_or_default()
+            .to_string();
+        let null = Json::Null;
+        let arguments = request.get("arguments").unwrap_or(&null);
+        let result = self.handle(processor, &command, arguments);
+        let request_seq = request.get("seq").cloned().unwrap_or(Json::Null);
+        let (action, detail) = match result {
+            Ok((body, action)) => (action, ("body", body)),
+            Err(message) => (Action::Nothing, ("message", message.into())),
+        };
+        self.send(Json::object([
+            ("type", "response".into()),
+            ("request_seq", request_seq),
+            ("success", (detail.0 == "body").into()),
+            ("command", command.as_str().into()),
+            detail,
+        ]));
+        if command == "initialize" {
+            self.event("initialized", Json::object([]));
+        }
+        action
+    }
+
+    fn handle(
+        &mut self,
+        processor: &mut Processor,
+        command: &str,
+        arguments: &Json,
+    ) -> Result<(Json, Action), String> {
+        let pc = processor.pc();
+        let depth = processor.stack().len();
+        let body = match command {
+            "initialize" => Json::object([
+                ("supportsConfigurationDoneRequest", true.into()),
+                ("supportsConditionalBreakpoints", true.into()),
+                ("supportsHitConditionalBreakpoints", true.into()),
+                ("supportsInstructionBreakpoints", true.into()),
+                ("supportsDataBreakpoints", true.into()),
+                ("supportsSteppingGranularity", true.into()),
+                ("supportsSetVariable", true.into()),
+                ("supportsReadMemoryRequest", true.into()),
+                ("supportsWriteMemoryRequest", true.into()),
+                ("supportsDisassembleRequest", true.into()),
+                ("supportsTerminateRequest", true.into()),
+            ]),
+            "launch" | "attach" => {
+                self.stop_on_entry = arguments
+                    .get("stopOnEntry")
+                    .and_then(Json::as_bool)
+                    .unwrap_or(false);
+                Json::object([])
+            }
+            "configurationDone" => {
+                let action = match self.stop_on_entry {
+                    true => Action::Stop("entry"),
+                    false => Action::Resume(Mode::Run),
+                };
+                return Ok((Json::object([]), action));
+            }
+            "setBreakpoints" => self.set_source_breakpoints(arguments)?,
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(arguments)?,
+            "setExceptionBreakpoints" => Json::object([("breakpoints", Vec::new().into())]),
+            "dataBreakpointInfo" => {
+                let name = arguments.get("name").and_then(Json::as_str).unwrap_or("");
+                match name.parse::<Register>() {
+                    Ok(register) => Json::object([
+                        ("dataId", register.to_string().into()),
+                        ("description", format!("{} changes", register).into()),
+                        ("accessTypes", vec!["write".into()].into()),
+                    ]),
+                    Err(e) => Json::object([("dataId", Json::Null), ("description", e.into())]),
+                }
+            }
+            "setDataBreakpoints" => self.set_data_breakpoints(arguments)?,
+            "threads" => Json::object([(
+                "threads",
+                vec![Json::object([
+                    ("id", 1u8.into()),
+                    ("name", "CHIP-8".into()),
+                ])]
+                .into(),
+            )]),
+            "stackTrace" => self.stack_trace(processor),
+            "scopes" => {
+                let scope = |name: &str, reference: i64| {
+                    Json::object([
+                        ("name", name.into()),
+                        ("variablesReference", reference.into()),
+                        ("expensive", false.into()),
+                    ])
+                };
+                Json::object([(
+                    "scopes",
+                    vec![
+                        scope("Registers", REGISTERS),
+                        scope("Timers", TIMERS),
+                        scope("Stack", STACK),
+                    ]
+                    .into(),
+                )])
+            }
+            "variables" => {
+                let reference = arguments.get("variablesReference").and_then(Json::as_i64);
+                Json::object([("variables", variables(processor, reference).into())])
+            }
+            "setVariable" => {
+                let name = arguments.get("name").and_then(Json::as_str).unwrap_or("");
+                let value = arguments.get("value").and_then(Json::as_str).unwrap_or("");
+                let register: Register = name.parse()?;
+                register.write(processor, number(value)?)?;
+                Json::object([("value", format_value(register, processor).into())])
+            }
+            "evaluate" => {
+                let expression = arguments
+                    .get("expression")
+                    .and_then(Json::as_str)
+                    .unwrap_or("");
+                let value = expression.parse::<Condition>()?.eval(processor);
+                let result = match value {
+                    0.. => format!("{:#x} ({})", value, value),
+                    _ => value.to_string(),
+                };
+                Json::object([
+                    ("result", result.into()),
+                    ("variablesReference", 0u8.into()),
+                ])
+            }
+            "readMemory" => {
+                let addr = memory_reference(arguments)?;
+                let count = arguments.get("count").and_then(Json::as_i64).unwrap_or(0);
+                let memory = processor.memory();
+                let start = addr.min(memory.len());
+                let end = addr.saturating_add(count.max(0) as usize).min(memory.len());
+                Json::object([
+                    ("address", format!("0x{:03X}", addr).into()),
+                    ("data", base64_encode(&memory[start..end]).into()),
+                    (
+                        "unreadableBytes",
+                        (count.max(0) as usize - (end - start)).into(),
+                    ),
+                ])
+            }
+            "writeMemory" => {
+                let addr = memory_reference(arguments)?;
+                let data = arguments.get("data").and_then(Json::as_str).unwrap_or("");
+                let bytes = base64_decode(data).ok_or("invalid base64 data")?;
+                let span = addr
+                    .checked_add(bytes.len())
+                    .and_then(|end| processor.memory_mut().get_mut(addr..end))
+                    .ok_or("write goes past the end of memory")?;
+                span.copy_from_slice(&bytes);
+                Json::object([("bytesWritten", bytes.len().into())])
+            }
+            "disassemble" => self.disassemble(processor, arguments)?,
+            "continue" => {
+                let body = Json::object([("allThreadsContinued", true.into())]);
+                return Ok((body, Action::Resume(Mode::Run)));
+            }
+            "next" | "stepIn" => {
+                let by_line =
+                    arguments.get("granularity").and_then(Json::as_str) != Some("instruction");
+                let mode = match self.source_map.location(pc) {
+                    Some((file, line)) if by_line => Mode::Line {
+                        file: file.to_string(),
+                        line,
+                        depth: (command == "next").then_some(depth),
+                    },
+                    _ if command == "next" => {
+                        match debugger::disassemble_at(processor.memory(), pc) {
+                            Some((text, _)) if text.starts_with("CALL") => {
+                                Mode::Over { pc: pc + 2, depth }
+                            }
+                            _ => Mode::Step,
+                        }
+                    }
+                    _ => Mode::Step,
+                };
+                return Ok((Json::object([]), Action::Resume(mode)));
+            }
+            "stepOut" => {
+                if depth == 0 {
+                    return Err("not in a subroutine".into());
+                }
+                return Ok((Json::object([]), Action::Resume(Mode::Out { depth })));
+            }
+            "pause" => return Ok((Json::object([]), Action::Stop("pause"))),
+            "disconnect" | "terminate" => return Ok((Json::object([]), Action::Quit)),
+            other => return Err(format!("unsupported request {}", other)),
+        };
+        Ok((body, Action::Nothing))
+    }
+
+    /// Replace the breakpoints in a source file.
+    fn set_source_breakpoints(&mut self, arguments: &Json) -> Result<Json, String> {
+        let path = arguments
+            .get("source")
+            .and_then(|source| source.get("path"))
+            .and_then(Json::as_str)
+            .ok_or("setBreakpoints needs a source path")?
+            .to_string();
+        for id in self.source_breakpoints.remove(&path).unwrap_or_default() {
+            self.debugger.remove(id);
+        }
+        let mut ids = Vec::new();
+        let mut results = Vec::new();
+        for breakpoint in arguments
+            .get("breakpoints")
+            .and_then(Json::as_array)
+            .unwrap_or(&[])
+        {
+            let line = breakpoint.get("line").and_then(Json::as_i64).unwrap_or(0);
+            // The first instruction from the line
+            let addr = self
+                .source_map
+                .addresses(&path, line as usize)
+                .into_iter()
+                .min();
+            let result = match addr {
+                Some(addr) => self.add(Trigger::Pc(addr), breakpoint),
+                None => Err("no code at this line".to_string()),
+            };
+            let mut fields = vec![("line", line.into())];
+            match result {
+                Ok(id) => {
+                    ids.push(id);
+                    fields.push(("id", id.into()));
+                    fields.push(("verified", true.into()));
+                }
+                Err(e) => {
+                    fields.push(("verified", false.into()));
+                    fields.push(("message", e.into()));
+                }
+            }
+            results.push(Json::Object(
+                fields
+                    .into_iter()
+                    .map(|(k, v)| (k.to_string(), v))
+                    .collect(),
+            ));
+        }
+        self.source_breakpoints.insert(path, ids);
+        Ok(Json::object([("breakpoints", results.into())]))
+    }
+
+    fn set_instruction_breakpoints(&mut self, arguments: &Json) -> Result<Json, String> {
+        for id in std::mem::take(&mut self.instruction_breakpoints) {
+            self.debugger.remove(id);
+        }
+        let mut results = Vec::new();
+        for breakpoint in arguments
+            .get("breakpoints")
+            .and_then(Json::as_array)
+            .unwrap_or(&[])
+        {
+            let reference = breakpoint
+                .get("instructionReference")
+                .and_then(Json::as_str)
+                .unwrap_or("");
+            let offset = breakpoint.get("offset").and_then(Json::as_i64).unwrap_or(0);
+            let result = number(reference).and_then(|addr| {
+                let addr = addr
+                    .checked_add_signed(offset as isize)
+                    .ok_or("address out of range")?;
+                self.add(Trigger::Pc(addr), breakpoint)
+            });
+            results.push(match result {
+                Ok(id) => {
+                    self.instruction_breakpoints.push(id);
+                    Json::object([("id", id.into()), ("verified", true.into())])
+                }
+                Err(e) => Json::object([("verified", false.into()), ("message", e.into())]),
+            });
+        }
+        Ok(Json::object([("breakpoints", results.into())]))
+    }
+
+    fn set_data_breakpoints(&mut self, arguments: &Json) -> Result<Json, String> {
+        for id in std::mem::take(&mut self.data_breakpoints) {
+            self.debugger.remove(id);
+        }
+        let mut results = Vec::new();
+        for breakpoint in arguments
+            .get("breakpoints")
+            .and_then(Json::as_array)
+            .unwrap_or(&[])
+        {
+            let data_id = breakpoint
+                .get("dataId")
+                .and_then(Json::as_str)
+                .unwrap_or("");
+            let result = data_id
+                .parse()
+                .and_then(|register| self.add(Trigger::Register(register), breakpoint));
+            results.push(match result {
+                Ok(id) => {
+                    self.data_breakpoints.push(id);
+                    Json::object([("id", id.into()), ("verified", true.into())])
+                }
+                Err(e) => Json::object([("verified", false.into()), ("message", e.into())]),
+            });
+        }
+        Ok(Json::object([("breakpoints", results.into())]))
+    }
+
+    /// Add a point with the condition and hit condition of a DAP breakpoint.
+    fn add(&mut self, trigger: Trigger, breakpoint: &Json) -> Result<usize, String> {
+        let mut point = Point::new(trigger);
+        if let Some(condition) = breakpoint.get("condition").and_then(Json::as_str) {
+            if !condition.trim().is_empty() {
+                point.condition = Some(condition.parse()?);
+            }
+        }
+        if let Some(hits) = breakpoint.get("hitCondition").and_then(Json::as_str) {
+            // Break on the Nth hit
+            let n: u64 = hits
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid hit count {}", hits))?;
+            point.ignore = n.saturating_sub(1);
+        }
+        Ok(self.debugger.add(point))
+    }
+
+    /// The current instruction, then each call below it.
+    fn stack_trace(&self, processor: &Processor) -> Json {
+        let stack = processor.stack();
+        // The address of each frame's instruction: pc, then the calls
+        let addrs: Vec<usize> = std::iter::once(processor.pc())
+            .chain(stack.iter().rev().map(|ret| ret.saturating_sub(2)))
+            .collect();
+        let frames: Vec<Json> = addrs
+            .iter()
+            .enumerate()
+            .map(|(n, &addr)| {
+                // Frames are named after the subroutine the next call entered
+                let name = match addrs.get(n + 1).and_then(|&call| word(processor, call)) {
+                    Some(opcode) => format!("0x{:03X}", opcode & 0xfff),
+                    None => "main".to_string(),
+                };
+                let mut fields = vec![
+                    ("id", n.into()),
+                    ("name", name.into()),
+                    (
+                        "instructionPointerReference",
+                        format!("0x{:03X}", addr).into(),
+                    ),
+                    ("line", 0u8.into()),
+                    ("column", 0u8.into()),
+                ];
+                if let Some((file, line)) = self.source_map.location(addr) {
+                    fields[3] = ("line", line.into());
+                    fields[4] = ("column", 1u8.into());
+                    fields.push(("source", Json::object([("path", file.into())])));
+                }
+                Json::Object(
+                    fields
+                        .into_iter()
+                        .map(|(k, v)| (k.to_string(), v))
+                        .collect(),
+                )
+            })
+            .collect();
+        Json::object([
+            ("totalFrames", frames.len().into()),
+            ("stackFrames", frames.into()),
+        ])
+    }
+
+    fn disassemble(&self, processor: &Processor, arguments: &Json) -> Result<Json, String> {
+        let base = i64::try_from(memory_reference(arguments)?)
+            .map_err(|_| "address out of range".to_string())?;
+        let offset = arguments
+            .get("instructionOffset")
+            .and_then(Json::as_i64)
+            .unwrap_or(0);
+        let count = arguments
+            .get("instructionCount")
+            .and_then(Json::as_i64)
+            .unwrap_or(0);
+        if count > MAX_INSTRUCTIONS {
+            return Err(format!(
+                "can't disassemble more than {} instructions",
+                MAX_INSTRUCTIONS
+            ));
+        }
+        // Counting back assumes two byte instructions, which almost all are
+        let mut addr = base.saturating_add(offset.saturating_mul(2));
+        let memory = processor.memory();
+        let mut instructions = Vec::new();
+        for _ in 0..count.max(0) {
+            let decoded = usize::try_from(addr)
+                .ok()
+                .and_then(|a| debugger::disassemble_at(memory, a).map(|d| (a, d)));
+            let Some((a, (text, size))) = decoded else {
+                instructions.push(Json::object([
+                    ("address", format!("{:#x}", addr.max(0)).into()),
+                    ("instruction", "".into()),
+                    ("presentationHint", "invalid".into()),
+                ]));
+                addr = addr.saturating_add(2);
+                continue;
+            };
+            let bytes: String = memory[a..(a + size).min(memory.len())]
+                .iter()
+                .map(|b| format!("{:02X}", b))
+                .collect();
+            let mut fields = vec![
+                ("address".to_string(), format!("0x{:03X}", a).into()),
+                ("instructionBytes".to_string(), bytes.into()),
+                ("instruction".to_string(), text.into()),
+            ];
+            if let Some((file, line)) = self.source_map.location(a) {
+                fields.push((
+                    "location".to_string(),
+                    Json::object([("path", file.into())]),
+                ));
+                fields.push(("line".to_string(), line.into()));
+            }
+            instructions.push(Json::Object(fields));
+            addr = addr.saturating_add(size as i64);
+        }
+        Ok(Json::object([("instructions", instructions.into())]))
+    }
+
+    /// Serve requests until the editor resumes. Returns false to quit.
+    fn serve(&mut self, processor: &mut Processor) -> bool {
+        loop {
+            let Ok(message) = self.requests.recv() else {
+                return false;
+            };
+            match self.dispatch(processor, message) {
+                Action::Nothing => {}
+                Action::Resume(mode) => {
+                    self.mode = mode;
+                    self.debugger.resume_from(processor.pc());
+                    return true;
+                }
+                Action::Stop(reason) => self.stopped(reason, Vec::new()),
+                Action::Quit => return false,
+            }
+        }
+    }
+}
+
+impl DebugFrontend for DapServer {
+    fn before_step(&mut self, processor: &mut Processor) -> bool {
+        if self.mode == Mode::Configuring {
+            return self.serve(processor);
+        }
+        let hits = self.debugger.check(processor);
+        loop {
+            match self.requests.try_recv() {
+                Ok(message) => match self.dispatch(processor, message) {
+                    Action::Nothing => {}
+                    Action::Resume(mode) => self.mode = mode,
+                    Action::Stop(reason) => self.pending = Some(reason),
+                    Action::Quit => return false,
+                },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return false,
+            }
+        }
+
+        let pc = processor.pc();
+        let depth = processor.stack().len();
+        let reason = if !hits.is_empty() {
+            let breakpoint = hits
+                .iter()
+                .any(|hit| matches!(hit.event, Event::Breakpoint { .. }));
+            Some(if breakpoint {
+                "breakpoint"
+            } else {
+                "data breakpoint"
+            })
+        } else if let Some(reason) = self.pending.take() {
+            Some(reason)
+        } else {
+            let stop = match &self.mode {
+                Mode::Configuring | Mode::Run => false,
+                Mode::Step => true,
+                Mode::Over {
+                    pc: target,
+                    depth: max,
+                } => pc == *target && depth <= *max,
+                Mode::Out { depth: max } => depth < *max,
+                Mode::Line {
+                    file,
+                    line,
+                    depth: max,
+                } => {
+                    let location = self.source_map.location(pc);
+                    location.is_some_and(|location| location != (file.as_str(), *line))
+                        && max.is_none_or(|max| depth <= max)
+                }
+            };
+            stop.then_some("step")
+        };
+        let Some(reason) = reason else {
+            return true;
+        };
+        self.stopped(reason, hits.iter().map(|hit| hit.id).collect());
+        self.serve(processor)
+    }
+
+    fn pause(&mut self) {
+        self.pending = Some("pause");
+    }
+}
+
+impl Drop for DapServer {
+    /// Tell the editor the program has ended.
+    fn drop(&mut self) {
+        self.event("exited", Json::object([("exitCode", 0u8.into())]));
+        self.event("terminated", Json::object([]));
+    }
+}
+
+/// Read the next message's content, or None at the end of the input.
+/// Messages over `MAX_MESSAGE` bytes are refused rather than buffered.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let length = length.unwrap_or(0);
+    if length > MAX_MESSAGE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("message of {} bytes is too long", length),
+        ));
+    }
+    let mut content = vec![0; length];
+    input.read_exact(&mut content)?;
+    Ok(Some(String::from_utf8_lossy(&content).into_owned()))
+}
+
+fn word(processor: &Processor, addr: usize) -> Option<u16> {
+    let bytes = processor.memory().get(addr..addr + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// Hex with a 0x prefix, or decimal.
+fn number(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| format!("invalid number {}", s))
+}
+
+fn memory_reference(arguments: &Json) -> Result<usize, String> {
+    let reference = arguments
+        .get("memoryReference")
+        .and_then(Json::as_str)
+        .ok_or("missing memoryReference")?;
+    let offset = arguments.get("offset").and_then(Json::as_i64).unwrap_or(0);
+    number(reference)?
+        .checked_add_signed(offset as isize)
+        .ok_or_else(|| "address out of range".to_string())
+}
+
+fn format_value(register: Register, processor: &Processor) -> String {
+    match register {
+        Register::I | Register::Pc => format!("0x{:03X}", register.read(processor)),
+        _ => format!("0x{:02X}", register.read(processor)),
+    }
+}
+
+fn variables(processor: &Processor, reference: Option<i64>) -> Vec<Json> {
+    let variable = |name: String, value: String, memory: Option<usize>| {
+        let mut fields = vec![
+            ("name".to_string(), name.into()),
+            ("value".to_string(), value.into()),
+            ("variablesReference".to_string(), 0u8.into()),
+        ];
+        if let Some(addr) = memory {
+            fields.push((
+                "memoryReference".to_string(),
+                format!("0x{:03X}", addr).into(),
+            ));
+        }
+        Json::Object(fields)
+    };
+    let register = |register: Register| {
+        let memory =
+            matches!(register, Register::I | Register::Pc).then(|| register.read(processor));
+        variable(
+            register.to_string(),
+            format_value(register, processor),
+            memory,
+        )
+    };
+    match reference {
+        Some(REGISTERS) => (0..16)
+            .map(Register::V)
+            .chain([Register::I, Register::Pc])
+            .map(register)
+            .collect(),
+        Some(TIMERS) => [Register::Delay, Register::Sound].map(register).to_vec(),
+        Some(STACK) => std::iter::once(register(Register::Sp))
+            .chain(
+                processor
+                    .stack()
+                    .iter()
+                    .enumerate()
+                    .rev()
+                    .map(|(n, &addr)| {
+                        variable(format!("#{}", n), format!("0x{:03X}", addr), Some(addr))
+                    }),
+            )
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let (mut bits, mut n) = (0u32, 0);
+    for c in s.bytes().filter(|&c| c != b'=') {
+        let value = BASE64.iter().position(|&b| b == c)? as u32;
+        bits = bits << 6 | value;
+        n += 6;
+        if n >= 8 {
+            n -= 8;
+            out.push((bits >> n) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::json::Json;
+    use super::{base64_decode, base64_encode, read_message, DapServer};
+    use crate::debugger::{DebugFrontend, SourceMap};
+    use crate::processor::Processor;
+    use std::io::{self, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    struct Client {
+        stream: TcpStream,
+        reader: BufReader<TcpStream>,
+        seq: u64,
+    }
+
+    impl Client {
+        fn receive(&mut self) -> Json {
+            let message = read_message(&mut self.reader).unwrap().unwrap();
+            message.parse().unwrap()
+        }
+
+        /// Send a request and return the body of the response.
+        fn request(&mut self, command: &str, arguments: &str) -> Json {
+            let reply = self.response(command, arguments);
+            let success = reply.get("success").and_then(Json::as_bool).unwrap();
+            assert!(success, "{} failed: {}", command, reply);
+            reply.get("body").cloned().unwrap_or(Json::Null)
+        }
+
+        /// Send a request and return the whole response.
+        fn response(&mut self, command: &str, arguments: &str) -> Json {
+            self.seq += 1;
+            let message = format!(
+                r#"{{"seq":{},"type":"request","command":"{}","arguments":{}}}"#,
+                self.seq, command, arguments
+            );
+            write!(
+                self.stream,
+                "Content-Length: {}\r\n\r\n{}",
+                message.len(),
+                message
+            )
+            .unwrap();
+            loop {
+                let reply = self.receive();
+                if reply.get("type").and_then(Json::as_str) == Some("response") {
+                    assert_eq!(reply.get("command").and_then(Json::as_str), Some(command));
+                    return reply;
+                }
+            }
+        }
+
+        /// Wait for a stopped event and return its reason.
+        fn stopped(&mut self) -> String {
+            loop {
+                let event = self.receive();
+                if event.get("event").and_then(Json::as_str) == Some("stopped") {
+                    let body = event.get("body").unwrap();
+                    return body
+                        .get("reason")
+                        .and_then(Json::as_str)
+                        .unwrap()
+                        .to_string();
+                }
+            }
+        }
+
+        fn pc(&mut self) -> String {
+            let trace = self.request("stackTrace", r#"{"threadId":1}"#);
+            let frames = trace.get("stackFrames").and_then(Json::as_array).unwrap();
+            let pc = frames[0]
+                .get("instructionPointerReference")
+                .and_then(Json::as_str);
+            pc.unwrap().to_string()
+        }
+    }
+
+    #[test]
+    fn test_read_message() {
+        let mut input = io::Cursor::new("Content-Length: 2\r\n\r\n{}");
+        assert_eq!(read_message(&mut input).unwrap().unwrap(), "{}");
+        assert!(read_message(&mut input).unwrap().is_none());
+
+        let mut input = io::Cursor::new("Content-Length: 99999999999\r\n\r\n{}");
+        assert!(read_message(&mut input).is_err());
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64_encode(b"CHIP-8"), "Q0hJUC04");
+        assert_eq!(base64_encode(&[0xbe, 0xef]), "vu8=");
+        assert_eq!(base64_decode("vu8=").unwrap(), [0xbe, 0xef]);
+        assert_eq!(base64_decode("Q0hJUC04").unwrap(), b"CHIP-8");
+        assert!(base64_decode("v!").is_none());
+    }
+
+    #[test]
+    fn test_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let stream = TcpStream::connect(addr).unwrap();
+            stream.set_nodelay(true).unwrap();
+            let reader = BufReader::new(stream.try_clone().unwrap());
+            let mut c = Client {
+                stream,
+                reader,
+                seq: 0,
+            };
+            let mut log = Vec::new();
+
+            c.request("initialize", r#"{"adapterID":"chip8"}"#);
+            c.request("launch", r#"{"stopOnEntry":true}"#);
+            let body = c.request(
+                "setBreakpoints",
+                r#"{"source":{"path":"/src/game.8o"},"breakpoints":[{"line":3,"condition":"v0 == 7"},{"line":9}]}"#,
+            );
+            log.push(body.to_string());
+            c.request(
+                "setInstructionBreakpoints",
+                r#"{"breakpoints":[{"instructionReference":"0x206"}]}"#,
+            );
+            c.request("configurationDone", "{}");
+            log.push(c.stopped());
+            log.push(c.pc());
+
+            c.request("continue", r#"{"threadId":1}"#);
+            log.push(c.stopped());
+            log.push(c.pc());
+            let registers = c.request("variables", r#"{"variablesReference":1}"#);
+            log.push(registers.get("variables").unwrap().as_array().unwrap()[0].to_string());
+
+            c.request("setInstructionBreakpoints", r#"{"breakpoints":[]}"#);
+            c.request("continue", r#"{"threadId":1}"#);
+            log.push(c.stopped());
+            log.push(c.pc());
+            let result = c.request("evaluate", r#"{"expression":"v0 + 1"}"#);
+            log.push(result.get("result").unwrap().to_string());
+
+            c.request("next", r#"{"threadId":1}"#);
+            log.push(c.stopped());
+            log.push(c.pc());
+            c.request("next", r#"{"threadId":1,"granularity":"instruction"}"#);
+            log.push(c.pc());
+
+            c.request(
+                "setVariable",
+                r#"{"variablesReference":1,"name":"V1","value":"0x2a"}"#,
+            );
+            c.request(
+                "writeMemory",
+                r#"{"memoryReference":"0x300","data":"vu8="}"#,
+            );
+            let memory = c.request("readMemory", r#"{"memoryReference":"0x2ff","count":3}"#);
+            log.push(memory.get("data").unwrap().to_string());
+            let overflow = c.response(
+                "writeMemory",
+                r#"{"memoryReference":"0xffffffffffffffff","data":"vu8="}"#,
+            );
+            log.push(overflow.get("message").unwrap().to_string());
+            let far = c.request(
+                "disassemble",
+                r#"{"memoryReference":"0x200","instructionOffset":-9223372036854775807,"instructionCount":1}"#,
+            );
+            log.push(far.to_string());
+            let stack = c.request("variables", r#"{"variablesReference":3}"#);
+            log.push(stack.to_string());
+            let code = c.request(
+                "disassemble",
+                r#"{"memoryReference":"0x202","instructionOffset":-1,"instructionCount":2}"#,
+            );
+            log.push(code.to_string());
+            c.request("disconnect", "{}");
+            log
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nodelay(true).unwrap();
+        let map: SourceMap = "0x200 game.8o:1\n0x202 game.8o:2\n0x204 game.8o:3\n\
+                              0x206 game.8o:4\n0x208 game.8o:5\n"
+            .parse()
+            .unwrap();
+        let mut server =
+            DapServer::new(stream.try_clone().unwrap(), Box::new(stream)).with_source_map(map);
+        let mut vm = Processor::new();
+        // LD V0, 5; ADD V0, 1; LD I, 0x300; LD [I], V0; JP 0x202
+        vm.load_rom(&[0x60, 0x05, 0x70, 0x01, 0xa3, 0x00, 0xf0, 0x55, 0x12, 0x02])
+            .unwrap();
+        while vm.run_frame_with(100, |vm| server.before_step(vm)).unwrap() {}
+        drop(server);
+
+        let log = client.join().unwrap();
+        assert_eq!(
+            log[0],
+            r#"{"breakpoints":[{"line":3,"id":1,"verified":true},{"line":9,"verified":false,"message":"no code at this line"}]}"#
+        );
+        assert_eq!(log[1..3], ["entry", "0x200"]);
+        // The instruction breakpoint, then the source one once V0 is 7
+        assert_eq!(log[3..5], ["breakpoint", "0x206"]);
+        assert_eq!(
+            log[5],
+            r#"{"name":"V0","value":"0x06","variablesReference":0}"#
+        );
+        assert_eq!(log[6..9], ["breakpoint", "0x204", "\"0x8 (8)\""]);
+        assert_eq!(log[9..12], ["step", "0x206", "0x208"]);
+        assert_eq!(log[12], "\"AL7v\"");
+        assert_eq!(log[13], "\"write goes past the end of memory\"");
+        assert_eq!(
+            log[14],
+            r#"{"instructions":[{"address":"0x0","instruction":"","presentationHint":"invalid"}]}"#
+        );
+        assert_eq!(
+            log[15],
+            r#"{"variables":[{"name":"SP","value":"0x00","variablesReference":0}]}"#
+        );
+        assert_eq!(
+            log[16],
+            r#"{"instructions":[{"address":"0x200","instructionBytes":"6005","instruction":"LD V0, 0x05","location":{"path":"game.8o"},"line":1},{"address":"0x202","instructionBytes":"7001","instruction":"ADD V0, 0x01","location":{"path":"game.8o"},"line":2}]}"#
+        );
+    }
+}
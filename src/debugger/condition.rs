@@ -0,0 +1,294 @@
+// Conditions for breakpoints and watchpoints, such as `v3 == 0x10 && i > 0x300`.
+// Operands are numbers (decimal, or hex with a 0x prefix), the registers
+// v0-vf, i, pc, dt, st and sp (the stack depth), and `[ADDR]` for the byte
+// in memory at ADDR. Arithmetic binds tighter than comparisons, which bind
+// tighter than `&&` and `||`, so `v0 & 0x80 != 0` means what it looks like.
+use super::Register;
+use crate::processor::Processor;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    source: String,
+    expr: Expr,
+}
+
+impl Condition {
+    pub fn eval(&self, processor: &Processor) -> i64 {
+        self.expr.eval(processor)
+    }
+
+    pub fn is_true(&self, processor: &Processor) -> bool {
+        self.eval(processor) != 0
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+        let expr = parser.or()?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(format!("unexpected {} in condition", token));
+        }
+        Ok(Self {
+            source: s.trim().to_string(),
+            expr,
+        })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    BitAnd,
+    BitOr,
+    BitXor,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    Number(i64),
+    Register(Register),
+    Memory(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn eval(&self, processor: &Processor) -> i64 {
+        match self {
+            Expr::Number(n) => *n,
+            Expr::Register(register) => register.read(processor) as i64,
+            Expr::Memory(addr) => {
+                let addr = addr.eval(processor);
+                usize::try_from(addr)
+                    .ok()
+                    .and_then(|addr| processor.memory().get(addr))
+                    .map_or(0, |&byte| byte as i64)
+            }
+            Expr::Not(expr) => (expr.eval(processor) == 0) as i64,
+            // Short circuit, so `[a]` past the end of memory can be guarded
+            Expr::Binary(Op::And, a, b) => {
+                (a.eval(processor) != 0 && b.eval(processor) != 0) as i64
+            }
+            Expr::Binary(Op::Or, a, b) => (a.eval(processor) != 0 || b.eval(processor) != 0) as i64,
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (a.eval(processor), b.eval(processor));
+                match op {
+                    Op::Eq => (a == b) as i64,
+                    Op::Ne => (a != b) as i64,
+                    Op::Lt => (a < b) as i64,
+                    Op::Le => (a <= b) as i64,
+                    Op::Gt => (a > b) as i64,
+                    Op::Ge => (a >= b) as i64,
+                    Op::Add => a.wrapping_add(b),
+                    Op::Sub => a.wrapping_sub(b),
+                    Op::BitAnd => a & b,
+                    Op::BitOr => a | b,
+                    Op::BitXor => a ^ b,
+                    Op::And | Op::Or => unreachable!(),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Name(name) => write!(f, "{}", name),
+            Token::Symbol(symbol) => write!(f, "{}", symbol),
+        }
+    }
+}
+
+// Longest first, so `&&` isn't read as two `&`
+const SYMBOLS: [&str; 18] = [
+    "&&", "||", "==", "!=", "<=", ">=", "<", ">", "+", "-", "&", "|", "^", "!", "(", ")", "[", "]",
+];
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = s.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if c.is_ascii_alphanumeric() {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            let word = &rest[..end];
+            let token = if c.is_ascii_digit() {
+                let n = match word.strip_prefix("0x") {
+                    Some(hex) => i64::from_str_radix(hex, 16),
+                    None => word.parse(),
+                };
+                Token::Number(n.map_err(|_| format!("invalid number {}", word))?)
+            } else {
+                Token::Name(word.to_ascii_lowercase())
+            };
+            tokens.push(token);
+            rest = &rest[end..];
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|symbol| rest.starts_with(*symbol))
+                .ok_or_else(|| format!("unexpected {} in condition", c))?;
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn eat(&mut self, symbol: &str) -> bool {
+        if matches!(self.tokens.get(self.pos), Some(Token::Symbol(s)) if *s == symbol) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Parse a left associative chain of `next` joined by any of `ops`.
+    fn chain(
+        &mut self,
+        ops: &[(&str, Op)],
+        next: fn(&mut Self) -> Result<Expr, String>,
+    ) -> Result<Expr, String> {
+        let mut expr = next(self)?;
+        'outer: loop {
+            for &(symbol, op) in ops {
+                if self.eat(symbol) {
+                    expr = Expr::Binary(op, Box::new(expr), Box::new(next(self)?));
+                    continue 'outer;
+                }
+            }
+            return Ok(expr);
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        self.chain(&[("||", Op::Or)], Self::and)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        self.chain(&[("&&", Op::And)], Self::comparison)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let ops = [
+            ("==", Op::Eq),
+            ("!=", Op::Ne),
+            ("<=", Op::Le),
+            (">=", Op::Ge),
+            ("<", Op::Lt),
+            (">", Op::Gt),
+        ];
+        self.chain(&ops, Self::arithmetic)
+    }
+
+    fn arithmetic(&mut self) -> Result<Expr, String> {
+        let ops = [
+            ("+", Op::Add),
+            ("-", Op::Sub),
+            ("&", Op::BitAnd),
+            ("|", Op::BitOr),
+            ("^", Op::BitXor),
+        ];
+        self.chain(&ops, Self::unary)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let expr = self.or()?;
+            return self.close(")", expr);
+        }
+        if self.eat("[") {
+            let expr = self.or()?;
+            return self.close("]", Expr::Memory(Box::new(expr)));
+        }
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Name(name)) => Ok(Expr::Register(name.parse()?)),
+            Some(token) => Err(format!("unexpected {} in condition", token)),
+            None => Err("condition ends early".to_string()),
+        }
+    }
+
+    fn close(&mut self, symbol: &str, expr: Expr) -> Result<Expr, String> {
+        if self.eat(symbol) {
+            Ok(expr)
+        } else {
+            Err(format!("missing {} in condition", symbol))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Condition;
+    use crate::processor::Processor;
+
+    #[test]
+    fn test_eval() {
+        let mut vm = Processor::new();
+        vm.set_register(3, 0x10);
+        vm.set_i(0x301);
+        vm.memory_mut()[0x301] = 7;
+
+        let eval = |s: &str| s.parse::<Condition>().unwrap().eval(&vm);
+        assert_eq!(eval("v3 == 0x10 && i > 0x300"), 1);
+        assert_eq!(eval("v3 == 16 && i > 0x301"), 0);
+        assert_eq!(eval("V3 + 1 == 17 || pc == 0"), 1);
+        assert_eq!(eval("[i] + [i - 1]"), 7);
+        assert_eq!(eval("v3 & 0x30 != 0"), 1);
+        assert_eq!(eval("!(v3 < 2) && sp == 0"), 1);
+        assert_eq!(eval("[0xffffff] == 0"), 1);
+
+        assert!("v3 ==".parse::<Condition>().is_err());
+        assert!("(v3".parse::<Condition>().is_err());
+        assert!("vg == 1".parse::<Condition>().is_err());
+        assert!("v3 = 1".parse::<Condition>().is_err());
+        assert!("1 2".parse::<Condition>().is_err());
+    }
+}
@@ -0,0 +1,624 @@
+// Breakpoints and watchpoints for the emulator core, independent of any
+// user interface. A `Debugger` is consulted before every instruction; it
+// reports the watchpoints the previous instruction triggered and the
+// breakpoints at the one about to run. Frontends decide what a hit means,
+// usually pausing the processor.
+mod condition;
+mod source_map;
+
+pub use condition::Condition;
+pub use source_map::SourceMap;
+
+use crate::instruction::Instruction;
+use crate::processor::{MemoryAccess, Processor, Timers};
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
+/// A register that conditions can read and watchpoints can watch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    V(u8),
+    I,
+    Pc,
+    Delay,
+    Sound,
+    /// The number of subroutines being run.
+    Sp,
+}
+
+impl Register {
+    pub fn read(&self, processor: &Processor) -> usize {
+        match *self {
+            Register::V(x) => processor.registers()[x as usize] as usize,
+            Register::I => processor.i() as usize,
+            Register::Pc => processor.pc(),
+            Register::Delay => processor.timers().delay as usize,
+            Register::Sound => processor.timers().sound as usize,
+            Register::Sp => processor.stack().len(),
+        }
+    }
+
+    pub fn write(&self, processor: &mut Processor, value: usize) -> Result<(), String> {
+        let too_big = || format!("{:#x} is too big for {}", value, self);
+        let byte = || u8::try_from(value).map_err(|_| too_big());
+        let timers = processor.timers();
+        match *self {
+            Register::V(x) => processor.set_register(x as usize, byte()?),
+            Register::I => processor.set_i(u16::try_from(value).map_err(|_| too_big())?),
+            Register::Pc => processor.set_pc(value),
+            Register::Delay => processor.set_timers(Timers {
+                delay: byte()?,
+                ..timers
+            }),
+            Register::Sound => processor.set_timers(Timers {
+                sound: byte()?,
+                ..timers
+            }),
+            Register::Sp => {
+                if !processor.set_sp(value) {
+                    return Err(too_big());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses the names `v0`-`vf`, `i`, `pc`, `dt`, `st` and `sp`, in any case.
+impl FromStr for Register {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.to_ascii_lowercase();
+        match name.as_str() {
+            "i" => Ok(Register::I),
+            "pc" => Ok(Register::Pc),
+            "dt" => Ok(Register::Delay),
+            "st" => Ok(Register::Sound),
+            "sp" => Ok(Register::Sp),
+            _ => name
+                .strip_prefix('v')
+                .filter(|x| x.len() == 1)
+                .and_then(|x| u8::from_str_radix(x, 16).ok())
+                .map(Register::V)
+                .ok_or_else(|| format!("unknown register {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Register::V(x) => write!(f, "V{:X}", x),
+            Register::I => write!(f, "I"),
+            Register::Pc => write!(f, "PC"),
+            Register::Delay => write!(f, "DT"),
+            Register::Sound => write!(f, "ST"),
+            Register::Sp => write!(f, "SP"),
+        }
+    }
+}
+
+/// Matches opcodes against a pattern such as `DXYN`: hex digits must match
+/// and any other character matches any digit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpcodePattern {
+    mask: u16,
+    value: u16,
+}
+
+impl OpcodePattern {
+    pub fn matches(&self, opcode: u16) -> bool {
+        opcode & self.mask == self.value
+    }
+}
+
+impl FromStr for OpcodePattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.chars().count() != 4 {
+            return Err(format!(
+                "invalid opcode pattern {} (expected four characters like DXYN)",
+                s
+            ));
+        }
+        let (mut mask, mut value) = (0, 0);
+        for c in s.chars() {
+            mask <<= 4;
+            value <<= 4;
+            if let Some(digit) = c.to_digit(16) {
+                mask |= 0xf;
+                value |= digit as u16;
+            }
+        }
+        Ok(Self { mask, value })
+    }
+}
+
+impl fmt::Display for OpcodePattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for shift in [12, 8, 4, 0] {
+            if self.mask >> shift & 0xf == 0 {
+                write!(f, "_")?;
+            } else {
+                write!(f, "{:X}", self.value >> shift & 0xf)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Trigger {
+    /// Before the instruction at an address runs.
+    Pc(usize),
+    /// Before any instruction matching a pattern runs.
+    Opcode(OpcodePattern),
+    /// After an instruction reads from an address range.
+    Read(Range<usize>),
+    /// After an instruction writes to an address range, even if the value
+    /// stays the same.
+    Write(Range<usize>),
+    /// After a register changes.
+    Register(Register),
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let range = |f: &mut fmt::Formatter, range: &Range<usize>| {
+            if range.len() == 1 {
+                write!(f, "0x{:03X}", range.start)
+            } else {
+                write!(f, "0x{:03X}-0x{:03X}", range.start, range.end - 1)
+            }
+        };
+        match self {
+            Trigger::Pc(addr) => write!(f, "breakpoint at 0x{:03X}", addr),
+            Trigger::Opcode(pattern) => write!(f, "breakpoint on {}", pattern),
+            Trigger::Read(addrs) => {
+                write!(f, "watchpoint on reads from ")?;
+                range(f, addrs)
+            }
+            Trigger::Write(addrs) => {
+                write!(f, "watchpoint on writes to ")?;
+                range(f, addrs)
+            }
+            Trigger::Register(register) => write!(f, "watchpoint on {}", register),
+        }
+    }
+}
+
+/// A breakpoint or watchpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Point {
+    pub trigger: Trigger,
+    /// Only hit while this holds.
+    pub condition: Option<Condition>,
+    /// The number of times the point has been hit.
+    pub hits: u64,
+    /// Hits to pass over before reporting any.
+    pub ignore: u64,
+    pub enabled: bool,
+}
+
+impl Point {
+    pub fn new(trigger: Trigger) -> Self {
+        Self {
+            trigger,
+            condition: None,
+            hits: 0,
+            ignore: 0,
+            enabled: true,
+        }
+    }
+
+    pub fn with_condition(mut self, condition: Condition) -> Self {
+        self.condition = Some(condition);
+        self
+    }
+}
+
+impl fmt::Display for Point {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.trigger)?;
+        if let Some(condition) = &self.condition {
+            write!(f, " if {}", condition)?;
+        }
+        write!(f, ", hit {} times", self.hits)?;
+        if self.ignore > 0 {
+            write!(f, ", ignoring {} more", self.ignore)?;
+        }
+        if !self.enabled {
+            write!(f, " (disabled)")?;
+        }
+        Ok(())
+    }
+}
+
+/// What set a point off. `pc` is the address of the instruction that ran,
+/// or is about to run for breakpoints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Breakpoint {
+        pc: usize,
+        opcode: u16,
+    },
+    Read {
+        pc: usize,
+        addr: usize,
+        value: u8,
+    },
+    Write {
+        pc: usize,
+        addr: usize,
+        old: u8,
+        new: u8,
+    },
+    Register {
+        pc: usize,
+        register: Register,
+        old: usize,
+        new: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hit {
+    /// The id `Debugger::add` returned for the point.
+    pub id: usize,
+    pub event: Event,
+}
+
+impl fmt::Display for Hit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.event {
+            Event::Breakpoint { pc, opcode } => {
+                write!(
+                    f,
+                    "#{}: breakpoint at 0x{:03X} ({:04X})",
+                    self.id, pc, opcode
+                )
+            }
+            Event::Read { pc, addr, value } => write!(
+                f,
+                "#{}: 0x{:03X} read 0x{:03X}: {:02X}",
+                self.id, pc, addr, value
+            ),
+            Event::Write { pc, addr, old, new } => write!(
+                f,
+                "#{}: 0x{:03X} wrote 0x{:03X}: {:02X} -> {:02X}",
+                self.id, pc, addr, old, new
+            ),
+            Event::Register {
+                pc,
+                register,
+                old,
+                new,
+            } => write!(
+                f,
+                "#{}: {} changed after 0x{:03X}: {:X} -> {:X}",
+                self.id, register, pc, old, new
+            ),
+        }
+    }
+}
+
+/// The instruction at `addr` as text, and its size in bytes. Words that
+/// aren't instructions come out as `???`.
+pub fn disassemble_at(memory: &[u8], addr: usize) -> Option<(String, usize)> {
+    let word = |addr: usize| {
+        memory
+            .get(addr..addr + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+    };
+    Some(match Instruction::decode(word(addr)?) {
+        Ok(Instruction::LoadILong) => {
+            let nnnn = word(addr + 2).unwrap_or(0);
+            (format!("LD I, 0x{:04X}", nnnn), 4)
+        }
+        Ok(instruction) => (instruction.to_string(), instruction.size()),
+        Err(_) => ("???".to_string(), 2),
+    })
+}
+
+/// A debugger frontend that takes control between instructions, like the
+/// monitor or the GDB stub.
+pub trait DebugFrontend {
+    /// The hook for `Processor::run_frame_with`. Returns false to quit.
+    fn before_step(&mut self, processor: &mut Processor) -> bool;
+
+    /// Stop before the next instruction.
+    fn pause(&mut self);
+}
+
+// The registers a watchpoint can see change, as of the last check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Snapshot {
+    pc: usize,
+    values: [usize; 21],
+}
+
+const WATCHABLE: [Register; 21] = {
+    let mut registers = [Register::I; 21];
+    let mut x = 0;
+    while x < 16 {
+        registers[x] = Register::V(x as u8);
+        x += 1;
+    }
+    registers[17] = Register::Pc;
+    registers[18] = Register::Delay;
+    registers[19] = Register::Sound;
+    registers[20] = Register::Sp;
+    registers
+};
+
+impl Snapshot {
+    fn take(processor: &Processor) -> Self {
+        Self {
+            pc: processor.pc(),
+            values: WATCHABLE.map(|register| register.read(processor)),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Debugger {
+    points: Vec<(usize, Point)>,
+    next_id: usize,
+    last: Option<Snapshot>,
+    // Breakpoints at this address are passed over at the next check
+    resumed_at: Option<usize>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a point, returning its id.
+    pub fn add(&mut self, point: Point) -> usize {
+        self.next_id += 1;
+        self.points.push((self.next_id, point));
+        self.next_id
+    }
+
+    /// Returns the point if there was one with that id.
+    pub fn remove(&mut self, id: usize) -> Option<Point> {
+        let index = self.points.iter().position(|(i, _)| *i == id)?;
+        Some(self.points.remove(index).1)
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut Point> {
+        self.points
+            .iter_mut()
+            .find(|(i, _)| *i == id)
+            .map(|(_, point)| point)
+    }
+
+    /// Every point with its id, in the order they were added.
+    pub fn points(&self) -> impl Iterator<Item = (usize, &Point)> {
+        self.points.iter().map(|(id, point)| (*id, point))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Pass over breakpoints at `pc` at the next check, so resuming from a
+    /// breakpoint doesn't stop again straight away.
+    pub fn resume_from(&mut self, pc: usize) {
+        self.resumed_at = Some(pc);
+    }
+
+    /// Call before every instruction. Returns the points hit since the
+    /// last call and at the instruction about to run.
+    pub fn check(&mut self, processor: &mut Processor) -> Vec<Hit> {
+        let watch_memory = self
+            .points
+            .iter()
+            .any(|(_, p)| matches!(p.trigger, Trigger::Read(_) | Trigger::Write(_)));
+        let accesses = processor.take_memory_accesses();
+        processor.record_memory_accesses(watch_memory);
+
+        let now = Snapshot::take(processor);
+        let last = self.last.replace(now);
+        let resumed_at = self.resumed_at.take();
+        let opcode = processor
+            .memory()
+            .get(now.pc..now.pc + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]));
+
+        let mut hits = Vec::new();
+        for (id, point) in &mut self.points {
+            if !point.enabled {
+                continue;
+            }
+            let mut events = Vec::new();
+            match &point.trigger {
+                Trigger::Pc(addr) if *addr == now.pc && resumed_at != Some(now.pc) => {
+                    events.push(Event::Breakpoint {
+                        pc: now.pc,
+                        opcode: opcode.unwrap_or_default(),
+                    });
+                }
+                Trigger::Opcode(pattern) if resumed_at != Some(now.pc) => {
+                    if let Some(opcode) = opcode.filter(|&opcode| pattern.matches(opcode)) {
+                        events.push(Event::Breakpoint { pc: now.pc, opcode });
+                    }
+                }
+                Trigger::Read(addrs) | Trigger::Write(addrs) => {
+                    let pc = last.map_or(now.pc, |last| last.pc);
+                    for access in &accesses {
+                        match (*access, &point.trigger) {
+                            (MemoryAccess::Read { addr, value }, Trigger::Read(_))
+                                if addrs.contains(&addr) =>
+                            {
+                                events.push(Event::Read { pc, addr, value });
+                            }
+                            (MemoryAccess::Write { addr, old, new }, Trigger::Write(_))
+                                if addrs.contains(&addr) =>
+                            {
+                                events.push(Event::Write { pc, addr, old, new });
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                Trigger::Register(register) => {
+                    let index = WATCHABLE.iter().position(|r| r == register).unwrap();
+                    if let Some(last) = last {
+                        let (old, new) = (last.values[index], now.values[index]);
+                        if old != new {
+                            events.push(Event::Register {
+                                pc: last.pc,
+                                register: *register,
+                                old,
+                                new,
+                            });
+                        }
+                    }
+                }
+                _ => {}
+            }
+
+            if events.is_empty()
+                || point
+                    .condition
+                    .as_ref()
+                    .is_some_and(|condition| !condition.is_true(processor))
+            {
+                continue;
+            }
+            point.hits += 1;
+            if point.ignore > 0 {
+                point.ignore -= 1;
+                continue;
+            }
+            hits.extend(events.into_iter().map(|event| Hit { id: *id, event }));
+        }
+        hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Debugger, Event, Hit, OpcodePattern, Point, Register, Trigger};
+    use crate::processor::Processor;
+
+    /// Run `vm` until the debugger reports something.
+    fn run(vm: &mut Processor, debugger: &mut Debugger) -> Vec<Hit> {
+        for _ in 0..100 {
+            let hits = debugger.check(vm);
+            if !hits.is_empty() {
+                debugger.resume_from(vm.pc());
+                return hits;
+            }
+            vm.step().unwrap();
+        }
+        Vec::new()
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let pattern: OpcodePattern = "dxyN".parse().unwrap();
+        assert!(pattern.matches(0xd125));
+        assert!(!pattern.matches(0xa125));
+        assert_eq!(pattern.to_string(), "D___");
+        assert!("DXY".parse::<OpcodePattern>().is_err());
+
+        let mut vm = Processor::new();
+        // ADD V0, 1; DRW V0, V1, 1; JP 0x200
+        vm.load_rom(&[0x70, 0x01, 0xd0, 0x11, 0x12, 0x00]).unwrap();
+        let mut debugger = Debugger::new();
+        let mut point =
+            Point::new(Trigger::Opcode(pattern)).with_condition("v0 >= 2".parse().unwrap());
+        point.ignore = 1;
+        let id = debugger.add(point);
+        let pc = debugger.add(Point::new(Trigger::Pc(0x204)));
+
+        // The first draw fails the condition, and the second is ignored
+        let hits = run(&mut vm, &mut debugger);
+        assert_eq!(hits[0].id, pc);
+        assert_eq!(vm.registers()[0], 1);
+        debugger.remove(pc);
+        let hits = run(&mut vm, &mut debugger);
+        assert_eq!(
+            hits,
+            [Hit {
+                id,
+                event: Event::Breakpoint {
+                    pc: 0x202,
+                    opcode: 0xd011
+                }
+            }]
+        );
+        assert_eq!(vm.registers()[0], 3);
+        assert_eq!(debugger.points().next().unwrap().1.hits, 2);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut vm = Processor::new();
+        // LD I, 0x300; LD V0, 5; LD [I], V0; LD V1, [I]; JP 0x208
+        vm.load_rom(&[0xa3, 0x00, 0x60, 0x05, 0xf0, 0x55, 0xf1, 0x65, 0x12, 0x08])
+            .unwrap();
+        let mut debugger = Debugger::new();
+        let write = debugger.add(Point::new(Trigger::Write(0x300..0x302)));
+        let read = debugger.add(Point::new(Trigger::Read(0x301..0x302)));
+        let v0 = debugger.add(Point::new(Trigger::Register(Register::V(0))));
+
+        let hit = |id, event| Hit { id, event };
+        assert_eq!(
+            run(&mut vm, &mut debugger),
+            [hit(
+                v0,
+                Event::Register {
+                    pc: 0x202,
+                    register: Register::V(0),
+                    old: 0,
+                    new: 5
+                }
+            )]
+        );
+        assert_eq!(
+            run(&mut vm, &mut debugger),
+            [hit(
+                write,
+                Event::Write {
+                    pc: 0x204,
+                    addr: 0x300,
+                    old: 0,
+                    new: 5
+                }
+            )]
+        );
+        // The store moved I on, so the load reads 0x301 and 0x302 into V0
+        // and V1
+        assert_eq!(
+            run(&mut vm, &mut debugger),
+            [
+                hit(
+                    read,
+                    Event::Read {
+                        pc: 0x206,
+                        addr: 0x301,
+                        value: 0
+                    }
+                ),
+                hit(
+                    v0,
+                    Event::Register {
+                        pc: 0x206,
+                        register: Register::V(0),
+                        old: 5,
+                        new: 0
+                    }
+                )
+            ]
+        );
+        // Instruction fetches aren't reads
+        assert_eq!(run(&mut vm, &mut debugger), []);
+    }
+}
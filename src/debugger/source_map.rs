@@ -0,0 +1,132 @@
+// Where in the source each instruction came from, for source level
+// debugging. Assemblers write it as text, one instruction per line:
+//
+//     0x200 game.8o:12
+//
+// Blank lines and lines starting with `#` are ignored.
+use std::ffi::OsStr;
+use std::fmt;
+use std::path::{Component, Path};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    // Address, file and line, in address order
+    entries: Vec<(usize, String, usize)>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that the instruction at `addr` came from `line` of `file`.
+    pub fn insert(&mut self, addr: usize, file: &str, line: usize) {
+        let index = self.entries.partition_point(|(a, _, _)| *a < addr);
+        match self.entries.get_mut(index) {
+            Some(entry) if entry.0 == addr => *entry = (addr, file.to_string(), line),
+            _ => self.entries.insert(index, (addr, file.to_string(), line)),
+        }
+    }
+
+    /// The file and line the instruction at `addr` came from.
+    pub fn location(&self, addr: usize) -> Option<(&str, usize)> {
+        let index = self
+            .entries
+            .binary_search_by_key(&addr, |(a, _, _)| *a)
+            .ok()?;
+        let (_, file, line) = &self.entries[index];
+        Some((file, *line))
+    }
+
+    /// The addresses of the instructions from `line` of `file`. Paths match
+    /// if the components of one end with all the components of the other,
+    /// since editors send absolute paths and maps usually hold relative
+    /// ones.
+    pub fn addresses(&self, file: &str, line: usize) -> Vec<usize> {
+        self.entries
+            .iter()
+            .filter(|(_, f, l)| *l == line && same_file(f, file))
+            .map(|(addr, _, _)| *addr)
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Whether `a` and `b` name the same file, comparing whole file and
+/// directory names so `agame.8o` doesn't match `game.8o`. Roots and `.` are
+/// left out, so `./game.8o` matches `/src/game.8o`.
+fn same_file(a: &str, b: &str) -> bool {
+    fn names(path: &str) -> Vec<&OsStr> {
+        Path::new(path)
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(name) => Some(name),
+                _ => None,
+            })
+            .collect()
+    }
+    let (a, b) = (names(a), names(b));
+    let (short, long) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    !short.is_empty() && long.ends_with(&short)
+}
+
+impl FromStr for SourceMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut map = Self::new();
+        for (n, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || format!("invalid source map entry on line {}: {}", n + 1, line);
+            let (addr, location) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+            let (file, source_line) = location.trim().rsplit_once(':').ok_or_else(invalid)?;
+            let addr = addr
+                .strip_prefix("0x")
+                .and_then(|hex| usize::from_str_radix(hex, 16).ok())
+                .ok_or_else(invalid)?;
+            map.insert(addr, file, source_line.parse().map_err(|_| invalid())?);
+        }
+        Ok(map)
+    }
+}
+
+impl fmt::Display for SourceMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (addr, file, line) in &self.entries {
+            writeln!(f, "0x{:03X} {}:{}", addr, file, line)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SourceMap;
+
+    #[test]
+    fn test_lookup() {
+        let text = "# game\n0x204 src/game.8o:3\n0x200 src/game.8o:1\n\n0x202 lib.8o:7\n";
+        let map: SourceMap = text.parse().unwrap();
+        assert_eq!(map.location(0x202), Some(("lib.8o", 7)));
+        assert_eq!(map.location(0x203), None);
+        assert_eq!(map.addresses("/home/me/src/game.8o", 3), [0x204]);
+        assert_eq!(map.addresses("game.8o", 2), []);
+        assert_eq!(map.addresses("./game.8o", 3), [0x204]);
+        assert_eq!(map.addresses("/home/me/src/agame.8o", 3), []);
+        assert_eq!(map.addresses("/home/me/mysrc/game.8o", 3), []);
+        assert_eq!(map.addresses("/", 7), []);
+        assert_eq!(
+            map.to_string(),
+            "0x200 src/game.8o:1\n0x202 lib.8o:7\n0x204 src/game.8o:3\n"
+        );
+        assert!("200 game.8o:1".parse::<SourceMap>().is_err());
+        assert!("0x200 game.8o".parse::<SourceMap>().is_err());
+    }
+}
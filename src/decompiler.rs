@@ -0,0 +1,608 @@
+// Turns a ROM into structured, Octo-like source, a step past the
+// disassembler's flat listing. It works on a `Disassembly`: the program is
+// split into subroutines at every call target, then each subroutine's jumps
+// and skips are matched against the shapes Octo compiles its control flow
+// to. A jump back to an earlier instruction closes a `loop ... again`, a
+// skip over a jump out of the loop is a `while`, and a skip over a forward
+// jump is `if ... begin`, with an `else` when the block ends by jumping
+// over the code after it. Registers with one clear use, like sprite
+// coordinates or the delay timer, get an `:alias` naming it.
+//
+// Jumps that don't fit a shape stay as `jump label`, so the output always
+// compiles back to the same bytes.
+use crate::disassembler::{Disassembly, Item};
+use crate::instruction::Instruction;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::Write;
+
+/// Structured source for the program in `disassembly`.
+pub fn decompile(disassembly: &Disassembly) -> String {
+    let items = disassembly.items();
+    let mut index: HashMap<usize, usize> = items
+        .iter()
+        .enumerate()
+        .map(|(i, item)| (item.addr(), i))
+        .collect();
+    // The end of the ROM, so blocks can run up to it
+    let end = items
+        .last()
+        .map_or(disassembly.origin(), |item| item.addr() + item.size());
+    index.insert(end, items.len());
+
+    let mut decompiler = Decompiler {
+        disassembly,
+        items,
+        index,
+        consumed: HashSet::new(),
+    };
+    // Every subroutine is structured on its own
+    let mut starts: BTreeSet<usize> = items
+        .iter()
+        .filter_map(|item| match item {
+            Item::Code {
+                instruction: Instruction::Call { nnn },
+                ..
+            } => decompiler.index.get(&(*nnn as usize)).copied(),
+            _ => None,
+        })
+        .collect();
+    starts.insert(0);
+    starts.insert(items.len());
+    let starts: Vec<usize> = starts.into_iter().collect();
+    let nodes: Vec<Node> = starts
+        .windows(2)
+        .flat_map(|span| decompiler.block(span[0], span[1], None))
+        .collect();
+
+    let mut writer = Writer {
+        disassembly,
+        items,
+        live: decompiler.live(),
+        printed: HashSet::new(),
+        aliases: aliases(disassembly, &nodes),
+        out: String::new(),
+    };
+    for (x, name) in writer.aliases.iter().enumerate() {
+        if let Some(name) = name {
+            writeln!(writer.out, ":alias {} v{:x}", name, x).unwrap();
+        }
+    }
+    writer.nodes(&nodes, 0);
+    writer.out
+}
+
+/// A piece of structured code. Items are referred to by their index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    /// An instruction or data byte as it is.
+    Item(usize),
+    /// A skip over a forward jump, and the jump over the `else` block
+    /// that ends the `then` block if there is one.
+    If {
+        skip: usize,
+        then: Vec<Node>,
+        otherwise: Option<(usize, Vec<Node>)>,
+    },
+    /// Code from `start` up to the jump back to it at `again`.
+    Loop {
+        start: usize,
+        body: Vec<Node>,
+        again: usize,
+    },
+    /// A skip over a jump out of the enclosing loop.
+    While { skip: usize },
+}
+
+struct Decompiler<'a> {
+    disassembly: &'a Disassembly,
+    items: &'a [Item],
+    // Item indexes by address
+    index: HashMap<usize, usize>,
+    // The addresses of jumps that became part of a structure
+    consumed: HashSet<usize>,
+}
+
+impl Decompiler<'_> {
+    // Where the item at `i` jumps to, if it's a plain jump
+    fn jump(&self, i: usize) -> Option<usize> {
+        match self.items.get(i)? {
+            Item::Code {
+                instruction: Instruction::Jump { nnn },
+                ..
+            } => Some(*nnn as usize),
+            _ => None,
+        }
+    }
+
+    fn skip(&self, i: usize) -> Option<Instruction> {
+        match self.items.get(i)? {
+            Item::Code { instruction, .. } if is_skip(*instruction) => Some(*instruction),
+            _ => None,
+        }
+    }
+
+    /// Structure the items from `start` up to `end`. `exit` is where a
+    /// `while` in the innermost loop jumps to.
+    fn block(&mut self, start: usize, end: usize, exit: Option<usize>) -> Vec<Node> {
+        let mut nodes = Vec::new();
+        let mut i = start;
+        while i < end {
+            let addr = self.items[i].addr();
+
+            // The furthest jump back to here closes a loop
+            if let Some(again) = (i..end).rev().find(|&j| self.jump(j) == Some(addr)) {
+                let again_addr = self.items[again].addr();
+                self.consumed.insert(again_addr);
+                let body = self.block(i, again, Some(again_addr + 2));
+                nodes.push(Node::Loop {
+                    start: i,
+                    body,
+                    again,
+                });
+                i = again + 1;
+                continue;
+            }
+
+            // A skip over a jump is a `while` or an `if ... begin`. The jump
+            // can't be labelled, since nothing can go between the two.
+            let jump = self
+                .skip(i)
+                .and(self.jump(i + 1))
+                .filter(|_| i + 1 < end)
+                .filter(|_| self.disassembly.label(self.items[i + 1].addr()).is_none());
+            if let Some(target) = jump {
+                let jump_addr = self.items[i + 1].addr();
+                if exit == Some(target) {
+                    self.consumed.insert(jump_addr);
+                    nodes.push(Node::While { skip: i });
+                    i += 2;
+                    continue;
+                }
+                let after = self.index.get(&target).copied();
+                if let Some(after) = after.filter(|&t| t > i + 1 && t <= end) {
+                    self.consumed.insert(jump_addr);
+                    // A then block ending in a jump forward skips an else block
+                    let last = after - 1;
+                    let over = self
+                        .jump(last)
+                        .filter(|&u| last > i + 1 && u > target)
+                        .and_then(|u| self.index.get(&u).copied())
+                        .filter(|&u| u <= end);
+                    let node = match over {
+                        Some(over) => {
+                            self.consumed.insert(self.items[last].addr());
+                            let then = self.block(i + 2, last, exit);
+                            let otherwise = self.block(after, over, exit);
+                            Node::If {
+                                skip: i,
+                                then,
+                                otherwise: Some((last, otherwise)),
+                            }
+                        }
+                        None => {
+                            let then = self.block(i + 2, after, exit);
+                            Node::If {
+                                skip: i,
+                                then,
+                                otherwise: None,
+                            }
+                        }
+                    };
+                    nodes.push(node);
+                    i = over.unwrap_or(after);
+                    continue;
+                }
+            }
+
+            nodes.push(Node::Item(i));
+            i += 1;
+        }
+        nodes
+    }
+
+    /// The addresses something still refers to, so need their labels.
+    fn live(&self) -> HashSet<usize> {
+        let mut live = HashSet::from([self.disassembly.origin()]);
+        for item in self.items {
+            let Item::Code {
+                addr,
+                instruction,
+                operand,
+            } = *item
+            else {
+                continue;
+            };
+            let target = match instruction {
+                Instruction::Jump { .. } if self.consumed.contains(&addr) => continue,
+                Instruction::Jump { nnn }
+                | Instruction::JumpOffset { nnn }
+                | Instruction::Call { nnn }
+                | Instruction::LoadI { nnn } => nnn as usize,
+                Instruction::LoadILong => operand.unwrap_or(0) as usize,
+                _ => continue,
+            };
+            live.insert(target);
+        }
+        live
+    }
+}
+
+/// What a register seems to be for, in order of precedence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Role {
+    X,
+    Y,
+    Counter,
+    Key,
+    Timer,
+    Sound,
+    Random,
+    Digit,
+}
+
+impl Role {
+    // Octo's keywords like `key` and `delay` can't be names
+    fn name(self) -> &'static str {
+        match self {
+            Role::X => "x",
+            Role::Y => "y",
+            Role::Counter => "count",
+            Role::Key => "keycode",
+            Role::Timer => "timer",
+            Role::Sound => "sound",
+            Role::Random => "rnd",
+            Role::Digit => "digit",
+        }
+    }
+}
+
+/// A name for each register where one use makes up at least a third of
+/// the instructions it appears in, numbered when several share a name. vf
+/// is left alone, since so much writes it as a flag.
+fn aliases(disassembly: &Disassembly, nodes: &[Node]) -> [Option<String>; 16] {
+    use Instruction::*;
+
+    let items = disassembly.items();
+    let mut roles: [BTreeMap<Role, usize>; 16] = Default::default();
+    let mut appearances = [0; 16];
+    let mut add = |x: u8, role: Role| *roles[x as usize].entry(role).or_default() += 1;
+    for item in items {
+        let Item::Code {
+            instruction,
+            operand,
+            ..
+        } = *item
+        else {
+            continue;
+        };
+        let mut mentioned: Vec<u8> = disassembly
+            .octo(instruction, operand)
+            .split(' ')
+            .filter_map(register)
+            .collect();
+        mentioned.dedup();
+        for x in mentioned {
+            appearances[x as usize] += 1;
+        }
+        match instruction {
+            Draw { x, y, .. } => {
+                add(x, Role::X);
+                add(y, Role::Y);
+            }
+            WaitKey { x } | SkipKey { x } | SkipNotKey { x } => add(x, Role::Key),
+            LoadDelay { x } | SetDelay { x } => add(x, Role::Timer),
+            SetSound { x } => add(x, Role::Sound),
+            Random { x, .. } => add(x, Role::Random),
+            LoadFont { x } | LoadBigFont { x } | StoreBcd { x } => add(x, Role::Digit),
+            _ => {}
+        }
+    }
+    // Whatever a `while` tests is counting towards the end of the loop
+    fn whiles(nodes: &[Node], items: &[Item], add: &mut dyn FnMut(u8, Role)) {
+        for node in nodes {
+            match node {
+                Node::While { skip } => {
+                    if let Item::Code { instruction, .. } = items[*skip] {
+                        add(registers(instruction).0, Role::Counter);
+                    }
+                }
+                Node::If {
+                    then, otherwise, ..
+                } => {
+                    whiles(then, items, add);
+                    if let Some((_, otherwise)) = otherwise {
+                        whiles(otherwise, items, add);
+                    }
+                }
+                Node::Loop { body, .. } => whiles(body, items, add),
+                Node::Item(_) => {}
+            }
+        }
+    }
+    whiles(nodes, items, &mut add);
+
+    let mut aliases: [Option<String>; 16] = Default::default();
+    let mut used: BTreeMap<Role, usize> = BTreeMap::new();
+    for (x, roles) in roles.iter().enumerate().take(15) {
+        let Some((&role, &n)) = roles.iter().min_by_key(|&(&role, &n)| (Reverse(n), role)) else {
+            continue;
+        };
+        if n * 3 < appearances[x] {
+            continue;
+        }
+        let n = used.entry(role).or_default();
+        *n += 1;
+        aliases[x] = Some(match n {
+            1 => role.name().to_string(),
+            n => format!("{}{}", role.name(), n),
+        });
+    }
+    aliases
+}
+
+// The register a word like `v3` names
+fn register(word: &str) -> Option<u8> {
+    let digit = word.strip_prefix('v').filter(|digit| digit.len() == 1)?;
+    u8::from_str_radix(digit, 16).ok()
+}
+
+fn is_skip(instruction: Instruction) -> bool {
+    condition(instruction, true).is_some()
+}
+
+// The x and y registers of a skip
+fn registers(instruction: Instruction) -> (u8, Option<u8>) {
+    match instruction {
+        Instruction::SkipEqImm { x, .. }
+        | Instruction::SkipNeImm { x, .. }
+        | Instruction::SkipKey { x }
+        | Instruction::SkipNotKey { x } => (x, None),
+        Instruction::SkipEqReg { x, y } | Instruction::SkipNeReg { x, y } => (x, Some(y)),
+        _ => (0, None),
+    }
+}
+
+/// The test a skip makes, as Octo writes it: what makes it skip, or with
+/// `skips` false what makes it run the next instruction.
+fn condition(instruction: Instruction, skips: bool) -> Option<String> {
+    let (x, op, rhs) = match instruction {
+        Instruction::SkipEqImm { x, kk } => (x, "==", format!(" 0x{:02X}", kk)),
+        Instruction::SkipNeImm { x, kk } => (x, "!=", format!(" 0x{:02X}", kk)),
+        Instruction::SkipEqReg { x, y } => (x, "==", format!(" v{:x}", y)),
+        Instruction::SkipNeReg { x, y } => (x, "!=", format!(" v{:x}", y)),
+        Instruction::SkipKey { x } => (x, "key", String::new()),
+        Instruction::SkipNotKey { x } => (x, "-key", String::new()),
+        _ => return None,
+    };
+    let op = match (op, skips) {
+        (op, true) => op,
+        ("==", false) => "!=",
+        ("!=", false) => "==",
+        ("key", false) => "-key",
+        (_, false) => "key",
+    };
+    Some(format!("v{:x} {}{}", x, op, rhs))
+}
+
+struct Writer<'a> {
+    disassembly: &'a Disassembly,
+    items: &'a [Item],
+    live: HashSet<usize>,
+    printed: HashSet<usize>,
+    aliases: [Option<String>; 16],
+    out: String,
+}
+
+impl Writer<'_> {
+    /// A line of code, with its address in a comment when it has one.
+    fn line(&mut self, depth: usize, text: &str, addr: Option<usize>) {
+        let code = format!("{}{}", "    ".repeat(depth + 1), self.rename(text));
+        match addr {
+            Some(addr) => writeln!(self.out, "{:<39} # 0x{:03X}", code, addr),
+            None => writeln!(self.out, "{}", code),
+        }
+        .unwrap();
+    }
+
+    // Registers with an alias are written by name
+    fn rename(&self, text: &str) -> String {
+        let words: Vec<&str> = text
+            .split(' ')
+            .map(
+                |word| match register(word).and_then(|x| self.aliases[x as usize].as_deref()) {
+                    Some(name) => name,
+                    None => word,
+                },
+            )
+            .collect();
+        words.join(" ")
+    }
+
+    /// The label at `addr`, if anything still refers to it.
+    fn label(&mut self, addr: usize, depth: usize) {
+        let Some(label) = self.disassembly.label(addr) else {
+            return;
+        };
+        if !self.live.contains(&addr) || !self.printed.insert(addr) {
+            return;
+        }
+        if depth == 0 && !self.out.is_empty() {
+            self.out.push('\n');
+        }
+        writeln!(self.out, ": {}", label).unwrap();
+    }
+
+    fn has_label(&self, addr: usize) -> bool {
+        self.disassembly.label(addr).is_some() && self.live.contains(&addr)
+    }
+
+    // The code item at `i` as a statement, if it's unlabelled and not a skip
+    fn simple_statement(&self, i: usize) -> Option<String> {
+        match self.items[i] {
+            Item::Code {
+                addr,
+                instruction,
+                operand,
+            } if !is_skip(instruction) && !self.has_label(addr) => {
+                Some(self.disassembly.octo(instruction, operand))
+            }
+            _ => None,
+        }
+    }
+
+    fn nodes(&mut self, nodes: &[Node], depth: usize) {
+        let mut k = 0;
+        while k < nodes.len() {
+            match &nodes[k] {
+                &Node::Item(i) => {
+                    let addr = self.items[i].addr();
+                    self.label(addr, depth);
+                    match self.items[i] {
+                        Item::Code {
+                            instruction,
+                            operand,
+                            ..
+                        } => {
+                            let mut text = self.disassembly.octo(instruction, operand);
+                            // On one line with what it guards, if that's simple
+                            if let (true, Some(&Node::Item(j))) =
+                                (is_skip(instruction), nodes.get(k + 1))
+                            {
+                                if let Some(next) = self.simple_statement(j) {
+                                    text = format!("{} {}", text, next);
+                                    k += 1;
+                                }
+                            }
+                            self.line(depth, &text, Some(addr));
+                        }
+                        Item::Unknown { opcode, .. } => {
+                            let [high, low] = opcode.to_be_bytes();
+                            let text = format!("0x{:02X} 0x{:02X}", high, low);
+                            self.line(depth, &text, Some(addr));
+                        }
+                        Item::Data { .. } => {
+                            // Up to 8 bytes a line, starting a new one at labels
+                            let mut bytes = Vec::new();
+                            while let Some(&Node::Item(j)) = nodes.get(k) {
+                                let Item::Data { addr, byte } = self.items[j] else {
+                                    break;
+                                };
+                                if bytes.len() == 8 || !bytes.is_empty() && self.has_label(addr) {
+                                    break;
+                                }
+                                bytes.push(format!("0x{:02X}", byte));
+                                k += 1;
+                            }
+                            self.line(depth, &bytes.join(" "), Some(addr));
+                            continue;
+                        }
+                    }
+                }
+                Node::If {
+                    skip,
+                    then,
+                    otherwise,
+                } => {
+                    let (instruction, addr) = self.skip(*skip);
+                    self.label(addr, depth);
+                    let condition = condition(instruction, true).unwrap_or_default();
+                    self.line(depth, &format!("if {} begin", condition), Some(addr));
+                    self.nodes(then, depth + 1);
+                    if let Some((jump, otherwise)) = otherwise {
+                        let addr = self.items[*jump].addr();
+                        self.label(addr, depth);
+                        self.line(depth, "else", Some(addr));
+                        self.nodes(otherwise, depth + 1);
+                    }
+                    self.line(depth, "end", None);
+                }
+                Node::Loop { start, body, again } => {
+                    let addr = self.items[*start].addr();
+                    self.label(addr, depth);
+                    if body.is_empty() {
+                        self.line(depth, "loop again", Some(addr));
+                    } else {
+                        self.line(depth, "loop", Some(addr));
+                        self.nodes(body, depth + 1);
+                        let addr = self.items[*again].addr();
+                        self.label(addr, depth);
+                        self.line(depth, "again", Some(addr));
+                    }
+                }
+                Node::While { skip } => {
+                    let (instruction, addr) = self.skip(*skip);
+                    self.label(addr, depth);
+                    let condition = condition(instruction, true).unwrap_or_default();
+                    self.line(depth, &format!("while {}", condition), Some(addr));
+                }
+            }
+            k += 1;
+        }
+    }
+
+    fn skip(&self, i: usize) -> (Instruction, usize) {
+        match self.items[i] {
+            Item::Code {
+                addr, instruction, ..
+            } => (instruction, addr),
+            ref item => unreachable!("{:?} isn't a skip", item),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decompile;
+    use crate::assembler::octo;
+    use crate::assembler::tests::test_roms;
+    use crate::disassembler::Disassembly;
+    use std::path::Path;
+
+    #[test]
+    fn test_decompile() {
+        // A loop drawing a sprite, leaving when v0 reaches 0x20, with an
+        // if/else in it, then a halt, a subroutine and the sprite
+        let rom = [
+            0x60, 0x00, 0xa2, 0x1a, 0xd0, 0x15, 0x40, 0x20, 0x12, 0x16, 0x30, 0x10, 0x12, 0x12,
+            0x70, 0x01, 0x12, 0x14, 0x22, 0x18, 0x12, 0x04, 0x12, 0x16, 0x00, 0xee, 0xf0, 0x90,
+        ];
+        let source = decompile(&Disassembly::new(&rom, 0x200));
+        assert_eq!(
+            source,
+            "\
+:alias y v1
+
+: main
+    v0 := 0x00                          # 0x200
+    i := data_21A                       # 0x202
+    loop                                # 0x204
+        sprite v0 y 5                   # 0x204
+        while v0 != 0x20                # 0x206
+        if v0 == 0x10 begin             # 0x20A
+            v0 += 0x01                  # 0x20E
+        else                            # 0x210
+            sub_218                     # 0x212
+        end
+    again                               # 0x214
+    loop again                          # 0x216
+
+: sub_218
+    return                              # 0x218
+
+: data_21A
+    0xF0 0x90                           # 0x21A
+"
+        );
+    }
+
+    #[test]
+    fn test_recompile() {
+        // Decompiled source compiles back to the same ROM
+        for (path, rom) in test_roms() {
+            let source = decompile(&Disassembly::new(&rom, 0x200));
+            let assembly = octo::compile(&source, Path::new("decompiled.8o")).unwrap();
+            assert_eq!(assembly.rom, rom, "{}", path.display());
+        }
+    }
+}
@@ -0,0 +1,507 @@
+// Turns a ROM back into a listing. Code is told apart from data by
+// following control flow from the entry point: jumps, calls and both sides
+// of every skip are explored, and whatever is never reached is data. Jump,
+// call and I targets get labels, data bytes are drawn as sprite rows, and
+// the listing is either conventional mnemonics or Octo source that
+// assembles back to the same bytes.
+use crate::instruction::Instruction;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// The syntax of a listing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Syntax {
+    /// Mnemonics like `LD V3, 0x10`, with `db` for data.
+    #[default]
+    Plain,
+    /// Octo, like `v3 := 0x10`.
+    Octo,
+}
+
+impl FromStr for Syntax {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(Syntax::Plain),
+            "octo" => Ok(Syntax::Octo),
+            _ => Err(format!("unknown syntax {}, expected plain or octo", s)),
+        }
+    }
+}
+
+/// One line of a listing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Item {
+    /// A reachable instruction. `operand` is the word after `F000`.
+    Code {
+        addr: usize,
+        instruction: Instruction,
+        operand: Option<u16>,
+    },
+    /// A reachable word that isn't an instruction.
+    Unknown { addr: usize, opcode: u16 },
+    /// A byte no path through the code runs.
+    Data { addr: usize, byte: u8 },
+}
+
+impl Item {
+    pub fn addr(&self) -> usize {
+        match *self {
+            Item::Code { addr, .. } | Item::Unknown { addr, .. } | Item::Data { addr, .. } => addr,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        match self {
+            Item::Code { instruction, .. } => instruction.size(),
+            Item::Unknown { .. } => 2,
+            Item::Data { .. } => 1,
+        }
+    }
+}
+
+// What a byte of the ROM turned out to be
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Byte {
+    Data,
+    Code,
+    Unknown,
+    // The rest of an instruction or unknown word
+    Continued,
+}
+
+// Why an address is labelled, in increasing order of precedence
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Label {
+    Data,
+    Code,
+    Subroutine,
+    Main,
+}
+
+/// A ROM split into code and data, with labels for the addresses it refers to.
+#[derive(Debug, Clone)]
+pub struct Disassembly {
+    origin: usize,
+    items: Vec<Item>,
+    labels: BTreeMap<usize, String>,
+}
+
+impl Disassembly {
+    /// Analyse `rom` loaded at `origin`, which is also where it starts running.
+    pub fn new(rom: &[u8], origin: usize) -> Self {
+        let word = |offset: usize| {
+            rom.get(offset..offset + 2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        };
+        let mut bytes = vec![Byte::Data; rom.len()];
+        let mut labels = BTreeMap::from([(origin, Label::Main)]);
+        let mut label = |addr: usize, kind: Label| {
+            let entry = labels.entry(addr).or_insert(kind);
+            *entry = kind.max(*entry);
+        };
+
+        let mut pending = vec![origin];
+        while let Some(addr) = pending.pop() {
+            let Some(offset) = addr.checked_sub(origin) else {
+                continue;
+            };
+            let Some(opcode) = word(offset) else {
+                continue;
+            };
+            // Already explored, or the middle of another instruction
+            if bytes[offset..offset + 2] != [Byte::Data; 2] {
+                continue;
+            }
+            let instruction = match Instruction::decode(opcode) {
+                Ok(instruction) => instruction,
+                Err(_) => {
+                    bytes[offset] = Byte::Unknown;
+                    bytes[offset + 1] = Byte::Continued;
+                    continue;
+                }
+            };
+            let size = instruction.size();
+            match bytes.get(offset..offset + size) {
+                Some(span) if span.iter().all(|&b| b == Byte::Data) => {}
+                _ => continue,
+            }
+            bytes[offset] = Byte::Code;
+            bytes[offset + 1..offset + size].fill(Byte::Continued);
+
+            let next = addr + size;
+            match instruction {
+                Instruction::Jump { nnn } => {
+                    label(nnn as usize, Label::Code);
+                    pending.push(nnn as usize);
+                }
+                Instruction::JumpOffset { nnn } => {
+                    // Usually a jump table, whose length can't be known
+                    label(nnn as usize, Label::Code);
+                    pending.push(nnn as usize);
+                }
+                Instruction::Call { nnn } => {
+                    label(nnn as usize, Label::Subroutine);
+                    pending.extend([nnn as usize, next]);
+                }
+                Instruction::Ret | Instruction::Exit => {}
+                Instruction::SkipEqImm { .. }
+                | Instruction::SkipNeImm { .. }
+                | Instruction::SkipEqReg { .. }
+                | Instruction::SkipNeReg { .. }
+                | Instruction::SkipKey { .. }
+                | Instruction::SkipNotKey { .. } => {
+                    // Skips jump over the whole of a long load
+                    let skipped = match word(next - origin) {
+                        Some(0xf000) => 4,
+                        _ => 2,
+                    };
+                    pending.extend([next, next + skipped]);
+                }
+                Instruction::LoadI { nnn } => {
+                    label(nnn as usize, Label::Data);
+                    pending.push(next);
+                }
+                Instruction::LoadILong => {
+                    if let Some(nnnn) = word(offset + 2) {
+                        label(nnnn as usize, Label::Data);
+                    }
+                    pending.push(next);
+                }
+                _ => pending.push(next),
+            }
+        }
+
+        let mut items = Vec::new();
+        for (offset, &kind) in bytes.iter().enumerate() {
+            let addr = origin + offset;
+            match kind {
+                Byte::Data => items.push(Item::Data {
+                    addr,
+                    byte: rom[offset],
+                }),
+                Byte::Unknown => items.push(Item::Unknown {
+                    addr,
+                    opcode: word(offset).unwrap_or(0),
+                }),
+                Byte::Code => {
+                    let opcode = word(offset).unwrap_or(0);
+                    if let Ok(instruction) = Instruction::decode(opcode) {
+                        let operand = (instruction == Instruction::LoadILong)
+                            .then(|| word(offset + 2))
+                            .flatten();
+                        items.push(Item::Code {
+                            addr,
+                            instruction,
+                            operand,
+                        });
+                    }
+                }
+                Byte::Continued => {}
+            }
+        }
+
+        // Only addresses that start a line can be labelled
+        let labels = labels
+            .into_iter()
+            .filter(|(addr, _)| {
+                addr.checked_sub(origin)
+                    .and_then(|offset| bytes.get(offset))
+                    .is_some_and(|&b| b != Byte::Continued)
+            })
+            .map(|(addr, kind)| {
+                let name = match kind {
+                    Label::Main => "main".to_string(),
+                    Label::Subroutine => format!("sub_{:03X}", addr),
+                    Label::Code => format!("label_{:03X}", addr),
+                    Label::Data => format!("data_{:03X}", addr),
+                };
+                (addr, name)
+            })
+            .collect();
+
+        Self {
+            origin,
+            items,
+            labels,
+        }
+    }
+
+    pub fn origin(&self) -> usize {
+        self.origin
+    }
+
+    /// The instructions and data in address order.
+    pub fn items(&self) -> &[Item] {
+        &self.items
+    }
+
+    /// The label at `addr`, if anything refers to it.
+    pub fn label(&self, addr: usize) -> Option<&str> {
+        self.labels.get(&addr).map(|name| name.as_str())
+    }
+
+    /// The addresses of reachable words that aren't instructions.
+    pub fn unknown(&self) -> Vec<usize> {
+        self.items
+            .iter()
+            .filter_map(|item| match item {
+                Item::Unknown { addr, .. } => Some(*addr),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The whole listing. Each line ends with a comment giving its address
+    /// and bytes, or the sprite row for data.
+    pub fn listing(&self, syntax: Syntax) -> String {
+        let comment = match syntax {
+            Syntax::Plain => ';',
+            Syntax::Octo => '#',
+        };
+        let mut out = String::new();
+        for item in &self.items {
+            if let Some(label) = self.label(item.addr()) {
+                if !out.is_empty() {
+                    out.push('\n');
+                }
+                match syntax {
+                    Syntax::Plain => writeln!(out, "{}:", label),
+                    Syntax::Octo => writeln!(out, ": {}", label),
+                }
+                .unwrap();
+            }
+            let (text, note) = match *item {
+                Item::Code {
+                    addr,
+                    instruction,
+                    operand,
+                } => {
+                    let text = match syntax {
+                        Syntax::Plain => self.plain(instruction, operand),
+                        Syntax::Octo => self.octo(instruction, operand),
+                    };
+                    let bytes = match operand {
+                        Some(nnnn) => format!("{:04X}{:04X}", instruction.encode(), nnnn),
+                        None => format!("{:04X}", instruction.encode()),
+                    };
+                    (text, format!("0x{:03X}  {}", addr, bytes))
+                }
+                Item::Unknown { addr, opcode } => {
+                    let [high, low] = opcode.to_be_bytes();
+                    let text = match syntax {
+                        Syntax::Plain => format!("db 0x{:02X}, 0x{:02X}", high, low),
+                        Syntax::Octo => format!("0x{:02X} 0x{:02X}", high, low),
+                    };
+                    (
+                        text,
+                        format!("0x{:03X}  {:04X}  unknown opcode", addr, opcode),
+                    )
+                }
+                Item::Data { addr, byte } => {
+                    let text = match syntax {
+                        Syntax::Plain => format!("db 0x{:02X}", byte),
+                        Syntax::Octo => format!("0x{:02X}", byte),
+                    };
+                    (text, format!("0x{:03X}  {}", addr, sprite_row(byte)))
+                }
+            };
+            writeln!(out, "    {:<24}{} {}", text, comment, note).unwrap();
+        }
+        out
+    }
+
+    // A label for the address if it has one, otherwise the number
+    fn target(&self, addr: usize) -> String {
+        match self.label(addr) {
+            Some(label) => label.to_string(),
+            None => format!("0x{:03X}", addr),
+        }
+    }
+
+    fn plain(&self, instruction: Instruction, operand: Option<u16>) -> String {
+        use Instruction::*;
+
+        match instruction {
+            Jump { nnn } => format!("JP {}", self.target(nnn as usize)),
+            Call { nnn } => format!("CALL {}", self.target(nnn as usize)),
+            LoadI { nnn } => format!("LD I, {}", self.target(nnn as usize)),
+            JumpOffset { nnn } => format!("JP V0, {}", self.target(nnn as usize)),
+            LoadILong => format!("LD I, LONG {}", self.target(operand.unwrap_or(0) as usize)),
+            // The bytes are right there in the comment, but a label reads better
+            _ => instruction.to_string(),
+        }
+    }
+
+    pub(crate) fn octo(&self, instruction: Instruction, operand: Option<u16>) -> String {
+        use Instruction::*;
+
+        // Octo's conditionals run the next statement when they hold, so
+        // each skip is written as the opposite test
+        match instruction {
+            Sys { nnn } => format!("0x{:02X} 0x{:02X}", nnn >> 8, nnn & 0xff),
+            Cls => "clear".to_string(),
+            Ret => "return".to_string(),
+            ScrollDown { n } => format!("scroll-down {}", n),
+            ScrollUp { n } => format!("scroll-up {}", n),
+            ScrollRight => "scroll-right".to_string(),
+            ScrollLeft => "scroll-left".to_string(),
+            Exit => "exit".to_string(),
+            LowRes => "lores".to_string(),
+            HighRes => "hires".to_string(),
+            Jump { nnn } => format!("jump {}", self.target(nnn as usize)),
+            Call { nnn } => match self.label(nnn as usize) {
+                Some(label) => label.to_string(),
+                None => format!(":call 0x{:03X}", nnn),
+            },
+            SkipEqImm { x, kk } => format!("if v{:x} != 0x{:02X} then", x, kk),
+            SkipNeImm { x, kk } => format!("if v{:x} == 0x{:02X} then", x, kk),
+            SkipEqReg { x, y } => format!("if v{:x} != v{:x} then", x, y),
+            StoreRange { x, y } => format!("save v{:x} - v{:x}", x, y),
+            LoadRange { x, y } => format!("load v{:x} - v{:x}", x, y),
+            LoadImm { x, kk } => format!("v{:x} := 0x{:02X}", x, kk),
+            AddImm { x, kk } => format!("v{:x} += 0x{:02X}", x, kk),
+            Move { x, y } => format!("v{:x} := v{:x}", x, y),
+            Or { x, y } => format!("v{:x} |= v{:x}", x, y),
+            And { x, y } => format!("v{:x} &= v{:x}", x, y),
+            Xor { x, y } => format!("v{:x} ^= v{:x}", x, y),
+            Add { x, y } => format!("v{:x} += v{:x}", x, y),
+            Sub { x, y } => format!("v{:x} -= v{:x}", x, y),
+            ShiftRight { x, y } => format!("v{:x} >>= v{:x}", x, y),
+            SubN { x, y } => format!("v{:x} =- v{:x}", x, y),
+            ShiftLeft { x, y } => format!("v{:x} <<= v{:x}", x, y),
+            SkipNeReg { x, y } => format!("if v{:x} == v{:x} then", x, y),
+            LoadI { nnn } => format!("i := {}", self.target(nnn as usize)),
+            JumpOffset { nnn } => format!("jump0 {}", self.target(nnn as usize)),
+            Random { x, kk } => format!("v{:x} := random 0x{:02X}", x, kk),
+            Draw { x, y, n } => format!("sprite v{:x} v{:x} {}", x, y, n),
+            SkipKey { x } => format!("if v{:x} -key then", x),
+            SkipNotKey { x } => format!("if v{:x} key then", x),
+            LoadDelay { x } => format!("v{:x} := delay", x),
+            WaitKey { x } => format!("v{:x} := key", x),
+            SetDelay { x } => format!("delay := v{:x}", x),
+            SetSound { x } => format!("buzzer := v{:x}", x),
+            AddI { x } => format!("i += v{:x}", x),
+            LoadFont { x } => format!("i := hex v{:x}", x),
+            LoadBigFont { x } => format!("i := bighex v{:x}", x),
+            StoreBcd { x } => format!("bcd v{:x}", x),
+            StoreRegs { x } => format!("save v{:x}", x),
+            LoadRegs { x } => format!("load v{:x}", x),
+            StoreFlags { x } => format!("saveflags v{:x}", x),
+            LoadFlags { x } => format!("loadflags v{:x}", x),
+            LoadILong => format!("i := long {}", self.target(operand.unwrap_or(0) as usize)),
+            Plane { n } => format!("plane {}", n),
+            LoadAudio => "audio".to_string(),
+            SetPitch { x } => format!("pitch := v{:x}", x),
+        }
+    }
+}
+
+/// A byte as a row of sprite pixels, `#` for lit and `.` for dark.
+pub fn sprite_row(byte: u8) -> String {
+    (0..8)
+        .map(|bit| if byte & (0x80 >> bit) != 0 { '#' } else { '.' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Disassembly, Item, Syntax};
+    use crate::instruction::Instruction;
+
+    // CALL 0x20A; SE V0, 0; F000 0x20D; JP 0x208 (itself)
+    // 0x20A: LD I, 0x20D; DRW V0, V0, 1; RET; a sprite; FFFF
+    // The FFFF is never reached, and the sprite is only loaded.
+    const ROM: [u8; 18] = [
+        0x22, 0x0a, 0x30, 0x00, 0xf0, 0x00, 0x02, 0x0d, 0x12, 0x08, 0xa2, 0x10, 0xd0, 0x01, 0x00,
+        0xee, 0x3c, 0xff,
+    ];
+
+    #[test]
+    fn test_flow() {
+        let disassembly = Disassembly::new(&ROM, 0x200);
+        let items = disassembly.items();
+        assert_eq!(
+            items[2],
+            Item::Code {
+                addr: 0x204,
+                instruction: Instruction::LoadILong,
+                operand: Some(0x020d),
+            }
+        );
+        assert_eq!(items[3].addr(), 0x208);
+        assert_eq!(
+            items[7],
+            Item::Data {
+                addr: 0x210,
+                byte: 0x3c
+            }
+        );
+        assert_eq!(disassembly.label(0x208), Some("label_208"));
+        assert_eq!(disassembly.label(0x20A), Some("sub_20A"));
+        assert_eq!(disassembly.label(0x210), Some("data_210"));
+        // Within the draw, so it can't be labelled
+        assert_eq!(disassembly.label(0x20D), None);
+        assert_eq!(disassembly.unknown(), []);
+
+        // Jumping into the middle of nowhere finds an unknown opcode
+        let disassembly = Disassembly::new(&[0x12, 0x04, 0x00, 0x00, 0xff, 0xff], 0x200);
+        assert_eq!(disassembly.unknown(), [0x204]);
+        assert_eq!(
+            disassembly.items()[1],
+            Item::Data {
+                addr: 0x202,
+                byte: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_listing() {
+        let disassembly = Disassembly::new(&ROM, 0x200);
+        assert_eq!(
+            disassembly.listing(Syntax::Plain),
+            "\
+main:
+    CALL sub_20A            ; 0x200  220A
+    SE V0, 0x00             ; 0x202  3000
+    LD I, LONG 0x20D        ; 0x204  F000020D
+
+label_208:
+    JP label_208            ; 0x208  1208
+
+sub_20A:
+    LD I, data_210          ; 0x20A  A210
+    DRW V0, V0, 1           ; 0x20C  D001
+    RET                     ; 0x20E  00EE
+
+data_210:
+    db 0x3C                 ; 0x210  ..####..
+    db 0xFF                 ; 0x211  ########
+"
+        );
+        assert_eq!(
+            disassembly.listing(Syntax::Octo),
+            "\
+: main
+    sub_20A                 # 0x200  220A
+    if v0 != 0x00 then      # 0x202  3000
+    i := long 0x20D         # 0x204  F000020D
+
+: label_208
+    jump label_208          # 0x208  1208
+
+: sub_20A
+    i := data_210           # 0x20A  A210
+    sprite v0 v0 1          # 0x20C  D001
+    return                  # 0x20E  00EE
+
+: data_210
+    0x3C                    # 0x210  ..####..
+    0xFF                    # 0x211  ########
+"
+        );
+    }
+}
@@ -0,0 +1,152 @@
+/// Colours for each pixel value as RGB, indexed by the plane bits: off,
+/// plane 1, plane 2 and both planes.
+pub const PALETTE: [[u8; 3]; 4] = [
+    [0x00, 0x00, 0x00],
+    [0xff, 0xff, 0xff],
+    [0xaa, 0xaa, 0xaa],
+    [0x55, 0x55, 0x55],
+];
+
+/// The display, one byte per pixel stored row by row. Each bit of a pixel
+/// is one bitplane, so classic programs only ever use 0 and 1 while XO-CHIP
+/// programs draw on two planes for four colours.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Framebuffer {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl Framebuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![0; width * height],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> u8 {
+        self.pixels[y * self.width + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, value: u8) {
+        self.pixels[y * self.width + x] = value;
+    }
+
+    /// XOR a lit pixel onto one plane of the display. Returns true if it
+    /// erased one.
+    pub fn toggle(&mut self, x: usize, y: usize, plane: u8) -> bool {
+        let pixel = &mut self.pixels[y * self.width + x];
+        *pixel ^= plane;
+        *pixel & plane == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.pixels.fill(0);
+    }
+
+    /// Clear only the selected planes.
+    pub fn clear_planes(&mut self, planes: u8) {
+        for pixel in &mut self.pixels {
+            *pixel &= !planes;
+        }
+    }
+
+    /// Change the resolution, clearing the display.
+    pub fn resize(&mut self, width: usize, height: usize) {
+        *self = Self::new(width, height);
+    }
+
+    /// Move the selected planes down `n` rows, filling in blank rows at the top.
+    pub fn scroll_down(&mut self, n: usize, planes: u8) {
+        self.shift(0, n as isize, planes);
+    }
+
+    /// Move the selected planes up `n` rows, filling in blank rows at the bottom.
+    pub fn scroll_up(&mut self, n: usize, planes: u8) {
+        self.shift(0, -(n as isize), planes);
+    }
+
+    /// Move the selected planes right `n` columns, filling in blank columns
+    /// on the left.
+    pub fn scroll_right(&mut self, n: usize, planes: u8) {
+        self.shift(n as isize, 0, planes);
+    }
+
+    /// Move the selected planes left `n` columns, filling in blank columns
+    /// on the right.
+    pub fn scroll_left(&mut self, n: usize, planes: u8) {
+        self.shift(-(n as isize), 0, planes);
+    }
+
+    fn shift(&mut self, dx: isize, dy: isize, planes: u8) {
+        let source = self.pixels.clone();
+        let (width, height) = (self.width as isize, self.height as isize);
+        for y in 0..height {
+            for x in 0..width {
+                let (from_x, from_y) = (x - dx, y - dy);
+                let moved = if (0..width).contains(&from_x) && (0..height).contains(&from_y) {
+                    source[(from_y * width + from_x) as usize]
+                } else {
+                    0
+                };
+                let pixel = &mut self.pixels[(y * width + x) as usize];
+                *pixel = *pixel & !planes | moved & planes;
+            }
+        }
+    }
+
+    /// Iterate over the rows from top to bottom.
+    pub fn rows(&self) -> impl Iterator<Item = &[u8]> {
+        self.pixels.chunks(self.width)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Framebuffer;
+
+    #[test]
+    fn test_scroll() {
+        let mut fb = Framebuffer::new(8, 4);
+        fb.toggle(1, 1, 1);
+        fb.scroll_down(2, 1);
+        assert_eq!(fb.get(1, 3), 1);
+        fb.scroll_right(4, 1);
+        assert_eq!(fb.get(5, 3), 1);
+        fb.scroll_left(5, 1);
+        assert_eq!(fb.get(0, 3), 1);
+        fb.scroll_up(3, 1);
+        assert_eq!(fb.get(0, 0), 1);
+        assert_eq!(fb.rows().flatten().filter(|&&p| p == 1).count(), 1);
+        fb.scroll_down(10, 1);
+        assert!(fb.rows().flatten().all(|&p| p == 0));
+    }
+
+    #[test]
+    fn test_planes() {
+        let mut fb = Framebuffer::new(8, 4);
+        assert!(!fb.toggle(2, 2, 1));
+        assert!(!fb.toggle(2, 2, 2));
+        assert_eq!(fb.get(2, 2), 3);
+
+        // Only the second plane moves
+        fb.scroll_left(2, 2);
+        assert_eq!(fb.get(2, 2), 1);
+        assert_eq!(fb.get(0, 2), 2);
+
+        fb.clear_planes(1);
+        assert_eq!(fb.get(2, 2), 0);
+        assert_eq!(fb.get(0, 2), 2);
+        assert!(fb.toggle(0, 2, 2));
+    }
+}
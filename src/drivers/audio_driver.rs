@@ -1,8 +1,9 @@
 use sdl2::audio::{AudioCallback, AudioSpecDesired};
+use virtual_machine::frontend::{AudioSink, Tone};
 
 // Standalone struct for the audio driver
 pub struct AudioDriver {
-    device: sdl2::audio::AudioDevice<SquareWave>,
+    device: sdl2::audio::AudioDevice<Waveform>,
 }
 
 impl AudioDriver {
@@ -15,8 +16,10 @@ impl AudioDriver {
             samples: None,
         };
 
-        let device = audio_subsystem.open_playback(None, &desired_spec, |spec| SquareWave {
-            phase_inc: frequency / spec.freq as f32,
+        let device = audio_subsystem.open_playback(None, &desired_spec, |spec| Waveform {
+            tone: Tone::Buzzer,
+            buzzer_frequency: frequency,
+            sample_rate: spec.freq as f32,
             phase: 0.0,
             volume,
         })?;
@@ -38,21 +41,48 @@ impl AudioDriver {
     }
 }
 
+impl AudioSink for AudioDriver {
+    fn set_playing(&mut self, playing: bool) {
+        if playing {
+            self.start();
+        } else if self.is_active() {
+            self.stop();
+        }
+    }
+
+    fn set_tone(&mut self, tone: Tone) {
+        // Keep the phase so a pattern changed mid-note carries on smoothly
+        self.device.lock().tone = tone;
+    }
+}
+
 // Struct for the audio callback
-struct SquareWave {
-    phase_inc: f32,
+struct Waveform {
+    tone: Tone,
+    buzzer_frequency: f32,
+    sample_rate: f32,
+    // How far through one cycle of the square wave or pattern we are, 0 to 1
     phase: f32,
     volume: f32,
 }
 
-impl AudioCallback for SquareWave {
+impl AudioCallback for Waveform {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
-        // Generate a square wave
         for x in out.iter_mut() {
-            *x = self.volume * if self.phase < 0.5 { 1.0 } else { -1.0 };
-            self.phase = (self.phase + self.phase_inc) % 1.0;
+            let (high, phase_inc) = match self.tone {
+                // Generate a square wave
+                Tone::Buzzer => (self.phase < 0.5, self.buzzer_frequency / self.sample_rate),
+                // Loop over the 128 bits of the pattern
+                Tone::Pattern { samples, rate } => {
+                    let bit = (self.phase * 128.) as usize % 128;
+                    let high = samples[bit / 8] >> (7 - bit % 8) & 0x1 == 1;
+                    (high, rate / 128. / self.sample_rate)
+                }
+            };
+            *x = self.volume * if high { 1.0 } else { -1.0 };
+            self.phase = (self.phase + phase_inc) % 1.0;
         }
     }
 }
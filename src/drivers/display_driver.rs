@@ -3,16 +3,33 @@ use sdl2::pixels::Color;
 use sdl2::rect::Rect;
 use sdl2::render::Canvas;
 use sdl2::video::Window;
+use virtual_machine::frontend::{Framebuffer, Processor, VideoSink, PALETTE};
+use virtual_machine::panels;
+
+// Window pixels per panel pixel when the debug panels are shown
+const PANEL_SCALE: u32 = 2;
 
 pub struct DisplayDriver {
     canvas: Canvas<Window>,
+    // The last display, kept to draw under the panels. None without panels.
+    display: Option<Framebuffer>,
 }
 
 impl DisplayDriver {
-    pub fn new(context: &sdl2::Sdl) -> Self {
+    /// With `debug_panels` the window is widened to show the machine's
+    /// state next to the display.
+    pub fn new(context: &sdl2::Sdl, debug_panels: bool) -> Self {
+        let (width, height) = if debug_panels {
+            (
+                panels::WIDTH as u32 * PANEL_SCALE,
+                panels::HEIGHT as u32 * PANEL_SCALE,
+            )
+        } else {
+            (64 * 8, 32 * 8)
+        };
         let video_subsystem = context.video().unwrap();
         let window = video_subsystem
-            .window("Chip-8 Emulator", 64 * 8, 32 * 8)
+            .window("Chip-8 Emulator", width, height)
             .position_centered()
             .build()
             .unwrap();
@@ -20,22 +37,73 @@ impl DisplayDriver {
         canvas.set_draw_color(Color::RGB(0, 0, 0));
         canvas.clear();
         canvas.present();
-        Self { canvas }
+        let display = debug_panels.then(|| Framebuffer::new(64, 32));
+        Self { canvas, display }
+    }
+
+    pub fn draw(&mut self, display: &Framebuffer) {
+        // Scale the display to fill the window whatever its resolution
+        let (width, _) = self.canvas.output_size().unwrap();
+        let scale = (width as usize / display.width()).max(1) as u32;
+
+        self.clear();
+        self.draw_pixels(display, scale);
+        self.canvas.present();
     }
 
-    pub fn draw(&mut self, display: &[[u8; 64]; 32]) {
-        self.canvas.set_draw_color(Color::RGB(0, 0, 0));
+    /// Draw the display in the panels' game area with the panels around it.
+    pub fn draw_with_panels(&mut self, display: &Framebuffer, panels: &Framebuffer) {
+        let game_width = panels::GAME_WIDTH as u32 * PANEL_SCALE;
+        let scale = (game_width as usize / display.width()).max(1) as u32;
+
+        self.clear();
+        self.draw_pixels(display, scale);
+        self.draw_pixels(panels, PANEL_SCALE);
+        self.canvas.present();
+    }
+
+    fn clear(&mut self) {
+        let [r, g, b] = PALETTE[0];
+        self.canvas.set_draw_color(Color::RGB(r, g, b));
         self.canvas.clear();
-        for y in 0..32 {
-            for x in 0..64 {
-                if display[y][x] == 1 {
-                    self.canvas.set_draw_color(Color::RGB(255, 255, 255));
-                    self.canvas
-                        .fill_rect(Rect::new(x as i32 * 8, y as i32 * 8, 8, 8))
-                        .unwrap();
+    }
+
+    fn draw_pixels(&mut self, framebuffer: &Framebuffer, scale: u32) {
+        // One batch per colour, since the panels light a lot of pixels
+        let mut rects: [Vec<Rect>; 4] = Default::default();
+        for (y, row) in framebuffer.rows().enumerate() {
+            for (x, &pixel) in row.iter().enumerate() {
+                if pixel != 0 {
+                    rects[pixel as usize & 0x3].push(Rect::new(
+                        x as i32 * scale as i32,
+                        y as i32 * scale as i32,
+                        scale,
+                        scale,
+                    ));
                 }
             }
         }
-        self.canvas.present();
+        for (color, rects) in PALETTE.iter().zip(&rects).skip(1) {
+            let [r, g, b] = *color;
+            self.canvas.set_draw_color(Color::RGB(r, g, b));
+            self.canvas.fill_rects(rects).unwrap();
+        }
+    }
+}
+
+impl VideoSink for DisplayDriver {
+    fn present(&mut self, framebuffer: &Framebuffer) {
+        match &mut self.display {
+            // Drawn with the panels in `present_state`
+            Some(display) => display.clone_from(framebuffer),
+            None => self.draw(framebuffer),
+        }
+    }
+
+    fn present_state(&mut self, processor: &Processor) {
+        if let Some(display) = self.display.take() {
+            self.draw_with_panels(&display, &panels::render(processor));
+            self.display = Some(display);
+        }
     }
 }
@@ -0,0 +1,114 @@
+// Keyboard input for chip-8 using SDL2
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::EventPump;
+use virtual_machine::frontend::{InputEvent, InputSource};
+use virtual_machine::savestate::SLOTS;
+
+pub struct InputDriver {
+    event_pump: EventPump,
+    // The quick save slot F5 and F9 use
+    slot: u8,
+}
+
+impl InputDriver {
+    pub fn new(context: &sdl2::Sdl) -> Self {
+        let event_pump = context.event_pump().unwrap();
+        Self {
+            event_pump,
+            slot: 0,
+        }
+    }
+}
+
+// Move `offset` slots forward, wrapping around
+fn cycle_slot(slot: &mut u8, offset: u8) {
+    *slot = (*slot + offset) % SLOTS;
+    eprintln!("save slot {}", slot);
+}
+
+fn parse_key(key: Keycode) -> Option<u8> {
+    match key {
+        Keycode::Num1
+        | Keycode::Num2
+        | Keycode::Num3
+        | Keycode::Num4
+        | Keycode::Num5
+        | Keycode::Num6
+        | Keycode::Num7
+        | Keycode::Num8
+        | Keycode::Num9
+        | Keycode::Num0 => Some(key.into_i32() as u8 - 48),
+        Keycode::A | Keycode::B | Keycode::C | Keycode::D | Keycode::E | Keycode::F => {
+            Some(key.into_i32() as u8 - 87)
+        }
+        _ => None,
+    }
+}
+
+impl InputSource for InputDriver {
+    fn poll(&mut self) -> Vec<InputEvent> {
+        let mut events = Vec::new();
+        for event in self.event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                } => events.push(InputEvent::Quit),
+                Event::KeyDown {
+                    keycode: Some(Keycode::Backspace),
+                    repeat: false,
+                    ..
+                } => events.push(InputEvent::RewindStart),
+                Event::KeyUp {
+                    keycode: Some(Keycode::Backspace),
+                    ..
+                } => events.push(InputEvent::RewindStop),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F5),
+                    repeat: false,
+                    ..
+                } => events.push(InputEvent::SaveState(self.slot)),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F9),
+                    repeat: false,
+                    ..
+                } => events.push(InputEvent::LoadState(self.slot)),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F6),
+                    repeat: false,
+                    ..
+                } => cycle_slot(&mut self.slot, SLOTS - 1),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F7),
+                    repeat: false,
+                    ..
+                } => cycle_slot(&mut self.slot, 1),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F12),
+                    repeat: false,
+                    ..
+                } => events.push(InputEvent::Break),
+                Event::KeyDown {
+                    keycode: Some(key),
+                    repeat: false,
+                    ..
+                } => {
+                    if let Some(key) = parse_key(key) {
+                        events.push(InputEvent::KeyDown(key));
+                    }
+                }
+                Event::KeyUp {
+                    keycode: Some(key), ..
+                } => {
+                    if let Some(key) = parse_key(key) {
+                        events.push(InputEvent::KeyUp(key));
+                    }
+                }
+                _ => {}
+            }
+        }
+        events
+    }
+}
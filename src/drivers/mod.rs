@@ -1,5 +1,7 @@
-mod display_driver;
 mod audio_driver;
+mod display_driver;
+mod input_driver;
 
+pub use audio_driver::AudioDriver;
 pub use display_driver::DisplayDriver;
-pub use audio_driver::AudioDriver;
\ No newline at end of file
+pub use input_driver::InputDriver;
@@ -0,0 +1,521 @@
+use crate::debugger::DebugFrontend;
+use crate::error::EmulatorError;
+use crate::frontend::{AudioSink, InputEvent, InputSource, VideoSink};
+use crate::monitor::Monitor;
+use crate::movie::{Movie, MovieInput};
+use crate::processor::Processor;
+use crate::rewind::Rewind;
+use crate::savestate;
+use crate::scheduler::Scheduler;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+/// Something that happened while running a frame, for the frontend to tell
+/// the user about.
+#[derive(Debug)]
+pub enum Notice {
+    /// An error the processor's policies logged rather than halting on.
+    Logged(EmulatorError),
+    /// An error the monitor paused on instead of stopping.
+    Paused(EmulatorError),
+    Saved {
+        slot: u8,
+        path: PathBuf,
+    },
+    SaveFailed {
+        slot: u8,
+        error: EmulatorError,
+    },
+    Loaded {
+        slot: u8,
+        path: PathBuf,
+    },
+    LoadFailed {
+        slot: u8,
+        error: EmulatorError,
+    },
+    /// Rewinding or loading a state was refused because of a movie.
+    MovieInTheWay,
+}
+
+impl fmt::Display for Notice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Notice::Logged(e) => write!(f, "warning: {}", e),
+            Notice::Paused(e) => write!(f, "error: {}", e),
+            Notice::Saved { slot, path } => {
+                write!(f, "saved slot {} to {}", slot, path.display())
+            }
+            Notice::SaveFailed { slot, error } => {
+                write!(f, "warning: couldn't save slot {}: {}", slot, error)
+            }
+            Notice::Loaded { slot, path } => {
+                write!(f, "loaded slot {} from {}", slot, path.display())
+            }
+            Notice::LoadFailed { slot, error } => {
+                write!(f, "warning: couldn't load slot {}: {}", slot, error)
+            }
+            Notice::MovieInTheWay => {
+                write!(f, "warning: can't go back in time during a movie")
+            }
+        }
+    }
+}
+
+/// The emulation loop, connecting a `Processor` to a set of frontends.
+pub struct Emulator {
+    processor: Processor,
+    video: Box<dyn VideoSink>,
+    audio: Box<dyn AudioSink>,
+    input: Box<dyn InputSource>,
+    scheduler: Scheduler,
+    rom_path: Option<PathBuf>,
+    rewind: Option<Rewind>,
+    rewinding: bool,
+    movie: Option<Movie>,
+    // The next input to replay when `movie` is being played back
+    playback: Option<usize>,
+    frame: u64,
+    lag_frames: u64,
+    monitor: Option<Box<dyn DebugFrontend>>,
+    notices: Vec<Notice>,
+}
+
+impl Emulator {
+    pub fn new(
+        processor: Processor,
+        video: Box<dyn VideoSink>,
+        audio: Box<dyn AudioSink>,
+        input: Box<dyn InputSource>,
+    ) -> Self {
+        Self {
+            processor,
+            video,
+            audio,
+            input,
+            scheduler: Scheduler::default(),
+            rom_path: None,
+            rewind: None,
+            rewinding: false,
+            movie: None,
+            playback: None,
+            frame: 0,
+            lag_frames: 0,
+            monitor: None,
+            notices: Vec::new(),
+        }
+    }
+
+    pub fn with_scheduler(mut self, scheduler: Scheduler) -> Self {
+        self.scheduler = scheduler;
+        self
+    }
+
+    /// Enable the quick save slots, kept next to the ROM at `rom_path`.
+    /// Without this, save and load events are ignored.
+    pub fn with_save_slots(mut self, rom_path: impl Into<PathBuf>) -> Self {
+        self.rom_path = Some(rom_path.into());
+        self
+    }
+
+    /// Record a rewind history. Without this, rewind events are ignored.
+    pub fn with_rewind(mut self, rewind: Rewind) -> Self {
+        self.rewind = Some(rewind);
+        self
+    }
+
+    /// Record keypad input into `movie`, which should have been started
+    /// with this emulator's processor and scheduler. Take it back with
+    /// `take_movie`. Rewinding and loading states are disabled meanwhile.
+    pub fn with_recording(mut self, movie: Movie) -> Self {
+        self.movie = Some(movie);
+        self.playback = None;
+        self
+    }
+
+    /// Feed the keypad from `movie`, ignoring keys from the input source
+    /// until it has finished. The processor and scheduler should come from
+    /// `Movie::processor` and `Movie::scheduler`.
+    pub fn with_playback(mut self, movie: Movie) -> Self {
+        self.movie = Some(movie);
+        self.playback = Some(0);
+        self
+    }
+
+    /// Run the processor under `monitor`, such as a `Monitor` or a
+    /// `GdbStub`. Without one, a monitor on the terminal is attached the
+    /// first time the input source asks to break.
+    pub fn with_monitor(mut self, monitor: impl DebugFrontend + 'static) -> Self {
+        self.monitor = Some(Box::new(monitor));
+        self
+    }
+
+    pub fn take_movie(&mut self) -> Option<Movie> {
+        self.playback = None;
+        self.movie.take()
+    }
+
+    /// Whether a movie being played back has reached its end.
+    pub fn is_movie_finished(&self) -> bool {
+        match (&self.movie, self.playback) {
+            (Some(movie), Some(_)) => self.frame >= movie.frames,
+            _ => false,
+        }
+    }
+
+    /// The number of frames run so far, not counting rewound ones.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// The number of frames in which the program didn't read the keypad.
+    /// Input during those frames can't have had any effect.
+    pub fn lag_frames(&self) -> u64 {
+        self.lag_frames
+    }
+
+    /// Take what has happened since the last call, oldest first.
+    pub fn take_notices(&mut self) -> Vec<Notice> {
+        std::mem::take(&mut self.notices)
+    }
+
+    pub fn processor(&self) -> &Processor {
+        &self.processor
+    }
+
+    pub fn processor_mut(&mut self) -> &mut Processor {
+        &mut self.processor
+    }
+
+    /// Run one frame. Returns false once the input source asks to quit or the
+    /// program exits.
+    pub fn run_frame(&mut self) -> Result<bool, EmulatorError> {
+        match &mut self.rewind {
+            Some(rewind) if self.rewinding => {
+                rewind.step_back(&mut self.processor);
+            }
+            rewind => {
+                let instructions = self.scheduler.instructions_for_frame();
+                match &mut self.monitor {
+                    Some(monitor) => {
+                        match self
+                            .processor
+                            .run_frame_with(instructions, |p| monitor.before_step(p))
+                        {
+                            Ok(true) => {}
+                            Ok(false) => return Ok(false),
+                            // The failing instruction had no effect, so the
+                            // program can be fixed up and resumed
+                            Err(e) => {
+                                self.notices.push(Notice::Paused(e));
+                                monitor.pause();
+                            }
+                        }
+                    }
+                    None => self.processor.run_frame(instructions)?,
+                }
+                let logged = self.processor.take_logged_errors();
+                self.notices.extend(logged.into_iter().map(Notice::Logged));
+                if !self.processor.take_input_polled() {
+                    self.lag_frames += 1;
+                }
+                if let Some(rewind) = rewind {
+                    rewind.record(&self.processor);
+                }
+                self.frame += 1;
+                if let (Some(movie), None) = (&mut self.movie, self.playback) {
+                    movie.frames = self.frame;
+                }
+            }
+        }
+
+        // A movie being played back owns the keypad until it ends
+        let replaying = self.playback.is_some() && !self.is_movie_finished();
+        for event in self.input.poll() {
+            match event {
+                InputEvent::KeyDown(key) if !replaying => self.press(key, true),
+                InputEvent::KeyUp(key) if !replaying => self.press(key, false),
+                InputEvent::KeyDown(_) | InputEvent::KeyUp(_) => {}
+                InputEvent::SaveState(slot) => self.quick_save(slot),
+                InputEvent::LoadState(_) | InputEvent::RewindStart if self.movie.is_some() => {
+                    self.notices.push(Notice::MovieInTheWay);
+                }
+                InputEvent::LoadState(slot) => self.quick_load(slot),
+                InputEvent::RewindStart => self.rewinding = self.rewind.is_some(),
+                InputEvent::RewindStop => self.rewinding = false,
+                InputEvent::Break => self
+                    .monitor
+                    .get_or_insert_with(|| Box::new(Monitor::stdio()))
+                    .pause(),
+                InputEvent::Quit => return Ok(false),
+            }
+        }
+        if replaying {
+            self.replay_inputs();
+        }
+
+        if self.processor.take_display_change() {
+            self.video.present(self.processor.framebuffer());
+        }
+        self.video.present_state(&self.processor);
+
+        if self.processor.take_audio_change() {
+            self.audio.set_tone(self.processor.tone());
+        }
+        let playing = self.processor.timers().sound > 0 && !self.rewinding;
+        self.audio.set_playing(playing);
+        Ok(!self.processor.is_halted())
+    }
+
+    fn press(&mut self, key: u8, down: bool) {
+        if down {
+            self.processor.set_key(key);
+        } else {
+            self.processor.release_key(key);
+        }
+        if let (Some(movie), None) = (&mut self.movie, self.playback) {
+            movie.inputs.push(MovieInput {
+                frame: self.frame,
+                key,
+                down,
+            });
+        }
+    }
+
+    /// Apply the movie inputs recorded after the current frame.
+    fn replay_inputs(&mut self) {
+        let (Some(movie), Some(next)) = (&self.movie, &mut self.playback) else {
+            return;
+        };
+        while let Some(input) = movie.inputs.get(*next) {
+            if input.frame > self.frame {
+                break;
+            }
+            if input.down {
+                self.processor.set_key(input.key);
+            } else {
+                self.processor.release_key(input.key);
+            }
+            *next += 1;
+        }
+    }
+
+    fn quick_save(&mut self, slot: u8) {
+        let Some(rom_path) = &self.rom_path else {
+            return;
+        };
+        let path = savestate::slot_path(rom_path, slot);
+        let notice = match fs::write(&path, self.processor.save_state()) {
+            Ok(()) => Notice::Saved { slot, path },
+            Err(e) => Notice::SaveFailed {
+                slot,
+                error: e.into(),
+            },
+        };
+        self.notices.push(notice);
+    }
+
+    fn quick_load(&mut self, slot: u8) {
+        let Some(rom_path) = &self.rom_path else {
+            return;
+        };
+        let path = savestate::slot_path(rom_path, slot);
+        let result = fs::read(&path)
+            .map_err(EmulatorError::from)
+            .and_then(|bytes| self.processor.load_state(&bytes));
+        let notice = match result {
+            Ok(()) => Notice::Loaded { slot, path },
+            Err(error) => Notice::LoadFailed { slot, error },
+        };
+        self.notices.push(notice);
+    }
+
+    /// Run frames in step with the wall clock until the input source asks to quit or
+    /// the program exits, passing notices to `report` after each frame.
+    pub fn run(&mut self, mut report: impl FnMut(Notice)) -> Result<(), EmulatorError> {
+        loop {
+            for _ in 0..self.scheduler.wait_for_frames() {
+                let running = self.run_frame();
+                self.take_notices().into_iter().for_each(&mut report);
+                if !running? {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Emulator, Notice};
+    use crate::error::{ErrorPolicies, ErrorPolicy};
+    use crate::frontend::{InputEvent, MemoryInput, NullAudio, NullInput, NullVideo};
+    use crate::movie::{Movie, MovieInput};
+    use crate::processor::Processor;
+    use crate::rewind::Rewind;
+    use crate::scheduler::Scheduler;
+
+    #[test]
+    fn test_input_reaches_processor() {
+        let mut vm = Processor::new();
+        // Wait for a key, then store it in V0
+        vm.load_rom(&[0xf0, 0x0a]).unwrap();
+
+        let mut input = MemoryInput::new();
+        input.push(vec![InputEvent::KeyDown(0x7)]);
+        input.push(vec![]);
+        input.push(vec![InputEvent::Quit]);
+
+        let mut emulator = Emulator::new(
+            vm,
+            Box::new(NullVideo),
+            Box::new(NullAudio),
+            Box::new(input),
+        )
+        .with_scheduler(Scheduler::with_instructions_per_frame(1));
+        assert!(emulator.run_frame().unwrap());
+        assert!(emulator.run_frame().unwrap());
+        assert!(!emulator.run_frame().unwrap());
+        assert_eq!(emulator.processor().registers()[0], 0x7);
+    }
+
+    #[test]
+    fn test_movie() {
+        // RND V1, 0xFF; SKNP V2; ADD V3, 1; JP 0x200
+        let rom = [0xc1, 0xff, 0xe2, 0xa1, 0x73, 0x01, 0x12, 0x00];
+        let mut vm = Processor::new();
+        vm.load_rom(&rom).unwrap();
+        let scheduler = Scheduler::with_cpu_hz(250.);
+        let movie = Movie::new(&vm, &scheduler, false);
+
+        let mut input = MemoryInput::new();
+        input.push(vec![]);
+        input.push(vec![InputEvent::KeyDown(0x0)]);
+        input.push(vec![]);
+        input.push(vec![InputEvent::KeyUp(0x0)]);
+
+        let mut recording = Emulator::new(
+            vm,
+            Box::new(NullVideo),
+            Box::new(NullAudio),
+            Box::new(input),
+        )
+        .with_scheduler(scheduler)
+        .with_recording(movie);
+        for _ in 0..6 {
+            recording.run_frame().unwrap();
+        }
+        assert_eq!(recording.lag_frames(), 0);
+        let movie = recording.take_movie().unwrap();
+        assert_eq!(movie.frames, 6);
+        assert_eq!(
+            movie.inputs,
+            [
+                MovieInput {
+                    frame: 2,
+                    key: 0x0,
+                    down: true
+                },
+                MovieInput {
+                    frame: 4,
+                    key: 0x0,
+                    down: false
+                }
+            ]
+        );
+
+        let mut playback = Emulator::new(
+            movie.processor(&rom).unwrap(),
+            Box::new(NullVideo),
+            Box::new(NullAudio),
+            Box::new(NullInput),
+        )
+        .with_scheduler(movie.scheduler())
+        .with_playback(movie);
+        while !playback.is_movie_finished() {
+            playback.run_frame().unwrap();
+        }
+        assert_eq!(
+            playback.processor().registers(),
+            recording.processor().registers()
+        );
+        assert_ne!(playback.processor().registers()[3], 0);
+    }
+
+    #[test]
+    fn test_notices() {
+        // LD I, 0xFFF; LD B, V0; JP 0x204
+        let mut vm = Processor::new();
+        vm.load_rom(&[0xaf, 0xff, 0xf0, 0x33, 0x12, 0x04]).unwrap();
+        vm.set_error_policies(ErrorPolicies {
+            memory: ErrorPolicy::Log,
+            ..ErrorPolicies::default()
+        });
+        let movie = Movie::new(&vm, &Scheduler::default(), false);
+
+        let mut input = MemoryInput::new();
+        input.push(vec![InputEvent::SaveState(1), InputEvent::LoadState(1)]);
+
+        let mut emulator = Emulator::new(
+            vm,
+            Box::new(NullVideo),
+            Box::new(NullAudio),
+            Box::new(input),
+        )
+        .with_save_slots("/nonexistent/game.ch8")
+        .with_recording(movie);
+        emulator.run_frame().unwrap();
+        let notices = emulator.take_notices();
+        assert!(matches!(
+            notices[..],
+            [
+                Notice::Logged(_),
+                Notice::Logged(_),
+                Notice::SaveFailed { slot: 1, .. },
+                Notice::MovieInTheWay
+            ]
+        ));
+        assert_eq!(
+            notices[3].to_string(),
+            "warning: can't go back in time during a movie"
+        );
+        emulator.run_frame().unwrap();
+        assert!(emulator.take_notices().is_empty());
+    }
+
+    #[test]
+    fn test_rewind() {
+        // ADD V0, 1; JP 0x200
+        let mut vm = Processor::new();
+        vm.load_rom(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+
+        let mut input = MemoryInput::new();
+        for _ in 0..5 {
+            input.push(vec![]);
+        }
+        input.push(vec![InputEvent::RewindStart]);
+        input.push(vec![]);
+        input.push(vec![InputEvent::RewindStop]);
+
+        let mut emulator = Emulator::new(
+            vm,
+            Box::new(NullVideo),
+            Box::new(NullAudio),
+            Box::new(input),
+        )
+        .with_scheduler(Scheduler::with_instructions_per_frame(2))
+        .with_rewind(Rewind::default());
+        for _ in 0..6 {
+            emulator.run_frame().unwrap();
+        }
+        assert_eq!(emulator.processor().registers()[0], 6);
+
+        // Two frames back to the snapshots after frames 6 and 5, then on again
+        emulator.run_frame().unwrap();
+        emulator.run_frame().unwrap();
+        assert_eq!(emulator.processor().registers()[0], 5);
+        emulator.run_frame().unwrap();
+        assert_eq!(emulator.processor().registers()[0], 6);
+    }
+}
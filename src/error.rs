@@ -0,0 +1,111 @@
+use crate::movie::MovieError;
+use crate::savestate::SaveStateError;
+use std::{error, fmt, io, str::FromStr};
+
+#[derive(Debug)]
+pub enum EmulatorError {
+    Io(io::Error),
+    RomTooLarge { size: usize, max: usize },
+    StackOverflow { pc: usize },
+    StackUnderflow { pc: usize },
+    MemoryOutOfBounds { addr: usize, pc: usize },
+    InvalidOpcode { opcode: u16, pc: usize },
+    SaveState(SaveStateError),
+    Movie(MovieError),
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmulatorError::Io(e) => write!(f, "{}", e),
+            EmulatorError::RomTooLarge { size, max } => {
+                write!(f, "ROM is {} bytes but at most {} fit in memory", size, max)
+            }
+            EmulatorError::StackOverflow { pc } => write!(f, "stack overflow at {:03X}", pc),
+            EmulatorError::StackUnderflow { pc } => write!(f, "stack underflow at {:03X}", pc),
+            EmulatorError::MemoryOutOfBounds { addr, pc } => {
+                write!(f, "memory access out of bounds at {:X} by {:03X}", addr, pc)
+            }
+            EmulatorError::InvalidOpcode { opcode, pc } => {
+                write!(f, "invalid opcode {:04X} at {:03X}", opcode, pc)
+            }
+            EmulatorError::SaveState(e) => write!(f, "{}", e),
+            EmulatorError::Movie(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for EmulatorError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            EmulatorError::Io(e) => Some(e),
+            EmulatorError::SaveState(e) => Some(e),
+            EmulatorError::Movie(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for EmulatorError {
+    fn from(e: io::Error) -> Self {
+        EmulatorError::Io(e)
+    }
+}
+
+impl From<SaveStateError> for EmulatorError {
+    fn from(e: SaveStateError) -> Self {
+        EmulatorError::SaveState(e)
+    }
+}
+
+impl From<MovieError> for EmulatorError {
+    fn from(e: MovieError) -> Self {
+        EmulatorError::Movie(e)
+    }
+}
+
+/// What to do when a class of runtime error happens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Stop and return the error from `step`.
+    Halt,
+    /// Record the error and carry on, skipping the faulting access or instruction.
+    Log,
+    /// Wrap the stack pointer or address around. Invalid opcodes can't wrap
+    /// and are treated as `Log`.
+    Wrap,
+}
+
+impl FromStr for ErrorPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "halt" => Ok(ErrorPolicy::Halt),
+            "log" => Ok(ErrorPolicy::Log),
+            "wrap" => Ok(ErrorPolicy::Wrap),
+            _ => Err(format!(
+                "unknown error policy {} (expected halt, log or wrap)",
+                s
+            )),
+        }
+    }
+}
+
+/// The policy for each class of runtime error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorPolicies {
+    pub stack: ErrorPolicy,
+    pub memory: ErrorPolicy,
+    pub invalid_opcode: ErrorPolicy,
+}
+
+impl Default for ErrorPolicies {
+    fn default() -> Self {
+        Self {
+            stack: ErrorPolicy::Halt,
+            memory: ErrorPolicy::Wrap,
+            invalid_opcode: ErrorPolicy::Halt,
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use super::{AudioSink, Framebuffer, InputEvent, InputSource, Tone, VideoSink};
+use std::collections::VecDeque;
+
+/// Keeps the most recently presented frame.
+#[derive(Debug, Default)]
+pub struct MemoryVideo {
+    pub framebuffer: Option<Framebuffer>,
+    pub presented: usize,
+}
+
+impl VideoSink for MemoryVideo {
+    fn present(&mut self, framebuffer: &Framebuffer) {
+        self.framebuffer = Some(framebuffer.clone());
+        self.presented += 1;
+    }
+}
+
+/// Records the buzzer state for every frame and the latest tone.
+#[derive(Debug)]
+pub struct MemoryAudio {
+    pub history: Vec<bool>,
+    pub tone: Tone,
+}
+
+impl Default for MemoryAudio {
+    fn default() -> Self {
+        Self {
+            history: Vec::new(),
+            tone: Tone::Buzzer,
+        }
+    }
+}
+
+impl MemoryAudio {
+    pub fn is_playing(&self) -> bool {
+        self.history.last().copied().unwrap_or(false)
+    }
+}
+
+impl AudioSink for MemoryAudio {
+    fn set_playing(&mut self, playing: bool) {
+        self.history.push(playing);
+    }
+
+    fn set_tone(&mut self, tone: Tone) {
+        self.tone = tone;
+    }
+}
+
+/// Hands out a scripted batch of events on each poll.
+#[derive(Debug, Default)]
+pub struct MemoryInput {
+    batches: VecDeque<Vec<InputEvent>>,
+}
+
+impl MemoryInput {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue the events returned by the next unanswered poll.
+    pub fn push(&mut self, events: Vec<InputEvent>) {
+        self.batches.push_back(events);
+    }
+}
+
+impl InputSource for MemoryInput {
+    fn poll(&mut self) -> Vec<InputEvent> {
+        self.batches.pop_front().unwrap_or_default()
+    }
+}
@@ -0,0 +1,52 @@
+// Frontend interfaces the emulation loop talks to, so the same core can
+// drive a window, a terminal, a test harness or an embedded display.
+mod memory;
+mod null;
+
+pub use memory::{MemoryAudio, MemoryInput, MemoryVideo};
+pub use null::{NullAudio, NullInput, NullVideo};
+
+pub use crate::display::{Framebuffer, PALETTE};
+pub use crate::processor::{Processor, Tone};
+
+/// Something that can show the contents of the display.
+pub trait VideoSink {
+    /// Called whenever the display changed during a frame.
+    fn present(&mut self, framebuffer: &Framebuffer);
+
+    /// Called every frame with the whole machine, for sinks that show more
+    /// than the display. Most can ignore this.
+    fn present_state(&mut self, _processor: &Processor) {}
+}
+
+/// Something that can make the buzzer sound.
+pub trait AudioSink {
+    /// Called every frame with whether the sound timer is running.
+    fn set_playing(&mut self, playing: bool);
+
+    /// Called when the program changes what the sound timer plays. A sink
+    /// that only has a buzzer can ignore this.
+    fn set_tone(&mut self, _tone: Tone) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputEvent {
+    KeyDown(u8),
+    KeyUp(u8),
+    /// Save the machine to a quick save slot.
+    SaveState(u8),
+    /// Restore the machine from a quick save slot.
+    LoadState(u8),
+    /// Start running backwards through the rewind history.
+    RewindStart,
+    RewindStop,
+    /// Stop in the machine language monitor.
+    Break,
+    Quit,
+}
+
+/// Something that produces keypad and control events.
+pub trait InputSource {
+    /// Return the events that happened since the last poll.
+    fn poll(&mut self) -> Vec<InputEvent>;
+}
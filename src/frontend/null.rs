@@ -0,0 +1,27 @@
+use super::{AudioSink, Framebuffer, InputEvent, InputSource, VideoSink};
+
+/// Discards every frame.
+#[derive(Debug, Default)]
+pub struct NullVideo;
+
+impl VideoSink for NullVideo {
+    fn present(&mut self, _framebuffer: &Framebuffer) {}
+}
+
+/// Never makes a sound.
+#[derive(Debug, Default)]
+pub struct NullAudio;
+
+impl AudioSink for NullAudio {
+    fn set_playing(&mut self, _playing: bool) {}
+}
+
+/// Never presses a key.
+#[derive(Debug, Default)]
+pub struct NullInput;
+
+impl InputSource for NullInput {
+    fn poll(&mut self) -> Vec<InputEvent> {
+        Vec::new()
+    }
+}
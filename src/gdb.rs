@@ -0,0 +1,583 @@
+// A stub for the GDB remote serial protocol, so `gdb` (or `lldb`) can attach
+// to a running ROM over a localhost TCP port. GDB has no CHIP-8 architecture,
+// so the stub serves a target description naming the registers: V0-VF, I,
+// PC, SP (the stack depth), DT and ST, in that order. Breakpoints and
+// watchpoints are kept by a `Debugger`.
+use crate::debugger::{DebugFrontend, Debugger, Event, Hit, Point, Register, Trigger};
+use crate::processor::Processor;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+
+/// The registers in GDB's order, with their sizes in bytes.
+const REGISTERS: [(Register, usize); 21] = [
+    (Register::V(0x0), 1),
+    (Register::V(0x1), 1),
+    (Register::V(0x2), 1),
+    (Register::V(0x3), 1),
+    (Register::V(0x4), 1),
+    (Register::V(0x5), 1),
+    (Register::V(0x6), 1),
+    (Register::V(0x7), 1),
+    (Register::V(0x8), 1),
+    (Register::V(0x9), 1),
+    (Register::V(0xa), 1),
+    (Register::V(0xb), 1),
+    (Register::V(0xc), 1),
+    (Register::V(0xd), 1),
+    (Register::V(0xe), 1),
+    (Register::V(0xf), 1),
+    (Register::I, 2),
+    (Register::Pc, 2),
+    (Register::Sp, 1),
+    (Register::Delay, 1),
+    (Register::Sound, 1),
+];
+
+/// Describe the registers to GDB.
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\"><feature name=\"org.chip8.core\">",
+    );
+    for (register, size) in REGISTERS {
+        let kind = match register {
+            Register::Pc => "code_ptr",
+            Register::I => "data_ptr",
+            _ if size == 1 => "uint8",
+            _ => "uint16",
+        };
+        xml += &format!(
+            "<reg name=\"{}\" bitsize=\"{}\" type=\"{}\"/>",
+            register.to_string().to_ascii_lowercase(),
+            size * 8,
+            kind
+        );
+    }
+    xml + "</feature></target>"
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Waiting for commands, without owing GDB a stop reply.
+    Attached,
+    Running,
+    Stepping,
+    /// Stopped by `pause`, and GDB hasn't been told yet.
+    Paused,
+}
+
+/// What the stub does after a packet.
+#[derive(Debug, PartialEq, Eq)]
+enum Reply {
+    Packet(String),
+    /// Carry on without answering; the stop reply comes later.
+    Resume(State),
+    Detach,
+    Kill,
+}
+
+pub struct GdbStub {
+    // None once GDB has gone
+    stream: Option<TcpStream>,
+    received: VecDeque<u8>,
+    debugger: Debugger,
+    // The debugger's ids for the points GDB set, by type, address and kind
+    points: HashMap<(u8, usize, usize), usize>,
+    state: State,
+}
+
+impl GdbStub {
+    /// Wait for GDB to connect on `port` of the loopback interface. The
+    /// program starts out stopped.
+    pub fn listen(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        eprintln!(
+            "waiting for gdb on {} (target remote :{})",
+            listener.local_addr()?,
+            port
+        );
+        let (stream, addr) = listener.accept()?;
+        eprintln!("gdb connected from {}", addr);
+        Self::new(stream)
+    }
+
+    /// A stub talking to GDB over `stream`.
+    pub fn new(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            stream: Some(stream),
+            received: VecDeque::new(),
+            debugger: Debugger::new(),
+            points: HashMap::new(),
+            state: State::Attached,
+        })
+    }
+
+    /// Read whatever has arrived without waiting. Returns false once the
+    /// connection is closed.
+    fn poll(&mut self) -> io::Result<bool> {
+        let Some(stream) = &mut self.stream else {
+            return Ok(false);
+        };
+        stream.set_nonblocking(true)?;
+        let mut buffer = [0; 1024];
+        let result = loop {
+            match stream.read(&mut buffer) {
+                Ok(0) => break Ok(false),
+                Ok(n) => self.received.extend(&buffer[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break Ok(true),
+                Err(e) => break Err(e),
+            }
+        };
+        stream.set_nonblocking(false)?;
+        result
+    }
+
+    /// Whether GDB sent a ^C to interrupt the program.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        if !self.poll()? {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        let interrupted = self.received.contains(&0x03);
+        self.received.retain(|&b| b != 0x03);
+        Ok(interrupted)
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        if self.received.is_empty() {
+            let stream = self.stream.as_mut().ok_or(ErrorKind::NotConnected)?;
+            let mut buffer = [0; 1024];
+            let n = stream.read(&mut buffer)?;
+            if n == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            self.received.extend(&buffer[..n]);
+        }
+        Ok(self.received.pop_front().unwrap())
+    }
+
+    /// The next packet's contents, acknowledging it.
+    fn receive(&mut self) -> io::Result<String> {
+        loop {
+            // Skip acknowledgements and interrupts between packets
+            while self.byte()? != b'$' {}
+            let mut data = Vec::new();
+            loop {
+                match self.byte()? {
+                    b'#' => break,
+                    b => data.push(b),
+                }
+            }
+            let checksum = [self.byte()?, self.byte()?];
+            let expected = format!("{:02x}", checksum_of(&data));
+            let stream = self.stream.as_mut().ok_or(ErrorKind::NotConnected)?;
+            if checksum.eq_ignore_ascii_case(expected.as_bytes()) {
+                stream.write_all(b"+")?;
+                return Ok(String::from_utf8_lossy(&data).into_owned());
+            }
+            stream.write_all(b"-")?;
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let stream = self.stream.as_mut().ok_or(ErrorKind::NotConnected)?;
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        stream.write_all(packet.as_bytes())
+    }
+
+    /// Tell GDB the program stopped, then serve packets until it resumes.
+    /// Returns false to quit.
+    fn stop(&mut self, processor: &mut Processor, reply: Option<String>) -> io::Result<bool> {
+        if let Some(reply) = reply {
+            self.send(&reply)?;
+        }
+        loop {
+            let packet = self.receive()?;
+            match self.execute(processor, &packet) {
+                Reply::Packet(reply) => self.send(&reply)?,
+                Reply::Resume(state) => {
+                    self.state = state;
+                    self.debugger.resume_from(processor.pc());
+                    return Ok(true);
+                }
+                Reply::Detach => {
+                    self.send("OK")?;
+                    self.detach();
+                    return Ok(true);
+                }
+                Reply::Kill => return Ok(false),
+            }
+        }
+    }
+
+    /// Let the program run on freely.
+    fn detach(&mut self) {
+        self.stream = None;
+        self.points.clear();
+        self.debugger = Debugger::new();
+        self.state = State::Running;
+    }
+
+    fn execute(&mut self, processor: &mut Processor, packet: &str) -> Reply {
+        let reply = |s: &str| Reply::Packet(s.to_string());
+        let (command, args) = packet.split_at(packet.len().min(1));
+        let result = match command {
+            "?" => Ok(Reply::Packet(signal(5))),
+            "g" => Ok(Reply::Packet(
+                REGISTERS
+                    .iter()
+                    .map(|&(register, size)| to_hex(register.read(processor), size))
+                    .collect(),
+            )),
+            "G" => self.write_registers(processor, args).map(|()| reply("OK")),
+            "p" => hex(args).and_then(|n| {
+                let &(register, size) = REGISTERS.get(n).ok_or(())?;
+                Ok(Reply::Packet(to_hex(register.read(processor), size)))
+            }),
+            "P" => args
+                .split_once('=')
+                .ok_or(())
+                .and_then(|(n, value)| {
+                    let &(register, size) = REGISTERS.get(hex(n)?).ok_or(())?;
+                    if value.len() != size * 2 {
+                        return Err(());
+                    }
+                    let value = from_hex(value).ok_or(())?;
+                    register.write(processor, value).map_err(|_| ())
+                })
+                .map(|()| reply("OK")),
+            "m" => memory_range(processor, args).map(|range| {
+                Reply::Packet(
+                    processor.memory()[range]
+                        .iter()
+                        .map(|b| format!("{:02x}", b))
+                        .collect(),
+                )
+            }),
+            "M" => args.split_once(':').ok_or(()).and_then(|(range, data)| {
+                let range = memory_range(processor, range)?;
+                let bytes = bytes(data).ok_or(())?;
+                if bytes.len() != range.len() {
+                    return Err(());
+                }
+                processor.memory_mut()[range].copy_from_slice(&bytes);
+                Ok(reply("OK"))
+            }),
+            "c" | "s" => {
+                if !args.is_empty() {
+                    match hex(args) {
+                        Ok(addr) => processor.set_pc(addr),
+                        Err(()) => return Reply::Packet(error(1)),
+                    }
+                }
+                Ok(Reply::Resume(if command == "c" {
+                    State::Running
+                } else {
+                    State::Stepping
+                }))
+            }
+            "Z" | "z" => self.set_point(processor, command == "Z", args),
+            "D" => Ok(Reply::Detach),
+            "k" => Ok(Reply::Kill),
+            // There is only one thread
+            "H" => Ok(reply("OK")),
+            "q" => Ok(Reply::Packet(query(packet))),
+            _ => Ok(reply("")),
+        };
+        result.unwrap_or_else(|()| Reply::Packet(error(1)))
+    }
+
+    fn write_registers(&mut self, processor: &mut Processor, data: &str) -> Result<(), ()> {
+        let mut rest = data;
+        let mut values = Vec::new();
+        for (register, size) in REGISTERS {
+            let (value, tail) = rest.split_at_checked(size * 2).ok_or(())?;
+            values.push((register, from_hex(value).ok_or(())?));
+            rest = tail;
+        }
+        // SP is the only register that can refuse a value of the right size,
+        // so write it first to leave the rest alone if it does
+        values.sort_by_key(|&(register, _)| register != Register::Sp);
+        for (register, value) in values {
+            register.write(processor, value).map_err(|_| ())?;
+        }
+        Ok(())
+    }
+
+    /// Handle `Z` and `z`: types 0 and 1 are breakpoints, 2 write
+    /// watchpoints and 3 read watchpoints. Watched ranges have to fit in
+    /// memory.
+    fn set_point(&mut self, processor: &Processor, insert: bool, args: &str) -> Result<Reply, ()> {
+        let mut fields = args.split([',', ';']);
+        let mut field = || fields.next().ok_or(());
+        let kind: u8 = field()?.parse().map_err(|_| ())?;
+        let addr = hex(field()?)?;
+        let len = hex(field()?)?;
+        let end = addr.checked_add(len.max(1)).ok_or(())?;
+        let watch = || {
+            if end > processor.memory().len() {
+                return Err(());
+            }
+            Ok(addr..end)
+        };
+        let trigger = match kind {
+            0 | 1 => Trigger::Pc(addr),
+            2 => Trigger::Write(watch()?),
+            3 => Trigger::Read(watch()?),
+            // Access watchpoints aren't supported
+            _ => return Ok(Reply::Packet(String::new())),
+        };
+        let key = (kind, addr, len);
+        if insert {
+            if !self.points.contains_key(&key) {
+                let id = self.debugger.add(Point::new(trigger));
+                self.points.insert(key, id);
+            }
+        } else if let Some(id) = self.points.remove(&key) {
+            self.debugger.remove(id);
+        }
+        Ok(Reply::Packet("OK".to_string()))
+    }
+}
+
+impl DebugFrontend for GdbStub {
+    fn before_step(&mut self, processor: &mut Processor) -> bool {
+        if self.stream.is_none() {
+            return true;
+        }
+        let hits = self.debugger.check(processor);
+        let reply = match (self.state, hits.first()) {
+            (State::Attached, _) => Ok(None),
+            (State::Running | State::Stepping, Some(hit)) => Ok(Some(stop_reply(hit))),
+            (State::Stepping, None) => Ok(Some(signal(5))),
+            (State::Paused, _) => Ok(Some(signal(2))),
+            (State::Running, None) => match self.interrupted() {
+                Ok(false) => return true,
+                Ok(true) => Ok(Some(signal(2))),
+                Err(e) => Err(e),
+            },
+        };
+        self.state = State::Attached;
+        match reply.and_then(|reply| self.stop(processor, reply)) {
+            Ok(running) => running,
+            // Let the program carry on if GDB goes away
+            Err(e) => {
+                eprintln!("gdb disconnected: {}", e);
+                self.detach();
+                true
+            }
+        }
+    }
+
+    fn pause(&mut self) {
+        if self.stream.is_some() {
+            self.state = State::Paused;
+        }
+    }
+}
+
+impl Drop for GdbStub {
+    /// Tell GDB the program exited, if it's waiting for it to stop.
+    fn drop(&mut self) {
+        if self.stream.is_some() && self.state != State::Attached {
+            let _ = self.send("W00");
+        }
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &b| sum.wrapping_add(b))
+}
+
+fn signal(n: u8) -> String {
+    format!("S{:02x}", n)
+}
+
+fn error(n: u8) -> String {
+    format!("E{:02x}", n)
+}
+
+fn stop_reply(hit: &Hit) -> String {
+    match hit.event {
+        Event::Breakpoint { .. } => "T05swbreak:;".to_string(),
+        Event::Write { addr, .. } => format!("T05watch:{:x};", addr),
+        Event::Read { addr, .. } => format!("T05rwatch:{:x};", addr),
+        Event::Register { .. } => signal(5),
+    }
+}
+
+fn query(packet: &str) -> String {
+    let (name, args) = packet.split_once(':').unwrap_or((packet, ""));
+    match name {
+        "qSupported" => "PacketSize=1000;qXfer:features:read+;swbreak+".to_string(),
+        "qAttached" => "1".to_string(),
+        "qC" => "QC1".to_string(),
+        "qfThreadInfo" => "m1".to_string(),
+        "qsThreadInfo" => "l".to_string(),
+        "qXfer" => {
+            let Some(("features", "read", "target.xml", range)) = split4(args) else {
+                return error(0);
+            };
+            let Some((offset, len)) = range.split_once(',') else {
+                return error(0);
+            };
+            let (Ok(offset), Ok(len)) = (hex(offset), hex(len)) else {
+                return error(0);
+            };
+            let xml = target_xml();
+            let chunk = xml.get(offset..).unwrap_or("");
+            if chunk.len() > len {
+                format!("m{}", &chunk[..len])
+            } else {
+                format!("l{}", chunk)
+            }
+        }
+        _ => String::new(),
+    }
+}
+
+fn split4(s: &str) -> Option<(&str, &str, &str, &str)> {
+    let mut parts = s.splitn(4, ':');
+    Some((parts.next()?, parts.next()?, parts.next()?, parts.next()?))
+}
+
+fn hex(s: &str) -> Result<usize, ()> {
+    usize::from_str_radix(s, 16).map_err(|_| ())
+}
+
+fn bytes(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Registers go over the wire in little endian order.
+fn to_hex(value: usize, size: usize) -> String {
+    (0..size)
+        .map(|n| format!("{:02x}", (value >> (n * 8)) & 0xff))
+        .collect()
+}
+
+fn from_hex(s: &str) -> Option<usize> {
+    let bytes = bytes(s)?;
+    Some(
+        bytes
+            .iter()
+            .rev()
+            .fold(0, |value, &b| value << 8 | b as usize),
+    )
+}
+
+fn memory_range(processor: &Processor, args: &str) -> Result<std::ops::Range<usize>, ()> {
+    let (addr, len) = args.split_once(',').ok_or(())?;
+    let (addr, len) = (hex(addr)?, hex(len)?);
+    let end = addr.checked_add(len).ok_or(())?;
+    if end > processor.memory().len() {
+        return Err(());
+    }
+    Ok(addr..end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{checksum_of, GdbStub};
+    use crate::debugger::DebugFrontend;
+    use crate::processor::Processor;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    /// Send a packet and return the reply, like a scripted GDB.
+    fn request(stream: &mut TcpStream, data: &str) -> String {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        stream.write_all(packet.as_bytes()).unwrap();
+        let mut byte = [0];
+        stream.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b'+');
+        if data == "k" {
+            return String::new();
+        }
+        stream.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b'$');
+        let mut reply = Vec::new();
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            if byte[0] == b'#' {
+                break;
+            }
+            reply.push(byte[0]);
+        }
+        let mut checksum = [0; 2];
+        stream.read_exact(&mut checksum).unwrap();
+        assert_eq!(checksum, format!("{:02x}", checksum_of(&reply)).as_bytes());
+        stream.write_all(b"+").unwrap();
+        String::from_utf8(reply).unwrap()
+    }
+
+    #[test]
+    fn test_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.set_nodelay(true).unwrap();
+            let script = [
+                "qSupported:swbreak+",
+                "qXfer:features:read:target.xml:0,20",
+                "?",
+                "p11",
+                "Z0,204,2",
+                "c",
+                "p0",
+                "M300,2:beef",
+                "m300,3",
+                "P0=2a",
+                "P12=ff",
+                "Z2,300,1",
+                "s",
+                "p11",
+                "c",
+                "m300,1",
+                "z2,300,1",
+                "g",
+                "m1000,1",
+                "Z2,ffffffffffffffff,2",
+                "Z3,fff,2",
+                "k",
+            ];
+            script.map(|data| request(&mut stream, data))
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut stub = GdbStub::new(stream).unwrap();
+        let mut vm = Processor::new();
+        // LD V0, 5; ADD V0, 1; LD I, 0x300; LD [I], V0; JP 0x202
+        vm.load_rom(&[0x60, 0x05, 0x70, 0x01, 0xa3, 0x00, 0xf0, 0x55, 0x12, 0x02])
+            .unwrap();
+        while vm.run_frame_with(100, |vm| stub.before_step(vm)).unwrap() {}
+
+        let replies = client.join().unwrap();
+        assert_eq!(replies[0], "PacketSize=1000;qXfer:features:read+;swbreak+");
+        assert_eq!(replies[1], "m<?xml version=\"1.0\"?><!DOCTYPE t");
+        assert_eq!(replies[2..5], ["S05", "0002", "OK"]);
+        // Stopped at the breakpoint after the ADD
+        assert_eq!(replies[5..9], ["T05swbreak:;", "06", "OK", "beef00"]);
+        // SP can't go past the stack
+        assert_eq!(replies[9..12], ["OK", "E01", "OK"]);
+        assert_eq!(replies[12..14], ["S05", "0602"]);
+        // The watchpoint stops after the store, with pc at the jump
+        assert_eq!(replies[14..17], ["T05watch:300;", "2a", "OK"]);
+        assert_eq!(&replies[17][..4], "2a00");
+        // I moved past the store
+        assert_eq!(&replies[17][32..38], "010308");
+        assert_eq!(replies[18], "E01");
+        // Watchpoints that overflow or run past memory are refused
+        assert_eq!(replies[19..21], ["E01", "E01"]);
+    }
+}
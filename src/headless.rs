@@ -0,0 +1,242 @@
+// Running programs without a window, for automation: scripted key presses
+// instead of a keyboard, and a set of conditions to stop at instead of a
+// user closing the window.
+use crate::emulator::{Emulator, Notice};
+use crate::error::EmulatorError;
+use crate::frontend::{InputEvent, InputSource};
+use crate::instruction::Instruction;
+use crate::processor::Processor;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Why a headless run stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// The frame limit was reached.
+    Frames,
+    /// The program exited with 00FD.
+    Halted,
+    /// The program is stuck jumping to itself, the usual way test ROMs end.
+    Loop { pc: usize },
+    /// The wall clock time limit ran out first.
+    Timeout,
+    /// The input source asked to quit.
+    Quit,
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stop::Frames => write!(f, "reached the frame limit"),
+            Stop::Halted => write!(f, "halted"),
+            Stop::Loop { pc } => write!(f, "jumped to itself at 0x{:03X}", pc),
+            Stop::Timeout => write!(f, "timed out"),
+            Stop::Quit => write!(f, "quit"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub frames: Option<u64>,
+    pub timeout: Option<Duration>,
+    pub stop_on_loop: bool,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            frames: None,
+            timeout: Some(Duration::from_secs(10)),
+            stop_on_loop: true,
+        }
+    }
+}
+
+/// Run frames as fast as possible until one of the `limits` is hit, passing
+/// the emulator's notices to `report` after each frame.
+pub fn run(
+    emulator: &mut Emulator,
+    limits: &Limits,
+    mut report: impl FnMut(Notice),
+) -> Result<Stop, EmulatorError> {
+    let start = Instant::now();
+    loop {
+        if limits
+            .frames
+            .is_some_and(|frames| emulator.frame() >= frames)
+        {
+            return Ok(Stop::Frames);
+        }
+        if limits
+            .timeout
+            .is_some_and(|timeout| start.elapsed() >= timeout)
+        {
+            return Ok(Stop::Timeout);
+        }
+        let running = emulator.run_frame();
+        emulator.take_notices().into_iter().for_each(&mut report);
+        if !running? {
+            if emulator.processor().is_halted() {
+                return Ok(Stop::Halted);
+            }
+            return Ok(Stop::Quit);
+        }
+        if limits.stop_on_loop && is_jump_to_self(emulator.processor()) {
+            return Ok(Stop::Loop {
+                pc: emulator.processor().pc(),
+            });
+        }
+    }
+}
+
+/// Whether the next instruction jumps to itself.
+pub fn is_jump_to_self(processor: &Processor) -> bool {
+    let pc = processor.pc();
+    let Some(&[high, low]) = processor.memory().get(pc..pc + 2) else {
+        return false;
+    };
+    let opcode = u16::from_be_bytes([high, low]);
+    matches!(Instruction::decode(opcode), Ok(Instruction::Jump { nnn }) if nnn as usize == pc)
+}
+
+/// Press `key` after frame `frame` and release it `frames` frames later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyPress {
+    pub frame: u64,
+    pub key: u8,
+    pub frames: u64,
+}
+
+/// Parses `FRAME:KEY[:FRAMES]`, with KEY in hex.
+impl FromStr for KeyPress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid key press {} (expected FRAME:KEY[:FRAMES])", s);
+        let mut parts = s.split(':');
+        let frame: u64 = parts
+            .next()
+            .and_then(|f| f.parse().ok())
+            .ok_or_else(invalid)?;
+        let key = parts
+            .next()
+            .and_then(|k| u8::from_str_radix(k, 16).ok())
+            .filter(|&k| k < 16)
+            .ok_or_else(invalid)?;
+        let frames = match parts.next() {
+            Some(n) => n.parse().map_err(|_| invalid())?,
+            None => 1,
+        };
+        // The release frame has to exist
+        if parts.next().is_some() || frame.checked_add(frames).is_none() {
+            return Err(invalid());
+        }
+        Ok(Self { frame, key, frames })
+    }
+}
+
+/// An input source playing back a list of key presses.
+#[derive(Debug, Default)]
+pub struct KeyScript {
+    presses: Vec<KeyPress>,
+    frame: u64,
+}
+
+impl KeyScript {
+    pub fn new(presses: Vec<KeyPress>) -> Self {
+        Self { presses, frame: 0 }
+    }
+}
+
+impl InputSource for KeyScript {
+    fn poll(&mut self) -> Vec<InputEvent> {
+        // Polled once after every frame
+        self.frame += 1;
+        let mut events = Vec::new();
+        for press in &self.presses {
+            if press.frame + press.frames == self.frame {
+                events.push(InputEvent::KeyUp(press.key));
+            }
+        }
+        for press in &self.presses {
+            if press.frame == self.frame {
+                events.push(InputEvent::KeyDown(press.key));
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, KeyPress, KeyScript, Limits, Stop};
+    use crate::emulator::Emulator;
+    use crate::frontend::{NullAudio, NullInput, NullVideo};
+    use crate::processor::Processor;
+    use crate::scheduler::Scheduler;
+
+    fn emulator(rom: &[u8], input: KeyScript) -> Emulator {
+        let mut vm = Processor::new();
+        vm.load_rom(rom).unwrap();
+        Emulator::new(
+            vm,
+            Box::new(NullVideo),
+            Box::new(NullAudio),
+            Box::new(input),
+        )
+        .with_scheduler(Scheduler::with_instructions_per_frame(10))
+    }
+
+    #[test]
+    fn test_stop_conditions() {
+        let limits = Limits::default();
+        // LD V0, K; JP 0x202
+        let script = KeyScript::new(vec!["3:a:2".parse().unwrap()]);
+        let mut vm = emulator(&[0xf0, 0x0a, 0x12, 0x02], script);
+        assert_eq!(
+            run(&mut vm, &limits, |_| {}).unwrap(),
+            Stop::Loop { pc: 0x202 }
+        );
+        assert_eq!(vm.frame(), 4);
+        assert_eq!(vm.processor().registers()[0], 0xa);
+
+        // EXIT is only available on SUPER-CHIP
+        let mut vm = Processor::with_platform(crate::platform::Platform::schip11());
+        vm.load_rom(&[0x00, 0xfd]).unwrap();
+        let mut vm = Emulator::new(
+            vm,
+            Box::new(NullVideo),
+            Box::new(NullAudio),
+            Box::new(NullInput),
+        );
+        assert_eq!(run(&mut vm, &limits, |_| {}).unwrap(), Stop::Halted);
+
+        // ADD V0, 1; JP 0x200
+        let mut vm = emulator(&[0x70, 0x01, 0x12, 0x00], KeyScript::default());
+        let frames = Limits {
+            frames: Some(5),
+            ..limits
+        };
+        assert_eq!(run(&mut vm, &frames, |_| {}).unwrap(), Stop::Frames);
+        assert_eq!(vm.frame(), 5);
+    }
+
+    #[test]
+    fn test_key_press() {
+        assert_eq!(
+            "12:F".parse(),
+            Ok(KeyPress {
+                frame: 12,
+                key: 0xf,
+                frames: 1
+            })
+        );
+        assert!("12:10".parse::<KeyPress>().is_err());
+        assert!("12".parse::<KeyPress>().is_err());
+        assert!("1:2:3:4".parse::<KeyPress>().is_err());
+        assert!("18446744073709551615:5:2".parse::<KeyPress>().is_err());
+        assert!("18446744073709551614:5".parse::<KeyPress>().is_ok());
+    }
+}
@@ -0,0 +1,200 @@
+// Encoders for writing the display out as an image: PNG in the palette
+// colours, PBM with every lit pixel black, and ASCII art. The PNG encoder
+// uses uncompressed deflate blocks, which keeps it dependency free and is
+// plenty for images this small.
+use crate::display::{Framebuffer, PALETTE};
+use std::path::Path;
+use std::str::FromStr;
+
+/// The characters `to_ascii` uses for each pixel value.
+pub const ASCII_PIXELS: [char; 4] = ['.', '#', '+', '*'];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Pbm,
+    Ascii,
+}
+
+impl FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "png" => Ok(ImageFormat::Png),
+            "pbm" => Ok(ImageFormat::Pbm),
+            "ascii" | "txt" => Ok(ImageFormat::Ascii),
+            _ => Err(format!(
+                "unknown image format {} (expected png, pbm or ascii)",
+                s
+            )),
+        }
+    }
+}
+
+impl ImageFormat {
+    /// Guess the format from a file extension, falling back to ASCII.
+    pub fn from_path(path: &Path) -> Self {
+        path.extension()
+            .and_then(|ext| ext.to_str()?.parse().ok())
+            .unwrap_or(ImageFormat::Ascii)
+    }
+
+    pub fn encode(&self, framebuffer: &Framebuffer) -> Vec<u8> {
+        match self {
+            ImageFormat::Png => to_png(framebuffer),
+            ImageFormat::Pbm => to_pbm(framebuffer),
+            ImageFormat::Ascii => to_ascii(framebuffer).into_bytes(),
+        }
+    }
+}
+
+/// One line per row using `ASCII_PIXELS`.
+pub fn to_ascii(framebuffer: &Framebuffer) -> String {
+    let mut out = String::new();
+    for row in framebuffer.rows() {
+        out.extend(row.iter().map(|&pixel| ASCII_PIXELS[pixel as usize & 0x3]));
+        out.push('\n');
+    }
+    out
+}
+
+/// Read back the output of `to_ascii`. Returns `None` for anything else.
+pub fn from_ascii(text: &str) -> Option<Framebuffer> {
+    let rows: Vec<&str> = text.lines().filter(|line| !line.is_empty()).collect();
+    let width = rows.first()?.chars().count();
+    let mut framebuffer = Framebuffer::new(width, rows.len());
+    for (y, row) in rows.iter().enumerate() {
+        if row.chars().count() != width {
+            return None;
+        }
+        for (x, c) in row.chars().enumerate() {
+            let pixel = ASCII_PIXELS.iter().position(|&p| p == c)? as u8;
+            if pixel != 0 {
+                framebuffer.toggle(x, y, pixel);
+            }
+        }
+    }
+    Some(framebuffer)
+}
+
+/// A plain PBM (P1) bitmap.
+pub fn to_pbm(framebuffer: &Framebuffer) -> Vec<u8> {
+    let mut out = format!("P1\n{} {}\n", framebuffer.width(), framebuffer.height());
+    for row in framebuffer.rows() {
+        let bits: Vec<&str> = row
+            .iter()
+            .map(|&pixel| if pixel != 0 { "1" } else { "0" })
+            .collect();
+        out.push_str(&bits.join(" "));
+        out.push('\n');
+    }
+    out.into_bytes()
+}
+
+/// An 8-bit RGB PNG.
+pub fn to_png(framebuffer: &Framebuffer) -> Vec<u8> {
+    let (width, height) = (framebuffer.width() as u32, framebuffer.height() as u32);
+
+    // Each scanline starts with filter type 0, none
+    let mut raw = Vec::new();
+    for row in framebuffer.rows() {
+        raw.push(0);
+        for &pixel in row {
+            raw.extend_from_slice(&PALETTE[pixel as usize & 0x3]);
+        }
+    }
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // Bit depth 8, colour type 2 (RGB), default compression, filter and no interlace
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut out = b"\x89PNG\r\n\x1a\n".to_vec();
+    chunk(&mut out, b"IHDR", &header);
+    chunk(&mut out, b"IDAT", &zlib_stored(&raw));
+    chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+fn chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Wrap `data` in a zlib stream of uncompressed deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xffff).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        out.push(blocks.peek().is_none() as u8);
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc32, from_ascii, to_ascii, to_pbm, to_png, ImageFormat};
+    use crate::display::Framebuffer;
+    use std::path::Path;
+
+    #[test]
+    fn test_formats() {
+        let mut fb = Framebuffer::new(3, 2);
+        fb.toggle(0, 0, 1);
+        fb.toggle(2, 1, 3);
+        assert_eq!(to_ascii(&fb), "#..\n..*\n");
+        assert_eq!(from_ascii(&to_ascii(&fb)), Some(fb.clone()));
+        assert_eq!(from_ascii("#..\n.\n"), None);
+        assert_eq!(to_pbm(&fb), b"P1\n3 2\n1 0 0\n0 0 1\n");
+
+        let png = to_png(&fb);
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(
+            ImageFormat::from_path(Path::new("out.png")),
+            ImageFormat::Png
+        );
+        assert_eq!(ImageFormat::from_path(Path::new("out")), ImageFormat::Ascii);
+    }
+}
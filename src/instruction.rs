@@ -0,0 +1,422 @@
+use std::{error, fmt};
+
+/// The instruction set families, each a superset of the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum InstructionSet {
+    Chip8,
+    SuperChip10,
+    SuperChip11,
+    XoChip,
+}
+
+/// A decoded CHIP-8 instruction. `x` and `y` are register indices, `kk` an
+/// 8-bit immediate, `n` a 4-bit immediate and `nnn` a 12-bit address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// 0NNN: Execute a machine code subroutine (ignored).
+    Sys { nnn: u16 },
+    /// 00E0: Clear the screen.
+    Cls,
+    /// 00EE: Return from a subroutine.
+    Ret,
+    /// 00CN: Scroll the display down N pixels.
+    ScrollDown { n: u8 },
+    /// 00DN: Scroll the selected planes up N pixels.
+    ScrollUp { n: u8 },
+    /// 00FB: Scroll the display right 4 pixels.
+    ScrollRight,
+    /// 00FC: Scroll the display left 4 pixels.
+    ScrollLeft,
+    /// 00FD: Exit the interpreter.
+    Exit,
+    /// 00FE: Switch to the 64x32 low resolution mode.
+    LowRes,
+    /// 00FF: Switch to the 128x64 high resolution mode.
+    HighRes,
+    /// 1NNN: Jump to NNN.
+    Jump { nnn: u16 },
+    /// 2NNN: Call the subroutine at NNN.
+    Call { nnn: u16 },
+    /// 3XKK: Skip if VX == KK.
+    SkipEqImm { x: u8, kk: u8 },
+    /// 4XKK: Skip if VX != KK.
+    SkipNeImm { x: u8, kk: u8 },
+    /// 5XY0: Skip if VX == VY.
+    SkipEqReg { x: u8, y: u8 },
+    /// 5XY2: Store VX..=VY at I, in descending order if X > Y.
+    StoreRange { x: u8, y: u8 },
+    /// 5XY3: Load VX..=VY from I, in descending order if X > Y.
+    LoadRange { x: u8, y: u8 },
+    /// 6XKK: VX = KK.
+    LoadImm { x: u8, kk: u8 },
+    /// 7XKK: VX += KK, without carry.
+    AddImm { x: u8, kk: u8 },
+    /// 8XY0: VX = VY.
+    Move { x: u8, y: u8 },
+    /// 8XY1: VX |= VY.
+    Or { x: u8, y: u8 },
+    /// 8XY2: VX &= VY.
+    And { x: u8, y: u8 },
+    /// 8XY3: VX ^= VY.
+    Xor { x: u8, y: u8 },
+    /// 8XY4: VX += VY, VF = carry.
+    Add { x: u8, y: u8 },
+    /// 8XY5: VX -= VY, VF = not borrow.
+    Sub { x: u8, y: u8 },
+    /// 8XY6: VX >>= 1, VF = shifted out bit.
+    ShiftRight { x: u8, y: u8 },
+    /// 8XY7: VX = VY - VX, VF = not borrow.
+    SubN { x: u8, y: u8 },
+    /// 8XYE: VX <<= 1, VF = shifted out bit.
+    ShiftLeft { x: u8, y: u8 },
+    /// 9XY0: Skip if VX != VY.
+    SkipNeReg { x: u8, y: u8 },
+    /// ANNN: I = NNN.
+    LoadI { nnn: u16 },
+    /// BNNN: Jump to NNN + V0.
+    JumpOffset { nnn: u16 },
+    /// CXKK: VX = random byte & KK.
+    Random { x: u8, kk: u8 },
+    /// DXYN: Draw an N-byte sprite from I at VX, VY. On SUPER-CHIP, DXY0
+    /// draws a 16x16 sprite.
+    Draw { x: u8, y: u8, n: u8 },
+    /// EX9E: Skip if the key in VX is pressed.
+    SkipKey { x: u8 },
+    /// EXA1: Skip if the key in VX isn't pressed.
+    SkipNotKey { x: u8 },
+    /// FX07: VX = delay timer.
+    LoadDelay { x: u8 },
+    /// FX0A: Wait for a key press and store it in VX.
+    WaitKey { x: u8 },
+    /// FX15: Delay timer = VX.
+    SetDelay { x: u8 },
+    /// FX18: Sound timer = VX.
+    SetSound { x: u8 },
+    /// FX1E: I += VX.
+    AddI { x: u8 },
+    /// FX29: I = address of the font sprite for VX.
+    LoadFont { x: u8 },
+    /// FX30: I = address of the large font sprite for VX.
+    LoadBigFont { x: u8 },
+    /// FX33: Store the BCD of VX at I, I+1, I+2.
+    StoreBcd { x: u8 },
+    /// FX55: Store V0..=VX at I.
+    StoreRegs { x: u8 },
+    /// FX65: Load V0..=VX from I.
+    LoadRegs { x: u8 },
+    /// FX75: Store V0..=VX in the RPL user flags.
+    StoreFlags { x: u8 },
+    /// FX85: Load V0..=VX from the RPL user flags.
+    LoadFlags { x: u8 },
+    /// F000 NNNN: I = NNNN, the 16-bit address in the following word.
+    LoadILong,
+    /// FN01: Select the bitplanes N that drawing, clearing and scrolling use.
+    Plane { n: u8 },
+    /// F002: Load the 16-byte audio pattern from I.
+    LoadAudio,
+    /// FX3A: Set the audio pattern playback pitch to VX.
+    SetPitch { x: u8 },
+}
+
+/// An opcode that doesn't correspond to any instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeError {
+    pub opcode: u16,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown opcode {:04X}", self.opcode)
+    }
+}
+
+impl error::Error for DecodeError {}
+
+impl Instruction {
+    pub fn decode(opcode: u16) -> Result<Self, DecodeError> {
+        use Instruction::*;
+
+        let x = ((opcode >> 8) & 0xf) as u8;
+        let y = ((opcode >> 4) & 0xf) as u8;
+        let n = (opcode & 0xf) as u8;
+        let kk = (opcode & 0xff) as u8;
+        let nnn = opcode & 0xfff;
+
+        let instruction = match (opcode >> 12, n) {
+            (0x0, _) => match opcode {
+                0x00e0 => Cls,
+                0x00ee => Ret,
+                0x00c0..=0x00cf => ScrollDown { n },
+                0x00d0..=0x00df => ScrollUp { n },
+                0x00fb => ScrollRight,
+                0x00fc => ScrollLeft,
+                0x00fd => Exit,
+                0x00fe => LowRes,
+                0x00ff => HighRes,
+                _ => Sys { nnn },
+            },
+            (0x1, _) => Jump { nnn },
+            (0x2, _) => Call { nnn },
+            (0x3, _) => SkipEqImm { x, kk },
+            (0x4, _) => SkipNeImm { x, kk },
+            (0x5, 0x0) => SkipEqReg { x, y },
+            (0x5, 0x2) => StoreRange { x, y },
+            (0x5, 0x3) => LoadRange { x, y },
+            (0x6, _) => LoadImm { x, kk },
+            (0x7, _) => AddImm { x, kk },
+            (0x8, 0x0) => Move { x, y },
+            (0x8, 0x1) => Or { x, y },
+            (0x8, 0x2) => And { x, y },
+            (0x8, 0x3) => Xor { x, y },
+            (0x8, 0x4) => Add { x, y },
+            (0x8, 0x5) => Sub { x, y },
+            (0x8, 0x6) => ShiftRight { x, y },
+            (0x8, 0x7) => SubN { x, y },
+            (0x8, 0xe) => ShiftLeft { x, y },
+            (0x9, 0x0) => SkipNeReg { x, y },
+            (0xa, _) => LoadI { nnn },
+            (0xb, _) => JumpOffset { nnn },
+            (0xc, _) => Random { x, kk },
+            (0xd, _) => Draw { x, y, n },
+            (0xe, _) => match kk {
+                0x9e => SkipKey { x },
+                0xa1 => SkipNotKey { x },
+                _ => return Err(DecodeError { opcode }),
+            },
+            (0xf, _) => match kk {
+                0x00 if x == 0 => LoadILong,
+                0x01 => Plane { n: x },
+                0x02 if x == 0 => LoadAudio,
+                0x3a => SetPitch { x },
+                0x07 => LoadDelay { x },
+                0x0a => WaitKey { x },
+                0x15 => SetDelay { x },
+                0x18 => SetSound { x },
+                0x1e => AddI { x },
+                0x29 => LoadFont { x },
+                0x30 => LoadBigFont { x },
+                0x33 => StoreBcd { x },
+                0x55 => StoreRegs { x },
+                0x65 => LoadRegs { x },
+                0x75 => StoreFlags { x },
+                0x85 => LoadFlags { x },
+                _ => return Err(DecodeError { opcode }),
+            },
+            _ => return Err(DecodeError { opcode }),
+        };
+        Ok(instruction)
+    }
+
+    pub fn encode(&self) -> u16 {
+        use Instruction::*;
+
+        fn xkk(base: u16, x: u8, kk: u8) -> u16 {
+            base | (x as u16 & 0xf) << 8 | kk as u16
+        }
+        fn xyn(base: u16, x: u8, y: u8, n: u8) -> u16 {
+            base | (x as u16 & 0xf) << 8 | (y as u16 & 0xf) << 4 | (n as u16 & 0xf)
+        }
+
+        match *self {
+            Sys { nnn } => nnn & 0xfff,
+            Cls => 0x00e0,
+            Ret => 0x00ee,
+            ScrollDown { n } => 0x00c0 | (n as u16 & 0xf),
+            ScrollUp { n } => 0x00d0 | (n as u16 & 0xf),
+            ScrollRight => 0x00fb,
+            ScrollLeft => 0x00fc,
+            Exit => 0x00fd,
+            LowRes => 0x00fe,
+            HighRes => 0x00ff,
+            Jump { nnn } => 0x1000 | nnn & 0xfff,
+            Call { nnn } => 0x2000 | nnn & 0xfff,
+            SkipEqImm { x, kk } => xkk(0x3000, x, kk),
+            SkipNeImm { x, kk } => xkk(0x4000, x, kk),
+            SkipEqReg { x, y } => xyn(0x5000, x, y, 0x0),
+            StoreRange { x, y } => xyn(0x5000, x, y, 0x2),
+            LoadRange { x, y } => xyn(0x5000, x, y, 0x3),
+            LoadImm { x, kk } => xkk(0x6000, x, kk),
+            AddImm { x, kk } => xkk(0x7000, x, kk),
+            Move { x, y } => xyn(0x8000, x, y, 0x0),
+            Or { x, y } => xyn(0x8000, x, y, 0x1),
+            And { x, y } => xyn(0x8000, x, y, 0x2),
+            Xor { x, y } => xyn(0x8000, x, y, 0x3),
+            Add { x, y } => xyn(0x8000, x, y, 0x4),
+            Sub { x, y } => xyn(0x8000, x, y, 0x5),
+            ShiftRight { x, y } => xyn(0x8000, x, y, 0x6),
+            SubN { x, y } => xyn(0x8000, x, y, 0x7),
+            ShiftLeft { x, y } => xyn(0x8000, x, y, 0xe),
+            SkipNeReg { x, y } => xyn(0x9000, x, y, 0x0),
+            LoadI { nnn } => 0xa000 | nnn & 0xfff,
+            JumpOffset { nnn } => 0xb000 | nnn & 0xfff,
+            Random { x, kk } => xkk(0xc000, x, kk),
+            Draw { x, y, n } => xyn(0xd000, x, y, n),
+            SkipKey { x } => xkk(0xe000, x, 0x9e),
+            SkipNotKey { x } => xkk(0xe000, x, 0xa1),
+            LoadDelay { x } => xkk(0xf000, x, 0x07),
+            WaitKey { x } => xkk(0xf000, x, 0x0a),
+            SetDelay { x } => xkk(0xf000, x, 0x15),
+            SetSound { x } => xkk(0xf000, x, 0x18),
+            AddI { x } => xkk(0xf000, x, 0x1e),
+            LoadFont { x } => xkk(0xf000, x, 0x29),
+            LoadBigFont { x } => xkk(0xf000, x, 0x30),
+            StoreBcd { x } => xkk(0xf000, x, 0x33),
+            StoreRegs { x } => xkk(0xf000, x, 0x55),
+            LoadRegs { x } => xkk(0xf000, x, 0x65),
+            StoreFlags { x } => xkk(0xf000, x, 0x75),
+            LoadFlags { x } => xkk(0xf000, x, 0x85),
+            LoadILong => 0xf000,
+            Plane { n } => xkk(0xf000, n, 0x01),
+            LoadAudio => 0xf002,
+            SetPitch { x } => xkk(0xf000, x, 0x3a),
+        }
+    }
+
+    /// The size in bytes, including any operand word that follows the opcode.
+    pub fn size(&self) -> usize {
+        match self {
+            Instruction::LoadILong => 4,
+            _ => 2,
+        }
+    }
+
+    /// The first instruction set that has this instruction.
+    pub fn instruction_set(&self) -> InstructionSet {
+        use Instruction::*;
+
+        match *self {
+            Exit | LowRes | HighRes | StoreFlags { .. } | LoadFlags { .. } => {
+                InstructionSet::SuperChip10
+            }
+            ScrollDown { .. } | ScrollRight | ScrollLeft | LoadBigFont { .. } => {
+                InstructionSet::SuperChip11
+            }
+            ScrollUp { .. }
+            | StoreRange { .. }
+            | LoadRange { .. }
+            | LoadILong
+            | Plane { .. }
+            | LoadAudio
+            | SetPitch { .. } => InstructionSet::XoChip,
+            _ => InstructionSet::Chip8,
+        }
+    }
+}
+
+/// Renders the instruction as a conventional mnemonic, e.g. `ADD V3, 0x10`.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Instruction::*;
+
+        match *self {
+            Sys { nnn } => write!(f, "SYS 0x{:03X}", nnn),
+            Cls => write!(f, "CLS"),
+            Ret => write!(f, "RET"),
+            ScrollDown { n } => write!(f, "SCD {}", n),
+            ScrollUp { n } => write!(f, "SCU {}", n),
+            ScrollRight => write!(f, "SCR"),
+            ScrollLeft => write!(f, "SCL"),
+            Exit => write!(f, "EXIT"),
+            LowRes => write!(f, "LOW"),
+            HighRes => write!(f, "HIGH"),
+            Jump { nnn } => write!(f, "JP 0x{:03X}", nnn),
+            Call { nnn } => write!(f, "CALL 0x{:03X}", nnn),
+            SkipEqImm { x, kk } => write!(f, "SE V{:X}, 0x{:02X}", x, kk),
+            SkipNeImm { x, kk } => write!(f, "SNE V{:X}, 0x{:02X}", x, kk),
+            SkipEqReg { x, y } => write!(f, "SE V{:X}, V{:X}", x, y),
+            StoreRange { x, y } => write!(f, "LD [I], V{:X}-V{:X}", x, y),
+            LoadRange { x, y } => write!(f, "LD V{:X}-V{:X}, [I]", x, y),
+            LoadImm { x, kk } => write!(f, "LD V{:X}, 0x{:02X}", x, kk),
+            AddImm { x, kk } => write!(f, "ADD V{:X}, 0x{:02X}", x, kk),
+            Move { x, y } => write!(f, "LD V{:X}, V{:X}", x, y),
+            Or { x, y } => write!(f, "OR V{:X}, V{:X}", x, y),
+            And { x, y } => write!(f, "AND V{:X}, V{:X}", x, y),
+            Xor { x, y } => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Add { x, y } => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Sub { x, y } => write!(f, "SUB V{:X}, V{:X}", x, y),
+            ShiftRight { x, y } => write!(f, "SHR V{:X}, V{:X}", x, y),
+            SubN { x, y } => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            ShiftLeft { x, y } => write!(f, "SHL V{:X}, V{:X}", x, y),
+            SkipNeReg { x, y } => write!(f, "SNE V{:X}, V{:X}", x, y),
+            LoadI { nnn } => write!(f, "LD I, 0x{:03X}", nnn),
+            JumpOffset { nnn } => write!(f, "JP V0, 0x{:03X}", nnn),
+            Random { x, kk } => write!(f, "RND V{:X}, 0x{:02X}", x, kk),
+            Draw { x, y, n } => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            SkipKey { x } => write!(f, "SKP V{:X}", x),
+            SkipNotKey { x } => write!(f, "SKNP V{:X}", x),
+            LoadDelay { x } => write!(f, "LD V{:X}, DT", x),
+            WaitKey { x } => write!(f, "LD V{:X}, K", x),
+            SetDelay { x } => write!(f, "LD DT, V{:X}", x),
+            SetSound { x } => write!(f, "LD ST, V{:X}", x),
+            AddI { x } => write!(f, "ADD I, V{:X}", x),
+            LoadFont { x } => write!(f, "LD F, V{:X}", x),
+            LoadBigFont { x } => write!(f, "LD HF, V{:X}", x),
+            StoreBcd { x } => write!(f, "LD B, V{:X}", x),
+            StoreRegs { x } => write!(f, "LD [I], V{:X}", x),
+            LoadRegs { x } => write!(f, "LD V{:X}, [I]", x),
+            StoreFlags { x } => write!(f, "LD R, V{:X}", x),
+            LoadFlags { x } => write!(f, "LD V{:X}, R", x),
+            LoadILong => write!(f, "LD I, LONG"),
+            Plane { n } => write!(f, "PLANE {}", n),
+            LoadAudio => write!(f, "LD AUDIO, [I]"),
+            SetPitch { x } => write!(f, "LD PITCH, V{:X}", x),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DecodeError, Instruction};
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(
+            Instruction::decode(0x7a05),
+            Ok(Instruction::AddImm { x: 0xa, kk: 0x05 })
+        );
+        assert_eq!(
+            Instruction::decode(0xd12f),
+            Ok(Instruction::Draw { x: 1, y: 2, n: 0xf })
+        );
+        assert_eq!(
+            Instruction::decode(0x00c4),
+            Ok(Instruction::ScrollDown { n: 4 })
+        );
+        assert_eq!(
+            Instruction::decode(0x5123),
+            Ok(Instruction::LoadRange { x: 1, y: 2 })
+        );
+        assert_eq!(Instruction::decode(0xf000), Ok(Instruction::LoadILong));
+        assert_eq!(Instruction::decode(0xf201), Ok(Instruction::Plane { n: 2 }));
+        assert_eq!(
+            Instruction::decode(0xf100),
+            Err(DecodeError { opcode: 0xf100 })
+        );
+        assert_eq!(
+            Instruction::decode(0x5121),
+            Err(DecodeError { opcode: 0x5121 })
+        );
+        assert_eq!(
+            Instruction::decode(0xf0ff),
+            Err(DecodeError { opcode: 0xf0ff })
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for opcode in 0..=u16::MAX {
+            if let Ok(instruction) = Instruction::decode(opcode) {
+                assert_eq!(instruction.encode(), opcode, "{}", instruction);
+            }
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            Instruction::LoadImm { x: 3, kk: 0x10 }.to_string(),
+            "LD V3, 0x10"
+        );
+        assert_eq!(Instruction::StoreRegs { x: 0xf }.to_string(), "LD [I], VF");
+    }
+}
@@ -0,0 +1,42 @@
+pub mod assembler;
+pub mod dap;
+pub mod debugger;
+pub mod decompiler;
+pub mod disassembler;
+pub mod display;
+pub mod emulator;
+pub mod error;
+pub mod frontend;
+pub mod gdb;
+pub mod headless;
+pub mod image;
+pub mod instruction;
+pub mod monitor;
+pub mod movie;
+pub mod panels;
+pub mod platform;
+pub mod processor;
+pub mod quirks;
+pub mod rewind;
+pub mod rng;
+pub mod savestate;
+pub mod scheduler;
+pub mod testsuite;
+
+pub use dap::DapServer;
+pub use debugger::{Condition, DebugFrontend, Debugger, SourceMap};
+pub use display::Framebuffer;
+pub use emulator::Emulator;
+pub use error::{EmulatorError, ErrorPolicies, ErrorPolicy};
+pub use gdb::GdbStub;
+pub use image::ImageFormat;
+pub use instruction::{DecodeError, Instruction, InstructionSet};
+pub use monitor::Monitor;
+pub use movie::{Movie, MovieError, MovieInput};
+pub use platform::Platform;
+pub use processor::{MemoryAccess, Processor, Timers, Tone};
+pub use quirks::{MemoryQuirk, Quirks};
+pub use rewind::Rewind;
+pub use rng::{Rng, RngMode};
+pub use savestate::SaveStateError;
+pub use scheduler::Scheduler;
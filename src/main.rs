@@ -1,11 +1,285 @@
+mod cli;
+#[cfg(feature = "sdl")]
 mod drivers;
-mod processor;
+
+use cli::{Command, Dap, Headless, Options, USAGE};
+use std::io::{self, Write};
+use std::path::Path;
+use std::{fs, process};
+use virtual_machine::assembler::{self, octo, AssembleError, Assembly};
+use virtual_machine::decompiler;
+use virtual_machine::disassembler::{Disassembly, Syntax};
+use virtual_machine::frontend::{AudioSink, InputSource, NullAudio, NullVideo, VideoSink};
+use virtual_machine::headless::{self, KeyScript, Stop};
+use virtual_machine::testsuite;
+use virtual_machine::{
+    DapServer, DebugFrontend, Emulator, EmulatorError, GdbStub, ImageFormat, Monitor, Movie,
+    Platform, Processor, SourceMap,
+};
+
+type Frontends = (Box<dyn VideoSink>, Box<dyn AudioSink>, Box<dyn InputSource>);
 
 fn main() {
-    // TODO: Handle malformed arguments
-    let args: Vec<String> = std::env::args().collect();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = match Command::parse(&args) {
+        Ok(Some(command)) => command,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let result = match command {
+        Command::Run(options) => run(*options),
+        Command::TestSuite { dir, update } => test_suite(Path::new(&dir), update),
+        Command::Disasm { rom, syntax } => disasm(&rom, syntax),
+        Command::Asm {
+            source,
+            output,
+            source_map,
+        } => asm(Path::new(&source), output, source_map),
+        Command::Decompile { rom } => decompile(&rom),
+    };
+    match result {
+        Ok(0) => {}
+        Ok(status) => process::exit(status),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Returns the exit status.
+fn run(mut options: Options) -> Result<i32, EmulatorError> {
+    // Octo source is compiled first, keeping its source map and debugging
+    let path = Path::new(&options.rom);
+    let (rom, assembly) = if path.extension().is_some_and(|e| e == "8o") {
+        match fs::read_to_string(path).map(|source| octo::compile(&source, path)) {
+            Ok(Ok(assembly)) => (assembly.rom.clone(), Some(assembly)),
+            Ok(Err(e)) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("Failed to load {}: {}", options.rom, e);
+                process::exit(1);
+            }
+        }
+    } else {
+        match fs::read(path) {
+            Ok(rom) => (rom, None),
+            Err(e) => {
+                eprintln!("Failed to load {}: {}", options.rom, e);
+                process::exit(1);
+            }
+        }
+    };
+
+    let playback = match &options.play {
+        Some(path) => Some(Movie::from_bytes(&fs::read(path)?)?),
+        None => None,
+    };
+    let (mut vm, scheduler) = match &playback {
+        Some(movie) => (movie.processor(&rom)?, movie.scheduler()),
+        None => {
+            let mut vm = Processor::with_platform(options.platform);
+            vm.set_rng(options.rng);
+            if let Err(e) = vm.load_rom(&rom) {
+                eprintln!("Failed to load {}: {}", options.rom, e);
+                process::exit(1);
+            }
+            if let Some(path) = &options.state {
+                vm.load_state(&fs::read(path)?)?;
+            }
+            (vm, options.scheduler)
+        }
+    };
+    vm.set_error_policies(options.policies);
+    let recording = options
+        .record
+        .as_ref()
+        .map(|_| Movie::new(&vm, &scheduler, options.state.is_some()));
+
+    if let (Some(headless), Some(movie)) = (&mut options.headless, &playback) {
+        headless.limits.frames.get_or_insert(movie.frames);
+    }
+    let (video, audio, input): Frontends = match &options.headless {
+        Some(headless) => (
+            Box::new(NullVideo),
+            Box::new(NullAudio),
+            Box::new(KeyScript::new(headless.keys.clone())),
+        ),
+        None => window_frontends(options.debug_panels),
+    };
+    let mut emulator = Emulator::new(vm, video, audio, input)
+        .with_scheduler(scheduler)
+        .with_save_slots(&options.rom);
+    // Nobody can hold the rewind key without a window
+    if let (Some(rewind), None) = (options.rewind, &options.headless) {
+        emulator = emulator.with_rewind(rewind);
+    }
+    if let Some(movie) = playback {
+        emulator = emulator.with_playback(movie);
+    }
+    if let Some(movie) = recording {
+        emulator = emulator.with_recording(movie);
+    }
+    // `:breakpoint` and `:monitor` bring up the monitor when nothing else
+    // is debugging and there's a terminal to use it from
+    let octo_debugging = assembly
+        .as_ref()
+        .is_some_and(|a| !a.breakpoints.is_empty() || !a.monitors.is_empty());
+    if options.monitor
+        || octo_debugging
+            && options.headless.is_none()
+            && options.gdb.is_none()
+            && options.dap.is_none()
+    {
+        let mut monitor = Monitor::stdio();
+        if options.monitor {
+            monitor.pause();
+        }
+        if let Some(assembly) = &assembly {
+            for (addr, name) in &assembly.breakpoints {
+                monitor.add_breakpoint(*addr, name);
+            }
+            for (name, addr, len) in &assembly.monitors {
+                monitor.add_display(name, *addr, *len);
+            }
+        }
+        emulator = emulator.with_monitor(monitor);
+    }
+    if let Some(port) = options.gdb {
+        emulator = emulator.with_monitor(GdbStub::listen(port)?);
+    }
+    if let Some(dap) = &options.dap {
+        let server = match dap {
+            Dap::Stdio => DapServer::stdio(),
+            Dap::Port(port) => DapServer::listen(*port)?,
+        };
+        let source_map = match &options.source_map {
+            Some(path) => fs::read_to_string(path)?
+                .parse::<SourceMap>()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            None => assembly
+                .map(|assembly| assembly.source_map)
+                .unwrap_or_default(),
+        };
+        emulator = emulator.with_monitor(server.with_source_map(source_map));
+    }
+
+    let result = match &options.headless {
+        Some(headless) => run_headless(&mut emulator, headless),
+        None => emulator.run(|notice| eprintln!("{}", notice)).map(|()| 0),
+    };
+    if let (Some(path), Some(movie)) = (&options.record, emulator.take_movie()) {
+        fs::write(path, movie.to_bytes())?;
+        eprintln!(
+            "recorded {} frames ({} lag frames) to {}",
+            movie.frames,
+            emulator.lag_frames(),
+            path
+        );
+    }
+    result
+}
+
+fn test_suite(dir: &Path, update: bool) -> Result<i32, EmulatorError> {
+    let report = testsuite::run(dir, update)?;
+    println!("{}", report);
+    Ok(if report.passed() { 0 } else { 1 })
+}
+
+fn disasm(path: &str, syntax: Syntax) -> Result<i32, EmulatorError> {
+    let rom = fs::read(path)?;
+    let disassembly = Disassembly::new(&rom, Platform::default().program_start);
+    print!("{}", disassembly.listing(syntax));
+    let unknown = disassembly.unknown();
+    if !unknown.is_empty() {
+        let addrs: Vec<String> = unknown
+            .iter()
+            .map(|addr| format!("0x{:03X}", addr))
+            .collect();
+        eprintln!("unknown opcodes at {}", addrs.join(", "));
+    }
+    Ok(0)
+}
+
+fn decompile(path: &str) -> Result<i32, EmulatorError> {
+    let rom = fs::read(path)?;
+    let disassembly = Disassembly::new(&rom, Platform::default().program_start);
+    print!("{}", decompiler::decompile(&disassembly));
+    Ok(0)
+}
+
+fn asm(
+    path: &Path,
+    output: Option<String>,
+    source_map: Option<String>,
+) -> Result<i32, EmulatorError> {
+    let source = fs::read_to_string(path)?;
+    let compile: fn(&str, &Path) -> Result<Assembly, AssembleError> =
+        if path.extension().is_some_and(|e| e == "8o") {
+            octo::compile
+        } else {
+            assembler::assemble
+        };
+    let assembly = match compile(&source, path) {
+        Ok(assembly) => assembly,
+        Err(e) => {
+            eprintln!("{}", e);
+            return Ok(1);
+        }
+    };
+    let output = output.unwrap_or_else(|| path.with_extension("ch8").display().to_string());
+    fs::write(&output, &assembly.rom)?;
+    if let Some(map) = source_map {
+        fs::write(map, assembly.source_map.to_string())?;
+    }
+    eprintln!("wrote {} bytes to {}", assembly.rom.len(), output);
+    Ok(0)
+}
+
+fn run_headless(emulator: &mut Emulator, options: &Headless) -> Result<i32, EmulatorError> {
+    let stop = headless::run(emulator, &options.limits, |notice| eprintln!("{}", notice))?;
+    eprintln!("{} after {} frames", stop, emulator.frame());
+
+    if let Some(path) = &options.output {
+        let format = options
+            .format
+            .unwrap_or_else(|| ImageFormat::from_path(Path::new(path)));
+        let image = format.encode(emulator.processor().framebuffer());
+        if path == "-" {
+            io::stdout().write_all(&image)?;
+        } else {
+            fs::write(path, image)?;
+        }
+    }
+    Ok(if stop == Stop::Timeout { 3 } else { 0 })
+}
+
+#[cfg(feature = "sdl")]
+fn window_frontends(debug_panels: bool) -> Frontends {
+    use drivers::{AudioDriver, DisplayDriver, InputDriver};
+
+    let sdl_context = sdl2::init().unwrap();
+    let display_driver = DisplayDriver::new(&sdl_context, debug_panels);
+    let audio_driver = AudioDriver::new(&sdl_context, 480.0, 0.25).unwrap();
+    let input_driver = InputDriver::new(&sdl_context);
+    (
+        Box::new(display_driver),
+        Box::new(audio_driver),
+        Box::new(input_driver),
+    )
+}
 
-    let mut vm = processor::Processor::new();
-    vm.load_program(&args[1]);
-    vm.run_program()
+#[cfg(not(feature = "sdl"))]
+fn window_frontends(_debug_panels: bool) -> Frontends {
+    eprintln!("This build has no window frontend; rebuild with the `sdl` feature.");
+    process::exit(1);
 }
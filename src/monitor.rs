@@ -0,0 +1,537 @@
+// A machine language monitor: a console for pausing the processor between
+// instructions, stepping through the program and inspecting or changing
+// the machine. It hooks into `Processor::run_frame_with`, so while it waits
+// at the prompt the frame is simply suspended. Breakpoints and watchpoints
+// are kept by a `Debugger`.
+use crate::debugger::{
+    self, Condition, DebugFrontend, Debugger, OpcodePattern, Point, Register, Trigger,
+};
+use crate::instruction::Instruction;
+use crate::processor::Processor;
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+pub const HELP: &str = "\
+Numbers are hexadecimal, with or without a 0x prefix. Breakpoints and
+watchpoints stop only while COND holds, e.g. `if v3 == 0x10 && i > 0x300`.
+  b, break [ADDR [if COND]]      Set a breakpoint at ADDR, or list every point
+  bo PATTERN [if COND]           Break on opcodes matching PATTERN, e.g. DXYN
+  w, watch ADDR [LEN] [if COND]  Stop after writes to LEN bytes from ADDR
+  w, watch REG [if COND]         Stop after V0-VF, I, PC, DT, ST or SP changes
+  rw, rwatch ADDR [LEN] [if COND]
+                                 Stop after reads from LEN bytes from ADDR
+  ignore ID N                    Pass over the next N hits of point ID
+  d, delete ID                   Remove point ID
+  s, step [N]                    Run N instructions (default: 1)
+  n, next                        Step, running a 2NNN call to the instruction after it
+  o, out                         Run until the current subroutine returns with 00EE
+  c, continue                    Run until the next breakpoint or watchpoint
+  r, regs                        Show the registers, I, timers and stack
+  x, dump [ADDR] [LEN]           Hex dump LEN bytes from ADDR (default: I, 0x40)
+  e, edit ADDR BYTE...           Write bytes to memory starting at ADDR
+  l, list [ADDR] [N]             Disassemble N instructions from ADDR (default: around pc)
+  set REG VALUE                  Set V0-VF, I, PC, DT, ST or SP
+  q, quit                        Stop the emulator
+  h, help                        Print this message";
+
+/// When the monitor takes over again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Only at breakpoints and watchpoints.
+    Run,
+    /// After `n` more instructions.
+    Step { n: u32 },
+    /// Once `pc` comes back with no more than `depth` subroutines running.
+    Over { pc: usize, depth: usize },
+    /// Once fewer than `depth` subroutines are running.
+    Out { depth: usize },
+}
+
+/// What the prompt does after a command.
+#[derive(Debug, PartialEq, Eq)]
+enum Reply {
+    Output(String),
+    Resume(Mode),
+    Quit,
+}
+
+pub struct Monitor {
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    debugger: Debugger,
+    mode: Mode,
+    // Names for breakpoints, by id
+    names: HashMap<usize, String>,
+    // Memory shown at every stop: a name, an address and a length
+    displays: Vec<(String, usize, usize)>,
+}
+
+impl Monitor {
+    /// A monitor that reads commands from `input` and prints to `output`.
+    /// It starts out running.
+    pub fn new(input: Box<dyn BufRead>, output: Box<dyn Write>) -> Self {
+        Self {
+            input,
+            output,
+            debugger: Debugger::new(),
+            mode: Mode::Run,
+            names: HashMap::new(),
+            displays: Vec::new(),
+        }
+    }
+
+    /// A monitor on the terminal.
+    pub fn stdio() -> Self {
+        Self::new(Box::new(io::stdin().lock()), Box::new(io::stdout()))
+    }
+
+    pub fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+
+    pub fn debugger_mut(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+
+    /// Stop at `addr`, printing `name` with the hit. Returns the point id.
+    pub fn add_breakpoint(&mut self, addr: usize, name: &str) -> usize {
+        let id = self.debugger.add(Point::new(Trigger::Pc(addr)));
+        self.names.insert(id, name.to_string());
+        id
+    }
+
+    /// Dump `len` bytes from `addr`, labelled `name`, whenever it stops.
+    pub fn add_display(&mut self, name: &str, addr: usize, len: usize) {
+        self.displays.push((name.to_string(), addr, len));
+    }
+
+    fn print(&mut self, text: &str) {
+        if !text.is_empty() {
+            let _ = writeln!(self.output, "{}", text);
+        }
+    }
+
+    fn execute(&mut self, processor: &mut Processor, line: &str) -> Result<Reply, String> {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Ok(Reply::Output(String::new()));
+        };
+        let mut args: Vec<&str> = words.collect();
+        let condition = match args.iter().position(|&word| word == "if") {
+            Some(n) => Some(args.split_off(n)[1..].join(" ").parse::<Condition>()?),
+            None => None,
+        };
+        let arg = |n: usize| args.get(n).copied().map(number).transpose();
+        let pc = processor.pc();
+
+        let reply = match command {
+            "b" | "break" => match arg(0)? {
+                Some(addr) => self.add(Trigger::Pc(addr), condition),
+                None if self.debugger.is_empty() => Reply::Output("no breakpoints".into()),
+                None => Reply::Output(
+                    self.debugger
+                        .points()
+                        .map(|(id, point)| format!("#{}: {}", id, point))
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                ),
+            },
+            "bo" => {
+                let pattern: OpcodePattern = args.first().ok_or("bo expects a pattern")?.parse()?;
+                self.add(Trigger::Opcode(pattern), condition)
+            }
+            "w" | "watch" | "rw" | "rwatch" => {
+                let target = *args.first().ok_or("watch expects an address or register")?;
+                if let Ok(register) = target.parse::<Register>() {
+                    if command.starts_with('r') {
+                        return Err("rwatch expects an address".into());
+                    }
+                    return Ok(self.add(Trigger::Register(register), condition));
+                }
+                let addr = number(target)?;
+                let end = addr
+                    .checked_add(arg(1)?.unwrap_or(1).max(1))
+                    .ok_or("watch goes past the end of memory")?;
+                let addrs = addr..end;
+                let trigger = if command.starts_with('r') {
+                    Trigger::Read(addrs)
+                } else {
+                    Trigger::Write(addrs)
+                };
+                self.add(trigger, condition)
+            }
+            "ignore" => {
+                let (Some(id), Some(n)) = (args.first(), arg(1)?) else {
+                    return Err("ignore expects a point and a count".into());
+                };
+                let id = id_number(id)?;
+                let point = self
+                    .debugger
+                    .get_mut(id)
+                    .ok_or(format!("no point #{}", id))?;
+                point.ignore = n as u64;
+                Reply::Output(format!("#{}: {}", id, point))
+            }
+            "d" | "delete" => {
+                let id = id_number(args.first().ok_or("delete expects a point")?)?;
+                self.debugger
+                    .remove(id)
+                    .ok_or(format!("no point #{}", id))?;
+                Reply::Output(String::new())
+            }
+            "s" | "step" => {
+                let n = arg(0)?.unwrap_or(1).max(1);
+                Reply::Resume(Mode::Step {
+                    n: n.min(u32::MAX as usize) as u32 - 1,
+                })
+            }
+            "n" | "next" => match decode(processor, pc) {
+                Some(Instruction::Call { .. }) => Reply::Resume(Mode::Over {
+                    pc: pc + 2,
+                    depth: processor.stack().len(),
+                }),
+                _ => Reply::Resume(Mode::Step { n: 0 }),
+            },
+            "o" | "out" => {
+                let depth = processor.stack().len();
+                if depth == 0 {
+                    return Err("not in a subroutine".into());
+                }
+                Reply::Resume(Mode::Out { depth })
+            }
+            "c" | "continue" => Reply::Resume(Mode::Run),
+            "r" | "regs" => Reply::Output(registers(processor)),
+            "x" | "dump" => {
+                let addr = arg(0)?.unwrap_or(processor.i() as usize);
+                let len = arg(1)?.unwrap_or(0x40);
+                if addr >= processor.memory().len() {
+                    return Err("dump starts past the end of memory".into());
+                }
+                Reply::Output(dump(processor.memory(), addr, len))
+            }
+            "e" | "edit" => {
+                let addr = arg(0)?.ok_or("edit expects an address")?;
+                let bytes = args[1..]
+                    .iter()
+                    .map(|s| byte(s))
+                    .collect::<Result<Vec<u8>, String>>()?;
+                let span = addr
+                    .checked_add(bytes.len())
+                    .and_then(|end| processor.memory_mut().get_mut(addr..end))
+                    .ok_or("edit goes past the end of memory")?;
+                span.copy_from_slice(&bytes);
+                Reply::Output(String::new())
+            }
+            "l" | "list" => {
+                // Start a few instructions back so pc has some context
+                let addr = arg(0)?.unwrap_or(pc.saturating_sub(6));
+                let n = arg(1)?.unwrap_or(10);
+                Reply::Output(disassemble(processor, addr, n))
+            }
+            "set" => {
+                let (Some(&name), Some(value)) = (args.first(), arg(1)?) else {
+                    return Err("set expects a register and a value".into());
+                };
+                name.parse::<Register>()?.write(processor, value)?;
+                Reply::Output(String::new())
+            }
+            "q" | "quit" => Reply::Quit,
+            "h" | "help" => Reply::Output(HELP.into()),
+            other => return Err(format!("unknown command {} (try help)", other)),
+        };
+        Ok(reply)
+    }
+
+    fn add(&mut self, trigger: Trigger, condition: Option<Condition>) -> Reply {
+        let mut point = Point::new(trigger);
+        point.condition = condition;
+        let text = point.to_string();
+        let id = self.debugger.add(point);
+        Reply::Output(format!("#{}: {}", id, text))
+    }
+}
+
+impl DebugFrontend for Monitor {
+    /// Prompts for commands if the processor should stop here.
+    fn before_step(&mut self, processor: &mut Processor) -> bool {
+        let pc = processor.pc();
+        let depth = processor.stack().len();
+        let hits = self.debugger.check(processor);
+        let breakpoint = !hits.is_empty();
+        let stop = match &mut self.mode {
+            Mode::Run => breakpoint,
+            Mode::Step { n: 0 } => true,
+            Mode::Step { n } => {
+                *n -= 1;
+                false
+            }
+            Mode::Over {
+                pc: target,
+                depth: max,
+            } => breakpoint || pc == *target && depth <= *max,
+            Mode::Out { depth: max } => breakpoint || depth < *max,
+        };
+        if !stop {
+            return true;
+        }
+
+        for hit in hits {
+            let text = match self.names.get(&hit.id) {
+                Some(name) => format!("{} ({})", hit, name),
+                None => hit.to_string(),
+            };
+            self.print(&text);
+        }
+        for i in 0..self.displays.len() {
+            let (name, addr, len) = &self.displays[i];
+            let text = format!("{} {}", name, dump(processor.memory(), *addr, *len));
+            self.print(&text);
+        }
+        self.print(&disassemble(processor, pc, 1));
+        loop {
+            if write!(self.output, "> ").and(self.output.flush()).is_err() {
+                return false;
+            }
+            let mut line = String::new();
+            match self.input.read_line(&mut line) {
+                // Nobody is left to type commands
+                Ok(0) | Err(_) => return false,
+                Ok(_) => {}
+            }
+            match self.execute(processor, &line) {
+                Ok(Reply::Output(text)) => self.print(&text),
+                Ok(Reply::Resume(mode)) => {
+                    self.mode = mode;
+                    self.debugger.resume_from(processor.pc());
+                    return true;
+                }
+                Ok(Reply::Quit) => return false,
+                Err(e) => self.print(&format!("error: {}", e)),
+            }
+        }
+    }
+
+    fn pause(&mut self) {
+        self.mode = Mode::Step { n: 0 };
+    }
+}
+
+fn number(s: &str) -> Result<usize, String> {
+    let digits = s.strip_prefix("0x").unwrap_or(s);
+    usize::from_str_radix(digits, 16).map_err(|_| format!("invalid number {}", s))
+}
+
+/// Point ids are decimal, and may be written as they're listed, like `#2`.
+fn id_number(s: &str) -> Result<usize, String> {
+    let digits = s.strip_prefix('#').unwrap_or(s);
+    digits.parse().map_err(|_| format!("invalid point {}", s))
+}
+
+fn byte(s: &str) -> Result<u8, String> {
+    u8::try_from(number(s)?).map_err(|_| format!("{} doesn't fit in a byte", s))
+}
+
+fn decode(processor: &Processor, addr: usize) -> Option<Instruction> {
+    let bytes = processor.memory().get(addr..addr + 2)?;
+    Instruction::decode(u16::from_be_bytes([bytes[0], bytes[1]])).ok()
+}
+
+fn registers(processor: &Processor) -> String {
+    let timers = processor.timers();
+    let mut out = format!(
+        "PC 0x{:03X}  I 0x{:03X}  DT {:02X}  ST {:02X}",
+        processor.pc(),
+        processor.i(),
+        timers.delay,
+        timers.sound
+    );
+    for (x, v) in processor.registers().iter().enumerate() {
+        out += if x % 8 == 0 { "\n" } else { "  " };
+        out += &format!("V{:X} {:02X}", x, v);
+    }
+    out += "\nstack:";
+    if processor.stack().is_empty() {
+        out += " empty";
+    }
+    for addr in processor.stack() {
+        out += &format!(" 0x{:03X}", addr);
+    }
+    out
+}
+
+fn dump(memory: &[u8], addr: usize, len: usize) -> String {
+    let end = addr.saturating_add(len).min(memory.len());
+    let lines: Vec<String> = (addr..end)
+        .step_by(16)
+        .map(|line| {
+            let bytes: Vec<String> = memory[line..(line + 16).min(end)]
+                .iter()
+                .map(|b| format!("{:02X}", b))
+                .collect();
+            format!("0x{:03X}: {}", line, bytes.join(" "))
+        })
+        .collect();
+    lines.join("\n")
+}
+
+/// `n` instructions from `addr`, marking `pc` with `>`.
+fn disassemble(processor: &Processor, mut addr: usize, n: usize) -> String {
+    let memory = processor.memory();
+    let mut lines = Vec::new();
+    for _ in 0..n {
+        let Some(bytes) = addr.checked_add(2).and_then(|end| memory.get(addr..end)) else {
+            break;
+        };
+        let opcode = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let (text, size) = debugger::disassemble_at(memory, addr).unwrap();
+        let marker = if addr == processor.pc() { '>' } else { ' ' };
+        lines.push(format!(
+            "{} 0x{:03X}  {:04X}  {}",
+            marker, addr, opcode, text
+        ));
+        addr += size;
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Mode, Monitor, Reply};
+    use crate::debugger::DebugFrontend;
+    use crate::processor::Processor;
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+
+    fn monitor() -> Monitor {
+        Monitor::new(Box::new(io::empty()), Box::new(io::sink()))
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut vm = Processor::new();
+        // CALL 0x206; JP 0x202; LD V0, 1; RET
+        vm.load_rom(&[0x22, 0x06, 0x12, 0x02, 0x00, 0x00, 0x60, 0x01, 0x00, 0xee])
+            .unwrap();
+        let mut m = monitor();
+
+        m.execute(&mut vm, "set v3 2a").unwrap();
+        m.execute(&mut vm, "set I 300").unwrap();
+        assert_eq!(vm.registers()[3], 0x2a);
+        assert_eq!(vm.i(), 0x300);
+        assert!(m.execute(&mut vm, "set v3 100").is_err());
+        assert!(m.execute(&mut vm, "set vg 1").is_err());
+
+        // Addresses near the top of the address space don't overflow
+        let max = "ffffffffffffffff";
+        assert!(m.execute(&mut vm, &format!("x {} 2", max)).is_err());
+        assert!(m.execute(&mut vm, &format!("e {} 1 2", max)).is_err());
+        assert!(m.execute(&mut vm, &format!("w {} 2", max)).is_err());
+        assert_eq!(
+            m.execute(&mut vm, &format!("x fff {}", max)).unwrap(),
+            Reply::Output("0xFFF: 00".into())
+        );
+        assert_eq!(
+            m.execute(&mut vm, &format!("l {}", max)).unwrap(),
+            Reply::Output(String::new())
+        );
+        assert_eq!(
+            m.execute(&mut vm, "s 100000000").unwrap(),
+            Reply::Resume(Mode::Step { n: u32::MAX - 1 })
+        );
+
+        m.execute(&mut vm, "e 300 de ad").unwrap();
+        assert_eq!(
+            m.execute(&mut vm, "x 300 2").unwrap(),
+            Reply::Output("0x300: DE AD".into())
+        );
+
+        m.execute(&mut vm, "b 0x206 if v3 == 0x2a").unwrap();
+        m.execute(&mut vm, "bo dxyn").unwrap();
+        m.execute(&mut vm, "w v3").unwrap();
+        m.execute(&mut vm, "rw 300 2").unwrap();
+        m.execute(&mut vm, "ignore #2 3").unwrap();
+        m.execute(&mut vm, "d 3").unwrap();
+        assert_eq!(
+            m.execute(&mut vm, "b").unwrap(),
+            Reply::Output(
+                "#1: breakpoint at 0x206 if v3 == 0x2a, hit 0 times\n\
+                 #2: breakpoint on D___, hit 0 times, ignoring 3 more\n\
+                 #4: watchpoint on reads from 0x300-0x301, hit 0 times"
+                    .into()
+            )
+        );
+        assert!(m.execute(&mut vm, "b 206 if v3 =").is_err());
+        assert!(m.execute(&mut vm, "rw v3").is_err());
+        assert!(m.execute(&mut vm, "d 3").is_err());
+        assert_eq!(
+            m.execute(&mut vm, "n").unwrap(),
+            Reply::Resume(Mode::Over {
+                pc: 0x202,
+                depth: 0
+            })
+        );
+        assert!(m.execute(&mut vm, "o").is_err());
+        assert_eq!(m.execute(&mut vm, "quit").unwrap(), Reply::Quit);
+        assert!(m.execute(&mut vm, "frobnicate").is_err());
+    }
+
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Run `vm` under a paused monitor fed `input`, returning where it stopped.
+    fn stops(vm: &mut Processor, input: &'static str) -> Vec<String> {
+        let output = Shared::default();
+        let mut m = Monitor::new(Box::new(io::Cursor::new(input)), Box::new(output.clone()));
+        m.pause();
+        while vm.run_frame_with(100, |vm| m.before_step(vm)).unwrap() {}
+        let text = String::from_utf8(output.0.take()).unwrap();
+        text.match_indices("> 0x")
+            .map(|(i, _)| text[i + 2..i + 7].to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_stepping() {
+        let mut vm = Processor::new();
+        // CALL 0x206; JP 0x202; LD V0, 1; RET
+        vm.load_rom(&[0x22, 0x06, 0x12, 0x02, 0x00, 0x00, 0x60, 0x01, 0x00, 0xee])
+            .unwrap();
+        let input = "b 208\nc\no\nq\n";
+        assert_eq!(stops(&mut vm, input), ["0x200", "0x208", "0x202"]);
+
+        vm.reset();
+        assert_eq!(stops(&mut vm, "s\ns\nq\n"), ["0x200", "0x206", "0x208"]);
+        vm.reset();
+        assert_eq!(stops(&mut vm, "n\nq\n"), ["0x200", "0x202"]);
+        assert_eq!(vm.registers()[0], 1);
+
+        // The watchpoint stops after the write, with pc at the RET
+        vm.reset();
+        assert_eq!(stops(&mut vm, "w v0\nc\nq\n"), ["0x200", "0x208"]);
+
+        // Named breakpoints and displays, as compiled Octo source asks for
+        vm.reset();
+        let output = Shared::default();
+        let input = io::Cursor::new("q\n");
+        let mut m = Monitor::new(Box::new(input), Box::new(output.clone()));
+        m.add_breakpoint(0x206, "sub");
+        m.add_display("code", 0x200, 2);
+        while vm.run_frame_with(100, |vm| m.before_step(vm)).unwrap() {}
+        assert_eq!(
+            String::from_utf8(output.0.take()).unwrap(),
+            "#1: breakpoint at 0x206 (6001) (sub)\ncode 0x200: 22 06\n\
+             > 0x206  6001  LD V0, 0x01\n> "
+        );
+    }
+}
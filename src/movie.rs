@@ -0,0 +1,230 @@
+// Input movies: everything needed to replay a run exactly. A movie holds
+// the machine configuration and optionally a save state to start from,
+// followed by every keypad change and the frame it happened after. Files
+// use the same encoding as save states.
+use crate::error::EmulatorError;
+use crate::platform::Platform;
+use crate::processor::Processor;
+use crate::quirks::Quirks;
+use crate::rng::{Rng, RngMode};
+use crate::savestate::{self, Reader, SaveStateError, Writer};
+use crate::scheduler::Scheduler;
+use std::{error, fmt};
+
+pub const MAGIC: [u8; 4] = *b"C8MV";
+
+/// Bumped whenever the layout changes. Movies in other versions are rejected.
+pub const FORMAT_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MovieError {
+    NotAMovie,
+    UnsupportedVersion(u16),
+    RomMismatch,
+    UnknownPlatform(String),
+    Corrupt,
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::NotAMovie => write!(f, "not a movie"),
+            MovieError::UnsupportedVersion(version) => write!(
+                f,
+                "movie format version {} isn't supported (expected {})",
+                version, FORMAT_VERSION
+            ),
+            MovieError::RomMismatch => write!(f, "movie was recorded with a different ROM"),
+            MovieError::UnknownPlatform(name) => write!(f, "movie uses unknown platform {}", name),
+            MovieError::Corrupt => write!(f, "movie is corrupt"),
+        }
+    }
+}
+
+impl error::Error for MovieError {}
+
+// Anything the reader reports inside a movie means the movie is damaged
+impl From<SaveStateError> for MovieError {
+    fn from(_: SaveStateError) -> Self {
+        MovieError::Corrupt
+    }
+}
+
+/// A keypad change, applied after the frame it was recorded on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MovieInput {
+    pub frame: u64,
+    pub key: u8,
+    pub down: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Movie {
+    /// The version of this crate that recorded the movie.
+    pub emulator_version: String,
+    pub rom_hash: u64,
+    pub platform: String,
+    pub quirks: Quirks,
+    /// The random number generator as it was when recording started.
+    pub rng: Rng,
+    /// A save state to start from instead of power-on.
+    pub start_state: Option<Vec<u8>>,
+    /// The number of frames recorded.
+    pub frames: u64,
+    pub inputs: Vec<MovieInput>,
+    // The scheduler's exact instruction budget, so every frame runs the same
+    // number of instructions
+    budget: (f64, f64),
+}
+
+impl Movie {
+    /// Start an empty movie of `processor` running on `scheduler`, from its
+    /// current state if `from_state` is set and from power-on otherwise.
+    pub fn new(processor: &Processor, scheduler: &Scheduler, from_state: bool) -> Self {
+        Self {
+            emulator_version: env!("CARGO_PKG_VERSION").to_string(),
+            rom_hash: processor.rom_hash(),
+            platform: processor.platform().name.to_string(),
+            quirks: processor.quirks(),
+            rng: processor.rng(),
+            start_state: from_state.then(|| processor.save_state()),
+            frames: 0,
+            inputs: Vec::new(),
+            budget: scheduler.budget(),
+        }
+    }
+
+    /// Build the machine the movie was recorded on, with `rom` loaded.
+    pub fn processor(&self, rom: &[u8]) -> Result<Processor, EmulatorError> {
+        if savestate::rom_hash(rom) != self.rom_hash {
+            return Err(MovieError::RomMismatch.into());
+        }
+        let platform = Platform::by_name(&self.platform)
+            .ok_or_else(|| MovieError::UnknownPlatform(self.platform.clone()))?;
+        let mut processor = Processor::with_platform(platform);
+        processor.set_quirks(self.quirks);
+        processor.set_rng(self.rng);
+        processor.load_rom(rom)?;
+        if let Some(state) = &self.start_state {
+            processor.load_state(state)?;
+        }
+        Ok(processor)
+    }
+
+    /// A scheduler running the same number of instructions each frame as
+    /// during recording.
+    pub fn scheduler(&self) -> Scheduler {
+        Scheduler::with_budget(self.budget)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.raw(&MAGIC);
+        w.u16(FORMAT_VERSION);
+        w.str(&self.emulator_version);
+        w.u64(self.rom_hash);
+        w.str(&self.platform);
+        w.quirks(&self.quirks);
+        w.bool(self.rng.mode() == RngMode::Vip);
+        w.u64(self.rng.state());
+        w.u64(self.budget.0.to_bits());
+        w.u64(self.budget.1.to_bits());
+        w.bool(self.start_state.is_some());
+        w.bytes(self.start_state.as_deref().unwrap_or_default());
+        w.u64(self.frames);
+        w.u32(self.inputs.len() as u32);
+        for input in &self.inputs {
+            w.u64(input.frame);
+            w.u8(input.key | (input.down as u8) << 7);
+        }
+        w.finish()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MovieError> {
+        let mut r = Reader::new(bytes);
+        if r.array::<4>() != Ok(MAGIC) {
+            return Err(MovieError::NotAMovie);
+        }
+        let version = r.u16()?;
+        if version != FORMAT_VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+
+        let emulator_version = r.str()?;
+        let rom_hash = r.u64()?;
+        let platform = r.str()?;
+        let quirks = r.quirks()?;
+        let mode = if r.bool()? {
+            RngMode::Vip
+        } else {
+            RngMode::Xorshift
+        };
+        let rng = Rng::new(mode, r.u64()?);
+        let budget = (f64::from_bits(r.u64()?), f64::from_bits(r.u64()?));
+        let has_state = r.bool()?;
+        let state = r.bytes()?;
+        let start_state = has_state.then(|| state.to_vec());
+        let frames = r.u64()?;
+
+        let count = r.u32()?;
+        let mut inputs = Vec::new();
+        for _ in 0..count {
+            let frame = r.u64()?;
+            let key = r.u8()?;
+            inputs.push(MovieInput {
+                frame,
+                key: key & 0xf,
+                down: key & 0x80 != 0,
+            });
+        }
+        r.finish()?;
+
+        Ok(Self {
+            emulator_version,
+            rom_hash,
+            platform,
+            quirks,
+            rng,
+            start_state,
+            frames,
+            inputs,
+            budget,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Movie, MovieError, MovieInput};
+    use crate::platform::Platform;
+    use crate::processor::Processor;
+    use crate::rng::{Rng, RngMode};
+    use crate::scheduler::Scheduler;
+
+    #[test]
+    fn test_round_trip() {
+        let mut vm = Processor::with_platform(Platform::chip48());
+        vm.load_rom(&[0x12, 0x00]).unwrap();
+        vm.set_rng(Rng::new(RngMode::Vip, 99));
+        let mut movie = Movie::new(&vm, &Scheduler::with_cpu_hz(1000.), true);
+        movie.frames = 3;
+        movie.inputs.push(MovieInput {
+            frame: 2,
+            key: 0xa,
+            down: true,
+        });
+
+        let bytes = movie.to_bytes();
+        assert_eq!(Movie::from_bytes(&bytes).unwrap(), movie);
+        assert_eq!(
+            Movie::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(MovieError::Corrupt)
+        );
+        assert_eq!(Movie::from_bytes(b"C8SS"), Err(MovieError::NotAMovie));
+
+        let replay = movie.processor(&[0x12, 0x00]).unwrap();
+        assert_eq!(replay.platform().name, "chip48");
+        assert_eq!(replay.rng(), vm.rng());
+        assert!(movie.processor(&[0x13, 0x00]).is_err());
+    }
+}
@@ -0,0 +1,325 @@
+// Debug panels shown next to the game screen: the registers, timers and
+// call stack, a disassembly around pc and a hex view of memory that follows
+// I. They are drawn as text into a `Framebuffer` with a 4x5 bitmap font in
+// the style of `FONT_SPRITES`, so a frontend shows them the same way it
+// shows the display, and no font library is needed.
+use crate::debugger;
+use crate::display::Framebuffer;
+use crate::processor::Processor;
+
+/// The size of the panels in their own pixels, which frontends scale up
+/// like the display.
+pub const WIDTH: usize = 450;
+pub const HEIGHT: usize = 196;
+
+/// The area at the top left left blank for the game screen: 4 panel
+/// pixels for each low resolution pixel.
+pub const GAME_WIDTH: usize = 256;
+pub const GAME_HEIGHT: usize = 128;
+
+/// Glyphs for ' ' to '_', four pixels wide in the high nibble of each of
+/// five rows. Lower case letters are drawn as upper case.
+pub const GLYPHS: [[u8; 5]; 64] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x40, 0x40, 0x40, 0x00, 0x40], // !
+    [0xA0, 0xA0, 0x00, 0x00, 0x00], // "
+    [0xA0, 0xF0, 0xA0, 0xF0, 0xA0], // #
+    [0x70, 0xA0, 0x60, 0x50, 0xE0], // $
+    [0x90, 0x10, 0x20, 0x40, 0x90], // %
+    [0x40, 0xA0, 0x40, 0xA0, 0xD0], // &
+    [0x40, 0x40, 0x00, 0x00, 0x00], // '
+    [0x20, 0x40, 0x40, 0x40, 0x20], // (
+    [0x40, 0x20, 0x20, 0x20, 0x40], // )
+    [0x00, 0xA0, 0x40, 0xA0, 0x00], // *
+    [0x00, 0x40, 0xE0, 0x40, 0x00], // +
+    [0x00, 0x00, 0x00, 0x40, 0x80], // ,
+    [0x00, 0x00, 0xE0, 0x00, 0x00], // -
+    [0x00, 0x00, 0x00, 0x00, 0x40], // .
+    [0x10, 0x10, 0x20, 0x40, 0x80], // /
+    [0xF0, 0x90, 0x90, 0x90, 0xF0], // 0
+    [0x20, 0x60, 0x20, 0x20, 0x70], // 1
+    [0xF0, 0x10, 0xF0, 0x80, 0xF0], // 2
+    [0xF0, 0x10, 0xF0, 0x10, 0xF0], // 3
+    [0x90, 0x90, 0xF0, 0x10, 0x10], // 4
+    [0xF0, 0x80, 0xF0, 0x10, 0xF0], // 5
+    [0xF0, 0x80, 0xF0, 0x90, 0xF0], // 6
+    [0xF0, 0x10, 0x20, 0x40, 0x40], // 7
+    [0xF0, 0x90, 0xF0, 0x90, 0xF0], // 8
+    [0xF0, 0x90, 0xF0, 0x10, 0xF0], // 9
+    [0x00, 0x40, 0x00, 0x40, 0x00], // :
+    [0x00, 0x40, 0x00, 0x40, 0x80], // ;
+    [0x20, 0x40, 0x80, 0x40, 0x20], // <
+    [0x00, 0xE0, 0x00, 0xE0, 0x00], // =
+    [0x80, 0x40, 0x20, 0x40, 0x80], // >
+    [0xE0, 0x10, 0x60, 0x00, 0x40], // ?
+    [0x60, 0x90, 0xB0, 0x80, 0x60], // @
+    [0xF0, 0x90, 0xF0, 0x90, 0x90], // A
+    [0xE0, 0x90, 0xE0, 0x90, 0xE0], // B
+    [0xF0, 0x80, 0x80, 0x80, 0xF0], // C
+    [0xE0, 0x90, 0x90, 0x90, 0xE0], // D
+    [0xF0, 0x80, 0xF0, 0x80, 0xF0], // E
+    [0xF0, 0x80, 0xF0, 0x80, 0x80], // F
+    [0xF0, 0x80, 0xB0, 0x90, 0xF0], // G
+    [0x90, 0x90, 0xF0, 0x90, 0x90], // H
+    [0xE0, 0x40, 0x40, 0x40, 0xE0], // I
+    [0x10, 0x10, 0x10, 0x90, 0xF0], // J
+    [0x90, 0xA0, 0xC0, 0xA0, 0x90], // K
+    [0x80, 0x80, 0x80, 0x80, 0xF0], // L
+    [0x90, 0xF0, 0xF0, 0x90, 0x90], // M
+    [0x90, 0xD0, 0xB0, 0x90, 0x90], // N
+    [0x60, 0x90, 0x90, 0x90, 0x60], // O
+    [0xE0, 0x90, 0xE0, 0x80, 0x80], // P
+    [0x60, 0x90, 0x90, 0xB0, 0x70], // Q
+    [0xE0, 0x90, 0xE0, 0xA0, 0x90], // R
+    [0x70, 0x80, 0x60, 0x10, 0xE0], // S
+    [0xE0, 0x40, 0x40, 0x40, 0x40], // T
+    [0x90, 0x90, 0x90, 0x90, 0xF0], // U
+    [0x90, 0x90, 0x90, 0xA0, 0x40], // V
+    [0x90, 0x90, 0xF0, 0xF0, 0x90], // W
+    [0x90, 0x90, 0x60, 0x90, 0x90], // X
+    [0xA0, 0xA0, 0x40, 0x40, 0x40], // Y
+    [0xF0, 0x10, 0x60, 0x80, 0xF0], // Z
+    [0x60, 0x40, 0x40, 0x40, 0x60], // [
+    [0x80, 0x80, 0x40, 0x20, 0x10], // \
+    [0x60, 0x20, 0x20, 0x20, 0x60], // ]
+    [0x40, 0xA0, 0x00, 0x00, 0x00], // ^
+    [0x00, 0x00, 0x00, 0x00, 0xF0], // _
+];
+
+// Each character takes a glyph and a gap on the right and below
+const CELL_WIDTH: usize = 5;
+const CELL_HEIGHT: usize = 7;
+
+// Pixel values, which `PALETTE` shows as white, light and dark grey
+const BRIGHT: u8 = 1;
+const NORMAL: u8 = 2;
+const DIM: u8 = 3;
+
+// Where each panel starts
+const REGISTERS_X: usize = GAME_WIDTH + 6;
+const CODE_X: usize = REGISTERS_X + 13 * CELL_WIDTH;
+const MEMORY_Y: usize = GAME_HEIGHT + 4;
+
+/// The glyph for `c`, or `?` for characters the font doesn't have.
+pub fn glyph(c: char) -> [u8; 5] {
+    let index = (c.to_ascii_uppercase() as usize)
+        .checked_sub(' ' as usize)
+        .filter(|&i| i < GLYPHS.len())
+        .unwrap_or('?' as usize - ' ' as usize);
+    GLYPHS[index]
+}
+
+/// Draw `text` with its top left corner at `x`, `y`, clipping it to the
+/// framebuffer. Returns the x just past the end.
+pub fn draw_text(
+    framebuffer: &mut Framebuffer,
+    x: usize,
+    y: usize,
+    text: &str,
+    value: u8,
+) -> usize {
+    let mut left = x;
+    for c in text.chars() {
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for column in 0..4 {
+                let (px, py) = (left + column, y + row);
+                if bits & (0x80 >> column) != 0
+                    && px < framebuffer.width()
+                    && py < framebuffer.height()
+                {
+                    framebuffer.set(px, py, value);
+                }
+            }
+        }
+        left += CELL_WIDTH;
+    }
+    left
+}
+
+/// Draw the panels for the machine as it is now. The game screen's area is
+/// left blank.
+pub fn render(processor: &Processor) -> Framebuffer {
+    let mut panels = Framebuffer::new(WIDTH, HEIGHT);
+    for y in 0..HEIGHT {
+        panels.set(GAME_WIDTH + 2, y, DIM);
+    }
+    for x in 0..GAME_WIDTH + 2 {
+        panels.set(x, GAME_HEIGHT + 1, DIM);
+    }
+    registers(&mut panels, processor);
+    code(&mut panels, processor);
+    memory(&mut panels, processor);
+    panels
+}
+
+/// Each line's y, starting at `top` and stopping at the bottom.
+fn lines(top: usize) -> impl Iterator<Item = usize> {
+    (top..=HEIGHT - CELL_HEIGHT + 2).step_by(CELL_HEIGHT)
+}
+
+fn registers(panels: &mut Framebuffer, processor: &Processor) {
+    let timers = processor.timers();
+    let values = processor.registers();
+    // Each line is pairs of a name and a value
+    let mut text = vec![
+        vec![("PC".to_string(), format!("{:03X}", processor.pc()))],
+        vec![("I ".to_string(), format!("{:03X}", processor.i()))],
+        vec![("DT".to_string(), format!("{:02X}", timers.delay))],
+        vec![("ST".to_string(), format!("{:02X}", timers.sound))],
+    ];
+    for x in 0..8 {
+        text.push(vec![
+            (format!("V{:X}", x), format!("{:02X}", values[x])),
+            (format!("V{:X}", x + 8), format!("{:02X}", values[x + 8])),
+        ]);
+    }
+    let stack = processor.stack();
+    text.push(vec![("STACK".to_string(), stack.len().to_string())]);
+    // Innermost call first
+    for addr in stack.iter().rev() {
+        text.push(vec![(String::new(), format!("{:03X}", addr))]);
+    }
+
+    for (line, y) in text.iter().zip(lines(2)) {
+        let mut x = REGISTERS_X;
+        for (name, value) in line {
+            x = draw_text(panels, x, y, name, DIM) + CELL_WIDTH;
+            x = draw_text(panels, x, y, value, NORMAL) + 2 * CELL_WIDTH;
+        }
+    }
+}
+
+fn code(panels: &mut Framebuffer, processor: &Processor) {
+    let memory = processor.memory();
+    let pc = processor.pc();
+    // Start a few instructions back so pc has some context. Counting back
+    // assumes two byte instructions, which almost all are.
+    let mut addr = pc.saturating_sub(8 * 2);
+    for y in lines(2) {
+        let Some((text, size)) = debugger::disassemble_at(memory, addr) else {
+            break;
+        };
+        let value = if addr == pc { BRIGHT } else { NORMAL };
+        let marker = if addr == pc { ">" } else { " " };
+        let x = draw_text(panels, CODE_X, y, marker, BRIGHT);
+        let x = draw_text(panels, x, y, &format!("{:03X} ", addr), DIM);
+        draw_text(panels, x, y, &text, value);
+        addr += size;
+    }
+}
+
+fn memory(panels: &mut Framebuffer, processor: &Processor) {
+    let memory = processor.memory();
+    let i = processor.i() as usize;
+    // Keep the row holding I second from the top
+    let last_row = memory.len().saturating_sub(1) & !0xf;
+    let mut addr = (i & !0xf)
+        .saturating_sub(0x10)
+        .min(last_row.saturating_sub(0x80));
+    for y in lines(MEMORY_Y) {
+        let Some(row) = memory.get(addr..(addr + 16).min(memory.len())) else {
+            break;
+        };
+        if row.is_empty() {
+            break;
+        }
+        let mut x = draw_text(panels, 2, y, &format!("{:03X}", addr), DIM) + CELL_WIDTH;
+        for (n, byte) in row.iter().enumerate() {
+            let value = if addr + n == i { BRIGHT } else { NORMAL };
+            x = draw_text(panels, x, y, &format!("{:02X}", byte), value) + CELL_WIDTH;
+        }
+        addr += 16;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        draw_text, glyph, render, CELL_WIDTH, CODE_X, HEIGHT, MEMORY_Y, REGISTERS_X, WIDTH,
+    };
+    use crate::display::Framebuffer;
+    use crate::image;
+    use crate::processor::Processor;
+
+    /// Read back `len` characters drawn at `x`, `y`.
+    fn read(framebuffer: &Framebuffer, x: usize, y: usize, len: usize) -> String {
+        (0..len)
+            .map(|n| {
+                let left = x + n * CELL_WIDTH;
+                let drawn: Vec<u8> = (0..5)
+                    .map(|row| {
+                        (0..4).fold(0, |bits, column| {
+                            let lit = framebuffer.get(left + column, y + row) != 0;
+                            bits | (lit as u8) << (7 - column)
+                        })
+                    })
+                    .collect();
+                (' '..='_').find(|&c| glyph(c) == drawn[..]).unwrap_or('~')
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_text() {
+        let mut framebuffer = Framebuffer::new(20, 5);
+        assert_eq!(draw_text(&mut framebuffer, 0, 0, "v0=a", 1), 20);
+        assert_eq!(
+            image::to_ascii(&framebuffer),
+            "\
+#..#.####......####.
+#..#.#..#.###..#..#.
+#..#.#..#......####.
+#.#..#..#.###..#..#.
+.#...####......#..#.
+"
+        );
+        assert_eq!(read(&framebuffer, 0, 0, 4), "V0=A");
+        // Every glyph can be told apart
+        for c in ' '..='_' {
+            assert_eq!(
+                (' '..='_').filter(|&d| glyph(d) == glyph(c)).count(),
+                1,
+                "{}",
+                c
+            );
+        }
+        assert_eq!(glyph('~'), glyph('?'));
+    }
+
+    #[test]
+    fn test_panels() {
+        let mut vm = Processor::new();
+        // CALL 0x204; LD V3, 0x2A; LD I, 0x305
+        vm.load_rom(&[0x22, 0x04, 0x63, 0x2a, 0xa3, 0x05]).unwrap();
+        vm.step().unwrap();
+        vm.set_register(0xb, 0x42);
+        vm.memory_mut()[0x305] = 0xee;
+        vm.step().unwrap();
+
+        let panels = render(&vm);
+        assert_eq!((panels.width(), panels.height()), (WIDTH, HEIGHT));
+        assert_eq!(read(&panels, REGISTERS_X, 2, 6), "PC 206");
+        assert_eq!(read(&panels, REGISTERS_X, 2 + 7, 6), "I  305");
+        assert_eq!(read(&panels, REGISTERS_X, 2 + 7 * 7, 12), "V3 00  VB 42");
+        assert_eq!(read(&panels, REGISTERS_X, 2 + 7 * 12, 7), "STACK 1");
+        assert_eq!(read(&panels, REGISTERS_X, 2 + 7 * 13, 4), " 200");
+        // pc is the ninth line, after eight instructions of context
+        assert_eq!(read(&panels, CODE_X, 2 + 7 * 8, 14), ">206 SYS 0X000");
+        assert_eq!(read(&panels, CODE_X, 2 + 7 * 7, 16), " 204 LD I, 0X305");
+        // I's row is second, and its byte is the sixth
+        assert_eq!(read(&panels, 2, MEMORY_Y + 7, 3), "300");
+        assert_eq!(
+            read(
+                &panels,
+                2 + 4 * CELL_WIDTH + 5 * 3 * CELL_WIDTH,
+                MEMORY_Y + 7,
+                2
+            ),
+            "EE"
+        );
+        assert_eq!(
+            panels.get(2 + 4 * CELL_WIDTH + 5 * 3 * CELL_WIDTH, MEMORY_Y + 7),
+            1
+        );
+    }
+}
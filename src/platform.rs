@@ -0,0 +1,241 @@
+// Named machine descriptions bundling everything that varies between the
+// systems CHIP-8 programs were written for.
+use crate::instruction::InstructionSet;
+use crate::quirks::{MemoryQuirk, Quirks};
+use crate::scheduler::DEFAULT_CPU_HZ;
+use std::str::FromStr;
+
+/// The font most interpreters since CHIP-48 ship with.
+pub const FONT_SPRITES: [u8; 80] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x20, 0x60, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
+/// The font built into the original COSMAC VIP interpreter.
+pub const VIP_FONT_SPRITES: [u8; 80] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x60, 0x20, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0xA0, 0xA0, 0xF0, 0x20, 0x20, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x10, 0x10, 0x10, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xF0, 0x50, 0x70, 0x50, 0xF0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xF0, 0x50, 0x50, 0x50, 0xF0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
+/// The 8x10 font SUPER-CHIP added for FX30. SUPER-CHIP only defined the
+/// digits; A-F come from XO-CHIP.
+pub const BIG_FONT_SPRITES: [u8; 160] = [
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+    0x3E, 0x7C, 0xC0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Platform {
+    pub name: &'static str,
+    pub instruction_set: InstructionSet,
+    pub memory_size: usize,
+    pub program_start: usize,
+    pub stack_depth: usize,
+    pub font: &'static [u8],
+    pub font_start: usize,
+    pub big_font: &'static [u8],
+    pub big_font_start: usize,
+    /// The low resolution display size. Platforms with SUPER-CHIP
+    /// instructions can switch to twice this in each direction.
+    pub screen_width: usize,
+    pub screen_height: usize,
+    pub cpu_hz: f64,
+    pub quirks: Quirks,
+}
+
+impl Default for Platform {
+    fn default() -> Self {
+        Self::modern()
+    }
+}
+
+impl Platform {
+    /// Names accepted by `Platform::by_name`.
+    pub const NAMES: [&'static str; 6] =
+        ["vip", "chip48", "schip10", "schip11", "xochip", "modern"];
+
+    /// The RCA COSMAC VIP running the original 1977 interpreter.
+    pub fn vip() -> Self {
+        Self {
+            name: "vip",
+            stack_depth: 12,
+            font: &VIP_FONT_SPRITES,
+            cpu_hz: 600.,
+            quirks: Quirks {
+                vf_reset: true,
+                memory: MemoryQuirk::IncrementXPlusOne,
+                display_wait: true,
+                clipping: true,
+                shifting: false,
+                jumping: false,
+            },
+            ..Self::modern()
+        }
+    }
+
+    /// CHIP-48 on the HP-48 calculators.
+    pub fn chip48() -> Self {
+        Self {
+            name: "chip48",
+            cpu_hz: 900.,
+            quirks: Quirks {
+                vf_reset: false,
+                memory: MemoryQuirk::IncrementX,
+                display_wait: false,
+                clipping: true,
+                shifting: true,
+                jumping: true,
+            },
+            ..Self::modern()
+        }
+    }
+
+    pub fn schip10() -> Self {
+        Self {
+            name: "schip10",
+            instruction_set: InstructionSet::SuperChip10,
+            cpu_hz: 1800.,
+            ..Self::chip48()
+        }
+    }
+
+    pub fn schip11() -> Self {
+        let mut platform = Self {
+            name: "schip11",
+            instruction_set: InstructionSet::SuperChip11,
+            ..Self::schip10()
+        };
+        platform.quirks.memory = MemoryQuirk::Unchanged;
+        platform
+    }
+
+    /// Octo's XO-CHIP extension.
+    pub fn xochip() -> Self {
+        Self {
+            name: "xochip",
+            instruction_set: InstructionSet::XoChip,
+            memory_size: 0x10000,
+            cpu_hz: 60_000.,
+            quirks: Quirks {
+                vf_reset: false,
+                memory: MemoryQuirk::IncrementXPlusOne,
+                display_wait: false,
+                clipping: false,
+                shifting: false,
+                jumping: false,
+            },
+            ..Self::modern()
+        }
+    }
+
+    /// The behaviour most CHIP-8 programs written today expect.
+    pub fn modern() -> Self {
+        Self {
+            name: "modern",
+            instruction_set: InstructionSet::Chip8,
+            memory_size: 4096,
+            program_start: 0x200,
+            stack_depth: 16,
+            font: &FONT_SPRITES,
+            font_start: 0x50,
+            big_font: &BIG_FONT_SPRITES,
+            big_font_start: 0xa0,
+            screen_width: 64,
+            screen_height: 32,
+            cpu_hz: DEFAULT_CPU_HZ,
+            quirks: Quirks::default(),
+        }
+    }
+
+    pub fn by_name(name: &str) -> Option<Self> {
+        match name {
+            "vip" => Some(Self::vip()),
+            "chip48" => Some(Self::chip48()),
+            "schip10" => Some(Self::schip10()),
+            "schip11" => Some(Self::schip11()),
+            "xochip" => Some(Self::xochip()),
+            "modern" => Some(Self::modern()),
+            _ => None,
+        }
+    }
+
+    /// The display size in high resolution mode, if the platform has one.
+    pub fn hires_size(&self) -> Option<(usize, usize)> {
+        (self.instruction_set >= InstructionSet::SuperChip10)
+            .then_some((self.screen_width * 2, self.screen_height * 2))
+    }
+
+    /// The largest ROM that fits in memory.
+    pub fn max_rom_size(&self) -> usize {
+        self.memory_size - self.program_start
+    }
+}
+
+impl FromStr for Platform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::by_name(s).ok_or_else(|| {
+            format!(
+                "unknown platform {} (expected one of {})",
+                s,
+                Self::NAMES.join(", ")
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Platform;
+
+    #[test]
+    fn test_by_name() {
+        for name in Platform::NAMES {
+            assert_eq!(Platform::by_name(name).unwrap().name, name);
+        }
+        assert!("cosmac".parse::<Platform>().is_err());
+    }
+}
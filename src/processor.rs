@@ -1,527 +1,1699 @@
-use crate::drivers::{AudioDriver, DisplayDriver};
-use sdl2::{event::Event, keyboard::Keycode};
-use std::{
-    fs, process, thread,
-    time::{Duration, Instant},
-    usize,
-};
-
-const PROGRAM_START: usize = 0x200;
-const MEMORY_SIZE: usize = 4096;
-const SCREEN_WIDTH: usize = 64;
-const SCREEN_HEIGHT: usize = 32;
-const SPRITE_START: usize = 0x50;
-const FRAME_RATE: f64 = 120.;
-
-pub const FONT_SPRITES: [u8; 80] = [
-    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
-    0x20, 0x60, 0x20, 0x20, 0x70, // 1
-    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
-    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
-    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
-    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
-    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
-    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
-    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
-    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
-    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
-    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
-    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
-    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
-    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
-    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
-];
-
-fn digit(hex: u16, place: u8) -> usize {
-    let offset = place << 2;
-    ((hex & (0xF << offset)) >> offset) as usize
-}
-
-fn parse_key(key: Keycode) -> u8 {
-    match key {
-        Keycode::Num1
-        | Keycode::Num2
-        | Keycode::Num3
-        | Keycode::Num4
-        | Keycode::Num5
-        | Keycode::Num6
-        | Keycode::Num7
-        | Keycode::Num8
-        | Keycode::Num9
-        | Keycode::Num0 => key.into_i32() as u8 - 48,
-        Keycode::A | Keycode::B | Keycode::C | Keycode::D | Keycode::E | Keycode::F => {
-            key.into_i32() as u8 - 87
-        }
-        _ => 0xff,
-    }
-}
+use crate::display::Framebuffer;
+use crate::error::{EmulatorError, ErrorPolicies, ErrorPolicy};
+use crate::instruction::{Instruction, InstructionSet};
+use crate::platform::Platform;
+use crate::quirks::{MemoryQuirk, Quirks};
+use crate::rng::{Rng, RngMode};
+use crate::savestate::{self, Header, Reader, SaveStateError, Writer};
+use std::fs;
 
 pub struct Processor {
-    memory: [u8; MEMORY_SIZE],
-    stack: [usize; 16],
-    display: [[u8; SCREEN_WIDTH]; SCREEN_HEIGHT],
+    platform: Platform,
+    memory: Vec<u8>,
+    stack: Vec<usize>,
+    display: Framebuffer,
     display_change: bool,
     keyboard: [u8; 16],
+    input_polled: bool,
     pc: usize,
     sp: usize,
     v: [u8; 16],
     i: u16,
     delay_timer: u8,
     sound_timer: u8,
+    rom: Vec<u8>,
+    policies: ErrorPolicies,
+    logged_errors: Vec<EmulatorError>,
+    quirks: Quirks,
+    vblank_wait: bool,
+    hires: bool,
+    planes: u8,
+    audio_pattern: Option<[u8; 16]>,
+    pitch: u8,
+    audio_change: bool,
+    halted: bool,
+    rpl: [u8; 16],
+    rng: Rng,
+    // Where `rng` starts again from after a reset
+    initial_rng: Rng,
+    // Instruction memory accesses, while they are being recorded
+    memory_accesses: Option<Vec<MemoryAccess>>,
+}
+
+/// What plays while the sound timer is running.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tone {
+    /// The plain buzzer of classic interpreters.
+    Buzzer,
+    /// An XO-CHIP pattern of 128 1-bit samples, most significant bit first,
+    /// looped at `rate` samples per second.
+    Pattern { samples: [u8; 16], rate: f32 },
+}
+
+/// A memory access made by an instruction, recorded while
+/// `record_memory_accesses` is on. Instruction fetches aren't included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryAccess {
+    Read { addr: usize, value: u8 },
+    Write { addr: usize, old: u8, new: u8 },
+}
+
+/// A snapshot of the two countdown timers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timers {
+    pub delay: u8,
+    pub sound: u8,
+}
+
+impl Default for Processor {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Processor {
     pub fn new() -> Self {
+        Self::with_platform(Platform::default())
+    }
+
+    pub fn with_platform(platform: Platform) -> Self {
+        let rng = Rng::default();
         let mut p = Self {
-            memory: [0; MEMORY_SIZE],
-            stack: [0; 16],
-            display: [[0; SCREEN_WIDTH]; SCREEN_HEIGHT],
+            memory: vec![0; platform.memory_size],
+            stack: vec![0; platform.stack_depth],
+            display: Framebuffer::new(platform.screen_width, platform.screen_height),
             display_change: false,
             keyboard: [0; 16],
-            pc: PROGRAM_START,
+            input_polled: false,
+            pc: platform.program_start,
             sp: 0,
             v: [0; 16],
             i: 0,
             delay_timer: 0,
             sound_timer: 0,
+            rom: Vec::new(),
+            policies: ErrorPolicies::default(),
+            logged_errors: Vec::new(),
+            quirks: platform.quirks,
+            vblank_wait: false,
+            hires: false,
+            planes: 1,
+            audio_pattern: None,
+            pitch: 64,
+            audio_change: false,
+            halted: false,
+            rpl: [0; 16],
+            rng,
+            initial_rng: rng,
+            memory_accesses: None,
+            platform,
         };
 
         // Load the font sprites into memory
-        for (pos, sprite) in FONT_SPRITES.iter().enumerate() {
-            p.memory[SPRITE_START + pos] = *sprite;
-        }
+        let font = p.platform.font_start..p.platform.font_start + p.platform.font.len();
+        p.memory[font].copy_from_slice(p.platform.font);
+        let big_font =
+            p.platform.big_font_start..p.platform.big_font_start + p.platform.big_font.len();
+        p.memory[big_font].copy_from_slice(p.platform.big_font);
 
         p
     }
 
-    fn step(&mut self) {
+    fn advance(&mut self) {
         self.pc += 2;
     }
 
-    pub fn load_program(&mut self, fp: &str) {
-        let bytes = fs::read(fp).unwrap();
-        for (pos, byte) in bytes.iter().enumerate() {
-            self.memory[PROGRAM_START + pos] = *byte;
+    /// Step over the instruction after the current one, which on XO-CHIP
+    /// may be the four byte F000 NNNN.
+    fn skip_next(&mut self) {
+        let next = self.pc + 2;
+        let long = self.platform.instruction_set >= InstructionSet::XoChip
+            && self.memory.get(next..next + 2) == Some(&[0xf0, 0x00]);
+        self.pc += if long { 4 } else { 2 };
+    }
+
+    pub fn load_program(&mut self, fp: &str) -> Result<(), EmulatorError> {
+        let bytes = fs::read(fp)?;
+        self.load_rom(&bytes)
+    }
+
+    pub fn load_rom(&mut self, bytes: &[u8]) -> Result<(), EmulatorError> {
+        let max = self.platform.max_rom_size();
+        if bytes.len() > max {
+            return Err(EmulatorError::RomTooLarge {
+                size: bytes.len(),
+                max,
+            });
         }
+        let start = self.platform.program_start;
+        self.memory[start..start + bytes.len()].copy_from_slice(bytes);
+        self.rom = bytes.to_vec();
+        Ok(())
     }
 
-    pub fn run_program(&mut self) {
-        let target_frame_duration = Duration::from_secs_f64(1. / FRAME_RATE);
+    /// Restore the power-on state, keeping the loaded program, settings
+    /// and the RPL user flags, which survive like they do on an HP-48. The
+    /// random number generator starts over from the same seed.
+    pub fn reset(&mut self) {
+        let rom = std::mem::take(&mut self.rom);
+        let (policies, quirks, rpl) = (self.policies, self.quirks, self.rpl);
+        let rng = self.initial_rng;
+        *self = Self::with_platform(self.platform.clone());
+        self.policies = policies;
+        self.quirks = quirks;
+        self.rpl = rpl;
+        self.set_rng(rng);
+        // The ROM fit before, so it fits again
+        self.load_rom(&rom).unwrap();
+    }
 
-        let sdl_context = sdl2::init().unwrap();
-        let mut display_driver = DisplayDriver::new(&sdl_context);
-        let audio_driver = AudioDriver::new(&sdl_context, 480.0, 0.25).unwrap();
+    /// The `savestate::rom_hash` of the loaded program.
+    pub fn rom_hash(&self) -> u64 {
+        savestate::rom_hash(&self.rom)
+    }
 
-        loop {
-            let frame_start = Instant::now();
+    /// Serialize the complete machine state, including the platform and
+    /// quirks, in the versioned `savestate` format.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        Header::new(self.rom_hash()).write(&mut w);
+        w.str(self.platform.name);
+        w.quirks(&self.quirks);
+        w.bytes(&self.memory);
+        w.u32(self.stack.len() as u32);
+        for &addr in &self.stack {
+            w.u32(addr as u32);
+        }
+        w.u32(self.sp as u32);
+        w.u32(self.pc as u32);
+        w.raw(&self.v);
+        w.u16(self.i);
+        w.u8(self.delay_timer);
+        w.u8(self.sound_timer);
+        w.u16(self.display.width() as u16);
+        w.u16(self.display.height() as u16);
+        for row in self.display.rows() {
+            w.raw(row);
+        }
+        w.raw(&self.keyboard);
+        w.bool(self.vblank_wait);
+        w.bool(self.hires);
+        w.u8(self.planes);
+        w.bool(self.halted);
+        w.raw(&self.rpl);
+        w.bool(self.audio_pattern.is_some());
+        w.raw(&self.audio_pattern.unwrap_or_default());
+        w.u8(self.pitch);
+        w.bool(self.rng.mode() == RngMode::Vip);
+        w.u64(self.rng.state());
+        w.finish()
+    }
 
-            let instruction =
-                ((self.memory[self.pc] as u16) << 8) | self.memory[self.pc + 1] as u16;
-            self.execute_instruction(instruction);
+    /// Restore a state from `save_state`. It must have been saved with the
+    /// same program loaded. On error the machine is left untouched.
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), EmulatorError> {
+        let mut r = Reader::new(bytes);
+        let header = Header::read(&mut r)?;
+        if header.rom_hash != self.rom_hash() {
+            return Err(SaveStateError::RomMismatch.into());
+        }
+        let name = r.str()?;
+        let platform =
+            Platform::by_name(&name).ok_or(SaveStateError::UnknownPlatform(name.clone()))?;
 
-            self.handle_events(&sdl_context);
-            if self.display_change {
-                display_driver.draw(&self.display);
-                self.display_change = false;
-            }
+        let mut p = Self::with_platform(platform);
+        p.policies = self.policies;
+        p.rom = self.rom.clone();
+        p.quirks = r.quirks()?;
 
-            if self.delay_timer > 0 {
-                self.delay_timer -= 1;
-            }
+        let memory = r.bytes()?;
+        let stack_depth = r.u32()? as usize;
+        if memory.len() != p.memory.len() || stack_depth != p.stack.len() {
+            return Err(SaveStateError::Corrupt.into());
+        }
+        p.memory.copy_from_slice(memory);
+        for addr in p.stack.iter_mut() {
+            *addr = r.u32()? as usize;
+        }
+        p.sp = r.u32()? as usize;
+        if p.sp > p.stack.len() {
+            return Err(SaveStateError::Corrupt.into());
+        }
+        p.pc = r.u32()? as usize;
+        p.v = r.array()?;
+        p.i = r.u16()?;
+        p.delay_timer = r.u8()?;
+        p.sound_timer = r.u8()?;
 
-            if self.sound_timer > 0 {
-                audio_driver.start();
-                self.sound_timer -= 1;
-            } else if audio_driver.is_active() {
-                audio_driver.stop();
+        let width = r.u16()? as usize;
+        let height = r.u16()? as usize;
+        let lores = (p.platform.screen_width, p.platform.screen_height);
+        if (width, height) != lores && Some((width, height)) != p.platform.hires_size() {
+            return Err(SaveStateError::Corrupt.into());
+        }
+        p.display.resize(width, height);
+        for y in 0..height {
+            for (x, &pixel) in r.raw(width)?.iter().enumerate() {
+                p.display.toggle(x, y, pixel);
             }
+        }
+
+        p.keyboard = r.array()?;
+        p.vblank_wait = r.bool()?;
+        p.hires = r.bool()?;
+        p.planes = r.u8()?;
+        p.halted = r.bool()?;
+        p.rpl = r.array()?;
+        let has_pattern = r.bool()?;
+        let pattern = r.array()?;
+        p.audio_pattern = has_pattern.then_some(pattern);
+        p.pitch = r.u8()?;
+        let mode = if r.bool()? {
+            RngMode::Vip
+        } else {
+            RngMode::Xorshift
+        };
+        p.rng = Rng::new(mode, r.u64()?);
+        p.initial_rng = self.initial_rng;
+        r.finish()?;
+
+        p.display_change = true;
+        p.audio_change = true;
+        *self = p;
+        Ok(())
+    }
+
+    /// Replace the random number generator used by CXKK, for example with
+    /// `Rng::new(RngMode::Xorshift, seed)` to make runs repeatable.
+    pub fn set_rng(&mut self, rng: Rng) {
+        self.rng = rng;
+        self.initial_rng = rng;
+    }
+
+    pub fn rng(&self) -> Rng {
+        self.rng
+    }
 
-            // Maintain a standard frame rate
-            let elapsed = frame_start.elapsed();
-            if let Some(remaining) = target_frame_duration.checked_sub(elapsed) {
-                thread::sleep(remaining);
+    pub fn set_error_policies(&mut self, policies: ErrorPolicies) {
+        self.policies = policies;
+    }
+
+    pub fn platform(&self) -> &Platform {
+        &self.platform
+    }
+
+    /// Override the quirks of the platform.
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    /// Drain the errors that were logged and skipped under `ErrorPolicy::Log`.
+    pub fn take_logged_errors(&mut self) -> Vec<EmulatorError> {
+        std::mem::take(&mut self.logged_errors)
+    }
+
+    /// Fetch and execute a single instruction. Under `ErrorPolicy::Halt` a
+    /// failing instruction has no effect and `pc` still points at it.
+    pub fn step(&mut self) -> Result<(), EmulatorError> {
+        if self.halted {
+            return Ok(());
+        }
+
+        let opcode = (self.fetch(self.pc)? as u16) << 8 | self.fetch(self.pc + 1)? as u16;
+        match Instruction::decode(opcode) {
+            Ok(instruction) if instruction.instruction_set() <= self.platform.instruction_set => {
+                self.execute(instruction)
+            }
+            // Newer instructions in the 0NNN range are machine code calls to
+            // older interpreters, which we ignore.
+            Ok(_) if opcode < 0x1000 => {
+                self.op_0nnn();
+                Ok(())
+            }
+            _ => {
+                let error = EmulatorError::InvalidOpcode {
+                    opcode,
+                    pc: self.pc,
+                };
+                self.handle(self.policies.invalid_opcode, error)?;
+                self.advance();
+                Ok(())
             }
         }
     }
 
-    pub fn handle_events(&mut self, sdl_context: &sdl2::Sdl) {
-        for event in sdl_context.event_pump().unwrap().poll_iter() {
-            match event {
-                Event::Quit { .. } => {
-                    process::exit(0);
-                }
-                Event::KeyDown {
-                    keycode: Some(Keycode::Escape),
-                    ..
-                } => {
-                    process::exit(0);
-                }
-                Event::KeyDown {
-                    keycode: Some(key), ..
-                } => {
-                    let key = parse_key(key);
-                    if key == 0xff {
-                        continue;
-                    }
-                    // Only stores one key at a time. Could update this to support multiple
-                    self.keyboard = [0; 16];
-                    self.keyboard[key as usize] = 1;
-                }
-                Event::KeyUp {
-                    keycode: Some(key), ..
-                } => {
-                    let key = parse_key(key);
-                    if key == 0xff {
-                        continue;
-                    }
-                    self.keyboard = [0; 16];
-                }
-                _ => {}
+    /// Run one 60 Hz frame: `instructions` instructions followed by a timer
+    /// tick. The frame ends early if a draw has to wait for the vertical blank.
+    pub fn run_frame(&mut self, instructions: u32) -> Result<(), EmulatorError> {
+        self.run_frame_with(instructions, |_| true).map(|_| ())
+    }
+
+    /// Like `run_frame`, but calls `before_step` ahead of every instruction.
+    /// It may pause there for as long as it likes, inspecting or changing
+    /// the processor, and abandons the rest of the frame by returning false.
+    /// Returns whether the frame ran to the end.
+    pub fn run_frame_with(
+        &mut self,
+        instructions: u32,
+        mut before_step: impl FnMut(&mut Self) -> bool,
+    ) -> Result<bool, EmulatorError> {
+        self.vblank_wait = false;
+        for _ in 0..instructions {
+            if !before_step(self) {
+                return Ok(false);
+            }
+            self.step()?;
+            if self.vblank_wait || self.halted {
+                break;
             }
         }
+        self.tick_timers();
+        Ok(true)
     }
 
-    fn execute_instruction(&mut self, instruction: u16) {
-        match digit(instruction, 3) {
-            0x0 => match instruction {
-                0x00e0 => self.op_00e0(instruction),
-                0x00ee => self.op_00ee(instruction),
-                _ => self.op_0nnn(instruction),
-            },
-            0x1 => self.op_1nnn(instruction),
-            0x2 => self.op_2nnn(instruction),
-            0x3 => self.op_3xkk(instruction),
-            0x4 => self.op_4xkk(instruction),
-            0x5 => match digit(instruction, 0) {
-                0x0 => self.op_5xy0(instruction),
-                _ => {}
-            },
-            0x6 => self.op_6xkk(instruction),
-            0x7 => self.op_7xkk(instruction),
-            0x8 => match digit(instruction, 0) {
-                0x0 => self.op_8xy0(instruction),
-                0x1 => self.op_8xy1(instruction),
-                0x2 => self.op_8xy2(instruction),
-                0x3 => self.op_8xy3(instruction),
-                0x4 => self.op_8xy4(instruction),
-                0x5 => self.op_8xy5(instruction),
-                0x6 => self.op_8xy6(instruction),
-                0x7 => self.op_8xy7(instruction),
-                0xe => self.op_8xye(instruction),
-                _ => {}
-            },
-            0x9 => match digit(instruction, 0) {
-                0x0 => self.op_9xy0(instruction),
-                _ => {}
-            },
-            0xa => self.op_annn(instruction),
-            0xb => self.op_bnnn(instruction),
-            0xc => self.op_cxkk(instruction),
-            0xd => self.op_dxyn(instruction),
-            0xe => match instruction as u8 {
-                0x9e => self.op_ex9e(instruction),
-                0xa1 => self.op_exa1(instruction),
-                _ => {}
-            },
-            0xf => match instruction as u8 {
-                0x07 => self.op_fx07(instruction),
-                0x0a => self.op_fx0a(instruction),
-                0x15 => self.op_fx15(instruction),
-                0x18 => self.op_fx18(instruction),
-                0x1e => self.op_fx1e(instruction),
-                0x29 => self.op_fx29(instruction),
-                0x33 => self.op_fx33(instruction),
-                0x55 => self.op_fx55(instruction),
-                0x65 => self.op_fx65(instruction),
-                _ => {}
+    /// Whether the program has exited with 00FD.
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Whether the SUPER-CHIP high resolution mode is on.
+    pub fn is_hires(&self) -> bool {
+        self.hires
+    }
+
+    /// Count both timers down by one. Call this at 60 Hz.
+    pub fn tick_timers(&mut self) {
+        self.delay_timer = self.delay_timer.saturating_sub(1);
+        self.sound_timer = self.sound_timer.saturating_sub(1);
+    }
+
+    pub fn timers(&self) -> Timers {
+        Timers {
+            delay: self.delay_timer,
+            sound: self.sound_timer,
+        }
+    }
+
+    pub fn set_timers(&mut self, timers: Timers) {
+        self.delay_timer = timers.delay;
+        self.sound_timer = timers.sound;
+    }
+
+    /// The sound played while the sound timer runs. Classic programs get the
+    /// buzzer until they load an XO-CHIP audio pattern.
+    pub fn tone(&self) -> Tone {
+        match self.audio_pattern {
+            Some(samples) => Tone::Pattern {
+                samples,
+                rate: 4000. * 2f32.powf((self.pitch as f32 - 64.) / 48.),
             },
-            _ => {}
+            None => Tone::Buzzer,
         }
     }
 
+    /// Returns whether the tone changed since the last call.
+    pub fn take_audio_change(&mut self) -> bool {
+        std::mem::take(&mut self.audio_change)
+    }
+
+    pub fn registers(&self) -> &[u8; 16] {
+        &self.v
+    }
+
+    pub fn set_register(&mut self, x: usize, value: u8) {
+        self.v[x & 0xf] = value;
+    }
+
+    pub fn i(&self) -> u16 {
+        self.i
+    }
+
+    pub fn set_i(&mut self, i: u16) {
+        self.i = i;
+    }
+
+    /// The address of the next instruction.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: usize) {
+        self.pc = pc;
+    }
+
+    /// The return addresses of the subroutines being run, innermost last.
+    pub fn stack(&self) -> &[usize] {
+        &self.stack[..self.sp]
+    }
+
+    /// Set the number of subroutines being run, keeping whatever return
+    /// addresses are already on the stack below it. Returns false, leaving
+    /// the stack alone, if it can't hold that many.
+    pub fn set_sp(&mut self, sp: usize) -> bool {
+        let fits = sp <= self.stack.len();
+        if fits {
+            self.sp = sp;
+        }
+        fits
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+
+    /// Start or stop recording the memory accesses instructions make.
+    pub fn record_memory_accesses(&mut self, on: bool) {
+        if on != self.memory_accesses.is_some() {
+            self.memory_accesses = on.then(Vec::new);
+        }
+    }
+
+    /// Drain the memory accesses recorded since the last call.
+    pub fn take_memory_accesses(&mut self) -> Vec<MemoryAccess> {
+        self.memory_accesses
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    pub fn framebuffer(&self) -> &Framebuffer {
+        &self.display
+    }
+
+    /// Returns whether the display changed since the last call.
+    pub fn take_display_change(&mut self) -> bool {
+        std::mem::take(&mut self.display_change)
+    }
+
+    /// Returns whether the program read the keypad since the last call.
+    pub fn take_input_polled(&mut self) -> bool {
+        std::mem::take(&mut self.input_polled)
+    }
+
+    /// Mark a key on the hex keypad (0x0-0xF) as held down.
+    pub fn set_key(&mut self, key: u8) {
+        self.keyboard[key as usize & 0xf] = 1;
+    }
+
+    pub fn is_key_down(&self, key: u8) -> bool {
+        self.keyboard[key as usize & 0xf] == 1
+    }
+
+    /// Mark a key on the hex keypad (0x0-0xF) as released.
+    pub fn release_key(&mut self, key: u8) {
+        self.keyboard[key as usize & 0xf] = 0;
+    }
+
+    /// Apply `policy` to `error`. Returns `Err` to halt, `Ok(true)` to wrap
+    /// around, or `Ok(false)` once the error has been logged.
+    fn handle(&mut self, policy: ErrorPolicy, error: EmulatorError) -> Result<bool, EmulatorError> {
+        match policy {
+            ErrorPolicy::Halt => Err(error),
+            ErrorPolicy::Log => {
+                self.logged_errors.push(error);
+                Ok(false)
+            }
+            ErrorPolicy::Wrap => Ok(true),
+        }
+    }
+
+    /// Map an address into memory. `None` means the access should be skipped.
+    fn resolve(&mut self, addr: usize) -> Result<Option<usize>, EmulatorError> {
+        let size = self.memory.len();
+        if addr < size {
+            return Ok(Some(addr));
+        }
+        let error = EmulatorError::MemoryOutOfBounds { addr, pc: self.pc };
+        let wrap = self.handle(self.policies.memory, error)?;
+        Ok(wrap.then_some(addr % size))
+    }
+
+    /// Fail up front if `len` bytes from `addr` would run past memory and
+    /// that halts, so multi-byte operations never halt half done.
+    fn check_span(&self, addr: usize, len: usize) -> Result<(), EmulatorError> {
+        let size = self.memory.len();
+        if self.policies.memory == ErrorPolicy::Halt && len > 0 && addr + len > size {
+            return Err(EmulatorError::MemoryOutOfBounds {
+                addr: addr.max(size),
+                pc: self.pc,
+            });
+        }
+        Ok(())
+    }
+
+    /// Read part of an instruction.
+    fn fetch(&mut self, addr: usize) -> Result<u8, EmulatorError> {
+        Ok(self.resolve(addr)?.map_or(0, |addr| self.memory[addr]))
+    }
+
+    fn read(&mut self, addr: usize) -> Result<u8, EmulatorError> {
+        let Some(addr) = self.resolve(addr)? else {
+            return Ok(0);
+        };
+        let value = self.memory[addr];
+        if let Some(accesses) = &mut self.memory_accesses {
+            accesses.push(MemoryAccess::Read { addr, value });
+        }
+        Ok(value)
+    }
+
+    fn write(&mut self, addr: usize, value: u8) -> Result<(), EmulatorError> {
+        let Some(addr) = self.resolve(addr)? else {
+            return Ok(());
+        };
+        if let Some(accesses) = &mut self.memory_accesses {
+            let old = self.memory[addr];
+            accesses.push(MemoryAccess::Write {
+                addr,
+                old,
+                new: value,
+            });
+        }
+        self.memory[addr] = value;
+        Ok(())
+    }
+
+    fn execute(&mut self, instruction: Instruction) -> Result<(), EmulatorError> {
+        use Instruction::*;
+
+        match instruction {
+            Sys { .. } => self.op_0nnn(),
+            Cls => self.op_00e0(),
+            Ret => self.op_00ee()?,
+            ScrollDown { n } => self.op_00cn(n as usize),
+            ScrollUp { n } => self.op_00dn(n as usize),
+            ScrollRight => self.op_00fb(),
+            ScrollLeft => self.op_00fc(),
+            Exit => self.op_00fd(),
+            LowRes => self.op_00fe(),
+            HighRes => self.op_00ff(),
+            Jump { nnn } => self.op_1nnn(nnn),
+            Call { nnn } => self.op_2nnn(nnn)?,
+            SkipEqImm { x, kk } => self.op_3xkk(x as usize, kk),
+            SkipNeImm { x, kk } => self.op_4xkk(x as usize, kk),
+            SkipEqReg { x, y } => self.op_5xy0(x as usize, y as usize),
+            StoreRange { x, y } => self.op_5xy2(x as usize, y as usize)?,
+            LoadRange { x, y } => self.op_5xy3(x as usize, y as usize)?,
+            LoadImm { x, kk } => self.op_6xkk(x as usize, kk),
+            AddImm { x, kk } => self.op_7xkk(x as usize, kk),
+            Move { x, y } => self.op_8xy0(x as usize, y as usize),
+            Or { x, y } => self.op_8xy1(x as usize, y as usize),
+            And { x, y } => self.op_8xy2(x as usize, y as usize),
+            Xor { x, y } => self.op_8xy3(x as usize, y as usize),
+            Add { x, y } => self.op_8xy4(x as usize, y as usize),
+            Sub { x, y } => self.op_8xy5(x as usize, y as usize),
+            ShiftRight { x, y } => self.op_8xy6(x as usize, y as usize),
+            SubN { x, y } => self.op_8xy7(x as usize, y as usize),
+            ShiftLeft { x, y } => self.op_8xye(x as usize, y as usize),
+            SkipNeReg { x, y } => self.op_9xy0(x as usize, y as usize),
+            LoadI { nnn } => self.op_annn(nnn),
+            JumpOffset { nnn } => self.op_bnnn(nnn),
+            Random { x, kk } => self.op_cxkk(x as usize, kk)?,
+            Draw { x, y, n } => self.op_dxyn(x as usize, y as usize, n as usize)?,
+            SkipKey { x } => self.op_ex9e(x as usize),
+            SkipNotKey { x } => self.op_exa1(x as usize),
+            LoadDelay { x } => self.op_fx07(x as usize),
+            WaitKey { x } => self.op_fx0a(x as usize),
+            SetDelay { x } => self.op_fx15(x as usize),
+            SetSound { x } => self.op_fx18(x as usize),
+            AddI { x } => self.op_fx1e(x as usize),
+            LoadFont { x } => self.op_fx29(x as usize),
+            LoadBigFont { x } => self.op_fx30(x as usize),
+            StoreBcd { x } => self.op_fx33(x as usize)?,
+            StoreRegs { x } => self.op_fx55(x as usize)?,
+            LoadRegs { x } => self.op_fx65(x as usize)?,
+            StoreFlags { x } => self.op_fx75(x as usize),
+            LoadFlags { x } => self.op_fx85(x as usize),
+            LoadILong => self.op_f000()?,
+            Plane { n } => self.op_fn01(n),
+            LoadAudio => self.op_f002()?,
+            SetPitch { x } => self.op_fx3a(x as usize),
+        }
+        Ok(())
+    }
+
     /// (DEPRECATED) Execute a machine code subroutine at address NNN.
-    fn op_0nnn(&mut self, _i: u16) {
-        self.step();
+    fn op_0nnn(&mut self) {
+        self.advance();
     }
 
-    /// Clear the screen.
-    fn op_00e0(&mut self, _i: u16) {
-        self.display = [[0; SCREEN_WIDTH]; SCREEN_HEIGHT];
+    /// Clear the selected planes of the screen.
+    fn op_00e0(&mut self) {
+        self.display.clear_planes(self.planes);
         self.display_change = true;
-        self.step();
+        self.advance();
     }
 
     /// Return from a subroutine.
-    fn op_00ee(&mut self, _i: u16) {
+    fn op_00ee(&mut self) -> Result<(), EmulatorError> {
+        if self.sp == 0 {
+            let error = EmulatorError::StackUnderflow { pc: self.pc };
+            if !self.handle(self.policies.stack, error)? {
+                self.advance();
+                return Ok(());
+            }
+            self.sp = self.stack.len();
+        }
         self.sp -= 1;
         self.pc = self.stack[self.sp];
-        self.step();
+        self.advance();
+        Ok(())
+    }
+
+    /// Scroll the display down N pixels.
+    fn op_00cn(&mut self, n: usize) {
+        self.display.scroll_down(n, self.planes);
+        self.display_change = true;
+        self.advance();
+    }
+
+    /// Scroll the display up N pixels.
+    fn op_00dn(&mut self, n: usize) {
+        self.display.scroll_up(n, self.planes);
+        self.display_change = true;
+        self.advance();
+    }
+
+    /// Scroll the display right 4 pixels.
+    fn op_00fb(&mut self) {
+        self.display.scroll_right(4, self.planes);
+        self.display_change = true;
+        self.advance();
+    }
+
+    /// Scroll the display left 4 pixels.
+    fn op_00fc(&mut self) {
+        self.display.scroll_left(4, self.planes);
+        self.display_change = true;
+        self.advance();
+    }
+
+    /// Exit the interpreter.
+    fn op_00fd(&mut self) {
+        self.halted = true;
+    }
+
+    /// Switch to low resolution mode, clearing the display.
+    fn op_00fe(&mut self) {
+        self.set_resolution(false);
+        self.advance();
+    }
+
+    /// Switch to high resolution mode, clearing the display.
+    fn op_00ff(&mut self) {
+        self.set_resolution(true);
+        self.advance();
+    }
+
+    fn set_resolution(&mut self, hires: bool) {
+        let (width, height) = match self.platform.hires_size() {
+            Some(size) if hires => size,
+            _ => (self.platform.screen_width, self.platform.screen_height),
+        };
+        self.hires = hires;
+        self.display.resize(width, height);
+        self.display_change = true;
     }
 
     /// Jump to address NNN.
-    fn op_1nnn(&mut self, i: u16) {
-        self.pc = i as usize & 0xfff;
+    fn op_1nnn(&mut self, nnn: u16) {
+        self.pc = nnn as usize;
     }
 
     /// Execute subroutine starting at address NNN.
-    fn op_2nnn(&mut self, i: u16) {
+    fn op_2nnn(&mut self, nnn: u16) -> Result<(), EmulatorError> {
+        if self.sp == self.stack.len() {
+            let error = EmulatorError::StackOverflow { pc: self.pc };
+            if !self.handle(self.policies.stack, error)? {
+                self.advance();
+                return Ok(());
+            }
+            self.sp = 0;
+        }
         self.stack[self.sp] = self.pc;
         self.sp += 1;
-        self.pc = i as usize & 0xfff;
+        self.pc = nnn as usize;
+        Ok(())
     }
 
     /// Skip the following instruction if the value of register VX equals KK.
-    fn op_3xkk(&mut self, i: u16) {
-        if self.v[(i >> 8) as usize & 0xf] == (i & 0xff) as u8 {
-            self.step();
+    fn op_3xkk(&mut self, x: usize, kk: u8) {
+        if self.v[x] == kk {
+            self.skip_next();
         }
-        self.step();
+        self.advance();
     }
 
     /// Skip the following instruction if the value of register VX doesn't equal KK.
-    fn op_4xkk(&mut self, i: u16) {
-        if self.v[(i >> 8) as usize & 0xf] != (i & 0xff) as u8 {
-            self.step();
+    fn op_4xkk(&mut self, x: usize, kk: u8) {
+        if self.v[x] != kk {
+            self.skip_next();
         }
-        self.step();
+        self.advance();
     }
 
     /// Skip the following instruction if the value of register VX equals the value of register VY.
-    fn op_5xy0(&mut self, i: u16) {
-        if self.v[(i >> 8) as usize & 0xf] == self.v[(i >> 4) as usize & 0xf] {
-            self.step();
+    fn op_5xy0(&mut self, x: usize, y: usize) {
+        if self.v[x] == self.v[y] {
+            self.skip_next();
         }
-        self.step();
+        self.advance();
+    }
+
+    /// Store the values of registers VX to VY inclusive in memory starting
+    /// at address I, without changing I.
+    fn op_5xy2(&mut self, x: usize, y: usize) -> Result<(), EmulatorError> {
+        let count = x.abs_diff(y) + 1;
+        self.check_span(self.i as usize, count)?;
+        for offset in 0..count {
+            let r = if x <= y { x + offset } else { x - offset };
+            self.write(self.i as usize + offset, self.v[r])?;
+        }
+        self.advance();
+        Ok(())
+    }
+
+    /// Fill registers VX to VY inclusive with values from memory starting
+    /// at address I, without changing I.
+    fn op_5xy3(&mut self, x: usize, y: usize) -> Result<(), EmulatorError> {
+        let count = x.abs_diff(y) + 1;
+        self.check_span(self.i as usize, count)?;
+        for offset in 0..count {
+            let r = if x <= y { x + offset } else { x - offset };
+            self.v[r] = self.read(self.i as usize + offset)?;
+        }
+        self.advance();
+        Ok(())
     }
 
     /// Store the value KK in register VX.
-    fn op_6xkk(&mut self, i: u16) {
-        self.v[(i >> 8) as usize & 0xf] = (i & 0xff) as u8;
-        self.step();
+    fn op_6xkk(&mut self, x: usize, kk: u8) {
+        self.v[x] = kk;
+        self.advance();
     }
 
     /// Add the value KK to the value of register VX.
-    fn op_7xkk(&mut self, i: u16) {
-        let v = (i >> 8) as usize & 0xf;
-        self.v[v] = self.v[v].wrapping_add((i & 0xff) as u8);
-        self.step();
+    fn op_7xkk(&mut self, x: usize, kk: u8) {
+        self.v[x] = self.v[x].wrapping_add(kk);
+        self.advance();
     }
 
     /// Store the value of register VY in register VX.
-    fn op_8xy0(&mut self, i: u16) {
-        self.v[(i >> 8) as usize & 0xf] = self.v[(i >> 4) as usize & 0xf];
-        self.step();
+    fn op_8xy0(&mut self, x: usize, y: usize) {
+        self.v[x] = self.v[y];
+        self.advance();
     }
 
     /// Set the value of register VX to the value of register VX OR the value of register VY.
-    fn op_8xy1(&mut self, i: u16) {
-        self.v[(i >> 8) as usize & 0xf] |= self.v[(i >> 4) as usize & 0xf];
-        self.step();
+    fn op_8xy1(&mut self, x: usize, y: usize) {
+        self.v[x] |= self.v[y];
+        if self.quirks.vf_reset {
+            self.v[0xf] = 0;
+        }
+        self.advance();
     }
 
     /// Set the value of register VX to the value of register VX AND the value of register VY.
-    fn op_8xy2(&mut self, i: u16) {
-        self.v[(i >> 8) as usize & 0xf] &= self.v[(i >> 4) as usize & 0xf];
-        self.step();
+    fn op_8xy2(&mut self, x: usize, y: usize) {
+        self.v[x] &= self.v[y];
+        if self.quirks.vf_reset {
+            self.v[0xf] = 0;
+        }
+        self.advance();
     }
 
     /// Set the value of register VX to the value of register VX XOR the value of register VY.
-    fn op_8xy3(&mut self, i: u16) {
-        self.v[(i >> 8) as usize & 0xf] ^= self.v[(i >> 4) as usize & 0xf];
-        self.step();
+    fn op_8xy3(&mut self, x: usize, y: usize) {
+        self.v[x] ^= self.v[y];
+        if self.quirks.vf_reset {
+            self.v[0xf] = 0;
+        }
+        self.advance();
     }
 
     /// Add the value of register VY to the value of register VX.
-    fn op_8xy4(&mut self, i: u16) {
-        let (result, carry) =
-            self.v[(i >> 8) as usize & 0xf].overflowing_add(self.v[(i >> 4) as usize & 0xf]);
-        self.v[(i >> 8) as usize & 0xf] = result;
+    fn op_8xy4(&mut self, x: usize, y: usize) {
+        let (result, carry) = self.v[x].overflowing_add(self.v[y]);
+        self.v[x] = result;
         self.v[0xf] = carry as u8;
-        self.step();
+        self.advance();
     }
 
     /// Subtract the value of register VY from the value of register VX.
-    fn op_8xy5(&mut self, i: u16) {
-        let (result, carry) =
-            self.v[(i >> 8) as usize & 0xf].overflowing_sub(self.v[(i >> 4) as usize & 0xf]);
-        self.v[(i >> 8) as usize & 0xf] = result;
+    fn op_8xy5(&mut self, x: usize, y: usize) {
+        let (result, carry) = self.v[x].overflowing_sub(self.v[y]);
+        self.v[x] = result;
         self.v[0xf] = !carry as u8;
-        self.step();
+        self.advance();
     }
 
     /// Store the value of register VY shifted right one bit in register VX.
-    fn op_8xy6(&mut self, i: u16) {
-        self.v[0xf] = self.v[(i >> 8) as usize & 0xf] & 0x1;
-        self.v[(i >> 8) as usize & 0xf] >>= 1;
-        self.step();
+    fn op_8xy6(&mut self, x: usize, y: usize) {
+        let value = if self.quirks.shifting {
+            self.v[x]
+        } else {
+            self.v[y]
+        };
+        let flag = value & 0x1;
+        self.v[x] = value >> 1;
+        self.v[0xf] = flag;
+        self.advance();
     }
 
     /// Set the value of register VX to the value of register VY minus the value of register VX.
-    fn op_8xy7(&mut self, i: u16) {
-        let (result, carry) =
-            self.v[(i >> 4) as usize & 0xf].overflowing_sub(self.v[(i >> 8) as usize & 0xf]);
-        self.v[(i >> 8) as usize & 0xf] = result;
+    fn op_8xy7(&mut self, x: usize, y: usize) {
+        let (result, carry) = self.v[y].overflowing_sub(self.v[x]);
+        self.v[x] = result;
         self.v[0xf] = !carry as u8;
-        self.step();
+        self.advance();
     }
 
     /// Store the value of register VY shifted left one bit in register VX.
-    fn op_8xye(&mut self, i: u16) {
-        self.v[0xf] = self.v[(i >> 8) as usize & 0xf] >> 7;
-        self.v[(i >> 8) as usize & 0xf] <<= 1;
-        self.step();
+    fn op_8xye(&mut self, x: usize, y: usize) {
+        let value = if self.quirks.shifting {
+            self.v[x]
+        } else {
+            self.v[y]
+        };
+        let flag = value >> 7;
+        self.v[x] = value << 1;
+        self.v[0xf] = flag;
+        self.advance();
     }
 
     /// Skip the following instruction if the value of register VX doesn't equal the value of register VY.
-    fn op_9xy0(&mut self, i: u16) {
-        if self.v[(i >> 8) as usize & 0xf] != self.v[(i >> 4) as usize & 0xf] {
-            self.step();
+    fn op_9xy0(&mut self, x: usize, y: usize) {
+        if self.v[x] != self.v[y] {
+            self.skip_next();
         }
-        self.step();
+        self.advance();
     }
 
     /// Store address NNN in register I.
-    fn op_annn(&mut self, i: u16) {
-        self.i = i & 0xfff;
-        self.step();
+    fn op_annn(&mut self, nnn: u16) {
+        self.i = nnn;
+        self.advance();
     }
 
-    /// Jump to address NNN + V0.
-    fn op_bnnn(&mut self, i: u16) {
-        self.pc = (i & 0xfff) as usize + self.v[0] as usize;
+    /// Jump to address NNN + V0 (or XNN + VX with the jumping quirk).
+    fn op_bnnn(&mut self, nnn: u16) {
+        let x = if self.quirks.jumping {
+            (nnn >> 8) as usize
+        } else {
+            0
+        };
+        self.pc = nnn as usize + self.v[x] as usize;
     }
 
     /// Set VX to a random number with a mask of NN (0 to 255).
-    fn op_cxkk(&mut self, i: u16) {
-        self.v[(i >> 8) as usize & 0xf] = rand::random::<u8>() & (i & 0xff) as u8;
-        self.step();
+    fn op_cxkk(&mut self, x: usize, kk: u8) -> Result<(), EmulatorError> {
+        let source = match self.rng.source() {
+            Some(addr) => self.read(addr)?,
+            None => 0,
+        };
+        self.v[x] = self.rng.next_u8(source) & kk;
+        self.advance();
+        Ok(())
     }
 
     /// Display the sprite stored at the address held in register I at
     /// position VX, VY with a width of 8 pixels and a height of N pixels.
-    fn op_dxyn(&mut self, i: u16) {
-        let x = self.v[(i >> 8) as usize & 0xf] as usize;
-        let y = self.v[(i >> 4) as usize & 0xf] as usize;
-        self.v[0xf] = 0;
-
-        for row in 0..(i & 0xf) as usize {
-            let sprite_byte = self.memory[self.i as usize + row];
-            for col in 0..8 {
-                let sprite_pixel = sprite_byte >> (7 - col) & 0x1;
-                let x_coord = (x + col) % SCREEN_WIDTH;
-                let y_coord = (y + row) % SCREEN_HEIGHT;
-                if sprite_pixel == 1 {
-                    if self.display[y_coord][x_coord] == 1 {
-                        self.v[0xf] = 1;
+    /// On SUPER-CHIP, a height of 0 draws a 16x16 sprite instead. On XO-CHIP
+    /// the sprite is drawn on each selected plane.
+    fn op_dxyn(&mut self, x: usize, y: usize, n: usize) -> Result<(), EmulatorError> {
+        let set = self.platform.instruction_set;
+        let (sprite_width, sprite_height) = match n {
+            // SUPER-CHIP 1.0 only draws big sprites in high resolution mode
+            0 if set == InstructionSet::SuperChip10 && !self.hires => (8, 16),
+            0 if set >= InstructionSet::SuperChip10 => (16, 16),
+            n => (8, n),
+        };
+        let bytes_per_row = sprite_width / 8;
+        let sprite_size = bytes_per_row * sprite_height;
+        // XO-CHIP draws one sprite per selected plane, stored back to back
+        let planes: Vec<u8> = [1, 2]
+            .into_iter()
+            .filter(|plane| self.planes & plane != 0)
+            .collect();
+        self.check_span(self.i as usize, sprite_size * planes.len())?;
+
+        let (width, height) = (self.display.width(), self.display.height());
+
+        // The starting position always wraps; the quirk decides whether the
+        // rest of the sprite wraps too or is clipped at the edges.
+        let x = self.v[x] as usize % width;
+        let y = self.v[y] as usize % height;
+
+        let mut collided_rows = 0;
+        let mut clipped_rows = 0;
+        for row in 0..sprite_height {
+            if self.quirks.clipping && y + row >= height {
+                clipped_rows += 1;
+                continue;
+            }
+
+            let mut collided = false;
+            for (index, &plane) in planes.iter().enumerate() {
+                let mut bits = 0u16;
+                for byte in 0..bytes_per_row {
+                    let addr = self.i as usize + index * sprite_size + row * bytes_per_row + byte;
+                    bits = bits << 8 | self.read(addr)? as u16;
+                }
+                for col in 0..sprite_width {
+                    if self.quirks.clipping && x + col >= width {
+                        break;
+                    }
+                    let sprite_pixel = bits >> (sprite_width - 1 - col) & 0x1;
+                    if sprite_pixel == 1
+                        && self
+                            .display
+                            .toggle((x + col) % width, (y + row) % height, plane)
+                    {
+                        collided = true;
                     }
-                    self.display[y_coord][x_coord] ^= 1;
                 }
             }
+            collided_rows += collided as u8;
         }
+
+        // SUPER-CHIP 1.1 reports how many rows collided or fell off the
+        // bottom of the screen in high resolution mode.
+        self.v[0xf] = if set == InstructionSet::SuperChip11 && self.hires {
+            collided_rows + clipped_rows
+        } else {
+            (collided_rows > 0) as u8
+        };
+
         self.display_change = true;
-        self.step();
+        self.vblank_wait = self.quirks.display_wait;
+        self.advance();
+        Ok(())
     }
 
     /// Skip the following instruction if the key stored in register VX is pressed.
-    fn op_ex9e(&mut self, i: u16) {
-        if self.keyboard[self.v[(i >> 8) as usize & 0xf] as usize] == 1 {
-            self.step();
+    fn op_ex9e(&mut self, x: usize) {
+        self.input_polled = true;
+        if self.keyboard[self.v[x] as usize & 0xf] == 1 {
+            self.skip_next();
         }
-        self.step();
+        self.advance();
     }
 
     /// Skip the following instruction if the key stored in register VX isn't pressed.
-    fn op_exa1(&mut self, i: u16) {
-        if self.keyboard[self.v[(i >> 8) as usize & 0xf] as usize] == 1 {
-            self.step();
+    fn op_exa1(&mut self, x: usize) {
+        self.input_polled = true;
+        if self.keyboard[self.v[x] as usize & 0xf] == 0 {
+            self.skip_next();
         }
-        self.step();
+        self.advance();
     }
 
     /// Set the value of register VX to the value of the delay timer.
-    fn op_fx07(&mut self, i: u16) {
-        self.v[(i >> 8) as usize & 0xf] = self.delay_timer;
-        self.step();
+    fn op_fx07(&mut self, x: usize) {
+        self.v[x] = self.delay_timer;
+        self.advance();
     }
 
     /// Wait for a keypress and store the result in register VX
-    fn op_fx0a(&mut self, i: u16) {
+    fn op_fx0a(&mut self, x: usize) {
+        self.input_polled = true;
         // wait for a key to be pressed, and store the value in register VX
-        if self.keyboard.iter().all(|&x| x == 0) {
+        if self.keyboard.iter().all(|&k| k == 0) {
             return;
         }
-        let key = self.keyboard.iter().position(|&x| x == 1);
-        self.v[(i >> 8) as usize & 0xf] = key.unwrap() as u8;
-        self.step();
+        let key = self.keyboard.iter().position(|&k| k == 1);
+        self.v[x] = key.unwrap() as u8;
+        self.advance();
     }
 
     /// Set the delay timer to the value of register VX.
-    fn op_fx15(&mut self, i: u16) {
-        self.delay_timer = self.v[(i >> 8) as usize & 0xf];
-        self.step();
+    fn op_fx15(&mut self, x: usize) {
+        self.delay_timer = self.v[x];
+        self.advance();
     }
 
     /// Set the sound timer to the value of register VX.
-    fn op_fx18(&mut self, i: u16) {
-        self.sound_timer = self.v[(i >> 8) as usize & 0xf];
-        self.step();
+    fn op_fx18(&mut self, x: usize) {
+        self.sound_timer = self.v[x];
+        self.advance();
     }
 
     /// Add the value of register VX to the value of register I.
-    fn op_fx1e(&mut self, i: u16) {
-        self.i += self.v[(i >> 8) as usize & 0xf] as u16;
-        self.step();
+    fn op_fx1e(&mut self, x: usize) {
+        self.i = self.i.wrapping_add(self.v[x] as u16);
+        self.advance();
     }
 
     /// Set I to the location of the sprite for the character in register VX.
-    fn op_fx29(&mut self, i: u16) {
-        self.i = self.v[(i >> 8) as usize & 0xf] as u16 * 5;
-        self.step();
+    fn op_fx29(&mut self, x: usize) {
+        self.i = (self.platform.font_start + (self.v[x] as usize & 0xf) * 5) as u16;
+        self.advance();
+    }
+
+    /// Set I to the location of the large sprite for the character in register VX.
+    fn op_fx30(&mut self, x: usize) {
+        self.i = (self.platform.big_font_start + (self.v[x] as usize & 0xf) * 10) as u16;
+        self.advance();
     }
 
     /// Store the binary-coded decimal representation of the value of register VX at addresses I, I+1, and I+2.
-    fn op_fx33(&mut self, i: u16) {
-        let x = self.v[(i >> 8) as usize & 0xf];
-        self.memory[self.i as usize] = x / 100;
-        self.memory[self.i as usize + 1] = (x / 10) % 10;
-        self.memory[self.i as usize + 2] = x % 10;
-        self.step();
+    fn op_fx33(&mut self, x: usize) -> Result<(), EmulatorError> {
+        let i = self.i as usize;
+        self.check_span(i, 3)?;
+        let x = self.v[x];
+        self.write(i, x / 100)?;
+        self.write(i + 1, (x / 10) % 10)?;
+        self.write(i + 2, x % 10)?;
+        self.advance();
+        Ok(())
+    }
+
+    /// Store the values of registers V0 to VX inclusive in memory starting at address I.
+    fn op_fx55(&mut self, x: usize) -> Result<(), EmulatorError> {
+        self.check_span(self.i as usize, x + 1)?;
+        for r in 0..=x {
+            self.write(self.i as usize + r, self.v[r])?;
+        }
+        self.increment_i_after_load_store(x);
+        self.advance();
+        Ok(())
     }
 
-    /// Store the values of registers V0 to VX inclusive in memory starting at address I. I is set to I + X + 1 after operation
-    fn op_fx55(&mut self, i: u16) {
-        for x in 0..((i >> 8) as usize & 0xf) {
-            self.memory[self.i as usize + x] = self.v[x];
-            self.i += 1;
+    /// Fill registers V0 to VX inclusive with values from memory starting at address I.
+    fn op_fx65(&mut self, x: usize) -> Result<(), EmulatorError> {
+        self.check_span(self.i as usize, x + 1)?;
+        for r in 0..=x {
+            self.v[r] = self.read(self.i as usize + r)?;
         }
-        self.step();
+        self.increment_i_after_load_store(x);
+        self.advance();
+        Ok(())
+    }
+
+    /// Store the values of registers V0 to VX inclusive in the RPL user flags.
+    fn op_fx75(&mut self, x: usize) {
+        self.rpl[..=x].copy_from_slice(&self.v[..=x]);
+        self.advance();
+    }
+
+    /// Fill registers V0 to VX inclusive from the RPL user flags.
+    fn op_fx85(&mut self, x: usize) {
+        self.v[..=x].copy_from_slice(&self.rpl[..=x]);
+        self.advance();
+    }
+
+    /// Store the 16-bit address in the following word in register I.
+    fn op_f000(&mut self) -> Result<(), EmulatorError> {
+        self.check_span(self.pc + 2, 2)?;
+        let nnnn = (self.fetch(self.pc + 2)? as u16) << 8 | self.fetch(self.pc + 3)? as u16;
+        self.i = nnnn;
+        self.pc += 4;
+        Ok(())
+    }
+
+    /// Select the bitplanes N that drawing, clearing and scrolling apply to.
+    fn op_fn01(&mut self, n: u8) {
+        self.planes = n & 0x3;
+        self.advance();
     }
 
-    /// Fill registers V0 to VX inclusive with values from memory starting at address I. I is set to I + X + 1 after operation
-    fn op_fx65(&mut self, i: u16) {
-        for x in 0..((i >> 8) as usize & 0xf) {
-            self.v[x] = self.memory[self.i as usize + x];
-            self.i += 1;
+    /// Load the 16-byte audio pattern starting at address I.
+    fn op_f002(&mut self) -> Result<(), EmulatorError> {
+        self.check_span(self.i as usize, 16)?;
+        let mut pattern = [0; 16];
+        for (offset, sample) in pattern.iter_mut().enumerate() {
+            *sample = self.read(self.i as usize + offset)?;
         }
-        self.step();
+        self.audio_pattern = Some(pattern);
+        self.audio_change = true;
+        self.advance();
+        Ok(())
+    }
+
+    /// Set the audio pattern playback pitch to the value of register VX.
+    fn op_fx3a(&mut self, x: usize) {
+        self.pitch = self.v[x];
+        self.audio_change = true;
+        self.advance();
+    }
+
+    fn increment_i_after_load_store(&mut self, x: usize) {
+        let increment = match self.quirks.memory {
+            MemoryQuirk::IncrementXPlusOne => x + 1,
+            MemoryQuirk::IncrementX => x,
+            MemoryQuirk::Unchanged => 0,
+        };
+        self.i = self.i.wrapping_add(increment as u16);
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::processor::{Processor, PROGRAM_START};
+    use crate::error::{EmulatorError, ErrorPolicies, ErrorPolicy};
+    use crate::platform::Platform;
+    use crate::processor::{MemoryAccess, Processor, Tone};
+    use crate::quirks::{MemoryQuirk, Quirks};
+    use crate::rng::{Rng, RngMode};
+    use crate::savestate::SaveStateError;
+
+    const PROGRAM_START: usize = 0x200;
 
     #[test]
     fn test_load_program() {
+        let path = std::env::temp_dir().join("virtual_machine_test_load_program.rom");
+        std::fs::write(&path, [0x6a, 0x02, 0x6b, 0x0c]).unwrap();
+
         let mut vm = Processor::new();
         assert_eq!(vm.memory[PROGRAM_START], 0x0000);
-        vm.load_program("./programs/pong.rom");
+        vm.load_program(path.to_str().unwrap()).unwrap();
         assert_eq!(vm.memory[PROGRAM_START], 0x6a);
     }
 
     #[test]
-    fn test_resolve_instruction() {}
+    fn test_step() {
+        let mut vm = Processor::new();
+        vm.load_rom(&[0x6a, 0x02, 0x7a, 0x03]).unwrap();
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.v[0xa], 0x05);
+        assert_eq!(vm.pc, PROGRAM_START + 4);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut vm = Processor::new();
+        vm.load_rom(&[0x6a, 0x02, 0xf0, 0x0a]).unwrap();
+        vm.step().unwrap();
+        vm.set_key(0x3);
+        vm.reset();
+        assert_eq!(vm.v[0xa], 0);
+        assert_eq!(vm.pc, PROGRAM_START);
+        assert_eq!(vm.keyboard, [0; 16]);
+        assert_eq!(vm.memory[PROGRAM_START], 0x6a);
+    }
+
+    #[test]
+    fn test_rom_too_large() {
+        let mut vm = Processor::new();
+        let rom = vec![0; 4096 - PROGRAM_START + 1];
+        assert!(matches!(
+            vm.load_rom(&rom),
+            Err(EmulatorError::RomTooLarge {
+                size: 3585,
+                max: 3584
+            })
+        ));
+    }
 
     #[test]
-    fn test_display() {}
+    fn test_stack_errors() {
+        let mut vm = Processor::new();
+        vm.load_rom(&[0x00, 0xee]).unwrap();
+        assert!(matches!(
+            vm.step(),
+            Err(EmulatorError::StackUnderflow { pc: PROGRAM_START })
+        ));
+        assert_eq!(vm.pc, PROGRAM_START);
+
+        // Calling ourselves forever overflows the 16-entry stack
+        let mut vm = Processor::new();
+        vm.load_rom(&[0x22, 0x00]).unwrap();
+        for _ in 0..16 {
+            vm.step().unwrap();
+        }
+        assert!(matches!(
+            vm.step(),
+            Err(EmulatorError::StackOverflow { .. })
+        ));
+
+        vm.set_error_policies(ErrorPolicies {
+            stack: ErrorPolicy::Wrap,
+            ..ErrorPolicies::default()
+        });
+        vm.step().unwrap();
+        assert_eq!(vm.sp, 1);
+    }
+
+    #[test]
+    fn test_memory_errors() {
+        // LD I, 0xFFF; LD B, V0
+        let rom = [0xaf, 0xff, 0xf0, 0x33];
+
+        let mut vm = Processor::new();
+        vm.load_rom(&rom).unwrap();
+        vm.set_error_policies(ErrorPolicies {
+            memory: ErrorPolicy::Halt,
+            ..ErrorPolicies::default()
+        });
+        vm.step().unwrap();
+        assert!(matches!(
+            vm.step(),
+            Err(EmulatorError::MemoryOutOfBounds {
+                addr: 0x1000,
+                pc: 0x202
+            })
+        ));
+
+        let mut vm = Processor::new();
+        vm.load_rom(&rom).unwrap();
+        vm.set_error_policies(ErrorPolicies {
+            memory: ErrorPolicy::Log,
+            ..ErrorPolicies::default()
+        });
+        vm.v[0] = 123;
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.memory[0xfff], 1);
+        assert_eq!(vm.memory[0x000], 0);
+        assert_eq!(vm.take_logged_errors().len(), 2);
+
+        let mut vm = Processor::new();
+        vm.load_rom(&rom).unwrap();
+        vm.v[0] = 123;
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.memory[0xfff], 1);
+        assert_eq!(vm.memory[0x000], 2);
+        assert_eq!(vm.memory[0x001], 3);
+    }
+
+    #[test]
+    fn test_key_skips() {
+        // SKP V0 and SKNP V0 with V0 = 5. SKNP skips while the key is up and
+        // runs on while it's held; it used to do the opposite.
+        let run = |opcode: u8, held: bool| {
+            let mut vm = Processor::new();
+            vm.load_rom(&[0xe0, opcode]).unwrap();
+            vm.v[0] = 5;
+            if held {
+                vm.set_key(5);
+            }
+            vm.step().unwrap();
+            vm.pc
+        };
+        assert_eq!(run(0x9e, true), PROGRAM_START + 4);
+        assert_eq!(run(0x9e, false), PROGRAM_START + 2);
+        assert_eq!(run(0xa1, true), PROGRAM_START + 2);
+        assert_eq!(run(0xa1, false), PROGRAM_START + 4);
+    }
+
+    #[test]
+    fn test_invalid_opcode() {
+        let mut vm = Processor::new();
+        vm.load_rom(&[0xff, 0xff]).unwrap();
+        assert!(matches!(
+            vm.step(),
+            Err(EmulatorError::InvalidOpcode {
+                opcode: 0xffff,
+                pc: PROGRAM_START
+            })
+        ));
+
+        vm.set_error_policies(ErrorPolicies {
+            invalid_opcode: ErrorPolicy::Log,
+            ..ErrorPolicies::default()
+        });
+        vm.step().unwrap();
+        assert_eq!(vm.pc, PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn test_load_store_quirk() {
+        // LD I, 0x300; LD [I], V2; LD V0..V2, [I]
+        let rom = [0xa3, 0x00, 0xf2, 0x55, 0xf2, 0x65];
+        for (memory, i) in [
+            (MemoryQuirk::IncrementXPlusOne, 0x306),
+            (MemoryQuirk::IncrementX, 0x304),
+            (MemoryQuirk::Unchanged, 0x300),
+        ] {
+            let mut vm = Processor::new();
+            vm.set_quirks(Quirks {
+                memory,
+                ..Quirks::default()
+            });
+            vm.load_rom(&rom).unwrap();
+            vm.v[..3].copy_from_slice(&[1, 2, 3]);
+            vm.step().unwrap();
+            vm.step().unwrap();
+            assert_eq!(vm.memory[0x300..0x304], [1, 2, 3, 0]);
+            vm.step().unwrap();
+            assert_eq!(vm.i, i);
+        }
+    }
+
+    #[test]
+    fn test_shift_and_jump_quirks() {
+        // SHR V0, V1; JP V0, 0x300 (or JP V3, 0x300)
+        let rom = [0x80, 0x16, 0xb3, 0x00];
+
+        let mut vm = Processor::new();
+        vm.set_quirks(Quirks {
+            shifting: false,
+            jumping: false,
+            ..Quirks::default()
+        });
+        vm.load_rom(&rom).unwrap();
+        vm.v[1] = 0x7;
+        vm.v[3] = 0x10;
+        vm.step().unwrap();
+        assert_eq!((vm.v[0], vm.v[0xf]), (0x3, 1));
+        vm.step().unwrap();
+        assert_eq!(vm.pc, 0x303);
+
+        let mut vm = Processor::new();
+        vm.set_quirks(Quirks {
+            shifting: true,
+            jumping: true,
+            ..Quirks::default()
+        });
+        vm.load_rom(&rom).unwrap();
+        vm.v[1] = 0x7;
+        vm.v[3] = 0x10;
+        vm.step().unwrap();
+        assert_eq!((vm.v[0], vm.v[0xf]), (0x0, 0));
+        vm.step().unwrap();
+        assert_eq!(vm.pc, 0x310);
+    }
+
+    #[test]
+    fn test_clipping_quirk() {
+        // LD I, font 0; DRW V0, V1, 5 at (62, 30)
+        for (clipping, wrapped) in [(false, 1), (true, 0)] {
+            let mut vm = Processor::new();
+            vm.set_quirks(Quirks {
+                clipping,
+                ..Quirks::default()
+            });
+            vm.load_rom(&[0xa0, 0x50, 0xd0, 0x15]).unwrap();
+            vm.v[0] = 62;
+            vm.v[1] = 30;
+            vm.step().unwrap();
+            vm.step().unwrap();
+            assert_eq!(vm.display.get(62, 30), 1);
+            assert_eq!(vm.display.get(1, 0), wrapped);
+        }
+    }
+
+    #[test]
+    fn test_display_wait_quirk() {
+        // Two draws in a row only get as far as the first one in a frame
+        let mut vm = Processor::new();
+        vm.set_quirks(Quirks {
+            display_wait: true,
+            ..Quirks::default()
+        });
+        vm.load_rom(&[0xd0, 0x01, 0xd0, 0x01]).unwrap();
+        vm.run_frame(10).unwrap();
+        assert_eq!(vm.pc, PROGRAM_START + 2);
+        vm.run_frame(10).unwrap();
+        assert_eq!(vm.pc, PROGRAM_START + 4);
+    }
+
+    #[test]
+    fn test_platform() {
+        let mut vm = Processor::with_platform(Platform::vip());
+        assert_eq!(vm.stack.len(), 12);
+        assert!(vm.quirks().vf_reset);
+
+        // LD V0, 0x4; LD F, V0
+        vm.load_rom(&[0x60, 0x04, 0xf0, 0x29]).unwrap();
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.i, 0x50 + 4 * 5);
+        assert_eq!(vm.memory[vm.i as usize], 0xa0);
+
+        let vm = Processor::with_platform(Platform::xochip());
+        assert_eq!(vm.memory.len(), 0x10000);
+    }
+
+    #[test]
+    fn test_superchip() {
+        // HIGH; LD HF, V0; DRW V1, V1, 0; SCR; LD R, V1; EXIT
+        let rom = [
+            0x00, 0xff, 0xf0, 0x30, 0xd1, 0x10, 0x00, 0xfb, 0xf1, 0x75, 0x00, 0xfd,
+        ];
+        let mut vm = Processor::with_platform(Platform::schip11());
+        vm.load_rom(&rom).unwrap();
+        vm.v[0] = 8;
+        vm.v[1] = 0x7e;
+
+        vm.step().unwrap();
+        assert!(vm.is_hires());
+        assert_eq!(vm.framebuffer().width(), 128);
+        vm.step().unwrap();
+        assert_eq!(vm.memory[vm.i as usize], 0x3c);
+
+        // A 16x16 sprite at (126, 126 % 64) is clipped after 2 columns and 2 rows
+        vm.set_quirks(Quirks {
+            clipping: true,
+            ..vm.quirks()
+        });
+        vm.step().unwrap();
+        assert_eq!(vm.v[0xf], 14);
+        assert_eq!(vm.display.get(126, 63), 1);
+        assert_eq!(vm.display.get(127, 63), 1);
+        let lit = vm.display.rows().flatten().filter(|&&p| p == 1).count();
+        assert_eq!(lit, 2);
+
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.rpl[..2], [8, 0x7e]);
+
+        vm.run_frame(10).unwrap();
+        assert!(vm.is_halted());
+        assert_eq!(vm.pc, PROGRAM_START + 10);
+
+        vm.reset();
+        assert!(!vm.is_hires());
+        assert_eq!(vm.rpl[..2], [8, 0x7e]);
+    }
+
+    #[test]
+    fn test_superchip_on_chip8() {
+        // HIGH is a machine code call on CHIP-8, FX75 doesn't exist
+        let mut vm = Processor::new();
+        vm.load_rom(&[0x00, 0xff, 0xf1, 0x75]).unwrap();
+        vm.step().unwrap();
+        assert!(!vm.is_hires());
+        assert!(matches!(
+            vm.step(),
+            Err(EmulatorError::InvalidOpcode { opcode: 0xf175, .. })
+        ));
+    }
+
+    #[test]
+    fn test_xochip() {
+        let rom = [
+            0x30, 0x00, // SE V0, 0x00
+            0xf0, 0x00, 0x12, 0x00, // LD I, LONG 0x1200 (skipped)
+            0xf0, 0x00, 0xe0, 0x00, // LD I, LONG 0xE000
+            0x52, 0x12, // LD [I], V2-V1
+            0x53, 0x43, // LD V3-V4, [I]
+            0xf3, 0x01, // PLANE 3
+            0xa2, 0x20, // LD I, 0x220
+            0xd0, 0x01, // DRW V0, V0, 1
+            0xf2, 0x01, // PLANE 2
+            0x00, 0xfb, // SCR
+            0x00, 0xe0, // CLS
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // padding
+            0x80, 0xc0, // one sprite row for each plane
+        ];
+        let mut vm = Processor::with_platform(Platform::xochip());
+        vm.load_rom(&rom).unwrap();
+        vm.v[1] = 0x11;
+        vm.v[2] = 0x22;
+
+        vm.step().unwrap();
+        assert_eq!(vm.pc, PROGRAM_START + 6);
+        vm.step().unwrap();
+        assert_eq!(vm.i, 0xe000);
+
+        vm.step().unwrap();
+        assert_eq!(vm.memory[0xe000..0xe002], [0x22, 0x11]);
+        assert_eq!(vm.i, 0xe000);
+        vm.step().unwrap();
+        assert_eq!(vm.v[3..5], [0x22, 0x11]);
+
+        for _ in 0..3 {
+            vm.step().unwrap();
+        }
+        assert_eq!(vm.display.get(0, 0), 3);
+        assert_eq!(vm.display.get(1, 0), 2);
+
+        // Scrolling and clearing only touch plane 2
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.display.get(0, 0), 1);
+        assert_eq!(vm.display.get(4, 0), 2);
+        vm.step().unwrap();
+        assert_eq!(vm.display.get(0, 0), 1);
+        assert_eq!(vm.display.get(4, 0), 0);
+    }
+
+    #[test]
+    fn test_xochip_audio() {
+        // LD I, 0x300; LD AUDIO, [I]; LD PITCH, V0
+        let mut vm = Processor::with_platform(Platform::xochip());
+        vm.load_rom(&[0xa3, 0x00, 0xf0, 0x02, 0xf0, 0x3a]).unwrap();
+        vm.memory[0x300..0x310].fill(0xf0);
+        vm.v[0] = 112;
+        assert_eq!(vm.tone(), Tone::Buzzer);
+
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert!(vm.take_audio_change());
+        assert_eq!(
+            vm.tone(),
+            Tone::Pattern {
+                samples: [0xf0; 16],
+                rate: 4000.
+            }
+        );
+
+        // 48 steps up doubles the rate
+        vm.step().unwrap();
+        assert!(vm.take_audio_change());
+        assert!(matches!(vm.tone(), Tone::Pattern { rate, .. } if rate == 8000.));
+        assert!(!vm.take_audio_change());
+    }
+
+    #[test]
+    fn test_save_state() {
+        // HIGH; RND V0, 0xFF; DRW V1, V1, 5; JP 0x202
+        let rom = [0x00, 0xff, 0xc0, 0xff, 0xd1, 0x15, 0x12, 0x02];
+        let mut vm = Processor::with_platform(Platform::schip11());
+        vm.load_rom(&rom).unwrap();
+        vm.set_quirks(Quirks {
+            clipping: false,
+            ..vm.quirks()
+        });
+        vm.run_frame(4).unwrap();
+        vm.set_key(0x5);
+        let state = vm.save_state();
+
+        let mut copy = Processor::new();
+        copy.load_rom(&rom).unwrap();
+        copy.load_state(&state).unwrap();
+        assert_eq!(copy.platform().name, "schip11");
+        assert!(!copy.quirks().clipping);
+        assert_eq!(copy.save_state(), state);
+
+        // Both continue identically, random numbers included
+        vm.run_frame(20).unwrap();
+        copy.run_frame(20).unwrap();
+        assert_eq!(copy.v, vm.v);
+        assert_eq!(copy.framebuffer(), vm.framebuffer());
+
+        let mut other = Processor::new();
+        other.load_rom(&[0x12, 0x00]).unwrap();
+        assert!(matches!(
+            other.load_state(&state),
+            Err(EmulatorError::SaveState(SaveStateError::RomMismatch))
+        ));
+        assert!(matches!(
+            copy.load_state(&state[..state.len() - 1]),
+            Err(EmulatorError::SaveState(SaveStateError::Corrupt))
+        ));
+        assert_eq!(copy.framebuffer(), vm.framebuffer());
+    }
+
+    #[test]
+    fn test_seed() {
+        // RND V0, 0xFF; RND V1, 0xFF
+        let rom = [0xc0, 0xff, 0xc1, 0xff];
+        let run = |rng| {
+            let mut vm = Processor::new();
+            vm.load_rom(&rom).unwrap();
+            vm.set_rng(rng);
+            vm.run_frame(2).unwrap();
+            vm.v
+        };
+        let seeded = run(Rng::new(RngMode::Xorshift, 1234));
+        assert_eq!(run(Rng::new(RngMode::Xorshift, 1234)), seeded);
+        assert_ne!(run(Rng::new(RngMode::Xorshift, 4321)), seeded);
+
+        // The VIP generator reads the big font at 0x100
+        let vip = run(Rng::new(RngMode::Vip, 0));
+        assert_eq!(vip[..2], [0x03, 0x41]);
+
+        // That read is recorded, so read watchpoints see it
+        let mut vm = Processor::new();
+        vm.load_rom(&rom).unwrap();
+        vm.set_rng(Rng::new(RngMode::Vip, 0));
+        vm.record_memory_accesses(true);
+        vm.step().unwrap();
+        assert_eq!(
+            vm.take_memory_accesses(),
+            [MemoryAccess::Read {
+                addr: 0x101,
+                value: 0x03
+            }]
+        );
+
+        let mut vm = Processor::new();
+        vm.load_rom(&rom).unwrap();
+        vm.set_rng(Rng::new(RngMode::Xorshift, 1234));
+        vm.run_frame(2).unwrap();
+        vm.reset();
+        vm.run_frame(2).unwrap();
+        assert_eq!(vm.v, seeded);
+    }
+
+    #[test]
+    fn test_resolve_instruction() {
+        // LD V0, 0x2A split across the end of memory
+        let mut vm = Processor::new();
+        vm.memory[0xfff] = 0x60;
+        vm.memory[0x000] = 0x2a;
+        vm.pc = 0xfff;
+        vm.step().unwrap();
+        assert_eq!(vm.v[0], 0x2a);
+
+        let mut vm = Processor::new();
+        vm.set_error_policies(ErrorPolicies {
+            memory: ErrorPolicy::Halt,
+            ..ErrorPolicies::default()
+        });
+        vm.memory[0xfff] = 0x60;
+        vm.pc = 0xfff;
+        assert!(matches!(
+            vm.step(),
+            Err(EmulatorError::MemoryOutOfBounds {
+                addr: 0x1000,
+                pc: 0xfff
+            })
+        ));
+    }
+
+    #[test]
+    fn test_display() {
+        // LD F, V0; DRW V1, V2, 5; DRW V1, V2, 5
+        let rom = [0xf0, 0x29, 0xd1, 0x25, 0xd1, 0x25];
+        let mut vm = Processor::new();
+        vm.load_rom(&rom).unwrap();
+        vm.v[1] = 62;
+        vm.v[2] = 1;
+        vm.step().unwrap();
+        vm.step().unwrap();
+        // The top of the 0 glyph wraps around the right edge
+        let row: Vec<u8> = (0..4)
+            .map(|x| vm.framebuffer().get((62 + x) % 64, 1))
+            .collect();
+        assert_eq!(row, [1, 1, 1, 1]);
+        assert_eq!(vm.framebuffer().get(63, 2), 0);
+        assert_eq!(vm.framebuffer().get(1, 2), 1);
+        assert_eq!(vm.v[0xf], 0);
+
+        vm.step().unwrap();
+        assert!(vm.framebuffer().rows().flatten().all(|&pixel| pixel == 0));
+        assert_eq!(vm.v[0xf], 1);
+    }
 }
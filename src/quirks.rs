@@ -0,0 +1,111 @@
+// Behaviours that differ between CHIP-8 implementations. The names follow
+// the quirks test ROM so results can be compared directly.
+use std::str::FromStr;
+
+/// How FX55/FX65 leave register I afterwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryQuirk {
+    /// I is set to I + X + 1, as on the COSMAC VIP.
+    IncrementXPlusOne,
+    /// I is set to I + X, as on CHIP-48 and SUPER-CHIP 1.0.
+    IncrementX,
+    /// I is left alone, as on SUPER-CHIP 1.1.
+    Unchanged,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// 8XY1, 8XY2 and 8XY3 reset VF to zero.
+    pub vf_reset: bool,
+    pub memory: MemoryQuirk,
+    /// DXYN waits for the start of the next frame before continuing.
+    pub display_wait: bool,
+    /// Sprites are clipped at the screen edges instead of wrapping around.
+    pub clipping: bool,
+    /// 8XY6 and 8XYE shift VX in place instead of storing VY shifted in VX.
+    pub shifting: bool,
+    /// BNNN jumps to XNN + VX instead of NNN + V0.
+    pub jumping: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Self {
+            vf_reset: false,
+            memory: MemoryQuirk::IncrementXPlusOne,
+            display_wait: false,
+            clipping: false,
+            shifting: true,
+            jumping: false,
+        }
+    }
+}
+
+impl FromStr for MemoryQuirk {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "x+1" => Ok(MemoryQuirk::IncrementXPlusOne),
+            "x" => Ok(MemoryQuirk::IncrementX),
+            "none" => Ok(MemoryQuirk::Unchanged),
+            _ => Err(format!(
+                "unknown memory quirk {} (expected x+1, x or none)",
+                s
+            )),
+        }
+    }
+}
+
+impl Quirks {
+    /// Apply a comma separated list of changes such as
+    /// `clipping,no-shifting,memory=x`. A bare name turns a quirk on and a
+    /// `no-` prefix turns it off.
+    pub fn apply(&mut self, spec: &str) -> Result<(), String> {
+        for item in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            if let Some(value) = item.strip_prefix("memory=") {
+                self.memory = value.parse()?;
+                continue;
+            }
+
+            let (name, enabled) = match item.strip_prefix("no-") {
+                Some(name) => (name, false),
+                None => (item, true),
+            };
+            match name {
+                "vf-reset" => self.vf_reset = enabled,
+                "memory" if enabled => self.memory = MemoryQuirk::IncrementXPlusOne,
+                "memory" => self.memory = MemoryQuirk::Unchanged,
+                "display-wait" => self.display_wait = enabled,
+                "clipping" => self.clipping = enabled,
+                "shifting" => self.shifting = enabled,
+                "jumping" => self.jumping = enabled,
+                _ => return Err(format!("unknown quirk {}", name)),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryQuirk, Quirks};
+
+    #[test]
+    fn test_apply() {
+        let mut quirks = Quirks::default();
+        quirks
+            .apply("vf-reset, clipping,no-shifting,memory=x")
+            .unwrap();
+        assert!(quirks.vf_reset);
+        assert!(quirks.clipping);
+        assert!(!quirks.shifting);
+        assert_eq!(quirks.memory, MemoryQuirk::IncrementX);
+
+        quirks.apply("no-memory").unwrap();
+        assert_eq!(quirks.memory, MemoryQuirk::Unchanged);
+
+        assert!(quirks.apply("warp-speed").is_err());
+        assert!(quirks.apply("memory=lots").is_err());
+    }
+}
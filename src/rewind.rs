@@ -0,0 +1,250 @@
+// Rewinding keeps a bounded history of save states. The newest state is
+// kept whole and each older one as its difference from the next newer one,
+// XORed and run-length encoded, since consecutive snapshots differ in only
+// a few bytes.
+use crate::processor::Processor;
+use std::collections::VecDeque;
+
+/// The default memory budget, 16 MiB.
+pub const DEFAULT_MEMORY: usize = 16 << 20;
+
+enum Entry {
+    /// The compressed XOR with the next newer state.
+    Delta(Vec<u8>),
+    /// The whole compressed state, used when the size of the state changed.
+    Full(Vec<u8>),
+}
+
+impl Entry {
+    fn size(&self) -> usize {
+        match self {
+            Entry::Delta(bytes) | Entry::Full(bytes) => bytes.len(),
+        }
+    }
+}
+
+pub struct Rewind {
+    interval: u32,
+    memory: usize,
+    frames: u32,
+    newest: Option<Vec<u8>>,
+    history: VecDeque<Entry>,
+    // Bytes used by `history`
+    used: usize,
+}
+
+impl Default for Rewind {
+    fn default() -> Self {
+        Self::new(1, DEFAULT_MEMORY)
+    }
+}
+
+impl Rewind {
+    /// Keep a snapshot every `interval` frames in at most `memory` bytes,
+    /// forgetting the oldest ones once that fills up.
+    pub fn new(interval: u32, memory: usize) -> Self {
+        Self {
+            interval: interval.max(1),
+            memory,
+            frames: 0,
+            newest: None,
+            history: VecDeque::new(),
+            used: 0,
+        }
+    }
+
+    /// Call once per frame while the program runs forwards.
+    pub fn record(&mut self, processor: &Processor) {
+        self.frames += 1;
+        if self.frames >= self.interval {
+            self.frames = 0;
+            self.push(processor.save_state());
+        }
+    }
+
+    /// Call once per frame while rewinding. Every `interval` frames the
+    /// processor goes back one snapshot, so time runs backwards at the same
+    /// speed it ran forwards. Held keys are left as they are. Returns false
+    /// once there is no history left.
+    pub fn step_back(&mut self, processor: &mut Processor) -> bool {
+        self.frames += 1;
+        if self.frames < self.interval {
+            return !self.is_empty();
+        }
+        self.frames = 0;
+        let Some(state) = self.pop() else {
+            return false;
+        };
+
+        let keys: Vec<bool> = (0..16).map(|key| processor.is_key_down(key)).collect();
+        // Every snapshot came from this processor, so it always loads
+        processor.load_state(&state).unwrap();
+        for (key, down) in (0..16).zip(keys) {
+            if down {
+                processor.set_key(key);
+            } else {
+                processor.release_key(key);
+            }
+        }
+        true
+    }
+
+    /// Add a state from `Processor::save_state` as the newest.
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(newest) = self.newest.take() {
+            let entry = if newest.len() == state.len() {
+                Entry::Delta(compress(&xor(&newest, &state)))
+            } else {
+                Entry::Full(compress(&newest))
+            };
+            self.used += entry.size();
+            self.history.push_back(entry);
+        }
+
+        if state.len() <= self.memory {
+            self.newest = Some(state);
+        }
+        while self.memory_used() > self.memory {
+            let entry = self.history.pop_front().unwrap();
+            self.used -= entry.size();
+        }
+    }
+
+    /// Remove and return the newest state.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let state = self.newest.take()?;
+        if let Some(entry) = self.history.pop_back() {
+            self.used -= entry.size();
+            self.newest = Some(match entry {
+                Entry::Delta(delta) => xor(&state, &decompress(&delta)),
+                Entry::Full(full) => decompress(&full),
+            });
+        }
+        Some(state)
+    }
+
+    /// The number of states kept.
+    pub fn len(&self) -> usize {
+        self.history.len() + self.newest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    /// The bytes taken up by the kept states.
+    pub fn memory_used(&self) -> usize {
+        self.used + self.newest.as_ref().map_or(0, Vec::len)
+    }
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b).map(|(a, b)| a ^ b).collect()
+}
+
+/// Replace each run of zeros with a zero followed by the run length as a
+/// LEB128 number. Other bytes are copied as they are.
+fn compress(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != 0 {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let run = bytes[i..].iter().take_while(|&&b| b == 0).count();
+        out.push(0);
+        let mut n = run;
+        while n >= 0x80 {
+            out.push(n as u8 | 0x80);
+            n >>= 7;
+        }
+        out.push(n as u8);
+        i += run;
+    }
+    out
+}
+
+fn decompress(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut bytes = bytes.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte != 0 {
+            out.push(byte);
+            continue;
+        }
+        let (mut run, mut shift) = (0, 0);
+        for &b in bytes.by_ref() {
+            run |= (b as usize & 0x7f) << shift;
+            shift += 7;
+            if b < 0x80 {
+                break;
+            }
+        }
+        out.resize(out.len() + run, 0);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compress, decompress, Rewind};
+    use crate::processor::Processor;
+
+    #[test]
+    fn test_compress() {
+        let mut bytes = vec![0; 1000];
+        bytes[3] = 7;
+        bytes[999] = 1;
+        let compressed = compress(&bytes);
+        assert!(compressed.len() < 10);
+        assert_eq!(decompress(&compressed), bytes);
+        assert_eq!(decompress(&compress(&[])), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_rewind() {
+        // ADD V0, 1; JP 0x200
+        let mut vm = Processor::new();
+        vm.load_rom(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+        let mut rewind = Rewind::new(2, usize::MAX);
+        for _ in 0..10 {
+            vm.run_frame(2).unwrap();
+            rewind.record(&vm);
+        }
+        assert_eq!(rewind.len(), 5);
+        assert_eq!(vm.registers()[0], 10);
+
+        // Back one snapshot every other frame: to 10, then 8
+        vm.set_key(0x4);
+        for _ in 0..4 {
+            assert!(rewind.step_back(&mut vm));
+        }
+        assert_eq!(vm.registers()[0], 8);
+        assert!(vm.is_key_down(0x4));
+        for _ in 0..6 {
+            rewind.step_back(&mut vm);
+        }
+        assert_eq!(vm.registers()[0], 2);
+        assert!(!rewind.step_back(&mut vm));
+    }
+
+    #[test]
+    fn test_memory_bound() {
+        let mut vm = Processor::new();
+        vm.load_rom(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+        let state_size = vm.save_state().len();
+        let mut rewind = Rewind::new(1, state_size + 100);
+        for _ in 0..1000 {
+            vm.run_frame(1).unwrap();
+            rewind.record(&vm);
+        }
+        assert!(rewind.memory_used() <= state_size + 100);
+        assert!(rewind.len() > 1 && rewind.len() < 1000);
+
+        let mut tiny = Rewind::new(1, 10);
+        tiny.record(&vm);
+        assert!(tiny.is_empty());
+    }
+}
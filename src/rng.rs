@@ -0,0 +1,134 @@
+// The random number source behind CXKK. It lives inside the machine rather
+// than using the thread RNG so runs can be repeated from a seed and its
+// state can be saved and restored.
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RngMode {
+    /// A xorshift64* generator.
+    Xorshift,
+    /// The shape of the COSMAC VIP interpreter's method, not its numbers: a
+    /// 16-bit counter is incremented, its low byte indexes a byte in the
+    /// page at 0x100, and that byte is added to its high byte, which is the
+    /// result. The VIP indexed its interpreter's own code, which isn't
+    /// included here, so this indexes the emulated memory at 0x100 instead,
+    /// usually the big font. A ROM that depends on the VIP's exact sequence
+    /// won't see it.
+    Vip,
+}
+
+impl FromStr for RngMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "xorshift" => Ok(RngMode::Xorshift),
+            "vip" => Ok(RngMode::Vip),
+            _ => Err(format!("unknown RNG {} (expected xorshift or vip)", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rng {
+    mode: RngMode,
+    state: u64,
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Self::from_entropy(RngMode::Xorshift)
+    }
+}
+
+impl Rng {
+    /// Start from `seed`, or continue from a state returned by `state`.
+    /// The VIP counter only uses the low 16 bits.
+    pub fn new(mode: RngMode, seed: u64) -> Self {
+        let state = match mode {
+            // Xorshift gets stuck at zero, so that is replaced with a fixed state
+            RngMode::Xorshift if seed == 0 => 0x9e37_79b9_7f4a_7c15,
+            RngMode::Xorshift => seed,
+            RngMode::Vip => seed & 0xffff,
+        };
+        Self { mode, state }
+    }
+
+    /// Start from an unpredictable seed.
+    pub fn from_entropy(mode: RngMode) -> Self {
+        Self::new(mode, rand::random())
+    }
+
+    pub fn mode(&self) -> RngMode {
+        self.mode
+    }
+
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    /// The address of the byte the next call to `next_u8` adds in, if the
+    /// mode reads one. The caller fetches it so the read is seen like any
+    /// other memory access.
+    pub fn source(&self) -> Option<usize> {
+        match self.mode {
+            RngMode::Xorshift => None,
+            RngMode::Vip => Some(0x100 + (self.state as u8).wrapping_add(1) as usize),
+        }
+    }
+
+    /// Produce the next byte. `source` is the byte at `source()`, and is
+    /// ignored when there is no such address.
+    pub fn next_u8(&mut self, source: u8) -> u8 {
+        match self.mode {
+            RngMode::Xorshift => {
+                self.state ^= self.state >> 12;
+                self.state ^= self.state << 25;
+                self.state ^= self.state >> 27;
+                (self.state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 56) as u8
+            }
+            RngMode::Vip => {
+                let counter = (self.state as u16).wrapping_add(1);
+                let [low, high] = counter.to_le_bytes();
+                let high = high.wrapping_add(source);
+                self.state = u16::from_le_bytes([low, high]) as u64;
+                high
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Rng, RngMode};
+
+    #[test]
+    fn test_from_state() {
+        let mut rng = Rng::new(RngMode::Xorshift, 1);
+        assert_eq!(rng.source(), None);
+        rng.next_u8(0);
+        let mut copy = Rng::new(rng.mode(), rng.state());
+        let bytes: Vec<u8> = (0..8).map(|_| rng.next_u8(0)).collect();
+        let copied: Vec<u8> = (0..8).map(|_| copy.next_u8(0)).collect();
+        assert_eq!(bytes, copied);
+        assert_ne!(Rng::new(RngMode::Xorshift, 0).state(), 0);
+    }
+
+    #[test]
+    fn test_vip() {
+        let mut memory = [0; 0x200];
+        memory[0x101] = 0x10;
+        memory[0x102] = 0x05;
+        let mut rng = Rng::new(RngMode::Vip, 0x2000);
+        let mut next = || {
+            let source = memory[rng.source().unwrap()];
+            rng.next_u8(source)
+        };
+        assert_eq!(next(), 0x30);
+        assert_eq!(next(), 0x35);
+        assert_eq!(next(), 0x35);
+        assert_eq!(rng.state(), 0x3503);
+        assert_eq!(Rng::new(RngMode::Vip, 0x12ff).source(), Some(0x100));
+        assert!("lcg".parse::<RngMode>().is_err());
+    }
+}
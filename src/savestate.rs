@@ -0,0 +1,283 @@
+// A versioned binary format holding the complete machine state. A state
+// starts with a header identifying the format, the emulator that wrote it
+// and the ROM it belongs to. All numbers are little endian.
+use crate::quirks::{MemoryQuirk, Quirks};
+use std::path::{Path, PathBuf};
+use std::{error, fmt};
+
+pub const MAGIC: [u8; 4] = *b"C8SS";
+
+/// Bumped whenever the layout changes. States in other versions are rejected.
+pub const FORMAT_VERSION: u16 = 2;
+
+/// The number of quick save slots.
+pub const SLOTS: u8 = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SaveStateError {
+    NotASaveState,
+    UnsupportedVersion(u16),
+    RomMismatch,
+    UnknownPlatform(String),
+    Corrupt,
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveStateError::NotASaveState => write!(f, "not a save state"),
+            SaveStateError::UnsupportedVersion(version) => write!(
+                f,
+                "save state format version {} isn't supported (expected {})",
+                version, FORMAT_VERSION
+            ),
+            SaveStateError::RomMismatch => write!(f, "save state belongs to a different ROM"),
+            SaveStateError::UnknownPlatform(name) => {
+                write!(f, "save state uses unknown platform {}", name)
+            }
+            SaveStateError::Corrupt => write!(f, "save state is corrupt"),
+        }
+    }
+}
+
+impl error::Error for SaveStateError {}
+
+/// The header at the start of every save state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub format_version: u16,
+    /// The version of this crate that wrote the state.
+    pub emulator_version: String,
+    /// The `rom_hash` of the program that was loaded.
+    pub rom_hash: u64,
+}
+
+impl Header {
+    pub fn new(rom_hash: u64) -> Self {
+        Self {
+            format_version: FORMAT_VERSION,
+            emulator_version: env!("CARGO_PKG_VERSION").to_string(),
+            rom_hash,
+        }
+    }
+
+    /// Read just the header of a save state.
+    pub fn parse(bytes: &[u8]) -> Result<Self, SaveStateError> {
+        Self::read(&mut Reader::new(bytes))
+    }
+
+    pub(crate) fn write(&self, w: &mut Writer) {
+        w.raw(&MAGIC);
+        w.u16(self.format_version);
+        w.str(&self.emulator_version);
+        w.u64(self.rom_hash);
+    }
+
+    pub(crate) fn read(r: &mut Reader) -> Result<Self, SaveStateError> {
+        if r.array::<4>() != Ok(MAGIC) {
+            return Err(SaveStateError::NotASaveState);
+        }
+        let format_version = r.u16()?;
+        if format_version != FORMAT_VERSION {
+            return Err(SaveStateError::UnsupportedVersion(format_version));
+        }
+        Ok(Self {
+            format_version,
+            emulator_version: r.str()?,
+            rom_hash: r.u64()?,
+        })
+    }
+}
+
+/// A 64-bit FNV-1a hash identifying a ROM.
+pub fn rom_hash(rom: &[u8]) -> u64 {
+    rom.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// The file quick save `slot` for the ROM at `rom` is kept in, next to the
+/// ROM: `game.ch8` saves slot 1 to `game.state1`.
+pub fn slot_path(rom: &Path, slot: u8) -> PathBuf {
+    rom.with_extension(format!("state{}", slot))
+}
+
+pub(crate) struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Self { bytes: Vec::new() }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Bytes whose length the reader already knows.
+    pub fn raw(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    /// Bytes preceded by their length.
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.raw(bytes);
+    }
+
+    pub fn str(&mut self, s: &str) {
+        self.bytes(s.as_bytes());
+    }
+
+    pub fn quirks(&mut self, quirks: &Quirks) {
+        self.bool(quirks.vf_reset);
+        self.u8(match quirks.memory {
+            MemoryQuirk::IncrementXPlusOne => 0,
+            MemoryQuirk::IncrementX => 1,
+            MemoryQuirk::Unchanged => 2,
+        });
+        self.bool(quirks.display_wait);
+        self.bool(quirks.clipping);
+        self.bool(quirks.shifting);
+        self.bool(quirks.jumping);
+    }
+}
+
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    /// Fail unless everything has been read.
+    pub fn finish(self) -> Result<(), SaveStateError> {
+        if self.bytes.is_empty() {
+            Ok(())
+        } else {
+            Err(SaveStateError::Corrupt)
+        }
+    }
+
+    pub fn raw(&mut self, len: usize) -> Result<&'a [u8], SaveStateError> {
+        if len > self.bytes.len() {
+            return Err(SaveStateError::Corrupt);
+        }
+        let (bytes, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N], SaveStateError> {
+        Ok(self.raw(N)?.try_into().unwrap())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, SaveStateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SaveStateError::Corrupt),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, SaveStateError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, SaveStateError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, SaveStateError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], SaveStateError> {
+        let len = self.u32()? as usize;
+        self.raw(len)
+    }
+
+    pub fn str(&mut self) -> Result<String, SaveStateError> {
+        let bytes = self.bytes()?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| SaveStateError::Corrupt)
+    }
+
+    pub fn quirks(&mut self) -> Result<Quirks, SaveStateError> {
+        Ok(Quirks {
+            vf_reset: self.bool()?,
+            memory: match self.u8()? {
+                0 => MemoryQuirk::IncrementXPlusOne,
+                1 => MemoryQuirk::IncrementX,
+                2 => MemoryQuirk::Unchanged,
+                _ => return Err(SaveStateError::Corrupt),
+            },
+            display_wait: self.bool()?,
+            clipping: self.bool()?,
+            shifting: self.bool()?,
+            jumping: self.bool()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{slot_path, Header, SaveStateError, Writer};
+    use std::path::Path;
+
+    #[test]
+    fn test_header() {
+        let mut w = Writer::new();
+        Header::new(42).write(&mut w);
+        let bytes = w.finish();
+        let header = Header::parse(&bytes).unwrap();
+        assert_eq!(header.rom_hash, 42);
+        assert_eq!(header.emulator_version, env!("CARGO_PKG_VERSION"));
+
+        assert_eq!(Header::parse(b"PNG"), Err(SaveStateError::NotASaveState));
+        let mut newer = bytes.clone();
+        newer[4] = 99;
+        assert_eq!(
+            Header::parse(&newer),
+            Err(SaveStateError::UnsupportedVersion(99))
+        );
+        assert_eq!(
+            Header::parse(&bytes[..bytes.len() - 1]),
+            Err(SaveStateError::Corrupt)
+        );
+    }
+
+    #[test]
+    fn test_slot_path() {
+        assert_eq!(
+            slot_path(Path::new("roms/pong.ch8"), 3),
+            Path::new("roms/pong.state3")
+        );
+    }
+}
@@ -0,0 +1,133 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The rate of the delay and sound timers, and of the display.
+pub const FRAME_RATE: f64 = 60.;
+/// A common CPU speed that most ROMs are comfortable with.
+pub const DEFAULT_CPU_HZ: f64 = 700.;
+/// How many frames we are willing to run back to back to catch up after a stall.
+const MAX_CATCH_UP_FRAMES: u32 = 5;
+
+/// Decides how many instructions run in each 60 Hz frame and when the next
+/// frame is due, keeping the CPU and timer clocks independent.
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    instructions_per_frame: f64,
+    instruction_debt: f64,
+    frame_duration: Duration,
+    next_frame: Option<Instant>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::with_cpu_hz(DEFAULT_CPU_HZ)
+    }
+}
+
+impl Scheduler {
+    /// Run the CPU at `hz` instructions per second.
+    pub fn with_cpu_hz(hz: f64) -> Self {
+        Self {
+            instructions_per_frame: hz.max(0.) / FRAME_RATE,
+            instruction_debt: 0.,
+            frame_duration: Duration::from_secs_f64(1. / FRAME_RATE),
+            next_frame: None,
+        }
+    }
+
+    /// Run exactly `n` instructions in every frame.
+    pub fn with_instructions_per_frame(n: u32) -> Self {
+        Self::with_cpu_hz(n as f64 * FRAME_RATE)
+    }
+
+    pub fn cpu_hz(&self) -> f64 {
+        self.instructions_per_frame * FRAME_RATE
+    }
+
+    /// The exact instruction budget, for recreating this schedule elsewhere
+    /// with `with_budget`.
+    pub(crate) fn budget(&self) -> (f64, f64) {
+        (self.instructions_per_frame, self.instruction_debt)
+    }
+
+    pub(crate) fn with_budget((instructions_per_frame, instruction_debt): (f64, f64)) -> Self {
+        Self {
+            instructions_per_frame,
+            instruction_debt,
+            ..Self::default()
+        }
+    }
+
+    /// The number of instructions to run in the next frame. Fractional
+    /// budgets are carried over so the long-run average matches the CPU speed.
+    pub fn instructions_for_frame(&mut self) -> u32 {
+        self.instruction_debt += self.instructions_per_frame;
+        let whole = self.instruction_debt.floor();
+        self.instruction_debt -= whole;
+        whole as u32
+    }
+
+    /// Sleep until at least one frame is due and return how many frames
+    /// should be run to stay in step with the wall clock.
+    pub fn wait_for_frames(&mut self) -> u32 {
+        loop {
+            let now = Instant::now();
+            match self.frames_due(now) {
+                0 => thread::sleep(self.next_frame.unwrap() - now),
+                frames => return frames,
+            }
+        }
+    }
+
+    /// The number of frames due at `now`. Deadlines advance by exactly one
+    /// frame duration each, so sleep inaccuracies don't accumulate as drift.
+    pub fn frames_due(&mut self, now: Instant) -> u32 {
+        let next_frame = *self.next_frame.get_or_insert(now);
+        if now < next_frame {
+            return 0;
+        }
+
+        let behind = (now - next_frame).as_secs_f64() / self.frame_duration.as_secs_f64();
+        let frames = behind as u32 + 1;
+        if frames > MAX_CATCH_UP_FRAMES {
+            // We fell too far behind (e.g. the window was dragged); resync
+            // instead of fast-forwarding through the backlog.
+            self.next_frame = Some(now + self.frame_duration);
+            return 1;
+        }
+        self.next_frame = Some(next_frame + self.frame_duration * frames);
+        frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Scheduler;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_fractional_instructions_per_frame() {
+        // 500 Hz is 8.33 instructions per frame
+        let mut scheduler = Scheduler::with_cpu_hz(500.);
+        let counts: Vec<u32> = (0..6).map(|_| scheduler.instructions_for_frame()).collect();
+        assert_eq!(counts.iter().sum::<u32>(), 50);
+        assert!(counts.iter().all(|&n| n == 8 || n == 9));
+    }
+
+    #[test]
+    fn test_frames_due() {
+        let mut scheduler = Scheduler::with_instructions_per_frame(10);
+        let start = Instant::now();
+        let frame = Duration::from_secs_f64(1. / 60.);
+
+        assert_eq!(scheduler.frames_due(start), 1);
+        assert_eq!(scheduler.frames_due(start + frame / 2), 0);
+        assert_eq!(scheduler.frames_due(start + frame * 3 + frame / 2), 3);
+        assert_eq!(scheduler.frames_due(start + frame * 4 - frame / 4), 0);
+        // Far behind: resync rather than running a burst of frames
+        assert_eq!(scheduler.frames_due(start + frame * 100), 1);
+        assert_eq!(scheduler.frames_due(start + frame * 100 + frame / 2), 0);
+    }
+}
@@ -0,0 +1,230 @@
+// The compatibility suite: the test ROMs kept in `test-roms/`, run
+// headlessly on every platform and compared against the displays they are
+// expected to end on. A ROM and platform pair without an expected image is
+// skipped, which is how ROMs for the extensions stay off older platforms.
+// The ROMs are built from the Octo sources beside them, and the expected
+// images come from `test-roms/reference.py`, not from here.
+use crate::display::Framebuffer;
+use crate::emulator::Emulator;
+use crate::error::EmulatorError;
+use crate::frontend::{NullAudio, NullVideo};
+use crate::headless::{self, KeyPress, KeyScript, Limits, Stop};
+use crate::image;
+use crate::platform::Platform;
+use crate::processor::Processor;
+use crate::rng::{Rng, RngMode};
+use crate::scheduler::Scheduler;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// A test ROM and how to drive it.
+#[derive(Debug, Clone, Copy)]
+pub struct Case {
+    pub name: &'static str,
+    /// Frames to give up after if the ROM hasn't finished.
+    pub frames: u64,
+    pub keys: &'static [KeyPress],
+}
+
+pub const CASES: [Case; 7] = [
+    Case::new("ibm", 60, &[]),
+    Case::new("opcodes", 300, &[]),
+    Case::new("flags", 300, &[]),
+    Case::new("quirks", 300, &[]),
+    Case::new(
+        "keypad",
+        300,
+        &[
+            KeyPress {
+                frame: 10,
+                key: 0x5,
+                frames: 2,
+            },
+            KeyPress {
+                frame: 30,
+                key: 0xa,
+                frames: 10,
+            },
+        ],
+    ),
+    Case::new("schip", 300, &[]),
+    Case::new("xochip", 300, &[]),
+];
+
+impl Case {
+    const fn new(name: &'static str, frames: u64, keys: &'static [KeyPress]) -> Self {
+        Self { name, frames, keys }
+    }
+
+    pub fn rom_path(&self, dir: &Path) -> PathBuf {
+        dir.join(format!("{}.ch8", self.name))
+    }
+
+    /// Where the display expected on `platform` is kept, as ASCII art.
+    pub fn expected_path(&self, dir: &Path, platform: &str) -> PathBuf {
+        dir.join("expected")
+            .join(format!("{}-{}.txt", self.name, platform))
+    }
+
+    /// Run `rom` on `platform` until it finishes, returning how it stopped
+    /// and the final display.
+    pub fn run(
+        &self,
+        rom: &[u8],
+        platform: Platform,
+    ) -> Result<(Stop, Framebuffer), EmulatorError> {
+        let scheduler = Scheduler::with_cpu_hz(platform.cpu_hz);
+        let mut processor = Processor::with_platform(platform);
+        processor.set_rng(Rng::new(RngMode::Xorshift, 1));
+        processor.load_rom(rom)?;
+        let mut emulator = Emulator::new(
+            processor,
+            Box::new(NullVideo),
+            Box::new(NullAudio),
+            Box::new(KeyScript::new(self.keys.to_vec())),
+        )
+        .with_scheduler(scheduler);
+        let limits = Limits {
+            frames: Some(self.frames),
+            timeout: Some(Duration::from_secs(10)),
+            stop_on_loop: true,
+        };
+        let stop = headless::run(&mut emulator, &limits, |_| {})?;
+        Ok((stop, emulator.processor().framebuffer().clone()))
+    }
+}
+
+#[derive(Debug)]
+pub enum Outcome {
+    Pass,
+    Fail,
+    /// The ROM never finished.
+    Stuck,
+    Error(EmulatorError),
+    /// There is no expected image for this platform.
+    Skipped,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Outcome::Pass => "pass",
+            Outcome::Fail => "FAIL",
+            Outcome::Stuck => "STUCK",
+            Outcome::Error(_) => "ERROR",
+            Outcome::Skipped => "-",
+        };
+        f.pad(s)
+    }
+}
+
+/// The outcome of every case on every platform.
+#[derive(Debug)]
+pub struct Report {
+    pub rows: Vec<(&'static str, Vec<Outcome>)>,
+}
+
+impl Report {
+    /// Whether nothing failed.
+    pub fn passed(&self) -> bool {
+        self.rows
+            .iter()
+            .flat_map(|(_, outcomes)| outcomes)
+            .all(|outcome| matches!(outcome, Outcome::Pass | Outcome::Skipped))
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:<10}", "")?;
+        for name in Platform::NAMES {
+            write!(f, "{:>9}", name)?;
+        }
+        for (name, outcomes) in &self.rows {
+            write!(f, "\n{:<10}", name)?;
+            for outcome in outcomes {
+                write!(f, "{:>9}", outcome)?;
+            }
+        }
+        for (name, outcomes) in &self.rows {
+            for (platform, outcome) in Platform::NAMES.iter().zip(outcomes) {
+                if let Outcome::Error(e) = outcome {
+                    write!(f, "\n{} on {}: {}", name, platform, e)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run every case in `dir` on every platform. With `update` set, the
+/// expected images are rewritten from the displays each ROM finishes on
+/// instead of being compared, which is only for looking at what the
+/// emulator draws; the committed images come from the reference
+/// interpreter.
+pub fn run(dir: &Path, update: bool) -> Result<Report, EmulatorError> {
+    let mut rows = Vec::new();
+    for case in CASES {
+        let rom = fs::read(case.rom_path(dir))?;
+        let mut outcomes = Vec::new();
+        for name in Platform::NAMES {
+            let path = case.expected_path(dir, name);
+            let expected = fs::read_to_string(&path).ok();
+            if expected.is_none() && !update {
+                outcomes.push(Outcome::Skipped);
+                continue;
+            }
+            let platform = Platform::by_name(name).unwrap();
+            let outcome = match case.run(&rom, platform) {
+                Ok((Stop::Halted | Stop::Loop { .. }, framebuffer)) if update => {
+                    fs::create_dir_all(path.parent().unwrap())?;
+                    fs::write(&path, image::to_ascii(&framebuffer))?;
+                    Outcome::Pass
+                }
+                Ok(_) | Err(_) if update => Outcome::Skipped,
+                Ok((Stop::Halted | Stop::Loop { .. }, framebuffer)) => {
+                    if expected.as_deref().and_then(image::from_ascii) == Some(framebuffer) {
+                        Outcome::Pass
+                    } else {
+                        Outcome::Fail
+                    }
+                }
+                Ok(_) => Outcome::Stuck,
+                Err(e) => Outcome::Error(e),
+            };
+            outcomes.push(outcome);
+        }
+        rows.push((case.name, outcomes));
+    }
+    Ok(Report { rows })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, CASES};
+    use crate::assembler::octo;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_suite() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test-roms");
+        let report = run(&dir, false).unwrap();
+        assert!(report.passed(), "\n{}", report);
+    }
+
+    #[test]
+    fn test_sources() {
+        // Every ROM is built from the source kept next to it
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test-roms");
+        for case in CASES {
+            let path = case.rom_path(&dir).with_extension("8o");
+            let source = fs::read_to_string(&path).unwrap();
+            let assembly = octo::compile(&source, &path).unwrap();
+            let rom = fs::read(case.rom_path(&dir)).unwrap();
+            assert_eq!(assembly.rom, rom, "{}", path.display());
+        }
+    }
+}